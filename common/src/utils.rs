@@ -6,3 +6,8 @@ pub const ZERO_ID: &str = match std::str::from_utf8(&[b'0'; 40]) {
     Ok(s) => s,
     Err(_) => panic!("can't get ZERO_ID"),
 };
+
+/// Whether `id` is the id made of zeros, of any object format.
+pub fn is_zero_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b == b'0')
+}
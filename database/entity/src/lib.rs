@@ -2,6 +2,7 @@
 
 pub mod prelude;
 
+pub mod access_token;
 pub mod commit;
 pub mod git_obj;
 pub mod locks;
@@ -9,7 +10,12 @@ pub mod meta;
 pub mod mr;
 pub mod mr_info;
 pub mod node;
+pub mod object_format;
+pub mod protected_ref;
 pub mod refs;
+pub mod ssh_key;
+pub mod symref;
+pub mod tag;
 pub mod issue;
 pub mod repo_directory;
 pub mod pull_request;
@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.3
 
+pub use super::access_token::Entity as AccessToken;
 pub use super::commit::Entity as Commit;
 pub use super::git_obj::Entity as GitObj;
 pub use super::locks::Entity as Locks;
@@ -7,6 +8,11 @@ pub use super::meta::Entity as Meta;
 pub use super::mr::Entity as Mr;
 pub use super::mr_info::Entity as MrInfo;
 pub use super::node::Entity as Node;
+pub use super::object_format::Entity as ObjectFormat;
+pub use super::protected_ref::Entity as ProtectedRef;
 pub use super::refs::Entity as Refs;
+pub use super::ssh_key::Entity as SshKey;
+pub use super::symref::Entity as Symref;
+pub use super::tag::Entity as Tag;
 pub use super::repo_directory::Entity as RepoDirectory;
 pub use super::pull_request::Entity as PullRequest;
@@ -9,8 +9,10 @@ use std::path::Path;
 
 use async_trait::async_trait;
 use chrono::DateTime;
+use chrono::NaiveDateTime;
 use chrono::Utc;
 
+use entity::access_token;
 use entity::commit;
 use entity::git_obj;
 use entity::issue;
@@ -19,7 +21,12 @@ use entity::meta;
 use entity::mr;
 use entity::mr_info;
 use entity::node;
+use entity::object_format;
+use entity::protected_ref;
 use entity::refs;
+use entity::ssh_key;
+use entity::symref;
+use entity::tag;
 use entity::pull_request;
 
 use entity::repo_directory;
@@ -31,12 +38,15 @@ use sea_orm::EntityTrait;
 use sea_orm::QueryFilter;
 use sea_orm::QuerySelect;
 use sea_orm::Set;
+use sea_orm::ActiveValue::NotSet;
+use sea_orm::TransactionTrait;
 
 use crate::driver::lfs::storage::MetaObject;
 use crate::driver::lfs::structs::Lock;
 use crate::driver::lfs::structs::RequestVars;
 use common::errors::GitLFSError;
 use common::errors::MegaError;
+use common::utils::is_zero_id;
 
 pub mod lfs;
 pub mod mysql;
@@ -66,6 +76,32 @@ pub trait ObjectStorage: Send + Sync {
             .unwrap())
     }
 
+    /// Removes the objects saved with a merge request that is dropped, e.g. a push whose pack
+    /// turned out to be corrupt. The `git_obj` rows saved along with the `mr` rows share their
+    /// ids, other copies of the same objects are kept.
+    async fn delete_mr_objects(&self, mr_id: i64) -> Result<(), MegaError> {
+        let txn = self.get_connection().begin().await?;
+        let ids: Vec<i64> = mr::Entity::find()
+            .filter(mr::Column::MrId.eq(mr_id))
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        for chunk in ids.chunks(1000) {
+            git_obj::Entity::delete_many()
+                .filter(git_obj::Column::Id.is_in(chunk.to_vec()))
+                .exec(&txn)
+                .await?;
+        }
+        mr::Entity::delete_many()
+            .filter(mr::Column::MrId.eq(mr_id))
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
     async fn save_mr_info(&self, mr_info: mr_info::ActiveModel) -> Result<bool, MegaError> {
         mr_info::Entity::insert(mr_info)
             .exec(self.get_connection())
@@ -119,7 +155,6 @@ pub trait ObjectStorage: Send + Sync {
     }
 
     async fn get_ref_object_id(&self, repo_path: &str) -> Result<Vec<refs::Model>, MegaError> {
-        // assuming HEAD points to branch master.
         Ok(refs::Entity::find()
             .filter(refs::Column::RepoPath.eq(repo_path))
             .all(self.get_connection())
@@ -192,6 +227,72 @@ pub trait ObjectStorage: Send + Sync {
             .unwrap();
     }
 
+    /// Applies the ref updates of a push in a single transaction, either all of them are
+    /// applied or none.
+    ///
+    /// An update only applies if the ref still points to `old_id`, which is the zero id for a
+    /// ref to create. A `new_id` of zero id deletes the ref. The row of the `tag` table of a
+    /// tag is replaced along with its ref.
+    async fn apply_ref_updates(
+        &self,
+        repo_path: &str,
+        updates: &[RefUpdate],
+    ) -> Result<(), RefUpdateError> {
+        let txn = self.get_connection().begin().await?;
+        for update in updates {
+            let current = refs::Entity::find()
+                .filter(refs::Column::RepoPath.eq(repo_path))
+                .filter(refs::Column::RefName.eq(update.ref_name.as_str()))
+                .lock_exclusive()
+                .one(&txn)
+                .await?;
+            let up_to_date = match &current {
+                None => is_zero_id(&update.old_id),
+                Some(r) => r.ref_git_id == update.old_id,
+            };
+            if !up_to_date {
+                // the transaction is rolled back when it's dropped
+                return Err(RefUpdateError::Stale(update.ref_name.clone()));
+            }
+            match current {
+                None if is_zero_id(&update.new_id) => {}
+                None => {
+                    refs::ActiveModel {
+                        id: NotSet,
+                        repo_path: Set(repo_path.to_owned()),
+                        ref_name: Set(update.ref_name.clone()),
+                        ref_git_id: Set(update.new_id.clone()),
+                        created_at: Set(chrono::Utc::now().naive_utc()),
+                        updated_at: Set(chrono::Utc::now().naive_utc()),
+                    }
+                    .insert(&txn)
+                    .await?;
+                }
+                Some(model) if is_zero_id(&update.new_id) => {
+                    refs::Entity::delete_by_id(model.id).exec(&txn).await?;
+                }
+                Some(model) => {
+                    let mut model: refs::ActiveModel = model.into();
+                    model.ref_git_id = Set(update.new_id.clone());
+                    model.updated_at = Set(chrono::Utc::now().naive_utc());
+                    model.update(&txn).await?;
+                }
+            }
+            if let Some(tag_name) = update.ref_name.strip_prefix("refs/tags/") {
+                tag::Entity::delete_many()
+                    .filter(tag::Column::RepoPath.eq(repo_path))
+                    .filter(tag::Column::TagName.eq(tag_name))
+                    .exec(&txn)
+                    .await?;
+                if let Some(tag) = &update.tag {
+                    tag.clone().insert(&txn).await?;
+                }
+            }
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
     async fn get_nodes_by_hashes(
         &self,
         hashes: Vec<String>,
@@ -240,6 +341,256 @@ pub trait ObjectStorage: Send + Sync {
             .unwrap();
         Ok(true)
     }
+
+    /// The tags of a repo, annotated or lightweight, see [`tag::Model`].
+    async fn get_tags_by_repo_path(&self, repo_path: &str) -> Result<Vec<tag::Model>, MegaError> {
+        Ok(tag::Entity::find()
+            .filter(tag::Column::RepoPath.eq(repo_path))
+            .all(self.get_connection())
+            .await
+            .unwrap())
+    }
+
+    /// The ref a symbolic ref like `HEAD` points to, e.g. `refs/heads/main`.
+    async fn get_symref(
+        &self,
+        repo_path: &str,
+        ref_name: &str,
+    ) -> Result<Option<symref::Model>, MegaError> {
+        Ok(symref::Entity::find()
+            .filter(symref::Column::RepoPath.eq(repo_path))
+            .filter(symref::Column::RefName.eq(ref_name))
+            .one(self.get_connection())
+            .await
+            .unwrap())
+    }
+
+    /// Points the symbolic ref `ref_name` to `target`, creating it if needed.
+    async fn save_symref(
+        &self,
+        repo_path: &str,
+        ref_name: &str,
+        target: &str,
+    ) -> Result<bool, MegaError> {
+        let now = chrono::Utc::now().naive_utc();
+        match self.get_symref(repo_path, ref_name).await? {
+            Some(model) => {
+                let mut model: symref::ActiveModel = model.into();
+                model.target = Set(target.to_owned());
+                model.updated_at = Set(now);
+                model.update(self.get_connection()).await.unwrap();
+            }
+            None => {
+                let model = symref::ActiveModel {
+                    id: NotSet,
+                    repo_path: Set(repo_path.to_owned()),
+                    ref_name: Set(ref_name.to_owned()),
+                    target: Set(target.to_owned()),
+                    created_at: Set(now),
+                    updated_at: Set(now),
+                };
+                model.insert(self.get_connection()).await.unwrap();
+            }
+        }
+        Ok(true)
+    }
+
+    /// The protection rules of a repo, see [`protected_ref::Model`].
+    async fn get_protected_refs(
+        &self,
+        repo_path: &str,
+    ) -> Result<Vec<protected_ref::Model>, MegaError> {
+        Ok(protected_ref::Entity::find()
+            .filter(protected_ref::Column::RepoPath.eq(repo_path))
+            .all(self.get_connection())
+            .await
+            .unwrap())
+    }
+
+    /// Saves a protection rule, replacing the rule of the repo with the same pattern.
+    async fn save_protected_ref(&self, rule: protected_ref::Model) -> Result<bool, MegaError> {
+        let now = chrono::Utc::now().naive_utc();
+        let existing = protected_ref::Entity::find()
+            .filter(protected_ref::Column::RepoPath.eq(&rule.repo_path))
+            .filter(protected_ref::Column::RefPattern.eq(&rule.ref_pattern))
+            .one(self.get_connection())
+            .await
+            .unwrap();
+        let mut model: protected_ref::ActiveModel = rule.into();
+        model.updated_at = Set(now);
+        match existing {
+            Some(existing) => {
+                model.id = Set(existing.id);
+                model.created_at = Set(existing.created_at);
+                model.reset_all().update(self.get_connection()).await.unwrap();
+            }
+            None => {
+                model.id = NotSet;
+                model.created_at = Set(now);
+                model.reset_all().insert(self.get_connection()).await.unwrap();
+            }
+        }
+        Ok(true)
+    }
+
+    async fn delete_protected_ref(
+        &self,
+        repo_path: &str,
+        ref_pattern: &str,
+    ) -> Result<bool, MegaError> {
+        let res = protected_ref::Entity::delete_many()
+            .filter(protected_ref::Column::RepoPath.eq(repo_path))
+            .filter(protected_ref::Column::RefPattern.eq(ref_pattern))
+            .exec(self.get_connection())
+            .await
+            .unwrap();
+        Ok(res.rows_affected > 0)
+    }
+
+    /// Registers the public key of a user, `public_key` is the line of its `.pub` file.
+    async fn save_ssh_key(
+        &self,
+        user_name: &str,
+        title: &str,
+        public_key: &str,
+        fingerprint: &str,
+    ) -> Result<ssh_key::Model, MegaError> {
+        let model = ssh_key::ActiveModel {
+            id: NotSet,
+            user_name: Set(user_name.to_owned()),
+            title: Set(title.to_owned()),
+            public_key: Set(public_key.to_owned()),
+            fingerprint: Set(fingerprint.to_owned()),
+            created_at: Set(chrono::Utc::now().naive_utc()),
+        };
+        Ok(model.insert(self.get_connection()).await.unwrap())
+    }
+
+    /// The registered key with this SHA-256 fingerprint, it identifies an SSH user.
+    async fn get_ssh_key_by_fingerprint(
+        &self,
+        fingerprint: &str,
+    ) -> Result<Option<ssh_key::Model>, MegaError> {
+        Ok(ssh_key::Entity::find()
+            .filter(ssh_key::Column::Fingerprint.eq(fingerprint))
+            .one(self.get_connection())
+            .await
+            .unwrap())
+    }
+
+    /// The keys of a user, or of every user if `user_name` is `None`.
+    async fn list_ssh_keys(
+        &self,
+        user_name: Option<&str>,
+    ) -> Result<Vec<ssh_key::Model>, MegaError> {
+        let mut query = ssh_key::Entity::find();
+        if let Some(user_name) = user_name {
+            query = query.filter(ssh_key::Column::UserName.eq(user_name));
+        }
+        Ok(query.all(self.get_connection()).await.unwrap())
+    }
+
+    async fn delete_ssh_key(&self, id: i32) -> Result<bool, MegaError> {
+        let res = ssh_key::Entity::delete_by_id(id)
+            .exec(self.get_connection())
+            .await
+            .unwrap();
+        Ok(res.rows_affected > 0)
+    }
+
+    /// Saves a personal access token of a user, only its SHA-256 hash is stored.
+    async fn save_access_token(
+        &self,
+        user_name: &str,
+        name: &str,
+        token: &str,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Result<access_token::Model, MegaError> {
+        let model = access_token::ActiveModel {
+            id: NotSet,
+            user_name: Set(user_name.to_owned()),
+            name: Set(name.to_owned()),
+            token_hash: Set(sha256::digest(token)),
+            created_at: Set(chrono::Utc::now().naive_utc()),
+            expires_at: Set(expires_at),
+        };
+        Ok(model.insert(self.get_connection()).await.unwrap())
+    }
+
+    /// The saved token matching `token`, expired or not.
+    async fn get_access_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<access_token::Model>, MegaError> {
+        Ok(access_token::Entity::find()
+            .filter(access_token::Column::TokenHash.eq(sha256::digest(token)))
+            .one(self.get_connection())
+            .await
+            .unwrap())
+    }
+
+    /// The tokens of a user, or of every user if `user_name` is `None`.
+    async fn list_access_tokens(
+        &self,
+        user_name: Option<&str>,
+    ) -> Result<Vec<access_token::Model>, MegaError> {
+        let mut query = access_token::Entity::find();
+        if let Some(user_name) = user_name {
+            query = query.filter(access_token::Column::UserName.eq(user_name));
+        }
+        Ok(query.all(self.get_connection()).await.unwrap())
+    }
+
+    async fn delete_access_token(&self, id: i32) -> Result<bool, MegaError> {
+        let res = access_token::Entity::delete_by_id(id)
+            .exec(self.get_connection())
+            .await
+            .unwrap();
+        Ok(res.rows_affected > 0)
+    }
+
+    /// The object format recorded for a repo, or for the repo owning it when `repo_path` is a
+    /// folder of a monorepo: the recorded path that is the longest prefix of `repo_path` wins.
+    /// `None` when no such repo ever received a push.
+    async fn get_object_format(&self, repo_path: &str) -> Result<Option<String>, MegaError> {
+        let paths: Vec<String> = Path::new(repo_path)
+            .ancestors()
+            .filter_map(|p| p.to_str())
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(object_format::Entity::find()
+            .filter(object_format::Column::RepoPath.is_in(paths))
+            .all(self.get_connection())
+            .await?
+            .into_iter()
+            .max_by_key(|m| m.repo_path.len())
+            .map(|m| m.format))
+    }
+
+    /// Records the object format of a repo, unless a concurrent push recorded one first.
+    /// Returns the format that ends up recorded for the repo.
+    async fn save_object_format(&self, repo_path: &str, format: &str) -> Result<String, MegaError> {
+        let inserted = object_format::ActiveModel {
+            id: NotSet,
+            repo_path: Set(repo_path.to_owned()),
+            format: Set(format.to_owned()),
+            created_at: Set(chrono::Utc::now().naive_utc()),
+        }
+        .insert(self.get_connection())
+        .await;
+        match inserted {
+            Ok(model) => Ok(model.format),
+            // the unique key on the repo path was hit, the other push won the race
+            Err(e) => object_format::Entity::find()
+                .filter(object_format::Column::RepoPath.eq(repo_path))
+                .one(self.get_connection())
+                .await?
+                .map(|m| m.format)
+                .ok_or_else(|| e.into()),
+        }
+    }
+
     async fn search_root_node_by_path(&self, repo_path: &Path) -> Option<node::Model> {
         tracing::debug!("file_name: {:?}", repo_path.file_name());
         let res = node::Entity::find()
@@ -458,7 +809,7 @@ pub trait ObjectStorage: Send + Sync {
     async fn lfs_delete_lock(
         &self,
         repo: &str,
-        _user: Option<String>,
+        user: Option<String>,
         id: &str,
         force: bool,
     ) -> Result<Lock, GitLFSError> {
@@ -491,7 +842,12 @@ pub trait ObjectStorage: Send + Sync {
 
                 for lock in locks_from_data.iter() {
                     if lock.id == *id {
-                        if Option::is_some(&lock.owner) && !force {
+                        // only the owner of a lock may remove it without forcing
+                        let owned = match &lock.owner {
+                            Some(owner) => user.as_ref() == Some(&owner.name),
+                            None => true,
+                        };
+                        if !owned && !force {
                             return Err(GitLFSError::GeneralError("".to_string()));
                         }
                         lock_to_delete.id = lock.id.to_owned();
@@ -608,6 +964,38 @@ pub trait ObjectStorage: Send + Sync {
 
 }
 
+/// A ref update of a push, see [`ObjectStorage::apply_ref_updates`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdate {
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+    /// The row of the `tag` table a created or moved tag gets.
+    pub tag: Option<tag::ActiveModel>,
+}
+
+#[derive(Debug)]
+pub enum RefUpdateError {
+    /// The ref doesn't point to the expected old id, it was updated by someone else.
+    Stale(String),
+    Db(DbErr),
+}
+
+impl std::fmt::Display for RefUpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RefUpdateError::Stale(ref_name) => write!(f, "{} has been updated meanwhile", ref_name),
+            RefUpdateError::Db(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<DbErr> for RefUpdateError {
+    fn from(err: DbErr) -> Self {
+        RefUpdateError::Db(err)
+    }
+}
+
 /// Performs batch saving of models in the database.
 ///
 /// The method takes a vector of models to be saved and performs batch inserts using the given entity type `E`.
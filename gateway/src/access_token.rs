@@ -0,0 +1,98 @@
+//!
+//!
+//!
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use database::DataSource;
+use rand::distributions::{Alphanumeric, DistString};
+
+// the tokens are recognizable by this prefix, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "mega_";
+
+#[derive(Args, Clone, Debug)]
+pub struct AccessTokenOptions {
+    #[command(subcommand)]
+    pub command: AccessTokenCommand,
+
+    #[arg(short, long, value_enum, default_value = "postgres", global = true)]
+    pub data_source: DataSource,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum AccessTokenCommand {
+    /// Create a personal access token for a user, it's only printed once
+    Add {
+        /// The user the token authenticates as
+        #[arg(long)]
+        user: String,
+
+        /// A name to recognize the token
+        #[arg(long)]
+        name: String,
+
+        /// Expire the token after that many days, never by default
+        #[arg(long)]
+        expires_in_days: Option<i64>,
+    },
+    /// List the tokens
+    List {
+        /// Only list the tokens of this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Revoke a token
+    Remove {
+        /// The id of the token, as listed
+        id: i32,
+    },
+}
+
+/// manage the personal access tokens of the http server
+pub async fn run(options: &AccessTokenOptions) -> Result<()> {
+    let storage = database::init(&options.data_source).await;
+    match &options.command {
+        AccessTokenCommand::Add {
+            user,
+            name,
+            expires_in_days,
+        } => {
+            let token = format!(
+                "{}{}",
+                TOKEN_PREFIX,
+                Alphanumeric.sample_string(&mut rand::thread_rng(), 40)
+            );
+            let expires_at = expires_in_days
+                .map(|days| chrono::Utc::now().naive_utc() + chrono::Duration::days(days));
+            let access_token = storage
+                .save_access_token(user, name, &token, expires_at)
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?;
+            println!("added token {} for {}: {}", access_token.id, user, token);
+        }
+        AccessTokenCommand::List { user } => {
+            let tokens = storage
+                .list_access_tokens(user.as_deref())
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?;
+            for token in tokens {
+                let expires_at = token
+                    .expires_at
+                    .map_or(String::from("never"), |t| t.to_string());
+                println!(
+                    "{}\t{}\t{}\texpires {}",
+                    token.id, token.user_name, token.name, expires_at
+                );
+            }
+        }
+        AccessTokenCommand::Remove { id } => {
+            let deleted = storage
+                .delete_access_token(*id)
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?;
+            if !deleted {
+                bail!("no token with id {}", id);
+            }
+        }
+    }
+    Ok(())
+}
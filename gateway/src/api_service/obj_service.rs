@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use axum::body::Full;
@@ -7,13 +7,20 @@ use axum::response::{IntoResponse, Json};
 use axum::{http::StatusCode, response::Response};
 
 use database::driver::ObjectStorage;
+use git::errors::GitError;
+use git::hash::{sync_with_hash_kind, HashKind};
 use git::internal::object::commit::Commit;
 use git::internal::object::tree::Tree;
 use git::internal::object::ObjectT;
+use git::protocol::archive::{ArchiveFormat, ArchiveOptions};
+use git::protocol::{http, PackProtocol, Protocol};
 use hyper::body::Bytes;
+use hyper::Body;
 
-use crate::model::object_detail::{BlobObjects, Directories, Item};
-use crate::model::query::DirectoryQuery;
+use crate::model::object_detail::{
+    BlobObjects, DefaultBranch, Directories, Item, ProtectedRef, Tags,
+};
+use crate::model::query::{ArchiveQuery, DirectoryQuery};
 
 pub struct ObjectService {
     pub storage: Arc<dyn ObjectStorage>,
@@ -117,7 +124,13 @@ impl ObjectService {
             _ => return Err((StatusCode::NOT_FOUND, "Tree not found".to_string())),
         };
 
-        let tree = Tree::new_from_data(tree_data);
+        // the ids of the tree items are as long as the id of the tree
+        let kind = if HashKind::Sha256.is_object_id(object_id) {
+            HashKind::Sha256
+        } else {
+            HashKind::Sha1
+        };
+        let tree = sync_with_hash_kind(kind, || Tree::new_from_data(tree_data));
         let child_ids = tree
             .tree_items
             .iter()
@@ -155,6 +168,167 @@ impl ObjectService {
         Ok(Json(data))
     }
 
+    pub async fn get_tags(&self, repo_path: &str) -> Result<Json<Tags>, (StatusCode, String)> {
+        let mut tags = match self.storage.get_tags_by_repo_path(repo_path).await {
+            Ok(tags) => tags,
+            Err(_) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Can not read the tags".to_string(),
+                ))
+            }
+        };
+        tags.sort_by(|a, b| a.tag_name.cmp(&b.tag_name));
+        let items = tags.into_iter().map(|x| x.into()).collect();
+        Ok(Json(Tags { items }))
+    }
+
+    pub async fn get_default_branch(
+        &self,
+        repo_path: &str,
+    ) -> Result<Json<DefaultBranch>, (StatusCode, String)> {
+        let pack_protocol = self.pack_protocol(repo_path);
+        let branch = pack_protocol.get_head_ref(&pack_protocol.path).await;
+        Ok(Json(DefaultBranch {
+            repo_path: repo_path.to_owned(),
+            branch,
+        }))
+    }
+
+    pub async fn set_default_branch(
+        &self,
+        payload: DefaultBranch,
+    ) -> Result<Json<DefaultBranch>, (StatusCode, String)> {
+        let Some(branch) = payload.branch else {
+            return Err((StatusCode::BAD_REQUEST, "branch is required".to_string()));
+        };
+        let pack_protocol = self.pack_protocol(&payload.repo_path);
+        match pack_protocol
+            .set_default_branch(&pack_protocol.path, &branch)
+            .await
+        {
+            Ok(target) => Ok(Json(DefaultBranch {
+                repo_path: payload.repo_path,
+                branch: Some(target),
+            })),
+            Err(e) => Err((StatusCode::NOT_FOUND, e.to_string())),
+        }
+    }
+
+    pub async fn get_protected_refs(
+        &self,
+        repo_path: &str,
+    ) -> Result<Json<Vec<ProtectedRef>>, (StatusCode, String)> {
+        match self.storage.get_protected_refs(repo_path).await {
+            Ok(rules) => Ok(Json(rules.into_iter().map(|x| x.into()).collect())),
+            Err(_) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Can not read the protected refs".to_string(),
+            )),
+        }
+    }
+
+    pub async fn save_protected_ref(
+        &self,
+        rule: ProtectedRef,
+    ) -> Result<Json<ProtectedRef>, (StatusCode, String)> {
+        if !rule.ref_pattern.starts_with("refs/") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "ref_pattern must start with refs/".to_string(),
+            ));
+        }
+        let repo_path = rule.repo_path.clone();
+        let ref_pattern = rule.ref_pattern.clone();
+        if self.storage.save_protected_ref(rule.into()).await.is_err() {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Can not save the protected ref".to_string(),
+            ));
+        }
+        let rules = self.storage.get_protected_refs(&repo_path).await.unwrap();
+        match rules.into_iter().find(|x| x.ref_pattern == ref_pattern) {
+            Some(rule) => Ok(Json(rule.into())),
+            None => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Can not save the protected ref".to_string(),
+            )),
+        }
+    }
+
+    pub async fn delete_protected_ref(
+        &self,
+        repo_path: &str,
+        ref_pattern: &str,
+    ) -> Result<StatusCode, (StatusCode, String)> {
+        match self
+            .storage
+            .delete_protected_ref(repo_path, ref_pattern)
+            .await
+        {
+            Ok(true) => Ok(StatusCode::NO_CONTENT),
+            _ => Err((StatusCode::NOT_FOUND, "Protected ref not found".to_string())),
+        }
+    }
+
+    /// Streams a `tar`, `tar.gz` or `zip` archive of the repo at a ref or a commit, the entries
+    /// are in a `<repo>-<ref>/` folder like the source tarballs of a release.
+    pub async fn get_archive(
+        &self,
+        query: ArchiveQuery,
+    ) -> Result<Response<Body>, (StatusCode, String)> {
+        let ArchiveQuery {
+            repo_path,
+            git_ref,
+            format,
+        } = query;
+        let format: ArchiveFormat = format
+            .parse()
+            .map_err(|e: GitError| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let repo_name = repo_path
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .unwrap_or("repo");
+        let short_ref = git_ref
+            .trim_start_matches("refs/")
+            .trim_start_matches("heads/")
+            .trim_start_matches("tags/");
+        let name = format!("{}-{}", repo_name, short_ref.replace('/', "-"));
+        let options = ArchiveOptions {
+            format,
+            tree_ish: git_ref.clone(),
+            prefix: format!("{}/", name),
+            ..Default::default()
+        };
+
+        let pack_protocol = self.pack_protocol(&repo_path);
+        let stream = match pack_protocol.get_archive_stream(options).await {
+            Ok(stream) => stream,
+            Err(e) => return Err((StatusCode::NOT_FOUND, e.to_string())),
+        };
+        // without side-band capabilities the archive is sent as it is
+        let (sender, body) = Body::channel();
+        tokio::spawn(http::send_pack(sender, stream, pack_protocol));
+
+        let file_name = format!("attachment; filename=\"{}.{}\"", name, format.extension());
+        let res = Response::builder()
+            .header("Content-Type", format.content_type())
+            .header("Content-Disposition", file_name)
+            .body(body)
+            .unwrap();
+        Ok(res)
+    }
+
+    fn pack_protocol(&self, repo_path: &str) -> PackProtocol {
+        PackProtocol::new(
+            PathBuf::from(repo_path),
+            self.storage.clone(),
+            Protocol::Http,
+        )
+    }
+
     pub async fn get_objects_data(
         &self,
         object_id: &str,
@@ -0,0 +1,211 @@
+//!
+//! Authentication of the HTTP requests with the personal access tokens of the users.
+//!
+//! A token is sent either as the password of HTTP Basic, which is what the git credential
+//! helpers do, or as a Bearer token. The user name of Basic is ignored, the token alone
+//! identifies the user. Reads stay anonymous, the requests that write (receive-pack, the LFS
+//! uploads and locks, the write methods of `/api/v1`) are answered with a 401 challenge until
+//! they carry a valid token.
+//!
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap, Method, Request, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::https::AppState;
+
+const CHALLENGE: &str = "Basic realm=\"Mega\", charset=\"UTF-8\"";
+
+/// The user a request is authenticated as, `None` for an anonymous request.
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    pub user: Option<String>,
+}
+
+impl Identity {
+    /// Whether the user is one of the `admins`, an anonymous request never is.
+    pub fn is_admin(&self, admins: &[String]) -> bool {
+        self.user.as_ref().is_some_and(|user| admins.contains(user))
+    }
+}
+
+/// Verifies the token of the request, if any, and adds its [`Identity`] to the extensions.
+pub async fn authenticate<B>(
+    State(state): State<AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let user = match parse_authorization(req.headers()) {
+        Ok(None) => None,
+        Ok(Some(token)) => match state.storage.get_access_token(&token).await {
+            Ok(Some(access_token))
+                if access_token
+                    .expires_at
+                    .is_none_or(|t| t > chrono::Utc::now().naive_utc()) =>
+            {
+                Some(access_token.user_name)
+            }
+            Ok(_) => return unauthorized("Invalid or expired token\n"),
+            Err(err) => {
+                tracing::error!("authenticate: {:?}", err);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+        Err(msg) => return unauthorized(msg),
+    };
+    if user.is_none() && requires_auth(req.method(), req.uri()) {
+        return unauthorized("Authentication required\n");
+    }
+    req.extensions_mut().insert(Identity { user });
+    next.run(req).await
+}
+
+fn unauthorized(msg: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, CHALLENGE)],
+        msg.to_owned(),
+    )
+        .into_response()
+}
+
+/// The token of the `Authorization` header, `None` if the request has no such header.
+fn parse_authorization(headers: &HeaderMap) -> Result<Option<String>, &'static str> {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| "Invalid authorization header\n")?;
+    let (scheme, credentials) = value.trim().split_once(' ').unwrap_or((value, ""));
+    let token = if scheme.eq_ignore_ascii_case("bearer") {
+        credentials.trim().to_owned()
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|d| String::from_utf8(d).ok())
+            .ok_or("Invalid basic credentials\n")?;
+        let (_user, password) = decoded
+            .split_once(':')
+            .ok_or("Invalid basic credentials\n")?;
+        password.to_owned()
+    } else {
+        return Err("Unsupported authorization scheme\n");
+    };
+    if token.is_empty() {
+        return Err("Empty token\n");
+    }
+    Ok(Some(token))
+}
+
+/// Whether the request writes to a repo, or its LFS store, or through the API.
+fn requires_auth(method: &Method, uri: &Uri) -> bool {
+    let path = uri.path();
+    if path.starts_with("/api/v1/") {
+        return ![Method::GET, Method::HEAD, Method::OPTIONS].contains(method);
+    }
+    // the ref discovery of a push, so that git asks for the credentials before sending the pack
+    let receive_pack_refs = path.ends_with("/info/refs")
+        && uri
+            .query()
+            .is_some_and(|q| q.split('&').any(|p| p == "service=git-receive-pack"));
+    let lfs_write = match *method {
+        Method::PUT => path.contains("/objects/"),
+        Method::POST => {
+            path.ends_with("/locks") || path.ends_with("/locks/verify") || path.ends_with("/unlock")
+        }
+        _ => false,
+    };
+    receive_pack_refs || path.ends_with("/git-receive-pack") || lfs_write
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue, Method, Uri};
+
+    use super::{parse_authorization, requires_auth, Identity};
+
+    #[test]
+    fn test_parse_authorization() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_authorization(&headers), Ok(None));
+        // base64 of "alice:mega_token"
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic YWxpY2U6bWVnYV90b2tlbg=="),
+        );
+        assert_eq!(
+            parse_authorization(&headers),
+            Ok(Some("mega_token".to_owned()))
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer mega_token"),
+        );
+        assert_eq!(
+            parse_authorization(&headers),
+            Ok(Some("mega_token".to_owned()))
+        );
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic !!"));
+        assert!(parse_authorization(&headers).is_err());
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Digest x"));
+        assert!(parse_authorization(&headers).is_err());
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let admins = vec!["alice".to_owned()];
+        let identity = |user: Option<&str>| Identity {
+            user: user.map(String::from),
+        };
+        assert!(identity(Some("alice")).is_admin(&admins));
+        assert!(!identity(Some("bob")).is_admin(&admins));
+        assert!(!identity(None).is_admin(&admins));
+        assert!(!identity(Some("alice")).is_admin(&[]));
+    }
+
+    #[test]
+    fn test_requires_auth() {
+        let uri = |s: &'static str| Uri::from_static(s);
+        assert!(!requires_auth(
+            &Method::GET,
+            &uri("/mega.git/info/refs?service=git-upload-pack")
+        ));
+        assert!(requires_auth(
+            &Method::GET,
+            &uri("/mega.git/info/refs?service=git-receive-pack")
+        ));
+        assert!(!requires_auth(
+            &Method::POST,
+            &uri("/mega.git/git-upload-pack")
+        ));
+        assert!(requires_auth(
+            &Method::POST,
+            &uri("/mega.git/git-receive-pack")
+        ));
+        assert!(!requires_auth(
+            &Method::GET,
+            &uri("/mega.git/info/lfs/objects/1a2b")
+        ));
+        assert!(requires_auth(
+            &Method::PUT,
+            &uri("/mega.git/info/lfs/objects/1a2b")
+        ));
+        assert!(requires_auth(
+            &Method::POST,
+            &uri("/mega.git/info/lfs/locks")
+        ));
+        assert!(!requires_auth(
+            &Method::GET,
+            &uri("/api/v1/tags?repo_path=/mega")
+        ));
+        assert!(requires_auth(
+            &Method::DELETE,
+            &uri("/api/v1/protected_refs")
+        ));
+    }
+}
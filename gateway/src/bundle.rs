@@ -0,0 +1,87 @@
+//!
+//!
+//!
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use database::DataSource;
+
+use git::protocol::hooks::PushHooks;
+use git::protocol::{PackProtocol, Protocol};
+
+#[derive(Args, Clone, Debug)]
+pub struct BundleOptions {
+    #[command(subcommand)]
+    pub command: BundleCommand,
+
+    #[arg(short, long, value_enum, default_value = "postgres", global = true)]
+    pub data_source: DataSource,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum BundleCommand {
+    /// Export a repo to a bundle file
+    Export {
+        /// The path of the repo in Mega
+        #[arg(long)]
+        repo_path: PathBuf,
+
+        #[arg(short, long, value_name = "FILE")]
+        file: PathBuf,
+
+        /// The refs to export, all the refs of the repo by default
+        #[arg(long = "ref", value_name = "REF")]
+        refs: Vec<String>,
+
+        /// Commits the receiver already has, they are left out of the bundle
+        #[arg(long, value_name = "COMMIT")]
+        basis: Vec<String>,
+    },
+    /// Import a bundle file into a repo
+    Import {
+        /// The path of the repo in Mega
+        #[arg(long)]
+        repo_path: PathBuf,
+
+        #[arg(short, long, value_name = "FILE")]
+        file: PathBuf,
+    },
+}
+
+/// export or import a bundle
+pub async fn run(options: &BundleOptions) -> Result<()> {
+    let storage = database::init(&options.data_source).await;
+    match &options.command {
+        BundleCommand::Export {
+            repo_path,
+            file,
+            refs,
+            basis,
+        } => {
+            let pack_protocol = PackProtocol::new(repo_path.clone(), storage, Protocol::Local);
+            let basis: HashSet<String> = basis.iter().cloned().collect();
+            let mut writer = tokio::io::BufWriter::new(tokio::fs::File::create(file).await?);
+            let header = pack_protocol
+                .create_bundle(refs, &basis, &mut writer)
+                .await?;
+            for (id, name) in &header.refs {
+                tracing::info!("exported {} {}", id, name);
+            }
+        }
+        BundleCommand::Import { repo_path, file } => {
+            let mut pack_protocol = PackProtocol::new(repo_path.clone(), storage, Protocol::Local);
+            pack_protocol.hooks = PushHooks::from_env();
+            let reader = std::fs::File::open(file)?;
+            let command_list = pack_protocol.import_bundle(reader).await?;
+            for command in &command_list {
+                tracing::info!("{}", command.get_status());
+            }
+            if command_list.iter().any(|c| !c.is_ok()) {
+                anyhow::bail!("some refs of the bundle were not imported");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//!
+//!
+//!
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use database::DataSource;
+
+use git::protocol::{PackProtocol, Protocol};
+use git::structure::fsck::FsckReport;
+
+#[derive(Args, Clone, Debug)]
+pub struct FsckOptions {
+    /// The path of the repo in Mega
+    pub repo_path: PathBuf,
+
+    /// Write the report to this file instead of the standard output
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    pub data_source: DataSource,
+}
+
+/// check the consistency of a repo and print the report as JSON
+pub async fn run(options: &FsckOptions) -> Result<FsckReport> {
+    let storage = database::init(&options.data_source).await;
+    let pack_protocol = PackProtocol::new(options.repo_path.clone(), storage, Protocol::Local);
+    let report = pack_protocol.fsck().await?;
+    let json = serde_json::to_string_pretty(&report)?;
+    match &options.output {
+        Some(file) => std::fs::write(file, json + "\n")?,
+        None => println!("{}", json),
+    }
+    Ok(report)
+}
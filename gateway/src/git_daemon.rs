@@ -0,0 +1,45 @@
+//!
+//!
+//!
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::Result;
+use clap::Args;
+use database::DataSource;
+use tokio::net::TcpListener;
+
+#[derive(Args, Clone, Debug)]
+pub struct GitDaemonOptions {
+    #[arg(long, default_value_t = String::from("127.0.0.1"))]
+    host: String,
+
+    #[arg(short, long, default_value_t = 9418)]
+    port: u16,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    pub data_source: DataSource,
+}
+
+/// start a git:// server, it serves clones and fetches only
+pub async fn server(options: &GitDaemonOptions) -> Result<(), std::io::Error> {
+    let GitDaemonOptions {
+        host,
+        port,
+        data_source,
+    } = options;
+    let storage = database::init(data_source).await;
+    let server_url = format!("{}:{}", host, port);
+    let addr = SocketAddr::from_str(&server_url).unwrap();
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("git daemon listening on {}", addr);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            if let Err(e) = git::protocol::daemon::serve(stream, storage).await {
+                tracing::error!("git daemon connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
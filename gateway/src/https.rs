@@ -12,22 +12,26 @@ use std::{net::SocketAddr, sync::Arc};
 use anyhow::Result;
 
 use axum::extract::{Query, State};
+use axum::middleware;
 use axum::response::Response;
 use axum::routing::get;
-use axum::{Router, Server};
+use axum::{Extension, Router, Server};
 use clap::Args;
 use database::driver::lfs::structs::LockListQuery;
 use database::driver::ObjectStorage;
 use database::DataSource;
 use git::lfs::{self, LfsConfig};
+use git::protocol::hooks::PushHooks;
 use git::protocol::{http, ServiceType};
 use git::protocol::{PackProtocol, Protocol};
-use hyper::{Body, Request, StatusCode, Uri};
+use hyper::{Body, HeaderMap, Request, StatusCode, Uri};
 use regex::Regex;
 use serde::Deserialize;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 
+use crate::auth::{self, Identity};
+
 /// Parameters for starting the HTTP service
 #[derive(Args, Clone, Debug)]
 pub struct HttpOptions {
@@ -49,12 +53,17 @@ pub struct HttpOptions {
 
     #[arg(short, long, value_enum, default_value = "postgres")]
     pub data_source: DataSource,
+
+    /// The users allowed to manage the protected refs, separated by commas. Nobody is by default
+    #[arg(long, value_delimiter = ',')]
+    pub admins: Vec<String>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub storage: Arc<dyn ObjectStorage>,
     pub options: HttpOptions,
+    pub hooks: PushHooks,
 }
 
 #[derive(Deserialize, Debug)]
@@ -79,6 +88,7 @@ pub async fn http_server(options: &HttpOptions) -> Result<(), Box<dyn std::error
         cert_path: _,
         lfs_content_path: _,
         data_source,
+        admins: _,
     } = options;
     let server_url = format!("{}:{}", host, port);
 
@@ -86,6 +96,7 @@ pub async fn http_server(options: &HttpOptions) -> Result<(), Box<dyn std::error
     let state = AppState {
         storage:database::init(data_source).await,
         options: options.to_owned(),
+        hooks: PushHooks::from_env(),
     };
     let app = Router::new()
         .nest("/api/v1", api_routers::routers(state.clone()))
@@ -95,6 +106,10 @@ pub async fn http_server(options: &HttpOptions) -> Result<(), Box<dyn std::error
                 .post(post_method_router)
                 .put(put_method_router),
         )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
         .layer(ServiceBuilder::new().layer(CorsLayer::new().allow_origin(Any)))
         .with_state(state);
 
@@ -106,11 +121,14 @@ pub async fn http_server(options: &HttpOptions) -> Result<(), Box<dyn std::error
 
 async fn get_method_router(
     state: State<AppState>,
+    Extension(identity): Extension<Identity>,
     Query(params): Query<GetParams>,
+    headers: HeaderMap,
     uri: Uri,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let mut lfs_config: LfsConfig = state.options.clone().into();
     lfs_config.storage = state.storage.clone();
+    lfs_config.user = identity.user;
 
     // Routing LFS services.
     if Regex::new(r"/objects/[a-z0-9]+$")
@@ -134,8 +152,32 @@ async fn get_method_router(
         return lfs::http::lfs_retrieve_lock(&lfs_config, lock_list_query).await;
     }
 
-    let service_name = params.service.unwrap();
+    // Clients of the dumb protocol read the files of the repo without a service parameter.
+    let Some(service_name) = params.service else {
+        // the loose objects of sha1 and sha256 repos
+        let dumb_file = Regex::new(concat!(
+            r"/(info/refs|HEAD|objects/info/packs",
+            r"|objects/[0-9a-f]{2}/([0-9a-f]{38}|[0-9a-f]{62}))$"
+        ))
+        .unwrap();
+        let Some(file) = dumb_file.captures(uri.path()).map(|c| c[1].to_owned()) else {
+            return Err((StatusCode::NOT_FOUND, String::from("Not found\n")));
+        };
+        let pack_protocol = PackProtocol::new(
+            remove_git_suffix(uri, &format!("/{}", file)),
+            state.storage.clone(),
+            Protocol::Http,
+        );
+        return http::git_dumb_request(pack_protocol, &file).await;
+    };
     let service_type = service_name.parse::<ServiceType>().unwrap();
+    // like git http-backend, archives are only served over ssh and by the archive api
+    if service_type == ServiceType::UploadArchive {
+        return Err((
+            StatusCode::FORBIDDEN,
+            String::from("Operation not supported\n"),
+        ));
+    }
 
     // # Discovering Reference
     // HTTP clients that support the "smart" protocol (or both the "smart" and "dumb" protocols) MUST
@@ -154,6 +196,7 @@ async fn get_method_router(
         state.storage.clone(),
         Protocol::Http,
     );
+    pack_protocol.version = http::get_protocol_version(&headers);
     let mut headers = HashMap::new();
     headers.insert(
         "Content-Type".to_string(),
@@ -169,18 +212,23 @@ async fn get_method_router(
         resp = resp.header(&key, val);
     }
 
-    let pkt_line_stream = pack_protocol.git_info_refs(service_type).await;
+    let pkt_line_stream = pack_protocol
+        .git_info_refs(service_type)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     let body = Body::from(pkt_line_stream.freeze());
     Ok(resp.body(body).unwrap())
 }
 
 async fn post_method_router(
     state: State<AppState>,
+    Extension(identity): Extension<Identity>,
     uri: Uri,
     req: Request<Body>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let mut lfs_config: LfsConfig = state.options.clone().into();
     lfs_config.storage = state.storage.clone();
+    lfs_config.user = identity.user.clone();
 
     // Routing LFS services.
     if Regex::new(r"/locks/verify$").unwrap().is_match(uri.path()) {
@@ -201,21 +249,24 @@ async fn post_method_router(
         .unwrap()
         .is_match(uri.path())
     {
-        let pack_protocol = PackProtocol::new(
+        let mut pack_protocol = PackProtocol::new(
             remove_git_suffix(uri, "/git-upload-pack"),
             state.storage.clone(),
             Protocol::Http,
         );
+        pack_protocol.version = http::get_protocol_version(req.headers());
         http::git_upload_pack(req, pack_protocol).await
     } else if Regex::new(r"/git-receive-pack$")
         .unwrap()
         .is_match(uri.path())
     {
-        let pack_protocol = PackProtocol::new(
+        let mut pack_protocol = PackProtocol::new(
             remove_git_suffix(uri, "/git-receive-pack"),
             state.storage.clone(),
             Protocol::Http,
         );
+        pack_protocol.pusher = identity.user;
+        pack_protocol.hooks = state.hooks.clone();
         http::git_receive_pack(req, pack_protocol).await
     } else {
         Err((
@@ -227,11 +278,13 @@ async fn post_method_router(
 
 async fn put_method_router(
     state: State<AppState>,
+    Extension(identity): Extension<Identity>,
     uri: Uri,
     req: Request<Body>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let mut lfs_config: LfsConfig = state.options.clone().into();
     lfs_config.storage = state.storage.clone();
+    lfs_config.user = identity.user;
     if Regex::new(r"/objects/[a-z0-9]+$")
         .unwrap()
         .is_match(uri.path())
@@ -254,15 +307,19 @@ mod api_routers {
 
     use axum::{
         extract::{Query, State},
-        response::IntoResponse,
+        response::{IntoResponse, Response},
         routing::get,
-        Json, Router,
+        Extension, Json, Router,
     };
-    use hyper::StatusCode;
+    use hyper::{Body, StatusCode};
 
     use crate::{
         api_service::obj_service::ObjectService,
-        model::{object_detail::{BlobObjects, Directories}, query::DirectoryQuery},
+        auth::Identity,
+        model::{
+            object_detail::{BlobObjects, DefaultBranch, Directories, ProtectedRef, Tags},
+            query::{ArchiveQuery, DirectoryQuery},
+        },
     };
 
     use super::AppState;
@@ -272,6 +329,18 @@ mod api_routers {
             .route("/blob", get(get_blob_object))
             .route("/tree", get(get_directories))
             .route("/object", get(get_origin_object))
+            .route("/tags", get(get_tags))
+            .route("/archive", get(get_archive))
+            .route(
+                "/default_branch",
+                get(get_default_branch).put(set_default_branch),
+            )
+            .route(
+                "/protected_refs",
+                get(get_protected_refs)
+                    .put(save_protected_ref)
+                    .delete(delete_protected_ref),
+            )
             .with_state(state)
     }
 
@@ -308,6 +377,101 @@ mod api_routers {
         };
         object_service.get_objects_data(object_id, repo_path).await
     }
+
+    async fn get_tags(
+        Query(query): Query<HashMap<String, String>>,
+        state: State<AppState>,
+    ) -> Result<Json<Tags>, (StatusCode, String)> {
+        let repo_path = query.get("repo_path").unwrap();
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service.get_tags(repo_path).await
+    }
+
+    async fn get_archive(
+        Query(query): Query<ArchiveQuery>,
+        state: State<AppState>,
+    ) -> Result<Response<Body>, (StatusCode, String)> {
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service.get_archive(query).await
+    }
+
+    async fn get_default_branch(
+        Query(query): Query<HashMap<String, String>>,
+        state: State<AppState>,
+    ) -> Result<Json<DefaultBranch>, (StatusCode, String)> {
+        let repo_path = query.get("repo_path").unwrap();
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service.get_default_branch(repo_path).await
+    }
+
+    async fn set_default_branch(
+        state: State<AppState>,
+        Extension(identity): Extension<Identity>,
+        Json(payload): Json<DefaultBranch>,
+    ) -> Result<Json<DefaultBranch>, (StatusCode, String)> {
+        check_admin(&state, &identity)?;
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service.set_default_branch(payload).await
+    }
+
+    async fn get_protected_refs(
+        Query(query): Query<HashMap<String, String>>,
+        state: State<AppState>,
+    ) -> Result<Json<Vec<ProtectedRef>>, (StatusCode, String)> {
+        let repo_path = query.get("repo_path").unwrap();
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service.get_protected_refs(repo_path).await
+    }
+
+    async fn save_protected_ref(
+        state: State<AppState>,
+        Extension(identity): Extension<Identity>,
+        Json(payload): Json<ProtectedRef>,
+    ) -> Result<Json<ProtectedRef>, (StatusCode, String)> {
+        check_admin(&state, &identity)?;
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service.save_protected_ref(payload).await
+    }
+
+    async fn delete_protected_ref(
+        Query(query): Query<HashMap<String, String>>,
+        state: State<AppState>,
+        Extension(identity): Extension<Identity>,
+    ) -> Result<StatusCode, (StatusCode, String)> {
+        check_admin(&state, &identity)?;
+        let repo_path = query.get("repo_path").unwrap();
+        let ref_pattern = query.get("ref_pattern").unwrap();
+        let object_service = ObjectService {
+            storage: state.storage.clone(),
+        };
+        object_service
+            .delete_protected_ref(repo_path, ref_pattern)
+            .await
+    }
+
+    // the protected refs and the default branches are managed by the admins only
+    fn check_admin(state: &AppState, identity: &Identity) -> Result<(), (StatusCode, String)> {
+        if identity.is_admin(&state.options.admins) {
+            Ok(())
+        } else {
+            Err((
+                StatusCode::FORBIDDEN,
+                String::from("Only an admin can manage the protected refs and default branches\n"),
+            ))
+        }
+    }
 }
 
 #[cfg(test)]
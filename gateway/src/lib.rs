@@ -10,11 +10,17 @@ use database::driver::mysql::storage::MysqlStorage;
 use git::lfs::LfsConfig;
 use https::HttpOptions;
 use webhook::WebhookOptions;
+pub mod access_token;
+pub mod bundle;
+pub mod fsck;
+pub mod git_daemon;
 pub mod https;
 pub mod ssh;
+pub mod ssh_key;
 pub mod webhook;
 mod model;
 mod api_service;
+mod auth;
 
 
 impl From<HttpOptions> for LfsConfig {
@@ -24,6 +30,7 @@ impl From<HttpOptions> for LfsConfig {
             port: value.port,
             lfs_content_path: value.lfs_content_path,
             storage: Arc::new(MysqlStorage::default()),
+            user: None,
         }
     }
 }
@@ -35,6 +42,7 @@ impl From<WebhookOptions> for LfsConfig {
             port: value.port,
             lfs_content_path: value.lfs_content_path,
             storage: Arc::new(MysqlStorage::default()),
+            user: None,
         }
     }
 }
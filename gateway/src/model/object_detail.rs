@@ -1,4 +1,4 @@
-use entity::{node, repo_directory};
+use entity::{node, protected_ref, repo_directory, tag};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -54,3 +54,83 @@ impl From<repo_directory::Model> for Item {
 pub struct BlobObjects {
     pub row_data: String,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct Tags {
+    pub items: Vec<TagItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TagItem {
+    pub name: String,
+    pub tag_id: String,
+    pub object_id: String,
+    pub object_type: String,
+    pub tagger: Option<String>,
+    pub message: Option<String>,
+    pub annotated: bool,
+}
+
+impl From<tag::Model> for TagItem {
+    fn from(value: tag::Model) -> Self {
+        TagItem {
+            annotated: value.tag_id != value.object_id,
+            name: value.tag_name,
+            tag_id: value.tag_id,
+            object_id: value.object_id,
+            object_type: value.object_type,
+            tagger: value.tagger,
+            message: value.message,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DefaultBranch {
+    pub repo_path: String,
+    pub branch: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProtectedRef {
+    pub repo_path: String,
+    pub ref_pattern: String,
+    #[serde(default)]
+    pub allowed_pushers: Vec<String>,
+    #[serde(default)]
+    pub allow_force_push: bool,
+    #[serde(default)]
+    pub allow_deletion: bool,
+    #[serde(default)]
+    pub require_linear_history: bool,
+}
+
+impl From<protected_ref::Model> for ProtectedRef {
+    fn from(value: protected_ref::Model) -> Self {
+        ProtectedRef {
+            repo_path: value.repo_path,
+            ref_pattern: value.ref_pattern,
+            allowed_pushers: value.allowed_pushers,
+            allow_force_push: value.allow_force_push,
+            allow_deletion: value.allow_deletion,
+            require_linear_history: value.require_linear_history,
+        }
+    }
+}
+
+impl From<ProtectedRef> for protected_ref::Model {
+    fn from(value: ProtectedRef) -> Self {
+        let now = chrono::Utc::now().naive_utc();
+        protected_ref::Model {
+            id: 0,
+            repo_path: value.repo_path,
+            ref_pattern: value.ref_pattern,
+            allowed_pushers: value.allowed_pushers,
+            allow_force_push: value.allow_force_push,
+            allow_deletion: value.allow_deletion,
+            require_linear_history: value.require_linear_history,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
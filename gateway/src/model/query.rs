@@ -11,3 +11,16 @@ pub struct DirectoryQuery {
 fn default_path() -> String {
     "/root".to_string()
 }
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    pub repo_path: String,
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    #[serde(default = "default_archive_format")]
+    pub format: String,
+}
+
+fn default_archive_format() -> String {
+    "tar.gz".to_string()
+}
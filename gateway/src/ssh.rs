@@ -13,11 +13,13 @@ use anyhow::Result;
 use clap::Args;
 use database::DataSource;
 use ed25519_dalek::{SigningKey, SIGNATURE_LENGTH};
+use russh::MethodSet;
 use russh_keys::key::KeyPair;
 
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use git::protocol::hooks::PushHooks;
 use git::protocol::ssh::SshServer;
 
 #[derive(Args, Clone, Debug)]
@@ -37,6 +39,10 @@ pub struct SshOptions {
     #[arg(short, long, default_value_os_t = PathBuf::from("lfs_content"))]
     lfs_content_path: PathBuf,
 
+    /// Accept any password, the clients authenticated this way are anonymous
+    #[arg(long)]
+    allow_password_auth: bool,
+
     #[arg(short, long, value_enum, default_value = "postgres")]
     pub data_source: DataSource,
 }
@@ -47,9 +53,14 @@ pub async fn server(command: &SshOptions) -> Result<(), std::io::Error> {
     let client_key = load_key().await.unwrap();
     let client_pubkey = Arc::new(client_key.clone_public_key().unwrap());
 
+    let mut methods = MethodSet::PUBLICKEY;
+    if command.allow_password_auth {
+        methods |= MethodSet::PASSWORD;
+    }
     let mut config = russh::server::Config {
         inactivity_timeout: Some(std::time::Duration::from_secs(10)),
         auth_rejection_time: std::time::Duration::from_secs(3),
+        methods,
         ..Default::default()
     };
     config.keys.push(client_key);
@@ -62,6 +73,7 @@ pub async fn server(command: &SshOptions) -> Result<(), std::io::Error> {
         key_path: _,
         cert_path: _,
         lfs_content_path: _,
+        allow_password_auth,
         data_source,
     } = command;
     let sh = SshServer {
@@ -69,7 +81,10 @@ pub async fn server(command: &SshOptions) -> Result<(), std::io::Error> {
         clients: Arc::new(Mutex::new(HashMap::new())),
         id: 0,
         storage: database::init(data_source).await,
-        pack_protocol: None,
+        channels: HashMap::new(),
+        user: None,
+        allow_password_auth: *allow_password_auth,
+        hooks: PushHooks::from_env(),
     };
     let server_url = format!("{}:{}", host, port);
     let addr = SocketAddr::from_str(&server_url).unwrap();
@@ -0,0 +1,127 @@
+//!
+//!
+//!
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use database::DataSource;
+use russh_keys::key::PublicKey;
+
+#[derive(Args, Clone, Debug)]
+pub struct SshKeyOptions {
+    #[command(subcommand)]
+    pub command: SshKeyCommand,
+
+    #[arg(short, long, value_enum, default_value = "postgres", global = true)]
+    pub data_source: DataSource,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum SshKeyCommand {
+    /// Register a public key for a user
+    Add {
+        /// The user the key authenticates as
+        #[arg(long)]
+        user: String,
+
+        /// The public key file, in the format of `~/.ssh/id_ed25519.pub`
+        #[arg(short, long, value_name = "FILE")]
+        file: PathBuf,
+
+        /// A name for the key, the comment of the key by default
+        #[arg(long)]
+        title: Option<String>,
+    },
+    /// List the registered keys
+    List {
+        /// Only list the keys of this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Remove a registered key
+    Remove {
+        /// The id of the key, as listed
+        id: i32,
+    },
+}
+
+/// manage the public keys of the ssh server
+pub async fn run(options: &SshKeyOptions) -> Result<()> {
+    let storage = database::init(&options.data_source).await;
+    match &options.command {
+        SshKeyCommand::Add { user, file, title } => {
+            let line = tokio::fs::read_to_string(file).await?;
+            let (public_key, comment) = parse_public_key(&line)?;
+            let fingerprint = public_key.fingerprint();
+            if let Some(key) = storage
+                .get_ssh_key_by_fingerprint(&fingerprint)
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?
+            {
+                bail!("the key is already registered for {}", key.user_name);
+            }
+            let title = title.clone().unwrap_or(comment);
+            let key = storage
+                .save_ssh_key(user, &title, line.trim(), &fingerprint)
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?;
+            println!("added key {} SHA256:{} for {}", key.id, fingerprint, user);
+        }
+        SshKeyCommand::List { user } => {
+            let keys = storage
+                .list_ssh_keys(user.as_deref())
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?;
+            for key in keys {
+                println!(
+                    "{}\t{}\tSHA256:{}\t{}",
+                    key.id, key.user_name, key.fingerprint, key.title
+                );
+            }
+        }
+        SshKeyCommand::Remove { id } => {
+            let deleted = storage
+                .delete_ssh_key(*id)
+                .await
+                .map_err(|e| anyhow!("{:?}", e))?;
+            if !deleted {
+                bail!("no key with id {}", id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a public key line of OpenSSH, `<type> <base64-key> [comment]`. Returns the key and
+/// its comment, empty if it has none.
+pub fn parse_public_key(line: &str) -> Result<(PublicKey, String)> {
+    let mut fields = line.split_whitespace();
+    let (Some(_key_type), Some(base64)) = (fields.next(), fields.next()) else {
+        bail!("invalid public key: {}", line.trim());
+    };
+    let public_key = russh_keys::parse_public_key_base64(base64)
+        .map_err(|e| anyhow!("invalid public key: {}", e))?;
+    let comment = fields.collect::<Vec<_>>().join(" ");
+    Ok((public_key, comment))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_public_key;
+
+    #[test]
+    fn test_parse_public_key() {
+        let line = "ssh-ed25519 \
+            AAAAC3NzaC1lZDI1NTE5AAAAIG5fpinHgD3WjV+WU0vNlGRM3RjOjOQf8b7e8G6C2t6E alice@laptop\n";
+        let (public_key, comment) = parse_public_key(line).unwrap();
+        assert_eq!(comment, "alice@laptop");
+        // the same fingerprint as `ssh-keygen -l`
+        assert_eq!(
+            public_key.fingerprint(),
+            "J3L6WOU3OK2viQypAcN/gPMxiZrPX1rLo1oayMH4tp0"
+        );
+        assert!(parse_public_key("ssh-ed25519").is_err());
+        assert!(parse_public_key("ssh-ed25519 not-base64").is_err());
+    }
+}
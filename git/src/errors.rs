@@ -7,6 +7,8 @@ use std::string::FromUtf8Error;
 
 use thiserror::Error;
 
+use common::errors::MegaError;
+
 #[derive(Error, Debug)]
 #[allow(unused)]
 pub enum GitError {
@@ -63,6 +65,27 @@ pub enum GitError {
 
     #[error("UTF-8 conversion error: {0}")]
     ConversionError(String),
+
+    #[error("The `{0}` is not a valid object filter.")]
+    InvalidFilter(String),
+
+    #[error("The side-band stream failed: {0}")]
+    SideBandStreamError(String),
+
+    #[error("Bundle error: {0}")]
+    BundleError(String),
+
+    #[error("Archive error: {0}")]
+    ArchiveError(String),
+
+    #[error("The `{0}` is not a branch of the repo.")]
+    UnknownBranch(String),
+
+    #[error("The object format `{0}` is not supported.")]
+    UnsupportedObjectFormat(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
 }
 
 impl From<FromUtf8Error> for GitError {
@@ -71,3 +94,9 @@ impl From<FromUtf8Error> for GitError {
         GitError::ConversionError(err.to_string())
     }
 }
+
+impl From<MegaError> for GitError {
+    fn from(err: MegaError) -> Self {
+        GitError::StorageError(err.to_string())
+    }
+}
@@ -1,149 +1,327 @@
-//! In Git, the SHA-1 hash algorithm is widely used to generate unique identifiers for Git objects.
-//! Each Git object corresponds to a unique SHA-1 hash value, which is used to identify the object's
-//! location in the Git database.
-//!
-
-use std::fmt::Display;
-
-use bstr::ByteSlice;
-use colored::Colorize;
-use sha1::{Digest, Sha1};
-use serde::{Deserialize, Serialize};
-/// The Hash struct which only contain the u8 array :`[u8;20]` is used to represent Git hash IDs,
-/// which are 40-character hexadecimal strings computed using the SHA-1 algorithm. In Git, each object
-/// is assigned a unique hash ID based on its content, which is used to identify
-/// the object's location in the Git database.The Hash struct provides a convenient
-/// way to store and manipulate Git hash IDs by using a separate struct for hash IDs to make
-/// code more readable and maintainable.
-#[allow(unused)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default,Deserialize, Serialize)]
-pub struct Hash(pub [u8; 20]);
-pub trait CompHash {
-    fn compute_hash(&self) -> Hash;
-}
-/// Display trait for Hash type
-impl Display for Hash {
-    /// # Attention
-    /// cause of the color chars for ,if you want to use the string without color ,
-    /// please call the func:`to_plain_str()` rather than the func:`to_string()`
-    /// # Example
-    ///  the hash value `18fd2deaaf152c7f1222c52fb2673f6192b375f0`<br>
-    ///  will be the `1;31m8d2deaaf152c7f1222c52fb2673f6192b375f00m`
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.to_plain_str().red().bold())
-    }
-}
-impl redis::ToRedisArgs for Hash{
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + redis::RedisWrite {
-        out.write_arg(&self.0)
-    }
-}
-impl Hash {
-    /// Calculate the SHA-1 hash of `Vec<u8>` data
-    /// # Example
-    /// ```
-    /// use git::hash::Hash;
-    ///
-    /// let hash = Hash::new(&vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 0]);
-    /// assert_eq!(hash.to_plain_str(), "e89ad5a9631c3efdded7e3ecce79b4d0fedce1bf");
-    /// ```
-    pub fn new(data: &Vec<u8>) -> Hash {
-        // Create a Sha1 object for calculating the SHA-1 hash
-        let mut hasher = Sha1::new();
-        // Input the data into the Sha1 object
-        hasher.update(data);
-        // Get the result of the hash
-        let hash_result = hasher.finalize();
-        // Convert the result to a 20-byte array
-        let result = <[u8; 20]>::from(hash_result);
-
-        Hash(result)
-    }
-
-    /// Create Hash from a byte array
-    pub fn new_from_bytes(bytes: &[u8]) -> Hash {
-        let mut h = Hash::default();
-        h.0.copy_from_slice(bytes);
-        h
-    }
-
-    /// Create Hash from a string, which is a 40-character hexadecimal string
-    pub fn new_from_str(s: &str) -> Hash {
-        let mut h = Hash::default();
-        h.0.copy_from_slice(&hex::decode(s).unwrap());
-        h
-    }
-
-    /// Create a Hash value by the row value
-    pub fn from_row(hex_hash: &[u8]) -> Hash {
-        Hash(<[u8; 20]>::try_from(hex_hash).unwrap())
-    }
-
-    /// Create plain String without the color chars
-    pub fn to_plain_str(self) -> String {
-        hex::encode(self.0)
-    }
-
-    pub fn to_data(self) -> Vec<u8> {
-        self.0.repeatn(1)
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn test_hash_new() {
-        // [98, 108, 111, 98] = blob
-        // [32] = Space
-        // [49, 52] = 14
-        // [0] = \x00
-        // [72, 101, 108, 108, 111, 44, 32, 87, 111, 114, 108, 100, 33, 10] = Hello, World! + LF
-        // let hash = super::Hash::new(&vec![
-        //     98, 108, 111, 98, 32, 49, 52, 0, 72, 101, 108, 108, 111, 44, 32, 87, 111, 114, 108,
-        //     100, 33, 10,
-        // ]);
-        let hash = super::Hash::new_from_bytes(&[
-            0x8a, 0xb6, 0x86, 0xea, 0xfe, 0xb1, 0xf4, 0x47, 0x02, 0x73, 0x8c, 0x8b, 0x0f, 0x24,
-            0xf2, 0x56, 0x7c, 0x36, 0xda, 0x6d,
-        ]);
-        assert_eq!(
-            hash.to_plain_str(),
-            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
-        );
-    }
-
-    #[test]
-    fn test_hash_new_from_str() {
-        let hash = super::Hash::new_from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d");
-        assert_eq!(
-            hash.to_plain_str(),
-            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
-        );
-    }
-
-    #[test]
-    fn test_hash_to_data() {
-        let hash = super::Hash::new_from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d");
-        assert_eq!(
-            hash.to_data(),
-            vec![
-                0x8a, 0xb6, 0x86, 0xea, 0xfe, 0xb1, 0xf4, 0x47, 0x02, 0x73, 0x8c, 0x8b, 0x0f, 0x24,
-                0xf2, 0x56, 0x7c, 0x36, 0xda, 0x6d
-            ]
-        );
-    }
-
-    #[test]
-    fn test_hash_from_bytes() {
-        let hash = super::Hash::new_from_bytes(&[
-            0x8a, 0xb6, 0x86, 0xea, 0xfe, 0xb1, 0xf4, 0x47, 0x02, 0x73, 0x8c, 0x8b, 0x0f, 0x24,
-            0xf2, 0x56, 0x7c, 0x36, 0xda, 0x6d,
-        ]);
-        assert_eq!(
-            hash.to_plain_str(),
-            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
-        );
-    }
-}
+//! In Git, the SHA-1 hash algorithm is widely used to generate unique identifiers for Git objects.
+//! Each Git object corresponds to a unique SHA-1 hash value, which is used to identify the object's
+//! location in the Git database. Repos can also use SHA-256, see [`HashKind`].
+//!
+
+use std::fmt::Display;
+use std::future::Future;
+use std::str::FromStr;
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::errors::GitError;
+
+/// The hash algorithm of a repo, its "object format". Repos created with
+/// `git init --object-format=sha256` name their objects with SHA-256 instead of SHA-1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Deserialize, Serialize)]
+pub enum HashKind {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashKind {
+    /// The length of an object id in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            HashKind::Sha1 => 20,
+            HashKind::Sha256 => 32,
+        }
+    }
+
+    /// The length of an object id in hexadecimal.
+    pub fn hex_len(&self) -> usize {
+        self.size() * 2
+    }
+
+    /// The name of the object format on the wire, e.g. `object-format=sha256`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashKind::Sha1 => "sha1",
+            HashKind::Sha256 => "sha256",
+        }
+    }
+
+    /// The id made of zeros, the old id of a ref being created or the new id of a ref being
+    /// deleted.
+    pub fn zero_id(&self) -> String {
+        "0".repeat(self.hex_len())
+    }
+
+    /// Whether `id` is a hexadecimal object id of this format.
+    pub fn is_object_id(&self, id: &str) -> bool {
+        id.len() == self.hex_len() && id.chars().all(|c| c.is_ascii_hexdigit())
+    }
+}
+
+impl Display for HashKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HashKind {
+    type Err = GitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(HashKind::Sha1),
+            "sha256" => Ok(HashKind::Sha256),
+            _ => Err(GitError::UnsupportedObjectFormat(s.to_owned())),
+        }
+    }
+}
+
+pub use common::utils::is_zero_id;
+
+tokio::task_local! {
+    static HASH_KIND: HashKind;
+}
+
+/// The object format of the repo being served, SHA-1 outside of [`with_hash_kind`].
+///
+/// The objects are hashed and parsed deep down the pack and object code, so the format is
+/// scoped to the task serving a repo rather than passed along with every object.
+pub fn get_hash_kind() -> HashKind {
+    HASH_KIND.try_with(|kind| *kind).unwrap_or_default()
+}
+
+/// Runs `f` with the objects hashed and parsed as `kind`.
+pub async fn with_hash_kind<F: Future>(kind: HashKind, f: F) -> F::Output {
+    HASH_KIND.scope(kind, f).await
+}
+
+/// Same as [`with_hash_kind`] for blocking code, such as the pack decoder.
+pub fn sync_with_hash_kind<R>(kind: HashKind, f: impl FnOnce() -> R) -> R {
+    HASH_KIND.sync_scope(kind, f)
+}
+
+/// Computes an object id, or the checksum of a pack, with the hash algorithm of a repo.
+#[derive(Clone)]
+pub enum ObjectHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl ObjectHasher {
+    pub fn new(kind: HashKind) -> Self {
+        match kind {
+            HashKind::Sha1 => ObjectHasher::Sha1(Sha1::new()),
+            HashKind::Sha256 => ObjectHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            ObjectHasher::Sha1(hasher) => hasher.update(data),
+            ObjectHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        match self {
+            ObjectHasher::Sha1(hasher) => Hash::Sha1(hasher.finalize().into()),
+            ObjectHasher::Sha256(hasher) => Hash::Sha256(hasher.finalize().into()),
+        }
+    }
+}
+
+/// The Hash enum holds the bytes of a Git hash ID, 20 bytes for SHA-1 and 32 bytes for SHA-256.
+/// They are written as 40 or 64-character hexadecimal strings. In Git, each object
+/// is assigned a unique hash ID based on its content, which is used to identify
+/// the object's location in the Git database.The Hash enum provides a convenient
+/// way to store and manipulate Git hash IDs by using a separate type for hash IDs to make
+/// code more readable and maintainable.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Hash {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+pub trait CompHash {
+    fn compute_hash(&self) -> Hash;
+}
+/// The zero id of the current object format, see [`get_hash_kind`].
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::zero(get_hash_kind())
+    }
+}
+/// Display trait for Hash type
+impl Display for Hash {
+    /// # Attention
+    /// cause of the color chars for ,if you want to use the string without color ,
+    /// please call the func:`to_plain_str()` rather than the func:`to_string()`
+    /// # Example
+    ///  the hash value `18fd2deaaf152c7f1222c52fb2673f6192b375f0`<br>
+    ///  will be the `1;31m8d2deaaf152c7f1222c52fb2673f6192b375f00m`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.to_plain_str().red().bold())
+    }
+}
+impl redis::ToRedisArgs for Hash{
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite {
+        out.write_arg(self.as_bytes())
+    }
+}
+impl Hash {
+    /// Calculate the hash of `Vec<u8>` data with the current object format
+    /// # Example
+    /// ```
+    /// use git::hash::Hash;
+    ///
+    /// let hash = Hash::new(&vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 0]);
+    /// assert_eq!(hash.to_plain_str(), "e89ad5a9631c3efdded7e3ecce79b4d0fedce1bf");
+    /// ```
+    pub fn new(data: &Vec<u8>) -> Hash {
+        let mut hasher = ObjectHasher::new(get_hash_kind());
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// The id made of zeros of an object format
+    pub fn zero(kind: HashKind) -> Hash {
+        match kind {
+            HashKind::Sha1 => Hash::Sha1([0u8; 20]),
+            HashKind::Sha256 => Hash::Sha256([0u8; 32]),
+        }
+    }
+
+    /// Create Hash from a byte array, its length tells the object format
+    pub fn new_from_bytes(bytes: &[u8]) -> Hash {
+        match bytes.len() {
+            20 => Hash::Sha1(bytes.try_into().unwrap()),
+            32 => Hash::Sha256(bytes.try_into().unwrap()),
+            len => panic!("invalid hash length: {}", len),
+        }
+    }
+
+    /// Create Hash from a string, which is a 40 or 64-character hexadecimal string
+    pub fn new_from_str(s: &str) -> Hash {
+        Hash::new_from_bytes(&hex::decode(s).unwrap())
+    }
+
+    /// Create a Hash value by the row value
+    pub fn from_row(hex_hash: &[u8]) -> Hash {
+        Hash::new_from_bytes(hex_hash)
+    }
+
+    /// The object format of the hash
+    pub fn kind(&self) -> HashKind {
+        match self {
+            Hash::Sha1(_) => HashKind::Sha1,
+            Hash::Sha256(_) => HashKind::Sha256,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Hash::Sha1(bytes) => bytes,
+            Hash::Sha256(bytes) => bytes,
+        }
+    }
+
+    /// Create plain String without the color chars
+    pub fn to_plain_str(self) -> String {
+        hex::encode(self.as_bytes())
+    }
+
+    pub fn to_data(self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_hash_new() {
+        // [98, 108, 111, 98] = blob
+        // [32] = Space
+        // [49, 52] = 14
+        // [0] = \x00
+        // [72, 101, 108, 108, 111, 44, 32, 87, 111, 114, 108, 100, 33, 10] = Hello, World! + LF
+        // let hash = super::Hash::new(&vec![
+        //     98, 108, 111, 98, 32, 49, 52, 0, 72, 101, 108, 108, 111, 44, 32, 87, 111, 114, 108,
+        //     100, 33, 10,
+        // ]);
+        let hash = super::Hash::new_from_bytes(&[
+            0x8a, 0xb6, 0x86, 0xea, 0xfe, 0xb1, 0xf4, 0x47, 0x02, 0x73, 0x8c, 0x8b, 0x0f, 0x24,
+            0xf2, 0x56, 0x7c, 0x36, 0xda, 0x6d,
+        ]);
+        assert_eq!(
+            hash.to_plain_str(),
+            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
+        );
+    }
+
+    #[test]
+    fn test_hash_new_from_str() {
+        let hash = super::Hash::new_from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d");
+        assert_eq!(
+            hash.to_plain_str(),
+            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
+        );
+    }
+
+    #[test]
+    fn test_hash_to_data() {
+        let hash = super::Hash::new_from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d");
+        assert_eq!(
+            hash.to_data(),
+            vec![
+                0x8a, 0xb6, 0x86, 0xea, 0xfe, 0xb1, 0xf4, 0x47, 0x02, 0x73, 0x8c, 0x8b, 0x0f, 0x24,
+                0xf2, 0x56, 0x7c, 0x36, 0xda, 0x6d
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_from_bytes() {
+        let hash = super::Hash::new_from_bytes(&[
+            0x8a, 0xb6, 0x86, 0xea, 0xfe, 0xb1, 0xf4, 0x47, 0x02, 0x73, 0x8c, 0x8b, 0x0f, 0x24,
+            0xf2, 0x56, 0x7c, 0x36, 0xda, 0x6d,
+        ]);
+        assert_eq!(
+            hash.to_plain_str(),
+            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
+        );
+    }
+
+    #[test]
+    fn test_hash_sha256() {
+        let id = "6ec7cdbf1b7ee3bb5bb2fe0b0348c3bfc51c5fc0a1d1dd39b4a4b72a4b5dbb8d";
+        let hash = super::Hash::new_from_str(id);
+        assert_eq!(hash.kind(), super::HashKind::Sha256);
+        assert_eq!(hash.to_plain_str(), id);
+        assert_eq!(hash.to_data().len(), 32);
+    }
+
+    #[test]
+    fn test_hash_kind() {
+        use super::{get_hash_kind, sync_with_hash_kind, Hash, HashKind};
+
+        assert_eq!(get_hash_kind(), HashKind::Sha1);
+        let data = b"blob 14\0Hello, World!\n".to_vec();
+        assert_eq!(
+            Hash::new(&data).to_plain_str(),
+            "8ab686eafeb1f44702738c8b0f24f2567c36da6d"
+        );
+        sync_with_hash_kind(HashKind::Sha256, || {
+            assert_eq!(get_hash_kind(), HashKind::Sha256);
+            // the same as `git hash-object` in a sha256 repo
+            assert_eq!(
+                Hash::new(&data).to_plain_str(),
+                "dabc789f60c22621c92df8736ff8cb60e35185584772b93b9315a3e2aab55653"
+            );
+            assert_eq!(Hash::default(), Hash::Sha256([0; 32]));
+        });
+        assert_eq!("sha256".parse::<HashKind>().unwrap(), HashKind::Sha256);
+        assert!("md5".parse::<HashKind>().is_err());
+        assert_eq!(HashKind::Sha256.zero_id().len(), 64);
+    }
+}
@@ -22,7 +22,6 @@ use entity::{
     git_obj,
 };
 use sea_orm::Set;
-use sha1::Digest;
 use std::{
     fmt::Display,
     io::{BufRead, Read},
@@ -81,10 +80,8 @@ pub trait ObjectT: Send + Sync + Display {
     {
         let mut content: Vec<u8> = Vec::with_capacity(size);
         read.read_to_end(&mut content).unwrap();
-        let h = read.hash.clone();
-        let hash_str = h.finalize();
         let mut result = Self::new_from_data(content);
-        result.set_hash(Hash::new_from_str(&format!("{:x}", hash_str)));
+        result.set_hash(read.hash.clone().finalize());
 
         result
     }
@@ -96,10 +93,8 @@ pub trait ObjectT: Send + Sync + Display {
     {
         let mut content: Vec<u8> = Vec::with_capacity(read.len());
         read.read_to_end(&mut content).unwrap();
-        let h = read.hash.clone();
-        let hash_str = h.finalize();
         let mut result = Self::new_from_data(content);
-        result.set_hash(Hash::new_from_str(&format!("{:x}", hash_str)));
+        result.set_hash(read.hash.clone().finalize());
         result
     }
 
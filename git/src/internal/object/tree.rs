@@ -19,7 +19,7 @@ use colored::Colorize;
 use std::fmt::Display;
 
 use crate::errors::GitError;
-use crate::hash::Hash;
+use crate::hash::{get_hash_kind, Hash};
 
 use crate::internal::ObjectType;
 
@@ -310,14 +310,15 @@ impl ObjectT for Tree {
         let mut i = 0;
         while i < data.len() {
             let index = data[i..].find_byte(0x00).unwrap();
-            let next = i + index + 21;
+            // the binary id follows the name and its NUL
+            let next = i + index + 1 + get_hash_kind().size();
 
             tree_items.push(TreeItem::new_from_bytes(&data[i..next]).unwrap());
             i = next
         }
 
         Tree {
-            id: Hash([0u8; 20]),
+            id: Hash::default(),
             tree_items,
         }
     }
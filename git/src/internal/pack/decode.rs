@@ -1,12 +1,8 @@
 use std::io::{self, BufRead};
 use std::io::{Read, Seek};
 
-use sha1::digest::core_api::CoreWrapper;
-use sha1::Digest;
-use sha1::Sha1;
-
 use super::{iterator::EntriesIter, Pack};
-use crate::hash::Hash;
+use crate::hash::{get_hash_kind, Hash, ObjectHasher};
 use crate::{errors::GitError, utils};
 #[allow(unused)]
 enum DecodeMod {
@@ -85,7 +81,7 @@ impl Pack {
 /// A BufReader for hash count during the pack data stream "read".
 pub struct HashCounter<R> {
     inner: R,
-    hash: CoreWrapper<sha1::Sha1Core>,
+    hash: ObjectHasher,
     count_hash: bool,
 }
 impl<R> HashCounter<R>
@@ -95,13 +91,12 @@ where
     pub fn new(inner: R, count_hash: bool) -> Self {
         Self {
             inner,
-            hash: Sha1::new(),
+            hash: ObjectHasher::new(get_hash_kind()),
             count_hash,
         }
     }
     pub fn final_hash(&self) -> Hash {
-        let re: [u8; 20] = self.hash.clone().finalize().into();
-        Hash(re)
+        self.hash.clone().finalize()
     }
 }
 impl<R> BufRead for HashCounter<R>
@@ -133,13 +128,8 @@ where
     }
 }
 
-fn read_tail_hash(tail: &mut impl Read) -> Hash {
-    let id: [u8; 20] = {
-        let mut id_buf = [0u8; 20];
-        tail.read_exact(&mut id_buf).unwrap();
-        id_buf
-    };
-    Hash::new_from_bytes(&id[..])
+pub(crate) fn read_tail_hash(tail: &mut impl Read) -> Hash {
+    utils::read_hash(tail).unwrap()
 }
 #[cfg(test)]
 mod test {
@@ -1,8 +1,8 @@
-use sha1::digest::core_api::CoreWrapper;
-use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read};
 use std::sync::Arc;
 
+use crate::hash::{get_hash_kind, ObjectHasher};
 use crate::internal::object::ObjectT;
 use crate::{errors::GitError, utils};
 
@@ -19,14 +19,14 @@ const COPY_ZERO_SIZE: usize = 0x10000;
 pub struct DeltaReader {
     result: BufReader<Cursor<Vec<u8>>>,
     len: usize,
-    pub hash: CoreWrapper<sha1::Sha1Core>,
+    pub hash: ObjectHasher,
 }
 impl DeltaReader {
     pub async fn new(reader: &mut impl Read, base_object: Arc<dyn ObjectT>) -> Self {
         let copy_obj = base_object.clone();
         let buffer = AsyncDeltaBuffer::new(reader, base_object).await;
 
-        let mut h = Sha1::new();
+        let mut h = ObjectHasher::new(get_hash_kind());
         h.update(copy_obj.get_type().to_bytes());
         h.update(b" ");
         h.update(buffer.result_size.to_string());
@@ -214,5 +214,183 @@ pub fn undelta(mut stream: &mut impl Read, base_info: &Vec<u8>) -> Vec<u8> {
     assert!(buffer.len() == result_size);
     buffer
 }
+
+// the length of the blocks of the base that are indexed, shorter matches aren't copied
+const DELTA_BLOCK_SIZE: usize = 16;
+// how many blocks with the same hash are kept, the others are not worth the search
+const DELTA_BUCKET_SIZE: usize = 64;
+const DELTA_HASH_BASE: u32 = 0x01000193;
+const DATA_INSTRUCTION_MAX: usize = 0x7f;
+
+/// An index of the blocks of a delta base, like the one of git's `diff-delta`.
+///
+/// The base is cut into blocks that are indexed by a rolling hash, a target is then scanned
+/// byte by byte for blocks of the base, which are extended as far as they match. Building a
+/// delta takes a time linear in the size of the target, whatever the two objects look like.
+pub struct DeltaIndex {
+    base: Vec<u8>,
+    blocks: HashMap<u32, Vec<usize>>,
+}
+
+impl DeltaIndex {
+    pub fn new(base: Vec<u8>) -> Self {
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        if base.len() >= DELTA_BLOCK_SIZE {
+            for offset in (0..=base.len() - DELTA_BLOCK_SIZE).step_by(DELTA_BLOCK_SIZE) {
+                let bucket = blocks
+                    .entry(block_hash(&base[offset..offset + DELTA_BLOCK_SIZE]))
+                    .or_default();
+                if bucket.len() < DELTA_BUCKET_SIZE {
+                    bucket.push(offset);
+                }
+            }
+        }
+        Self { base, blocks }
+    }
+
+    /// The data the index was built from.
+    pub fn base(&self) -> &[u8] {
+        &self.base
+    }
+
+    /// The delta from the base to `target`, `None` if it would be longer than `max_size`, the
+    /// search stops as soon as that's known.
+    pub fn delta(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut delta = utils::write_size_encoding(self.base.len());
+        delta.append(&mut utils::write_size_encoding(target.len()));
+        // the start of the target bytes that are not copied yet
+        let mut insert = 0;
+        let mut pos = 0;
+        let mut hash = None;
+        while pos + DELTA_BLOCK_SIZE <= target.len() {
+            let h = hash.unwrap_or_else(|| block_hash(&target[pos..pos + DELTA_BLOCK_SIZE]));
+            match self.longest_match(h, &target[pos..]) {
+                Some((mut offset, mut len)) => {
+                    // the match may start in the bytes that were going to be inserted
+                    while pos > insert && offset > 0 && self.base[offset - 1] == target[pos - 1] {
+                        offset -= 1;
+                        pos -= 1;
+                        len += 1;
+                    }
+                    write_data_instructions(&mut delta, &target[insert..pos]);
+                    write_copy_instructions(&mut delta, offset, len);
+                    pos += len;
+                    insert = pos;
+                    hash = None;
+                }
+                None => {
+                    hash = target
+                        .get(pos + DELTA_BLOCK_SIZE)
+                        .map(|&next| roll_hash(h, target[pos], next));
+                    pos += 1;
+                }
+            }
+            if delta.len() + pos - insert > max_size {
+                return None;
+            }
+        }
+        write_data_instructions(&mut delta, &target[insert..]);
+        (delta.len() <= max_size).then_some(delta)
+    }
+
+    // the offset in the base and the length of the longest match of the start of `target`
+    fn longest_match(&self, hash: u32, target: &[u8]) -> Option<(usize, usize)> {
+        let mut best = None;
+        let mut best_len = DELTA_BLOCK_SIZE - 1;
+        for &offset in self.blocks.get(&hash)? {
+            let len = self.base[offset..]
+                .iter()
+                .zip(target)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best_len {
+                best_len = len;
+                best = Some((offset, len));
+            }
+        }
+        best
+    }
+}
+
+fn block_hash(block: &[u8]) -> u32 {
+    block.iter().fold(0u32, |h, &c| {
+        h.wrapping_mul(DELTA_HASH_BASE).wrapping_add(c as u32)
+    })
+}
+
+// the hash of the block one byte further, `out` leaves the block and `next` enters it
+fn roll_hash(hash: u32, out: u8, next: u8) -> u32 {
+    let weight = DELTA_HASH_BASE.wrapping_pow(DELTA_BLOCK_SIZE as u32 - 1);
+    hash.wrapping_sub((out as u32).wrapping_mul(weight))
+        .wrapping_mul(DELTA_HASH_BASE)
+        .wrapping_add(next as u32)
+}
+
+fn write_data_instructions(delta: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(DATA_INSTRUCTION_MAX) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+fn write_copy_instructions(delta: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(COPY_ZERO_SIZE);
+        let at = delta.len();
+        let mut instruction = COPY_INSTRUCTION_FLAG;
+        delta.push(instruction);
+        for i in 0..COPY_OFFSET_BYTES {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                instruction |= 1 << i;
+                delta.push(byte);
+            }
+        }
+        // a size of 0x10000 is written as no byte at all
+        for i in 0..COPY_SIZE_BYTES {
+            let byte = (size >> (8 * i)) as u8;
+            if byte != 0 {
+                instruction |= 1 << (COPY_OFFSET_BYTES + i);
+                delta.push(byte);
+            }
+        }
+        delta[at] = instruction;
+        offset += size;
+        len -= size;
+    }
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::io::Cursor;
+
+    use super::{undelta, DeltaIndex};
+
+    #[test]
+    fn test_delta_index() {
+        let mut seed = 1u32;
+        let base: Vec<u8> = (0..500_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut target = base[1000..150_000].to_vec();
+        target.extend_from_slice(b"some new bytes in the middle");
+        target.extend_from_slice(&base[3..90_000]);
+        let index = DeltaIndex::new(base.clone());
+        let delta = index.delta(&target, target.len()).unwrap();
+        assert!(delta.len() < 100);
+        assert_eq!(undelta(&mut Cursor::new(delta), &base), target);
+    }
+
+    #[test]
+    fn test_delta_index_max_size() {
+        let base = b"a base that has nothing in common with the target".to_vec();
+        let target = b"0123456789abcdefghijklmnopqrstuvwxyz".to_vec();
+        let index = DeltaIndex::new(base.clone());
+        assert_eq!(index.delta(&target, target.len()), None);
+        let delta = index.delta(&target, target.len() + 16).unwrap();
+        assert_eq!(undelta(&mut Cursor::new(delta), &base), target);
+    }
+}
@@ -1,24 +1,119 @@
-use entity::git_obj;
-use sha1::{Digest, Sha1};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
 use std::io::{Cursor, Write};
 use std::sync::Arc;
 
-use crate::internal::diff::DeltaDiff;
+use crate::hash::{get_hash_kind, Hash, ObjectHasher};
 use crate::internal::object::ObjectT;
+use crate::internal::pack::delta::DeltaIndex;
 use crate::internal::zlib::stream::deflate::Write as Writer;
+use crate::internal::ObjectType;
+use crate::utils;
 
 use std::io::Error;
 
-use super::header::EntryHeader;
+/// Objects larger than that are never encoded as deltas, indexing them costs more than it saves.
+pub const MAX_DELTA_OBJECT_SIZE: usize = 1024 * 1024;
 
-const SLID_WINDWOS: usize = 20;
+/// How hard an [`Encoder`] searches for deltas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaOptions {
+    /// How many of the objects written last are tried as the base of an object, 0 turns the
+    /// delta search off.
+    pub window: usize,
+    /// The longest chain of deltas an object is resolved through.
+    pub depth: usize,
+    /// Refers to the base of a delta by its offset in the pack (`OFS_DELTA`) rather than by its
+    /// id (`REF_DELTA`), the client has to advertise `ofs-delta`.
+    pub ofs_delta: bool,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            depth: 50,
+            ofs_delta: true,
+        }
+    }
+}
+
+impl DeltaOptions {
+    /// The default options, the window and the depth can be changed with
+    /// `GIT_INTERNAL_ENCODE_DELTA_WINDOW` and `GIT_INTERNAL_ENCODE_DELTA_DEPTH`.
+    pub fn from_env(ofs_delta: bool) -> Self {
+        let mut options = Self {
+            ofs_delta,
+            ..Self::default()
+        };
+        utils::get_env_number("GIT_INTERNAL_ENCODE_DELTA_WINDOW", &mut options.window);
+        utils::get_env_number("GIT_INTERNAL_ENCODE_DELTA_DEPTH", &mut options.depth);
+        options
+    }
+}
+
+/// An object to add to a pack with [`Encoder::add_delta_objects`].
+#[derive(Debug, Clone)]
+pub struct DeltaObject {
+    pub hash: Hash,
+    pub obj_type: ObjectType,
+    pub data: Vec<u8>,
+    /// The [`name_hash`] of the path of the object, 0 when it has none.
+    pub name_hash: u32,
+}
+
+impl DeltaObject {
+    pub fn new(hash: Hash, obj_type: ObjectType, data: Vec<u8>) -> Self {
+        Self {
+            hash,
+            obj_type,
+            data,
+            name_hash: 0,
+        }
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.name_hash = name_hash(path);
+        self
+    }
+}
+
+impl From<Arc<dyn ObjectT>> for DeltaObject {
+    fn from(obj: Arc<dyn ObjectT>) -> Self {
+        Self::new(obj.get_hash(), obj.get_type(), obj.get_raw())
+    }
+}
+
+/// The hash git sorts the objects of a pack by, so that the versions of a file end up next to
+/// each other. The last characters of the path weigh the most, files with the same name or
+/// extension in other directories come close too.
+pub fn name_hash(path: &str) -> u32 {
+    path.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
 
-#[allow(unused)]
-struct Encoder<W> {
+// an object written to the pack which may be the base of the next ones
+struct WindowEntry {
+    hash: Hash,
+    obj_type: ObjectType,
+    index: DeltaIndex,
+    offset: u64,
+    // the length of the delta chain of the object, 0 when it's written whole
+    depth: usize,
+}
+
+/// Encodes a pack object by object into `inner`, the pack checksum is computed as the
+/// objects are written.
+pub struct Encoder<W> {
     inner: W,
-    hash: Sha1,
+    hash: ObjectHasher,
+    // the offset of the next object
+    offset: u64,
+    options: DeltaOptions,
+    window: VecDeque<WindowEntry>,
 }
-#[allow(unused)]
+
 impl<W> Encoder<W>
 where
     W: Write,
@@ -26,82 +121,145 @@ where
     pub fn init(object_number: usize, mut inner: W) -> Self {
         let head = encode_header(object_number);
         inner.write_all(&head).unwrap();
-        let mut hash = Sha1::new();
+        let mut hash = ObjectHasher::new(get_hash_kind());
         hash.update(&head);
-        Self { inner, hash }
+        Self {
+            inner,
+            hash,
+            offset: head.len() as u64,
+            options: DeltaOptions::default(),
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Sets how [`Encoder::add_delta_objects`] searches for deltas.
+    pub fn with_delta_options(mut self, options: DeltaOptions) -> Self {
+        self.options = options;
+        self
     }
+
+    /// Adds the objects whole.
     pub fn add_objects(&mut self, obj_vec: Vec<Arc<dyn ObjectT>>) -> Result<(), Error> {
-        let ls = obj_vec.len();
         for obj in obj_vec {
             let obj_data = encode_one_object(obj)?;
-            self.hash.update(&obj_data);
-            self.inner.write_all(&obj_data)?;
+            self.write_entry(&obj_data)?;
         }
         Ok(())
     }
-    /// Added batch insertion support for offset delta compression.
-    /// Note: The input array should meet the requirements of magic sorting, otherwise a good delta compression rate cannot be obtained
-    pub fn add_oject_model(&mut self, obj_vec: Vec<git_obj::Model>) -> Result<(), Error> {
-        let batch_size = obj_vec.len();
-        for i in 0..batch_size {
-            let mut best_j = SLID_WINDWOS + 1;
-            let mut best_ssam_rate: f64 = 0.0;
-            // delta from base object by slid window
-            for j in 1..SLID_WINDWOS {
-                if i < j {
-                    break;
-                }
-                let pos = i - j;
-                if !obj_vec[pos].object_type.eq(&obj_vec[i].object_type) {
-                    break;
+
+    /// Adds the objects as deltas against the objects written before them when it's smaller,
+    /// returns how many of them are deltas.
+    ///
+    /// The objects are sorted by type, path and decreasing size, and each one is searched in
+    /// the [`DeltaIndex`] of the last `window` objects of the same type, across the calls. The
+    /// calls should come in the same order for the deltas to be found, the versions of a file
+    /// in different calls are only compared when they are close. The search is CPU bound, an
+    /// async caller should run it on a blocking thread.
+    pub fn add_delta_objects(&mut self, mut objects: Vec<DeltaObject>) -> Result<usize, Error> {
+        objects.sort_by_key(|o| (o.obj_type, o.name_hash, Reverse(o.data.len())));
+        let mut deltas = 0;
+        for object in objects {
+            let offset = self.offset;
+            let depth = match self.find_delta(&object) {
+                Some((base, delta)) => {
+                    let base = &self.window[base];
+                    let obj_data = if self.options.ofs_delta {
+                        encode_ofs_delta(offset - base.offset, &delta)?
+                    } else {
+                        encode_ref_delta(&base.hash, &delta)?
+                    };
+                    let depth = base.depth + 1;
+                    self.write_entry(&obj_data)?;
+                    deltas += 1;
+                    depth
                 }
-                let differ = DeltaDiff::new(&obj_vec[i - j].data, &obj_vec[i].data);
-                let diff_rate = differ.get_ssam_rate();
-                if (diff_rate > best_ssam_rate) && diff_rate > 0.5 {
-                    best_ssam_rate = diff_rate;
-                    best_j = j;
+                None => {
+                    let obj_data = encode_one_ojbect(
+                        object.obj_type.type2number(),
+                        object.data.len(),
+                        &object.data,
+                    )?;
+                    self.write_entry(&obj_data)?;
+                    0
                 }
+            };
+            if self.options.window == 0 || object.data.len() > MAX_DELTA_OBJECT_SIZE {
+                continue;
             }
-            let obj_data = if best_j == SLID_WINDWOS + 1 {
-                encode_one_ojbect(
-                    EntryHeader::from_string(&obj_vec[i].object_type).to_number(),
-                    obj_vec[i].data.len(),
-                    &obj_vec[i].data,
-                )
-            } else {
-                let differ = DeltaDiff::new(&obj_vec[i - best_j].data, &obj_vec[i].data);
-                let after = differ.encode();
-                encode_one_ojbect(6, after.len(), &after)
+            if self.window.len() >= self.options.window {
+                self.window.pop_front();
             }
-            .unwrap();
-            self.hash.update(&obj_data);
-            self.inner.write_all(&obj_data)?;
+            self.window.push_back(WindowEntry {
+                hash: object.hash,
+                obj_type: object.obj_type,
+                index: DeltaIndex::new(object.data),
+                offset,
+                depth,
+            });
         }
-        Ok(())
+        Ok(deltas)
+    }
+
+    /// Adds an object as a `REF_DELTA` against `base_id`, `delta` is the delta from the base
+    /// to the object. A base that isn't in the pack makes it a thin pack.
+    pub fn add_ref_delta(&mut self, base_id: &Hash, delta: &[u8]) -> Result<(), Error> {
+        let obj_data = encode_ref_delta(base_id, delta)?;
+        self.write_entry(&obj_data)
     }
+
     pub fn finish(&mut self) -> Result<(), Error> {
         let hash_result = self.hash.clone().finalize();
-        self.inner.write_all(&hash_result)?;
+        self.inner.write_all(hash_result.as_bytes())?;
         Ok(())
     }
-}
-//
 
-pub fn pack_encode(obj_vec: Vec<Arc<dyn ObjectT>>) -> Result<Vec<u8>, Error> {
-    let mut hash = Sha1::new();
-    let mut out_data = Vec::new();
-    let header_data = encode_header(obj_vec.len());
-    hash.update(&header_data);
-    out_data.write_all(&header_data)?;
+    // the index in the window of the best base of `object` and the delta against it
+    fn find_delta(&self, object: &DeltaObject) -> Option<(usize, Vec<u8>)> {
+        if object.data.len() > MAX_DELTA_OBJECT_SIZE {
+            return None;
+        }
+        // a delta has to save at least half of the object, like git does
+        let mut max_size = (object.data.len() / 2).saturating_sub(get_hash_kind().size());
+        let mut best = None;
+        for (i, base) in self.window.iter().enumerate().rev() {
+            let base_size = base.index.base().len();
+            // the delta inserts at least the bytes the object has more than the base
+            if base.obj_type != object.obj_type
+                || base.depth >= self.options.depth
+                || base_size < object.data.len() / 32
+                || object.data.len().saturating_sub(base_size) >= max_size
+            {
+                continue;
+            }
+            if let Some(delta) = base.index.delta(&object.data, max_size - 1) {
+                max_size = delta.len();
+                best = Some((i, delta));
+            }
+        }
+        best
+    }
+
+    fn write_entry(&mut self, obj_data: &[u8]) -> Result<(), Error> {
+        self.hash.update(obj_data);
+        self.inner.write_all(obj_data)?;
+        self.offset += obj_data.len() as u64;
+        Ok(())
+    }
+}
 
-    for obj in obj_vec {
-        let obj_data = encode_one_object(obj)?;
-        hash.update(&obj_data);
-        out_data.write_all(&obj_data)?;
+impl Encoder<Vec<u8>> {
+    /// Takes the bytes encoded so far, so that a pack can be sent while it's being encoded.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.inner)
     }
-    let hash_result = hash.finalize();
-    out_data.write_all(&hash_result)?;
-    Ok(out_data)
+}
+
+/// Encodes the objects into a pack, with `OFS_DELTA` deltas between them.
+pub fn pack_encode(obj_vec: Vec<Arc<dyn ObjectT>>) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder::init(obj_vec.len(), Vec::new());
+    encoder.add_delta_objects(obj_vec.into_iter().map(DeltaObject::from).collect())?;
+    encoder.finish()?;
+    Ok(encoder.take_output())
 }
 
 fn encode_header(object_number: usize) -> Vec<u8> {
@@ -109,7 +267,6 @@ fn encode_header(object_number: usize) -> Vec<u8> {
         b'P', b'A', b'C', b'K', // The logotype of the Pack File
         0, 0, 0, 2,
     ]; // THe Version  of the Pack File
-    assert_ne!(object_number, 0); // guarantee self.number_of_objects!=0
     assert!(object_number < (1 << 32));
     //TODO: GitError:numbers of objects should  < 4G ,
     //Encode the number of object  into file
@@ -180,6 +337,27 @@ fn encode_one_ojbect(git_type: u8, size: usize, data: &[u8]) -> Result<Vec<u8>,
     Ok(header_data)
 }
 
+fn encode_ref_delta(base_id: &Hash, delta: &[u8]) -> Result<Vec<u8>, Error> {
+    let obj_data = encode_one_ojbect(7, delta.len(), delta)?;
+    Ok(insert_after_header(obj_data, base_id.to_data()))
+}
+
+// `distance` is how many bytes before the object its base starts
+fn encode_ofs_delta(distance: u64, delta: &[u8]) -> Result<Vec<u8>, Error> {
+    let obj_data = encode_one_ojbect(6, delta.len(), delta)?;
+    Ok(insert_after_header(
+        obj_data,
+        utils::write_offset_encoding(distance),
+    ))
+}
+
+// the base of a delta goes between the header and the compressed delta
+fn insert_after_header(mut obj_data: Vec<u8>, base: Vec<u8>) -> Vec<u8> {
+    let header_len = obj_data.iter().position(|b| b & 0x80 == 0).unwrap() + 1;
+    obj_data.splice(header_len..header_len, base);
+    obj_data
+}
+
 fn u32_vec(value: u32) -> Vec<u8> {
     vec![
         (value >> 24 & 0xff) as u8,
@@ -197,18 +375,22 @@ mod tests {
     use crate::{
         hash::Hash,
         internal::{
-            object::{blob::Blob, ObjectT},
-            pack::Pack,
+            diff::DeltaDiff,
+            object::{blob::Blob, meta::Meta, ObjectT},
+            pack::{delta::undelta, index::PackIndex, Pack},
+            zlib::stream::inflate::ReadPlain,
+            ObjectType,
         },
+        utils,
     };
-    use std::io::Cursor;
+    use std::io::{Cursor, Read};
     use std::sync::Arc;
 
-    use super::{pack_encode, Encoder};
+    use super::{name_hash, pack_encode, DeltaObject, DeltaOptions, Encoder};
 
     #[test]
     fn test_a_simple_encode() {
-        let id = Hash([0u8; 20]);
+        let id = Hash::default();
         let data = String::from("hello,1").into_bytes();
         let mut obj_vec: Vec<Arc<dyn ObjectT>> = Vec::new();
         let b1 = Blob { id, data };
@@ -224,7 +406,7 @@ mod tests {
 
     #[test]
     fn test_pack_encoder() {
-        let id = Hash([0u8; 20]);
+        let id = Hash::default();
         let mut pack_data = Vec::with_capacity(1000);
         // Encoder::init
         let mut encoder = Encoder::init(2, &mut pack_data);
@@ -242,4 +424,136 @@ mod tests {
         let mut buff = Cursor::new(pack_data);
         block_on(Pack::decode(&mut buff)).unwrap();
     }
+
+    #[test]
+    fn test_pack_encoder_take_output() {
+        let id = Hash::default();
+        let blob = |data: &str| -> Arc<dyn ObjectT> {
+            Arc::new(Blob {
+                id,
+                data: data.as_bytes().to_vec(),
+            })
+        };
+        let mut encoder = Encoder::init(2, Vec::new());
+        let mut pack_data = encoder.take_output();
+        encoder.add_objects(vec![blob("hello,1")]).unwrap();
+        pack_data.extend(encoder.take_output());
+        encoder.add_objects(vec![blob("hello,2")]).unwrap();
+        encoder.finish().unwrap();
+        pack_data.extend(encoder.take_output());
+
+        assert_eq!(
+            pack_data,
+            pack_encode(vec![blob("hello,1"), blob("hello,2")]).unwrap()
+        );
+        let mut buff = Cursor::new(pack_data);
+        block_on(Pack::decode(&mut buff)).unwrap();
+    }
+
+    #[test]
+    fn test_add_ref_delta() {
+        let base = b"hello world, this is the base object".to_vec();
+        let target = b"hello world, this is the new object".to_vec();
+        let delta = DeltaDiff::new(&base, &target).encode();
+        let base_id = Hash::Sha1([1u8; 20]);
+        let mut encoder = Encoder::init(1, Vec::new());
+        encoder.add_ref_delta(&base_id, &delta).unwrap();
+        encoder.finish().unwrap();
+        let pack_data = encoder.take_output();
+
+        let mut reader = Cursor::new(&pack_data[12..]);
+        let (type_num, size) = utils::read_type_and_size(&mut reader).unwrap();
+        assert_eq!((type_num, size), (7, delta.len()));
+        assert_eq!(utils::read_hash(&mut reader).unwrap(), base_id);
+        let mut content = Vec::new();
+        ReadPlain::new(&mut reader)
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(undelta(&mut Cursor::new(content), &base), target);
+    }
+
+    #[test]
+    fn test_add_delta_objects() {
+        // the versions of a file, each one adds lines to the previous one
+        let versions: Vec<Vec<u8>> = (1..=6)
+            .map(|n| {
+                let lines = (0..n * 20).map(|i| format!("line {} of the file\n", i));
+                lines.collect::<String>().into_bytes()
+            })
+            .collect();
+        for ofs_delta in [true, false] {
+            let options = DeltaOptions {
+                window: 10,
+                depth: 2,
+                ofs_delta,
+            };
+            let objects = versions
+                .iter()
+                .map(|data| {
+                    let hash = Meta::calculate_id(ObjectType::Blob, data);
+                    DeltaObject::new(hash, ObjectType::Blob, data.clone()).with_path("src/lib.rs")
+                })
+                .collect();
+            let mut encoder = Encoder::init(versions.len(), Vec::new()).with_delta_options(options);
+            let deltas = encoder.add_delta_objects(objects).unwrap();
+            encoder.finish().unwrap();
+            let pack = encoder.take_output();
+            assert!(deltas > 0);
+
+            let index = PackIndex::from_pack(&pack).unwrap();
+            let mut types = vec![];
+            for entry in index.entries() {
+                let mut reader = Cursor::new(&pack[entry.offset as usize..]);
+                types.push(utils::read_type_and_size(&mut reader).unwrap().0);
+            }
+            let delta_type = if ofs_delta { 6 } else { 7 };
+            assert_eq!(types.iter().filter(|t| **t == delta_type).count(), deltas);
+            assert_eq!(
+                types.iter().filter(|t| **t == 3).count(),
+                versions.len() - deltas
+            );
+            // the largest version is written whole, the others are deltas of the larger ones
+            let (first_type, _) = utils::read_type_and_size(&mut Cursor::new(&pack[12..])).unwrap();
+            assert_eq!(first_type, 3);
+
+            let mut reader = Cursor::new(&pack);
+            for data in &versions {
+                let hash = Meta::calculate_id(ObjectType::Blob, data);
+                let object = index.read_object(&mut reader, &hash).unwrap();
+                assert_eq!(object, Some((ObjectType::Blob, data.clone())));
+            }
+        }
+    }
+
+    #[test]
+    fn test_delta_search_off() {
+        let data = b"hello world, this is the same object again".to_vec();
+        let objects = (0..3)
+            .map(|_| DeltaObject::new(Hash::default(), ObjectType::Blob, data.clone()))
+            .collect();
+        let options = DeltaOptions {
+            window: 0,
+            ..DeltaOptions::default()
+        };
+        let mut encoder = Encoder::init(3, Vec::new()).with_delta_options(options);
+        assert_eq!(encoder.add_delta_objects(objects).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_name_hash() {
+        assert_eq!(name_hash(""), 0);
+        assert_eq!(name_hash("a b"), name_hash("ab"));
+        // the values of pack_name_hash in git
+        assert_eq!(name_hash("a"), 0x61000000);
+        assert_eq!(name_hash("ab"), 0x7a400000);
+        assert_eq!(name_hash("src/main.rs"), 0x94a152b0);
+        assert_eq!(name_hash("lib/main.rs"), 0x94a14f00);
+        // the files with the same name sort next to each other
+        let mut paths = ["src/main.rs", "docs/main.md", "src/lib.rs", "lib/main.rs"];
+        paths.sort_by_key(|path| name_hash(path));
+        assert_eq!(
+            paths,
+            ["docs/main.md", "src/lib.rs", "lib/main.rs", "src/main.rs"]
+        );
+    }
 }
@@ -0,0 +1,637 @@
+//!
+//! The pack index, the `.idx` file that locates the objects of a pack by their hash.
+//!
+//! Git [Pack-Format](https://git-scm.com/docs/pack-format), the version 2 of the index:
+//!
+//! ```text
+//! magic "\377tOc", version 2
+//! fanout table: 256 u32, the number of objects whose hash starts with a byte <= i
+//! the hashes of the objects, sorted
+//! the CRC32 of the packed data of each object
+//! the offset of each object in the pack, a u32 with the high bit set is an index into the
+//!   large offsets table instead
+//! large offsets table: u64, for the offsets that don't fit in 31 bits
+//! the checksum of the pack, then the checksum of the index
+//! ```
+//!
+//! An index can be generated for any pack, thin packs received on push included, and lets
+//! the objects be read straight from the pack file without decoding all of it.
+//!
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use super::{delta::undelta, Pack};
+use crate::{
+    errors::GitError,
+    hash::{get_hash_kind, Hash, ObjectHasher},
+    internal::{zlib::stream::inflate::ReadPlain, ObjectType},
+    utils,
+};
+
+const IDX_MAGIC: &[u8; 4] = b"\xfftOc";
+const IDX_VERSION: u32 = 2;
+const FANOUT_SIZE: usize = 256 * 4;
+// an offset with this bit set is an index into the large offsets table
+const LARGE_OFFSET_FLAG: u32 = 0x8000_0000;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// An object of the pack: its hash, the CRC32 of its packed data and where it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub hash: Hash,
+    pub crc32: u32,
+    pub offset: u64,
+}
+
+/// The index of a pack, its entries are sorted by hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackIndex {
+    entries: Vec<IndexEntry>,
+    fanout: [u32; 256],
+    pack_checksum: Hash,
+}
+
+// what an entry of a pack is made from
+#[derive(Debug, Clone, Copy)]
+enum EntryBase {
+    Object(ObjectType),
+    Offset(u64),
+    Hash(Hash),
+}
+
+// an entry of a pack with its inflated data, a delta isn't applied yet
+struct RawEntry {
+    offset: u64,
+    crc32: u32,
+    base: EntryBase,
+    data: Vec<u8>,
+}
+
+impl PackIndex {
+    pub fn new(mut entries: Vec<IndexEntry>, pack_checksum: Hash) -> Self {
+        entries.sort_by_key(|entry| entry.hash);
+        let mut fanout = [0u32; 256];
+        for entry in &entries {
+            fanout[entry.hash.as_bytes()[0] as usize] += 1;
+        }
+        let mut count = 0;
+        for slot in fanout.iter_mut() {
+            count += *slot;
+            *slot = count;
+        }
+        PackIndex {
+            entries,
+            fanout,
+            pack_checksum,
+        }
+    }
+
+    /// Generates the index of a pack, like `git index-pack`.
+    ///
+    /// Every delta of the pack must have its base in the pack, see
+    /// [`PackIndex::from_thin_pack`] for the packs received on push.
+    pub fn from_pack(pack: &[u8]) -> Result<Self, GitError> {
+        Self::from_thin_pack(pack, |_| None)
+    }
+
+    /// Generates the index of a pack whose `REF_DELTA` bases may not be in the pack.
+    ///
+    /// `find_base` returns the type and data of such a base, e.g. from the storage. The bases
+    /// are only used to compute the hashes of the deltas, they aren't added to the index.
+    pub fn from_thin_pack(
+        pack: &[u8],
+        find_base: impl FnMut(&Hash) -> Option<(ObjectType, Vec<u8>)>,
+    ) -> Result<Self, GitError> {
+        let hash_size = get_hash_kind().size();
+        if pack.len() < 12 + hash_size {
+            return Err(GitError::InvalidPackFile(format!(
+                "the pack is too short, {} bytes",
+                pack.len()
+            )));
+        }
+        let body_len = pack.len() - hash_size;
+        let pack_checksum = Hash::new_from_bytes(&pack[body_len..]);
+        let mut hasher = ObjectHasher::new(get_hash_kind());
+        hasher.update(&pack[..body_len]);
+        let hash = hasher.finalize();
+        if hash != pack_checksum {
+            return Err(GitError::InvalidPackFile(format!(
+                "checksum mismatch, expected {} got {}",
+                pack_checksum, hash
+            )));
+        }
+
+        let mut reader = Cursor::new(&pack[..body_len]);
+        let number_of_objects = Pack::check_header(&mut reader)?.number_of_objects();
+        let mut entries = Vec::new();
+        for _ in 0..number_of_objects {
+            let offset = reader.position();
+            let (base, data) = read_entry(&mut reader, offset)?;
+            let crc32 = CRC32.checksum(&pack[offset as usize..reader.position() as usize]);
+            entries.push(RawEntry {
+                offset,
+                crc32,
+                base,
+                data,
+            });
+        }
+        if reader.position() as usize != body_len {
+            return Err(GitError::InvalidPackFile(format!(
+                "{} bytes after the last object",
+                body_len - reader.position() as usize
+            )));
+        }
+
+        let hashes =
+            Resolver::new(entries.iter().map(|e| e.offset)).run(&mut entries, find_base)?;
+        let entries = entries
+            .iter()
+            .zip(hashes)
+            .map(|(entry, hash)| IndexEntry {
+                hash,
+                crc32: entry.crc32,
+                offset: entry.offset,
+            })
+            .collect();
+        Ok(PackIndex::new(entries, pack_checksum))
+    }
+
+    /// Reads an idx v2 file, its checksum and layout are checked.
+    pub fn read(mut reader: impl Read) -> Result<Self, GitError> {
+        let invalid = |reason: &str| GitError::InvalidIdxFile(reason.to_owned());
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| GitError::InvalidIdxFile(e.to_string()))?;
+        let hash_size = get_hash_kind().size();
+        if data.len() < 8 + FANOUT_SIZE + 2 * hash_size {
+            return Err(invalid("the index is too short"));
+        }
+        if data[..4] != IDX_MAGIC[..] {
+            return Err(invalid("the magic number is wrong"));
+        }
+        let version = read_u32(&data[4..]);
+        if version != IDX_VERSION {
+            return Err(GitError::InvalidIdxFile(format!("version {}", version)));
+        }
+        let checksum_start = data.len() - hash_size;
+        let mut hasher = ObjectHasher::new(get_hash_kind());
+        hasher.update(&data[..checksum_start]);
+        if hasher.finalize() != Hash::new_from_bytes(&data[checksum_start..]) {
+            return Err(invalid("the checksum of the index doesn't match"));
+        }
+
+        let mut fanout = [0u32; 256];
+        let mut previous = 0;
+        for (i, count) in fanout.iter_mut().enumerate() {
+            *count = read_u32(&data[8 + i * 4..]);
+            if *count < previous {
+                return Err(invalid("the fanout table isn't sorted"));
+            }
+            previous = *count;
+        }
+        let len = fanout[255] as usize;
+        let hashes_start = 8 + FANOUT_SIZE;
+        let crc_start = hashes_start + len * hash_size;
+        let offsets_start = crc_start + len * 4;
+        let large_offsets_start = offsets_start + len * 4;
+        if large_offsets_start + 2 * hash_size > data.len() {
+            return Err(invalid("the index is too short for its objects"));
+        }
+        let large_offsets = &data[large_offsets_start..data.len() - 2 * hash_size];
+        if large_offsets.len() % 8 != 0 {
+            return Err(invalid("the large offsets table is truncated"));
+        }
+
+        let mut entries = Vec::with_capacity(len);
+        for i in 0..len {
+            let hash_start = hashes_start + i * hash_size;
+            let hash = Hash::new_from_bytes(&data[hash_start..hash_start + hash_size]);
+            let offset = read_u32(&data[offsets_start + i * 4..]);
+            let offset = if offset & LARGE_OFFSET_FLAG == 0 {
+                offset as u64
+            } else {
+                let start = (offset & !LARGE_OFFSET_FLAG) as usize * 8;
+                let bytes = large_offsets
+                    .get(start..start + 8)
+                    .ok_or_else(|| invalid("a large offset is out of the table"))?;
+                u64::from_be_bytes(bytes.try_into().unwrap())
+            };
+            entries.push(IndexEntry {
+                hash,
+                crc32: read_u32(&data[crc_start + i * 4..]),
+                offset,
+            });
+        }
+        if entries.windows(2).any(|w| w[0].hash >= w[1].hash) {
+            return Err(invalid("the hashes aren't sorted"));
+        }
+        let index = PackIndex {
+            entries,
+            fanout,
+            pack_checksum: Hash::new_from_bytes(&data[checksum_start - hash_size..checksum_start]),
+        };
+        if PackIndex::new(index.entries.clone(), index.pack_checksum).fanout != fanout {
+            return Err(invalid("the fanout table doesn't match the hashes"));
+        }
+        Ok(index)
+    }
+
+    /// Writes the index in the idx v2 format, followed by its checksum.
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let hash_size = self.pack_checksum.kind().size();
+        let mut data = Vec::with_capacity(8 + FANOUT_SIZE + self.len() * (hash_size + 8));
+        data.extend_from_slice(IDX_MAGIC);
+        data.extend_from_slice(&IDX_VERSION.to_be_bytes());
+        for count in self.fanout {
+            data.extend_from_slice(&count.to_be_bytes());
+        }
+        for entry in &self.entries {
+            data.extend_from_slice(entry.hash.as_bytes());
+        }
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.crc32.to_be_bytes());
+        }
+        let mut large_offsets = Vec::new();
+        for entry in &self.entries {
+            let offset = if entry.offset < LARGE_OFFSET_FLAG as u64 {
+                entry.offset as u32
+            } else {
+                large_offsets.push(entry.offset);
+                (large_offsets.len() - 1) as u32 | LARGE_OFFSET_FLAG
+            };
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        for offset in large_offsets {
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        data.extend_from_slice(self.pack_checksum.as_bytes());
+        let mut hasher = ObjectHasher::new(self.pack_checksum.kind());
+        hasher.update(&data);
+        data.extend_from_slice(hasher.finalize().as_bytes());
+        data
+    }
+
+    /// The path of the index of a pack, `pack-<checksum>.idx` next to `pack-<checksum>.pack`.
+    pub fn index_path(pack_path: &Path) -> PathBuf {
+        pack_path.with_extension("idx")
+    }
+
+    /// Reads the index of a pack file, it's generated and written next to the pack if it
+    /// doesn't exist yet.
+    pub fn open(pack_path: &Path) -> Result<Self, GitError> {
+        let index_path = Self::index_path(pack_path);
+        if let Ok(file) = fs::File::open(&index_path) {
+            return Self::read(BufReader::new(file));
+        }
+        let pack = fs::read(pack_path)
+            .map_err(|e| GitError::InvalidPackFile(format!("{}: {}", pack_path.display(), e)))?;
+        let index = Self::from_pack(&pack)?;
+        fs::write(&index_path, index.to_bytes())
+            .map_err(|e| GitError::InvalidIdxFile(format!("{}: {}", index_path.display(), e)))?;
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The entries of the index, sorted by hash.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn pack_checksum(&self) -> Hash {
+        self.pack_checksum
+    }
+
+    /// Looks an object up by its hash, only the hashes sharing its first byte are searched.
+    pub fn find(&self, hash: &Hash) -> Option<&IndexEntry> {
+        let first = hash.as_bytes()[0] as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let end = self.fanout[first] as usize;
+        let entries = &self.entries[start..end];
+        entries
+            .binary_search_by_key(hash, |entry| entry.hash)
+            .ok()
+            .map(|i| &entries[i])
+    }
+
+    /// Reads an object from the pack the index was generated for, its deltas are applied.
+    ///
+    /// Returns `None` if the object isn't in the pack.
+    pub fn read_object(
+        &self,
+        pack: &mut (impl Read + Seek),
+        hash: &Hash,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, GitError> {
+        let Some(entry) = self.find(hash) else {
+            return Ok(None);
+        };
+        let mut offset = entry.offset;
+        let mut deltas = Vec::new();
+        let (object_type, mut data) = loop {
+            pack.seek(SeekFrom::Start(offset))
+                .map_err(|e| GitError::InvalidPackFile(e.to_string()))?;
+            let (base, data) = read_entry(&mut BufReader::new(&mut *pack), offset)?;
+            match base {
+                EntryBase::Object(object_type) => break (object_type, data),
+                EntryBase::Offset(base_offset) => offset = base_offset,
+                EntryBase::Hash(base_hash) => {
+                    offset = self
+                        .find(&base_hash)
+                        .ok_or_else(|| GitError::NotFountHashValue(base_hash.to_plain_str()))?
+                        .offset
+                }
+            }
+            deltas.push(data);
+            // a chain longer than the pack loops
+            if deltas.len() > self.len() {
+                return Err(GitError::DeltaObjectError(format!(
+                    "the delta chain of {} loops",
+                    hash.to_plain_str()
+                )));
+            }
+        };
+        for delta in deltas.iter().rev() {
+            data = undelta(&mut Cursor::new(delta), &data);
+        }
+        Ok(Some((object_type, data)))
+    }
+}
+
+/// Resolves the deltas of a pack to compute the hashes of its objects.
+///
+/// A delta is applied as soon as its base is, the deltas waiting for a base that isn't in the
+/// pack are left to the bases found at the end.
+struct Resolver {
+    by_offset: HashMap<u64, usize>,
+    objects: Vec<Option<(ObjectType, Vec<u8>)>>,
+    hashes: Vec<Option<Hash>>,
+    by_hash: HashMap<Hash, usize>,
+    waiting_offset: HashMap<usize, Vec<usize>>,
+    waiting_hash: HashMap<Hash, Vec<usize>>,
+}
+
+impl Resolver {
+    fn new(offsets: impl Iterator<Item = u64>) -> Self {
+        let by_offset: HashMap<u64, usize> = offsets.enumerate().map(|(i, o)| (o, i)).collect();
+        let len = by_offset.len();
+        Resolver {
+            by_offset,
+            objects: vec![None; len],
+            hashes: vec![None; len],
+            by_hash: HashMap::new(),
+            waiting_offset: HashMap::new(),
+            waiting_hash: HashMap::new(),
+        }
+    }
+
+    fn run(
+        mut self,
+        entries: &mut [RawEntry],
+        mut find_base: impl FnMut(&Hash) -> Option<(ObjectType, Vec<u8>)>,
+    ) -> Result<Vec<Hash>, GitError> {
+        for i in 0..entries.len() {
+            let base = match entries[i].base {
+                EntryBase::Object(object_type) => {
+                    let data = std::mem::take(&mut entries[i].data);
+                    self.resolve(entries, i, object_type, data);
+                    continue;
+                }
+                EntryBase::Offset(offset) => {
+                    let j = *self.by_offset.get(&offset).ok_or_else(|| {
+                        GitError::InvalidObjectInfo(format!("no object at offset {}", offset))
+                    })?;
+                    if self.objects[j].is_none() {
+                        self.waiting_offset.entry(j).or_default().push(i);
+                        continue;
+                    }
+                    j
+                }
+                EntryBase::Hash(hash) => match self.by_hash.get(&hash) {
+                    Some(j) => *j,
+                    None => {
+                        self.waiting_hash.entry(hash).or_default().push(i);
+                        continue;
+                    }
+                },
+            };
+            let (object_type, base) = self.objects[base].as_ref().unwrap();
+            let data = undelta(&mut Cursor::new(&entries[i].data), base);
+            let object_type = *object_type;
+            self.resolve(entries, i, object_type, data);
+        }
+        // the bases of a thin pack
+        while let Some(hash) = self.waiting_hash.keys().next().copied() {
+            let (object_type, base) =
+                find_base(&hash).ok_or_else(|| GitError::NotFountHashValue(hash.to_plain_str()))?;
+            for i in self.waiting_hash.remove(&hash).unwrap() {
+                let data = undelta(&mut Cursor::new(&entries[i].data), &base);
+                self.resolve(entries, i, object_type, data);
+            }
+        }
+        self.hashes
+            .into_iter()
+            .enumerate()
+            .map(|(i, hash)| {
+                hash.ok_or_else(|| {
+                    GitError::DeltaObjectError(format!(
+                        "the base of the delta at offset {} is missing",
+                        entries[i].offset
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    // stores a resolved object, then applies the deltas waiting for it
+    fn resolve(&mut self, entries: &[RawEntry], i: usize, object_type: ObjectType, data: Vec<u8>) {
+        let mut ready = vec![(i, data)];
+        while let Some((i, data)) = ready.pop() {
+            let hash = object_hash(object_type, &data);
+            let waiting = self.waiting_offset.remove(&i).into_iter().flatten();
+            for j in waiting.chain(self.waiting_hash.remove(&hash).into_iter().flatten()) {
+                ready.push((j, undelta(&mut Cursor::new(&entries[j].data), &data)));
+            }
+            self.hashes[i] = Some(hash);
+            self.by_hash.insert(hash, i);
+            self.objects[i] = Some((object_type, data));
+        }
+    }
+}
+
+// reads the entry of a pack at `offset`, the data of a delta is the delta itself
+fn read_entry(reader: &mut impl BufRead, offset: u64) -> Result<(EntryBase, Vec<u8>), GitError> {
+    let invalid = |e: io::Error| GitError::InvalidPackFile(e.to_string());
+    let (type_num, size) = utils::read_type_and_size(reader).map_err(invalid)?;
+    let base = match ObjectType::number2type(type_num)? {
+        ObjectType::OffsetDelta => {
+            let distance = utils::read_offset_encoding(reader, &mut 0).map_err(invalid)?;
+            let base_offset = offset.checked_sub(distance).ok_or_else(|| {
+                GitError::InvalidObjectInfo("Invalid OffsetDelta offset".to_string())
+            })?;
+            EntryBase::Offset(base_offset)
+        }
+        ObjectType::HashDelta => EntryBase::Hash(utils::read_hash(reader).map_err(invalid)?),
+        object_type => EntryBase::Object(object_type),
+    };
+    let mut data = Vec::with_capacity(size);
+    ReadPlain::new(reader)
+        .read_to_end(&mut data)
+        .map_err(invalid)?;
+    if data.len() != size {
+        return Err(GitError::InvalidPackFile(format!(
+            "the object at offset {} has {} bytes instead of {}",
+            offset,
+            data.len(),
+            size
+        )));
+    }
+    Ok((base, data))
+}
+
+fn object_hash(object_type: ObjectType, data: &[u8]) -> Hash {
+    let mut h = ObjectHasher::new(get_hash_kind());
+    h.update(object_type.to_bytes());
+    h.update(b" ");
+    h.update(data.len().to_string());
+    h.update(b"\0");
+    h.update(data);
+    h.finalize()
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use super::{object_hash, IndexEntry, PackIndex};
+    use crate::{
+        hash::Hash,
+        internal::{
+            diff::DeltaDiff,
+            object::{blob::Blob, ObjectT},
+            pack::encode::{pack_encode, Encoder},
+            ObjectType,
+        },
+    };
+
+    fn blob(data: &str) -> Arc<dyn ObjectT> {
+        Arc::new(Blob {
+            id: Hash::default(),
+            data: data.as_bytes().to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_index_encoded_pack() {
+        let contents = ["hello,1", "hello,2", "hello,3"];
+        let pack = pack_encode(contents.into_iter().map(blob).collect()).unwrap();
+        let index = PackIndex::from_pack(&pack).unwrap();
+        assert_eq!(index.len(), 3);
+        assert!(index.entries().windows(2).all(|w| w[0].hash < w[1].hash));
+        assert_eq!(index.pack_checksum().as_bytes(), &pack[pack.len() - 20..]);
+
+        let mut reader = Cursor::new(&pack);
+        for content in contents {
+            let hash = object_hash(ObjectType::Blob, content.as_bytes());
+            let entry = index.find(&hash).unwrap();
+            assert!(entry.offset >= 12);
+            let (object_type, data) = index.read_object(&mut reader, &hash).unwrap().unwrap();
+            assert_eq!(object_type, ObjectType::Blob);
+            assert_eq!(data, content.as_bytes());
+        }
+        let missing = object_hash(ObjectType::Blob, b"hello,4");
+        assert!(index.find(&missing).is_none());
+        assert!(index.read_object(&mut reader, &missing).unwrap().is_none());
+
+        let bytes = index.to_bytes();
+        assert_eq!(&bytes[..8], b"\xfftOc\x00\x00\x00\x02");
+        assert_eq!(PackIndex::read(&bytes[..]).unwrap(), index);
+    }
+
+    #[test]
+    fn test_index_ref_delta() {
+        let base = b"hello world, this is the base object".to_vec();
+        let target = b"hello world, this is the new object".to_vec();
+        let base_hash = object_hash(ObjectType::Blob, &base);
+        let target_hash = object_hash(ObjectType::Blob, &target);
+        let delta = DeltaDiff::new(&base, &target).encode();
+
+        // the delta comes before its base
+        let mut encoder = Encoder::init(2, Vec::new());
+        encoder.add_ref_delta(&base_hash, &delta).unwrap();
+        encoder
+            .add_objects(vec![blob(std::str::from_utf8(&base).unwrap())])
+            .unwrap();
+        encoder.finish().unwrap();
+        let pack = encoder.take_output();
+        let index = PackIndex::from_pack(&pack).unwrap();
+        assert_eq!(index.find(&target_hash).unwrap().offset, 12);
+        let object = index
+            .read_object(&mut Cursor::new(&pack), &target_hash)
+            .unwrap();
+        assert_eq!(object, Some((ObjectType::Blob, target.clone())));
+
+        // a thin pack, the base isn't in the pack
+        let mut encoder = Encoder::init(1, Vec::new());
+        encoder.add_ref_delta(&base_hash, &delta).unwrap();
+        encoder.finish().unwrap();
+        let pack = encoder.take_output();
+        assert!(PackIndex::from_pack(&pack).is_err());
+        let index = PackIndex::from_thin_pack(&pack, |hash| {
+            (*hash == base_hash).then(|| (ObjectType::Blob, base.clone()))
+        })
+        .unwrap();
+        assert_eq!(index.len(), 1);
+        assert!(index.find(&target_hash).is_some());
+        assert!(index.find(&base_hash).is_none());
+    }
+
+    #[test]
+    fn test_index_large_offsets() {
+        let entry = |byte: u8, offset: u64| IndexEntry {
+            hash: Hash::Sha1([byte; 20]),
+            crc32: byte as u32,
+            offset,
+        };
+        let index = PackIndex::new(
+            vec![entry(3, 1 << 32), entry(1, 12), entry(2, 0x8000_0000)],
+            Hash::Sha1([9; 20]),
+        );
+        assert_eq!(index.entries()[0], entry(1, 12));
+        let bytes = index.to_bytes();
+        // 2 large offsets, the pack checksum and the index checksum
+        assert_eq!(bytes.len(), 8 + 1024 + 3 * (20 + 4 + 4) + 2 * 8 + 2 * 20);
+        let read = PackIndex::read(&bytes[..]).unwrap();
+        assert_eq!(read, index);
+        assert_eq!(read.find(&Hash::Sha1([3; 20])).unwrap().offset, 1 << 32);
+
+        let mut corrupted = bytes.clone();
+        corrupted[8 + 1024] ^= 1;
+        assert!(PackIndex::read(&corrupted[..]).is_err());
+    }
+}
@@ -122,7 +122,7 @@ impl<BR: std::io::BufRead> EntriesIter<BR> {
             } else if type_num == 7 {
                 // Ref Delta Object
                 let hash = utils::read_hash(&mut self.inner).unwrap();
-                iter_offset += 20;
+                iter_offset += hash.kind().size();
 
                 if let Some(bo) = self.cache.get_by_hash(hash) {
                     base_object = bo;
@@ -248,7 +248,7 @@ impl<BR: std::io::BufRead> EntriesIter<BR> {
             } else if type_num == 7 {
                 // Ref Delta Object
                 let hash = utils::read_hash(&mut self.inner).unwrap();
-                iter_offset += 20;
+                iter_offset += hash.kind().size();
 
                 if let Some(bo) = self.cache.get_by_hash(hash) {
                     base_object = bo;
@@ -19,8 +19,10 @@ pub mod decode;
 pub mod delta;
 pub mod encode;
 mod header;
+pub mod index;
 pub mod iterator;
 pub mod preload;
+pub mod stream;
 /// ### Represents a Git pack file.
 ///  `head`: The file header, typically "PACK"<br>
 /// `version`: The pack file version <br>
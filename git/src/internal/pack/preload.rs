@@ -1,6 +1,7 @@
 use super::{counter::GitTypeCounter, delta::undelta, EntryHeader, Pack};
 use crate::{
     errors::GitError,
+    hash::{get_hash_kind, with_hash_kind, ObjectHasher},
     internal::{
         pack::{counter::DecodeCounter, cqueue::CircularQueue, Hash},
         zlib::stream::inflate::ReadPlain,
@@ -15,7 +16,6 @@ use num_cpus;
 
 use redis::{ToRedisArgs, FromRedisValue, RedisError, ErrorKind};
 use sea_orm::Set;
-use sha1::{Digest, Sha1};
 use std::{
     collections::HashMap,
     io::{Cursor, Read},
@@ -150,7 +150,7 @@ impl PackPreload {
                 7 => {
                     // Ref Delta Object
                     let hash = utils::read_hash(&mut r).unwrap();
-                    iter_offset += 20;
+                    iter_offset += hash.kind().size();
                     EntryHeader::RefDelta { base_id: hash }
                 }
                 _ => todo!(), //error
@@ -212,6 +212,8 @@ pub async fn decode_load(p: PackPreload, storage: Arc<dyn ObjectStorage>) -> Res
     tracing::info!("Deal with the object using {} threads. ", cpu_number);
     let share: Arc<RwLock<PackPreload>> = Arc::new(RwLock::new(p));
     let mr_id = generate_id();
+    // the producers hash the objects in tasks of their own
+    let kind = get_hash_kind();
     
     let mut cache_type: String= String::new();
     utils::get_env_number("GIT_INTERNAL_DECODE_CACHE_TYEP", &mut cache_type);
@@ -230,17 +232,17 @@ pub async fn decode_load(p: PackPreload, storage: Arc<dyn ObjectStorage>) -> Res
             };
             match &cache_type as &str {
                 "redis" => 
-                tokio::spawn(async move {
+                tokio::spawn(with_hash_kind(kind, async move {
                     produce_object::<kvObjectCache<Entry>>(shard_clone, st_clone, begin, end, counter_clone, mr_id).await
-                }),
+                })),
                 "lru" =>
-                tokio::spawn(async move {
+                tokio::spawn(with_hash_kind(kind, async move {
                     produce_object::<ObjectCache<Entry>>(shard_clone, st_clone, begin, end, counter_clone, mr_id).await
-                }),
+                })),
                 _ =>
-                tokio::spawn(async move {
+                tokio::spawn(with_hash_kind(kind, async move {
                     produce_object::<ObjectCache<Entry>>(shard_clone, st_clone, begin, end, counter_clone, mr_id).await
-                }),
+                })),
             }
         })
         .collect();
@@ -458,14 +460,13 @@ fn compute_hash(mut e: Entry) -> Entry {
         _ => (),
     }
 
-    let mut h = Sha1::new();
+    let mut h = ObjectHasher::new(get_hash_kind());
     h.update(e.header.to_bytes());
     h.update(b" ");
     h.update(e.data.len().to_string());
     h.update(b"\0");
     h.update(&e.data);
-    let re: [u8; 20] = h.finalize().into();
-    e.hash = Some(Hash(re));
+    e.hash = Some(h.finalize());
     e
 }
 
@@ -0,0 +1,444 @@
+//!
+//! Streaming pack decoding.
+//!
+//! Unlike [`super::preload::PackPreload`], which loads every entry of a pack in memory before
+//! decoding it, the entries are decoded one by one while the pack is still being received and
+//! are stored in batches. The memory used is bounded by the delta base cache and the batch
+//! size, not by the size of the pack.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, Cursor, Read};
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use common::errors::MegaError;
+use database::{driver::ObjectStorage, utils::id_generator::generate_id};
+use entity::{git_obj, mr};
+use sea_orm::Set;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::Receiver;
+
+use super::cache::{_Cache, ObjectCache};
+use super::decode::{read_tail_hash, HashCounter};
+use super::{delta::undelta, EntryHeader, Pack};
+use crate::{
+    errors::GitError,
+    hash::{get_hash_kind, Hash, ObjectHasher},
+    internal::zlib::stream::inflate::ReadPlain,
+    utils,
+};
+
+// flush the pending objects to the storage once they hold that many bytes
+const MAX_BATCH_BYTES: usize = 64 * 1024 * 1024;
+// reject a pack whose `REF_DELTA` entries waiting for their base hold more bytes than that
+const MAX_PENDING_DELTA_BYTES: usize = 256 * 1024 * 1024;
+
+/// A blocking [`Read`] over the chunks of a pack sent through a channel.
+///
+/// It lets the sync pack decoder run in a blocking thread while the request body is still
+/// being received, the bounded channel applies back pressure to the sender.
+pub struct ChannelReader {
+    rx: Receiver<Bytes>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    pub fn new(first: Bytes, rx: Receiver<Bytes>) -> Self {
+        ChannelReader { rx, current: first }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.current = chunk,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.current.len());
+        self.current.copy_to_slice(&mut buf[..len]);
+        Ok(len)
+    }
+}
+
+#[derive(Clone)]
+struct Object {
+    header: EntryHeader,
+    data: Vec<u8>,
+}
+
+/// What a decoded pack brought to the storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeSummary {
+    /// the merge request the objects are saved with
+    pub mr_id: i64,
+    /// the number of objects in the pack
+    pub objects: usize,
+    /// the number of deltas among them
+    pub deltas: usize,
+    /// the number of `REF_DELTA` bases that are not in the pack but were found in the storage,
+    /// a thin pack relies on them. Like `git index-pack --fix-thin` appends them to the pack,
+    /// they are added to the merge request, which holds `objects + local_objects` objects.
+    pub local_objects: usize,
+}
+
+/// The decoded objects waiting to be saved.
+struct Batch {
+    mr_id: i64,
+    objects: Vec<(Hash, Object)>,
+    // the external bases of a thin pack, they are only added to the merge request
+    external: Vec<(Hash, EntryHeader)>,
+    index: HashMap<Hash, usize>,
+    bytes: usize,
+    max_len: usize,
+}
+
+impl Batch {
+    fn get(&self, hash: &Hash) -> Option<&Object> {
+        self.index.get(hash).map(|i| &self.objects[*i].1)
+    }
+
+    fn push(&mut self, hash: Hash, object: Object) {
+        self.bytes += object.data.len();
+        self.index.insert(hash, self.objects.len());
+        self.objects.push((hash, object));
+    }
+
+    fn push_external(&mut self, hash: Hash, header: EntryHeader) {
+        self.external.push((hash, header));
+    }
+
+    fn is_full(&self) -> bool {
+        self.objects.len() >= self.max_len || self.bytes >= MAX_BATCH_BYTES
+    }
+
+    /// Saves the objects, each `git_obj` row has the id of its `mr` row so that the objects of
+    /// a rejected pack can be removed by [`ObjectStorage::delete_mr_objects`].
+    fn save(&mut self, storage: &Arc<dyn ObjectStorage>, handle: &Handle) -> Result<(), GitError> {
+        if self.objects.is_empty() && self.external.is_empty() {
+            return Ok(());
+        }
+        let mut mr_models = Vec::with_capacity(self.objects.len() + self.external.len());
+        let mut obj_models = Vec::with_capacity(self.objects.len());
+        for (hash, header) in self.external.drain(..) {
+            mr_models.push(mr::ActiveModel {
+                id: Set(generate_id()),
+                mr_id: Set(self.mr_id),
+                git_id: Set(hash.to_plain_str()),
+                object_type: Set(String::from_utf8_lossy(header.to_bytes()).to_string()),
+                created_at: Set(chrono::Utc::now().naive_utc()),
+            });
+        }
+        for (hash, object) in self.objects.drain(..) {
+            let object_type = String::from_utf8_lossy(object.header.to_bytes()).to_string();
+            let id = generate_id();
+            mr_models.push(mr::ActiveModel {
+                id: Set(id),
+                mr_id: Set(self.mr_id),
+                git_id: Set(hash.to_plain_str()),
+                object_type: Set(object_type.clone()),
+                created_at: Set(chrono::Utc::now().naive_utc()),
+            });
+            obj_models.push(git_obj::ActiveModel {
+                id: Set(id),
+                git_id: Set(hash.to_plain_str()),
+                object_type: Set(object_type),
+                data: Set(object.data),
+            });
+        }
+        handle.block_on(async {
+            storage.save_mr_objects(mr_models).await?;
+            if !obj_models.is_empty() {
+                storage.save_obj_data(obj_models).await?;
+            }
+            Ok::<_, GitError>(())
+        })?;
+        self.index.clear();
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+/// Decodes a pack from `reader` entry by entry and saves the objects to the storage.
+///
+/// It's a blocking function, run it with [`tokio::task::spawn_blocking`]; `handle` is used to
+/// call the async storage. Delta bases that were evicted from the cache are read back from the
+/// pending batch or the storage.
+///
+/// The pack may be thin: the bases of its `REF_DELTA` entries can be objects that were stored
+/// before in the repo at `repo_path` instead of objects of the pack. A `REF_DELTA` whose base comes later in the pack is
+/// held until the base is decoded.
+///
+/// `on_progress` is called with the number of decoded objects and the total after each object.
+///
+/// The batches are saved before the checksum at the end of the pack is read, the objects
+/// already saved are removed again if the pack turns out to be invalid.
+///
+/// # Returns
+///
+/// The `mr_id` the objects are saved with and the counts of the pack, or an error if the pack
+/// is malformed, a delta base can't be found or its checksum doesn't match.
+pub fn decode_stream<R: Read>(
+    reader: R,
+    storage: Arc<dyn ObjectStorage>,
+    repo_path: &str,
+    handle: Handle,
+    on_progress: impl FnMut(usize, usize),
+) -> Result<DecodeSummary, GitError> {
+    let mr_id = generate_id();
+    let result = decode_objects(reader, mr_id, &storage, repo_path, &handle, on_progress);
+    if let Err(e) = &result {
+        tracing::warn!("Invalid pack, dropping its objects: {}", e);
+        if let Err(e) = handle.block_on(storage.delete_mr_objects(mr_id)) {
+            tracing::error!("Failed to drop the objects of mr {}: {}", mr_id, e);
+        }
+    }
+    result
+}
+
+fn decode_objects<R: Read>(
+    reader: R,
+    mr_id: i64,
+    storage: &Arc<dyn ObjectStorage>,
+    repo_path: &str,
+    handle: &Handle,
+    mut on_progress: impl FnMut(usize, usize),
+) -> Result<DecodeSummary, GitError> {
+    let mut r = HashCounter::new(BufReader::new(reader), true);
+    let pack = Pack::check_header(&mut r)?;
+    let obj_number = pack.number_of_objects();
+    tracing::info!("Start decoding the pack stream, objects:{}", obj_number);
+
+    let mut cache_size = 1000;
+    utils::get_env_number("GIT_INTERNAL_DECODE_CACHE_SIZE", &mut cache_size);
+    let cache: ObjectCache<Object> = ObjectCache::new(Some(cache_size));
+    let mut batch_size = 10000;
+    utils::get_env_number("GIT_INTERNAL_DECODE_STORAGE_BATCH_SIZE", &mut batch_size);
+    let mut batch = Batch {
+        mr_id,
+        objects: Vec::new(),
+        external: Vec::new(),
+        index: HashMap::new(),
+        bytes: 0,
+        max_len: batch_size,
+    };
+
+    let invalid = |e: io::Error| GitError::InvalidPackFile(e.to_string());
+    // looks for an already decoded object in the cache, the pending batch or the storage
+    let find_base = |hash: Hash, batch: &Batch| -> Option<Object> {
+        if let Some(obj) = cache.get_by_hash(hash) {
+            return Some(obj);
+        }
+        if let Some(obj) = batch.get(&hash) {
+            return Some(obj.clone());
+        }
+        match handle.block_on(storage.get_obj_data_by_id(&hash.to_plain_str())) {
+            Ok(Some(model)) => Some(Object {
+                header: EntryHeader::from_string(&model.object_type),
+                data: model.data,
+            }),
+            _ => None,
+        }
+    };
+    // the objects decoded so far, to tell the external bases of a thin pack apart
+    let mut decoded: HashSet<Hash> = HashSet::new();
+    let mut external: HashSet<Hash> = HashSet::new();
+    // the `REF_DELTA` entries waiting for their base, with their offset
+    let mut pending: HashMap<Hash, Vec<(usize, Vec<u8>)>> = HashMap::new();
+    let mut pending_bytes: usize = 0;
+    let mut deltas = 0;
+
+    let mut offset: usize = 12;
+    for i in 0..obj_number {
+        if i % 10000 == 0 {
+            tracing::info!("Decoding git objects:{}", i);
+        }
+        let mut iter_offset: usize = 0;
+        let (type_num, size) = utils::read_type_and_size(&mut r).map_err(invalid)?;
+        iter_offset += utils::get_7bit_count(size << 3);
+        let header = match type_num {
+            1 => EntryHeader::Commit,
+            2 => EntryHeader::Tree,
+            3 => EntryHeader::Blob,
+            4 => EntryHeader::Tag,
+            6 => {
+                let delta_offset =
+                    utils::read_offset_encoding(&mut r, &mut iter_offset).map_err(invalid)?;
+                let base_distance = offset.checked_sub(delta_offset as usize).ok_or_else(|| {
+                    GitError::InvalidObjectInfo("Invalid OffsetDelta offset".to_string())
+                })?;
+                EntryHeader::OfsDelta { base_distance }
+            }
+            7 => {
+                let base_id = utils::read_hash(&mut r).map_err(invalid)?;
+                iter_offset += base_id.kind().size();
+                EntryHeader::RefDelta { base_id }
+            }
+            other => {
+                return Err(GitError::InvalidObjectType(other.to_string()));
+            }
+        };
+        let mut inflater = ReadPlain::new(&mut r);
+        let mut content = Vec::with_capacity(size);
+        inflater.read_to_end(&mut content).map_err(invalid)?;
+        iter_offset += inflater.decompressor.total_in() as usize;
+
+        let object = match header {
+            EntryHeader::OfsDelta { base_distance } => {
+                deltas += 1;
+                let base_hash = cache
+                    .get_hash(base_distance)
+                    .ok_or_else(|| GitError::InvalidObjectInfo(base_distance.to_string()))?;
+                let base = find_base(base_hash, &batch)
+                    .ok_or_else(|| GitError::NotFountHashValue(base_hash.to_plain_str()))?;
+                Some(Object {
+                    header: base.header,
+                    data: undelta(&mut Cursor::new(content), &base.data),
+                })
+            }
+            EntryHeader::RefDelta { base_id } => {
+                deltas += 1;
+                // the storage is shared by all the repos, a thin pack is only completed with
+                // the objects of the repo it's sent to
+                let base = find_base(base_id, &batch).filter(|_| {
+                    decoded.contains(&base_id)
+                        || external.contains(&base_id)
+                        || is_repo_object(storage, handle, repo_path, &base_id)
+                });
+                match base {
+                    Some(base) => {
+                        if !decoded.contains(&base_id) && external.insert(base_id) {
+                            batch.push_external(base_id, base.header.clone());
+                        }
+                        Some(Object {
+                            header: base.header,
+                            data: undelta(&mut Cursor::new(content), &base.data),
+                        })
+                    }
+                    None => {
+                        pending_bytes += content.len();
+                        if pending_bytes > MAX_PENDING_DELTA_BYTES {
+                            return Err(GitError::InvalidPackFile(format!(
+                                "too many deltas waiting for their base, last one is {}",
+                                base_id
+                            )));
+                        }
+                        pending.entry(base_id).or_default().push((offset, content));
+                        None
+                    }
+                }
+            }
+            header => Some(Object {
+                header,
+                data: content,
+            }),
+        };
+        // a decoded object may be the base of the deltas waiting for it
+        let mut ready: Vec<(usize, Object)> = object.map(|o| (offset, o)).into_iter().collect();
+        while let Some((object_offset, object)) = ready.pop() {
+            let hash = object_hash(&object);
+            for (delta_offset, delta) in pending.remove(&hash).unwrap_or_default() {
+                pending_bytes -= delta.len();
+                let data = undelta(&mut Cursor::new(delta), &object.data);
+                ready.push((
+                    delta_offset,
+                    Object {
+                        header: object.header.clone(),
+                        data,
+                    },
+                ));
+            }
+            decoded.insert(hash);
+            cache.put(object_offset, hash, object.clone());
+            batch.push(hash, object);
+            if batch.is_full() {
+                batch.save(storage, handle)?;
+            }
+        }
+        offset += iter_offset;
+        on_progress(i + 1, obj_number);
+    }
+    if let Some(base_id) = pending.keys().next() {
+        return Err(GitError::NotFountHashValue(base_id.to_plain_str()));
+    }
+    batch.save(storage, handle)?;
+
+    let hash = r.final_hash();
+    let signature = read_tail_hash(&mut r);
+    if hash != signature {
+        return Err(GitError::InvalidPackFile(format!(
+            "checksum mismatch, expected {} got {}",
+            signature, hash
+        )));
+    }
+    Ok(DecodeSummary {
+        mr_id: batch.mr_id,
+        objects: obj_number,
+        deltas,
+        local_objects: external.len(),
+    })
+}
+
+fn object_hash(object: &Object) -> Hash {
+    let mut h = ObjectHasher::new(get_hash_kind());
+    h.update(object.header.to_bytes());
+    h.update(b" ");
+    h.update(object.data.len().to_string());
+    h.update(b"\0");
+    h.update(&object.data);
+    h.finalize()
+}
+
+// whether an object was pushed to the repo: a commit of the repo, an object of its trees or
+// one of its annotated tags
+fn is_repo_object(
+    storage: &Arc<dyn ObjectStorage>,
+    handle: &Handle,
+    repo_path: &str,
+    id: &Hash,
+) -> bool {
+    let ids = vec![id.to_plain_str()];
+    let found = handle.block_on(async {
+        let commits = storage.get_commit_by_hashes(ids.clone()).await?;
+        if commits.iter().any(|c| c.repo_path == repo_path) {
+            return Ok(true);
+        }
+        let nodes = storage.get_nodes_by_hashes(ids.clone()).await?;
+        if nodes.iter().any(|n| n.repo_path == repo_path) {
+            return Ok(true);
+        }
+        let tags = storage.get_tags_by_repo_path(repo_path).await?;
+        Ok::<_, MegaError>(tags.iter().any(|t| ids.contains(&t.tag_id)))
+    });
+    found.unwrap_or_else(|e| {
+        tracing::error!("failed to look up {}: {}", ids[0], e);
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+
+    use super::ChannelReader;
+
+    #[test]
+    fn test_channel_reader() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.try_send(Bytes::from_static(b"CK")).unwrap();
+        tx.try_send(Bytes::from_static(b"")).unwrap();
+        tx.try_send(Bytes::from_static(b"\x00\x00\x00\x02"))
+            .unwrap();
+        drop(tx);
+
+        let mut reader = ChannelReader::new(Bytes::from_static(b"PA"), rx);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"PACK\x00\x00\x00\x02");
+    }
+}
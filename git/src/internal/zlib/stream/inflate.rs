@@ -1,8 +1,8 @@
 use std::{io, io::BufRead};
 
+use crate::hash::{get_hash_kind, ObjectHasher};
 use crate::internal::ObjectType;
 use flate2::{Decompress, FlushDecompress, Status};
-use sha1::{digest::core_api::CoreWrapper, Digest, Sha1};
 /// ReadBoxed is to unzip information from a  DEFLATE stream,
 /// which hash [`BufRead`] trait.
 /// For a continuous stream of DEFLATE information, the structure
@@ -15,7 +15,7 @@ pub struct ReadBoxed<R> {
     pub decompressor: Box<Decompress>,
     /// the [`_count_hash`] decide whether to calculate the hash value in the [`read`] method
     _count_hash: bool,
-    pub hash: CoreWrapper<sha1::Sha1Core>,
+    pub hash: ObjectHasher,
 }
 impl<R> ReadBoxed<R>
 where
@@ -24,7 +24,7 @@ where
     /// Nen a ReadBoxed for zlib read, the Output ReadBoxed is for the Common Object,
     /// but not for the Delta Object,if that ,see new_for_delta method below.
     pub fn new(inner: R, obj_type: ObjectType, size: usize) -> Self {
-        let mut hash = sha1::Sha1::new();
+        let mut hash = ObjectHasher::new(get_hash_kind());
         hash.update(obj_type.to_bytes());
         hash.update(b" ");
         hash.update(size.to_string());
@@ -40,7 +40,7 @@ where
     pub fn new_for_delta(inner: R) -> Self {
         ReadBoxed {
             inner,
-            hash: Sha1::new(),
+            hash: ObjectHasher::new(get_hash_kind()),
             _count_hash: false,
             decompressor: Box::new(Decompress::new(true)),
         }
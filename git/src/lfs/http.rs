@@ -133,7 +133,11 @@ pub async fn lfs_verify_lock(
         lock_list.next_cursor = next_cursor;
 
         for lock in locks.iter() {
-            if Option::is_none(&lock.owner) {
+            let ours = match &lock.owner {
+                Some(owner) => config.user.as_ref() == Some(&owner.name),
+                None => true,
+            };
+            if ours {
                 lock_list.ours.push(lock.clone());
             } else {
                 lock_list.theirs.push(lock.clone());
@@ -204,7 +208,7 @@ pub async fn lfs_create_lock(
             random_num
         },
         path: lock_request.path.to_owned(),
-        owner: None,
+        owner: config.user.clone().map(|name| User { name }),
         locked_at: {
             let locked_at: DateTime<Utc> = Utc::now();
             locked_at.to_rfc3339()
@@ -272,7 +276,7 @@ pub async fn lfs_delete_lock(
         .storage
         .lfs_delete_lock(
             &unlock_request.refs.name,
-            None,
+            config.user.clone(),
             id,
             unlock_request.force.unwrap_or(false),
         )
@@ -13,4 +13,7 @@ pub struct LfsConfig {
     pub lfs_content_path: PathBuf,
 
     pub storage: Arc<dyn ObjectStorage>,
+
+    /// The authenticated user of the request, the owner of the locks it creates
+    pub user: Option<String>,
 }
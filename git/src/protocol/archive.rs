@@ -0,0 +1,1019 @@
+//!
+//! Archives of a commit of the repo, the `tar`, `tar.gz` and `zip` files of `git archive`.
+//!
+//! They are served by the `git-upload-archive` service to `git archive --remote`, and to
+//! plain HTTP clients by the archive API. The client of upload-archive sends its command line
+//! as `argument` pkt-lines ended by a flush-pkt:
+//!
+//! ```bash
+//! request  = *PKT-LINE("argument" SP arg LF) flush-pkt
+//! response = PKT-LINE("ACK" LF) flush-pkt side-band-64k(archive) flush-pkt
+//!          / PKT-LINE("NACK" SP reason LF)
+//! ```
+//!
+//! The archive is built from the trees of the commit while it's being sent, the modes of the
+//! tree items are kept: executables stay executable and symlinks are stored as symlinks.
+//!
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Datelike, Timelike};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+
+use crate::errors::GitError;
+use crate::hash::{with_hash_kind, Hash};
+use crate::internal::object::signature::Signature;
+use crate::internal::object::tree::{Tree, TreeItemMode};
+use crate::internal::object::ObjectT;
+use crate::structure::symrefs::HEAD;
+
+use super::bundle::ref_matches;
+use super::pack::{add_pkt_line_string, read_pkt_line, LF, PKT_LINE_END_MARKER};
+use super::sideband::{spawn_side_band_stream, SideBandSender, SideBandStream};
+use super::{Capability, PackProtocol, SideBind};
+
+const BLOCK_SIZE: usize = 512;
+
+// tar files are padded to a multiple of 20 blocks, like tar and git do
+const RECORD_SIZE: usize = 20 * BLOCK_SIZE;
+
+// how much of the archive is buffered before it's sent
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// the sizes and offsets of a zip from that value on are in its zip64 extensions
+const ZIP64_LIMIT: u64 = 0xFFFFFFFF;
+
+/// The file format of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = GitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tgz" | "tar.gz" => Ok(ArchiveFormat::TarGz),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(GitError::ArchiveError(format!(
+                "unknown archive format `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+// the commit an archive is made of
+struct ArchiveCommit {
+    // the repo the refs and the objects are looked up in, the archived folder is in it
+    repo_path: PathBuf,
+    id: String,
+    tree: Hash,
+    mtime: u64,
+}
+
+/// What to archive and how, the options of `git archive`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ArchiveOptions {
+    pub format: ArchiveFormat,
+    /// the commit to archive, a ref name, `HEAD` or a commit id
+    pub tree_ish: String,
+    /// prepended to the path of every entry, e.g. `mega-1.0/`
+    pub prefix: String,
+    /// only the entries under these paths are archived, all of them if it's empty
+    pub paths: Vec<String>,
+    /// the compression level of `tar.gz` and `zip`, from 0 to 9
+    pub level: Option<u32>,
+}
+
+impl ArchiveOptions {
+    /// Parses the command line of `git archive --remote`, as sent to upload-archive.
+    pub fn parse(args: &[String]) -> Result<ArchiveOptions, GitError> {
+        let mut options = ArchiveOptions::default();
+        let mut tree_ish = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(format) = arg.strip_prefix("--format=") {
+                options.format = format.parse()?;
+            } else if let Some(prefix) = arg.strip_prefix("--prefix=") {
+                options.prefix = prefix.to_owned();
+            } else if let Some(level) = arg.strip_prefix('-').and_then(|l| l.parse().ok()) {
+                if level > 9 {
+                    return Err(GitError::ArchiveError(format!("invalid option {}", arg)));
+                }
+                options.level = Some(level);
+            } else if arg == "-v" || arg == "--verbose" || arg == "--worktree-attributes" {
+                // there is no progress to report and no worktree to read attributes from
+            } else if arg == "--" {
+                options
+                    .paths
+                    .extend(args.by_ref().map(|p| normalize_path(p)));
+            } else if arg.starts_with('-') {
+                return Err(GitError::ArchiveError(format!(
+                    "unsupported option {}",
+                    arg
+                )));
+            } else if tree_ish.is_none() {
+                tree_ish = Some(arg.to_owned());
+            } else {
+                options.paths.push(normalize_path(arg));
+            }
+        }
+        options.tree_ish =
+            tree_ish.ok_or_else(|| GitError::ArchiveError(String::from("no tree-ish given")))?;
+        Ok(options)
+    }
+
+    // whether the entry at `path` is archived, the trees leading to the paths are too
+    fn filter(&self, path: &str) -> bool {
+        self.paths.is_empty()
+            || self
+                .paths
+                .iter()
+                .any(|p| is_under(path, p) || is_under(p, path))
+    }
+}
+
+fn normalize_path(path: &str) -> String {
+    path.trim_start_matches("./")
+        .trim_end_matches('/')
+        .to_owned()
+}
+
+// whether `path` is `dir` itself or inside it
+fn is_under(path: &str, dir: &str) -> bool {
+    path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl PackProtocol {
+    /// # Handles an upload-archive request.
+    ///
+    /// `request` holds the `argument` pkt-lines of the client up to the flush-pkt. Returns the
+    /// `ACK` with the side-band stream of the archive, or the `NACK` telling the client why the
+    /// archive can't be built.
+    pub async fn git_upload_archive(
+        &mut self,
+        request: &mut Bytes,
+    ) -> (BytesMut, Option<SideBandStream>) {
+        let mut args = Vec::new();
+        loop {
+            let (bytes_take, pkt_line) = read_pkt_line(request);
+            if bytes_take == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&pkt_line);
+            let line = line.strip_suffix(LF).unwrap_or(&line);
+            match line.strip_prefix("argument ") {
+                Some(arg) => args.push(arg.to_owned()),
+                None => tracing::warn!("unexpected upload-archive line: {}", line),
+            }
+        }
+        tracing::info!("upload-archive arguments: {:?}", args);
+
+        let mut buf = BytesMut::new();
+        let archive = match ArchiveOptions::parse(&args) {
+            Ok(options) => self.get_archive_stream(options).await,
+            Err(e) => Err(e),
+        };
+        match archive {
+            Ok(stream) => {
+                // the archive always goes through the side-band
+                self.capabilities = vec![Capability::SideBand64k];
+                add_pkt_line_string(&mut buf, format!("ACK{}", LF));
+                buf.extend_from_slice(PKT_LINE_END_MARKER);
+                (buf, Some(stream))
+            }
+            Err(e) => {
+                add_pkt_line_string(&mut buf, format!("NACK {}{}", e, LF));
+                (buf, None)
+            }
+        }
+    }
+
+    /// # Streams an archive of a commit of the repo.
+    ///
+    /// The commit and the paths are checked before the stream is returned, so that a bad
+    /// request can be refused before any of the archive is sent. The archive is sent on band
+    /// 1 of the stream.
+    pub async fn get_archive_stream(
+        &self,
+        options: ArchiveOptions,
+    ) -> Result<SideBandStream, GitError> {
+        let kind = self.get_object_format().await?;
+        with_hash_kind(kind, async {
+            let commit = self.resolve_archive_commit(&options.tree_ish).await?;
+            let root = self
+                .read_archive_tree(&commit.repo_path, &commit.tree)
+                .await?;
+            for path in &options.paths {
+                self.check_archive_path(&commit.repo_path, &root, path)
+                    .await?;
+            }
+            let protocol = self.clone();
+            Ok(spawn_side_band_stream(move |sender| async move {
+                protocol
+                    .write_archive(&options, &commit, root, &sender)
+                    .await
+            }))
+        })
+        .await
+    }
+
+    // the commit a tree-ish points to. The refs of a folder are those of the repo it is in,
+    // its archive is the tree of the folder in that commit. Like git archive, a commit id must
+    // be reachable from the refs of the repo.
+    async fn resolve_archive_commit(&self, tree_ish: &str) -> Result<ArchiveCommit, GitError> {
+        let not_found = || GitError::ArchiveError(format!("not a valid object name: {}", tree_ish));
+        let mut repo_path = self.path.as_path();
+        let mut refs = vec![];
+        for path in self.path.ancestors() {
+            refs = self
+                .storage
+                .get_ref_object_id(path.to_str().unwrap())
+                .await?;
+            if !refs.is_empty() {
+                repo_path = path;
+                break;
+            }
+        }
+        let found = if tree_ish == HEAD {
+            let head = self.get_head_ref(repo_path).await;
+            refs.iter()
+                .find(|r| Some(&r.ref_name) == head.as_ref())
+                .or(refs.first())
+        } else {
+            refs.iter().find(|r| ref_matches(&r.ref_name, tree_ish))
+        };
+        let mut graph = self.get_commit_graph(repo_path).await?;
+        let id = match found {
+            // an annotated tag is archived as the commit it points to
+            Some(r) => {
+                let peeled = self.get_peeled_refs(repo_path).await;
+                peeled.get(&r.ref_name).unwrap_or(&r.ref_git_id).clone()
+            }
+            None => {
+                let want = HashSet::from([tree_ish.to_owned()]);
+                let unreachable = self.find_unreachable_want(repo_path, &graph, &want);
+                if !graph.contains_key(tree_ish) || unreachable.await.is_some() {
+                    return Err(not_found());
+                }
+                tree_ish.to_owned()
+            }
+        };
+        // the storage is shared by all the repos, the commit must be one of this repo
+        let commit = graph.remove(&id).ok_or_else(not_found)?;
+        let mut tree_id = Hash::new_from_str(&commit.tree);
+        let folder = self.path.strip_prefix(repo_path).unwrap();
+        for name in folder.iter() {
+            let tree = self.read_archive_tree(repo_path, &tree_id).await?;
+            let item = tree
+                .tree_items
+                .iter()
+                .find(|item| item.mode == TreeItemMode::Tree && item.name.as_str() == name)
+                .ok_or_else(|| {
+                    GitError::ArchiveError(format!(
+                        "{} is not a folder of {} in {}",
+                        folder.display(),
+                        tree_ish,
+                        repo_path.display()
+                    ))
+                })?;
+            tree_id = item.id;
+        }
+        let mtime = commit
+            .committer
+            .and_then(|c| Signature::new_from_data(c.into_bytes()).ok())
+            .map_or(0, |c| c.timestamp as u64);
+        Ok(ArchiveCommit {
+            repo_path: repo_path.to_path_buf(),
+            id: commit.git_id,
+            tree: tree_id,
+            mtime,
+        })
+    }
+
+    async fn read_archive_tree(&self, repo_path: &Path, id: &Hash) -> Result<Tree, GitError> {
+        match self.read_archive_object(repo_path, id, "tree").await? {
+            Some(data) => Ok(Tree::new_from_data(data)),
+            None => Err(GitError::ArchiveError(format!(
+                "missing tree {}",
+                id.to_plain_str()
+            ))),
+        }
+    }
+
+    // only the trees and the blobs of the repo at `repo_path` are read
+    async fn read_archive_object(
+        &self,
+        repo_path: &Path,
+        id: &Hash,
+        object_type: &str,
+    ) -> Result<Option<Vec<u8>>, GitError> {
+        let id = id.to_plain_str();
+        let repo_path = repo_path.to_str().unwrap();
+        let nodes = self.storage.get_nodes_by_hashes(vec![id.clone()]).await?;
+        if !nodes.iter().any(|node| node.repo_path == repo_path) {
+            return Ok(None);
+        }
+        let model = self.storage.get_obj_data_by_id(&id).await?;
+        Ok(model
+            .filter(|m| m.object_type == object_type)
+            .map(|m| m.data))
+    }
+
+    // a path given to archive must exist in the tree, like a pathspec of git archive
+    async fn check_archive_path(
+        &self,
+        repo_path: &Path,
+        root: &Tree,
+        path: &str,
+    ) -> Result<(), GitError> {
+        let not_found =
+            || GitError::ArchiveError(format!("pathspec '{}' did not match any files", path));
+        let mut tree = root.clone();
+        let mut components = path.split('/').peekable();
+        while let Some(name) = components.next() {
+            let item = tree
+                .tree_items
+                .iter()
+                .find(|item| item.name == name)
+                .ok_or_else(not_found)?;
+            if components.peek().is_none() {
+                return Ok(());
+            }
+            if item.mode != TreeItemMode::Tree {
+                return Err(not_found());
+            }
+            tree = self.read_archive_tree(repo_path, &item.id).await?;
+        }
+        Err(not_found())
+    }
+
+    // walks the trees depth first in the order of their items, the way git archive does
+    async fn write_archive(
+        &self,
+        options: &ArchiveOptions,
+        commit: &ArchiveCommit,
+        root: Tree,
+        sender: &SideBandSender,
+    ) -> Result<(), GitError> {
+        let mut writer = ArchiveWriter::new(options.format, options.level, commit.mtime);
+        writer.start(&commit.id)?;
+        if !options.prefix.is_empty() && options.prefix.ends_with('/') {
+            writer.add(&options.prefix, EntryKind::Dir, &[])?;
+        }
+        let mut stack = vec![(String::new(), root.tree_items.into_iter())];
+        while let Some((dir, items)) = stack.last_mut() {
+            let Some(item) = items.next() else {
+                stack.pop();
+                continue;
+            };
+            let path = format!("{}{}", dir, item.name);
+            if !options.filter(&path) {
+                continue;
+            }
+            let name = format!("{}{}", options.prefix, path);
+            match item.mode {
+                TreeItemMode::Tree => {
+                    writer.add(&format!("{}/", name), EntryKind::Dir, &[])?;
+                    let tree = self.read_archive_tree(&commit.repo_path, &item.id).await?;
+                    stack.push((format!("{}/", path), tree.tree_items.into_iter()));
+                }
+                // the submodules are left empty, their commits aren't in the repo
+                TreeItemMode::Commit => writer.add(&format!("{}/", name), EntryKind::Dir, &[])?,
+                mode => {
+                    let blob = self.read_archive_object(&commit.repo_path, &item.id, "blob");
+                    let Some(data) = blob.await? else {
+                        return Err(GitError::ArchiveError(format!(
+                            "missing blob {} of {}",
+                            item.id.to_plain_str(),
+                            path
+                        )));
+                    };
+                    let kind = match mode {
+                        TreeItemMode::Link => EntryKind::Symlink,
+                        TreeItemMode::BlobExecutable => EntryKind::Executable,
+                        _ => EntryKind::File,
+                    };
+                    writer.add(&name, kind, &data)?;
+                }
+            }
+            if writer.pending() >= CHUNK_SIZE {
+                let chunk = writer.take();
+                if sender
+                    .send(Ok((SideBind::PackfileData, chunk)))
+                    .await
+                    .is_err()
+                {
+                    // the client is gone
+                    return Ok(());
+                }
+            }
+        }
+        let chunk = writer.finish(&commit.id)?;
+        let _ = sender.send(Ok((SideBind::PackfileData, chunk))).await;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryKind {
+    Dir,
+    File,
+    Executable,
+    Symlink,
+}
+
+impl EntryKind {
+    // the permissions of git archive, with its default umask of 002
+    fn mode(&self) -> u32 {
+        match self {
+            EntryKind::Dir => 0o040775,
+            EntryKind::File => 0o100664,
+            EntryKind::Executable => 0o100775,
+            EntryKind::Symlink => 0o120777,
+        }
+    }
+}
+
+// an entry of the central directory of a zip
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    mode: u32,
+    offset: u64,
+}
+
+enum Output {
+    Plain(Vec<u8>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+/// Writes the entries of an archive, the output is taken chunk by chunk while it's written.
+struct ArchiveWriter {
+    format: ArchiveFormat,
+    level: Compression,
+    mtime: u64,
+    output: Output,
+    // the length of the archive before it's compressed
+    written: u64,
+    zip_entries: Vec<ZipEntry>,
+}
+
+impl ArchiveWriter {
+    fn new(format: ArchiveFormat, level: Option<u32>, mtime: u64) -> Self {
+        let level = level.map_or(Compression::default(), Compression::new);
+        let output = match format {
+            ArchiveFormat::TarGz => Output::Gzip(GzEncoder::new(Vec::new(), level)),
+            _ => Output::Plain(Vec::new()),
+        };
+        ArchiveWriter {
+            format,
+            level,
+            mtime,
+            output,
+            written: 0,
+            zip_entries: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), GitError> {
+        self.written += data.len() as u64;
+        match &mut self.output {
+            Output::Plain(buf) => buf.extend_from_slice(data),
+            Output::Gzip(encoder) => encoder
+                .write_all(data)
+                .map_err(|e| GitError::ArchiveError(e.to_string()))?,
+        }
+        Ok(())
+    }
+
+    // the length of the output that hasn't been taken yet
+    fn pending(&self) -> usize {
+        match &self.output {
+            Output::Plain(buf) => buf.len(),
+            Output::Gzip(encoder) => encoder.get_ref().len(),
+        }
+    }
+
+    fn take(&mut self) -> Bytes {
+        match &mut self.output {
+            Output::Plain(buf) => std::mem::take(buf).into(),
+            Output::Gzip(encoder) => std::mem::take(encoder.get_mut()).into(),
+        }
+    }
+
+    // a tar starts with the id of the commit in its global header, for `git get-tar-commit-id`
+    fn start(&mut self, commit_id: &str) -> Result<(), GitError> {
+        if self.format == ArchiveFormat::Zip {
+            return Ok(());
+        }
+        let records = pax_record("comment", commit_id);
+        let header = tar_header(
+            "pax_global_header",
+            0o666,
+            records.len() as u64,
+            self.mtime,
+            b'g',
+            "",
+        );
+        self.write(&header)?;
+        self.write_tar_data(&records)
+    }
+
+    fn add(&mut self, name: &str, kind: EntryKind, data: &[u8]) -> Result<(), GitError> {
+        match self.format {
+            ArchiveFormat::Zip => self.add_zip_entry(name, kind, data),
+            _ => self.add_tar_entry(name, kind, data),
+        }
+    }
+
+    fn add_tar_entry(&mut self, name: &str, kind: EntryKind, data: &[u8]) -> Result<(), GitError> {
+        // the target of a symlink is its content
+        let (typeflag, link_name, data) = match kind {
+            EntryKind::Dir => (b'5', "", &[][..]),
+            EntryKind::Symlink => (b'2', std::str::from_utf8(data).unwrap_or_default(), &[][..]),
+            _ => (b'0', "", data),
+        };
+        // the names that don't fit in the header go to a pax extended header
+        let mut records = Vec::new();
+        if name.len() > 100 {
+            records.extend(pax_record("path", name));
+        }
+        if link_name.len() > 100 {
+            records.extend(pax_record("linkpath", link_name));
+        }
+        if !records.is_empty() {
+            let header = tar_header(
+                "pax_header",
+                0o666,
+                records.len() as u64,
+                self.mtime,
+                b'x',
+                "",
+            );
+            self.write(&header)?;
+            self.write_tar_data(&records)?;
+        }
+        let mode = kind.mode() & 0o7777;
+        let header = tar_header(
+            name,
+            mode,
+            data.len() as u64,
+            self.mtime,
+            typeflag,
+            link_name,
+        );
+        self.write(&header)?;
+        self.write_tar_data(data)
+    }
+
+    fn write_tar_data(&mut self, data: &[u8]) -> Result<(), GitError> {
+        self.write(data)?;
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.write(&vec![0; padding])
+    }
+
+    fn add_zip_entry(&mut self, name: &str, kind: EntryKind, data: &[u8]) -> Result<(), GitError> {
+        let (method, compressed) = match kind {
+            EntryKind::File | EntryKind::Executable if !data.is_empty() => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), self.level);
+                let compressed = encoder
+                    .write_all(data)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|e| GitError::ArchiveError(e.to_string()))?;
+                // what deflate can't shrink is stored as it is
+                if compressed.len() < data.len() {
+                    (8, Some(compressed))
+                } else {
+                    (0, None)
+                }
+            }
+            _ => (0, None),
+        };
+        let stored = compressed.as_deref().unwrap_or(data);
+        let entry = ZipEntry {
+            name: name.to_owned(),
+            method,
+            crc: CRC32.checksum(data),
+            compressed_size: stored.len() as u64,
+            size: data.len() as u64,
+            mode: kind.mode(),
+            offset: self.written,
+        };
+        // the sizes of a large entry are in a zip64 extra field
+        let extra = zip64_extra(&[entry.size, entry.compressed_size], true);
+        let (time, date) = dos_date_time(self.mtime);
+        let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&zip_version(method, !extra.is_empty()).to_le_bytes());
+        header.extend_from_slice(&zip_flags(name).to_le_bytes());
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&entry.crc.to_le_bytes());
+        header.extend_from_slice(&zip32(entry.compressed_size).to_le_bytes());
+        header.extend_from_slice(&zip32(entry.size).to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&extra);
+        self.write(&header)?;
+        self.write(stored)?;
+        self.zip_entries.push(entry);
+        Ok(())
+    }
+
+    /// Ends the archive and returns the rest of the output.
+    fn finish(mut self, commit_id: &str) -> Result<Bytes, GitError> {
+        match self.format {
+            ArchiveFormat::Zip => self.finish_zip(commit_id)?,
+            _ => {
+                // two empty blocks end a tar, then the record is filled
+                let end = 2 * BLOCK_SIZE as u64;
+                let padding = (RECORD_SIZE as u64 - (self.written + end) % RECORD_SIZE as u64)
+                    % RECORD_SIZE as u64;
+                self.write(&vec![0; (end + padding) as usize])?;
+            }
+        }
+        match self.output {
+            Output::Plain(buf) => Ok(buf.into()),
+            Output::Gzip(encoder) => encoder
+                .finish()
+                .map(Bytes::from)
+                .map_err(|e| GitError::ArchiveError(e.to_string())),
+        }
+    }
+
+    // the central directory, the id of the commit is the comment of the zip. An archive of
+    // more than 65534 entries or 4 GiB ends with the zip64 records.
+    fn finish_zip(&mut self, commit_id: &str) -> Result<(), GitError> {
+        let offset = self.written;
+        let count = self.zip_entries.len() as u64;
+        let (time, date) = dos_date_time(self.mtime);
+        let mut directory = Vec::new();
+        for entry in &self.zip_entries {
+            let extra = zip64_extra(&[entry.size, entry.compressed_size, entry.offset], false);
+            let version = zip_version(entry.method, !extra.is_empty());
+            directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            // made by unix, so that the modes are read from the external attributes
+            directory.extend_from_slice(&((3 << 8) | version).to_le_bytes());
+            directory.extend_from_slice(&version.to_le_bytes());
+            directory.extend_from_slice(&zip_flags(&entry.name).to_le_bytes());
+            directory.extend_from_slice(&entry.method.to_le_bytes());
+            directory.extend_from_slice(&time.to_le_bytes());
+            directory.extend_from_slice(&date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&zip32(entry.compressed_size).to_le_bytes());
+            directory.extend_from_slice(&zip32(entry.size).to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            // no comment, disk 0, no internal attributes
+            directory.extend_from_slice(&[0; 6]);
+            // the MS-DOS directory attribute helps the unzips that ignore the unix modes
+            let dos_attributes = if entry.name.ends_with('/') { 0x10 } else { 0 };
+            directory.extend_from_slice(&((entry.mode << 16) | dos_attributes).to_le_bytes());
+            directory.extend_from_slice(&zip32(entry.offset).to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+            directory.extend_from_slice(&extra);
+        }
+        let size = directory.len() as u64;
+        if count >= 0xFFFF || size >= ZIP64_LIMIT || offset >= ZIP64_LIMIT {
+            // the zip64 end of central directory record, then its locator
+            directory.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            directory.extend_from_slice(&44u64.to_le_bytes());
+            directory.extend_from_slice(&((3 << 8) | 45u16).to_le_bytes());
+            directory.extend_from_slice(&45u16.to_le_bytes());
+            directory.extend_from_slice(&[0; 8]);
+            directory.extend_from_slice(&count.to_le_bytes());
+            directory.extend_from_slice(&count.to_le_bytes());
+            directory.extend_from_slice(&size.to_le_bytes());
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            directory.extend_from_slice(&0u32.to_le_bytes());
+            directory.extend_from_slice(&(offset + size).to_le_bytes());
+            directory.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let count = count.min(0xFFFF) as u16;
+        directory.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        directory.extend_from_slice(&[0; 4]);
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&zip32(size).to_le_bytes());
+        directory.extend_from_slice(&zip32(offset).to_le_bytes());
+        directory.extend_from_slice(&(commit_id.len() as u16).to_le_bytes());
+        directory.extend_from_slice(commit_id.as_bytes());
+        self.write(&directory)
+    }
+}
+
+// a ustar header, see `man 5 tar`
+fn tar_header(
+    name: &str,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    typeflag: u8,
+    link_name: &str,
+) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+    let mut put = |offset: usize, len: usize, value: &[u8]| {
+        let len = value.len().min(len);
+        header[offset..offset + len].copy_from_slice(&value[..len]);
+    };
+    let octal = |value: u64, len: usize| format!("{:0width$o}\0", value, width = len - 1);
+    put(0, 100, name.as_bytes());
+    put(100, 8, octal(mode as u64, 8).as_bytes());
+    put(108, 8, octal(0, 8).as_bytes());
+    put(116, 8, octal(0, 8).as_bytes());
+    put(124, 12, octal(size, 12).as_bytes());
+    put(136, 12, octal(mtime, 12).as_bytes());
+    put(156, 1, &[typeflag]);
+    put(157, 100, link_name.as_bytes());
+    put(257, 6, b"ustar\0");
+    put(263, 2, b"00");
+    put(265, 32, b"root");
+    put(297, 32, b"root");
+    put(329, 8, octal(0, 8).as_bytes());
+    put(337, 8, octal(0, 8).as_bytes());
+    // the checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+// `<length> <key>=<value>\n`, the length counts itself
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let base = key.len() + value.len() + 3;
+    let mut len = base;
+    loop {
+        let next = base + len.to_string().len();
+        if next == len {
+            break;
+        }
+        len = next;
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+fn zip_version(method: u16, zip64: bool) -> u16 {
+    if zip64 {
+        45
+    } else if method == 8 {
+        20
+    } else {
+        10
+    }
+}
+
+// a size or an offset of a zip record, the ones that don't fit are in the zip64 extra field
+fn zip32(value: u64) -> u32 {
+    if value >= ZIP64_LIMIT {
+        u32::MAX
+    } else {
+        value as u32
+    }
+}
+
+// the zip64 extra field of the `values` that don't fit in their records, a local header has
+// either both sizes or none of them
+fn zip64_extra(values: &[u64], all: bool) -> Vec<u8> {
+    let large: Vec<u64> = if all && values.iter().any(|&v| v >= ZIP64_LIMIT) {
+        values.to_vec()
+    } else {
+        values
+            .iter()
+            .copied()
+            .filter(|&v| v >= ZIP64_LIMIT)
+            .collect()
+    };
+    if large.is_empty() {
+        return vec![];
+    }
+    let mut extra = Vec::with_capacity(4 + 8 * large.len());
+    extra.extend_from_slice(&1u16.to_le_bytes());
+    extra.extend_from_slice(&(8 * large.len() as u16).to_le_bytes());
+    for value in large {
+        extra.extend_from_slice(&value.to_le_bytes());
+    }
+    extra
+}
+
+// names that aren't ASCII are flagged as UTF-8
+fn zip_flags(name: &str) -> u16 {
+    if name.is_ascii() {
+        0
+    } else {
+        1 << 11
+    }
+}
+
+// the MS-DOS time and date of the entries, the earliest date of MS-DOS is 1980
+fn dos_date_time(mtime: u64) -> (u16, u16) {
+    let time = DateTime::from_timestamp(mtime as i64, 0).unwrap_or_default();
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = (((time.year() - 1980) as u32) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        pax_record, tar_header, ArchiveFormat, ArchiveOptions, ArchiveWriter, EntryKind,
+        BLOCK_SIZE, RECORD_SIZE,
+    };
+
+    #[test]
+    fn test_parse_archive_options() {
+        let args: Vec<String> = [
+            "--format=zip",
+            "--prefix=mega/",
+            "-9",
+            "v1.0",
+            "src",
+            "docs/",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        let options = ArchiveOptions::parse(&args).unwrap();
+        assert_eq!(options.format, ArchiveFormat::Zip);
+        assert_eq!(options.prefix, "mega/");
+        assert_eq!(options.level, Some(9));
+        assert_eq!(options.tree_ish, "v1.0");
+        assert_eq!(options.paths, vec!["src", "docs"]);
+        assert!(options.filter("src/main.rs"));
+        assert!(options.filter("docs"));
+        assert!(!options.filter("srcs"));
+
+        assert!(ArchiveOptions::parse(&[String::from("--format=rar")]).is_err());
+        assert!(ArchiveOptions::parse(&[String::from("--remote=x")]).is_err());
+        assert!(ArchiveOptions::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(pax_record("path", "a"), b"9 path=a\n");
+        // 99 bytes without the length, which then takes 3 digits
+        let value = "x".repeat(92);
+        assert_eq!(pax_record("path", &value).len(), 102);
+    }
+
+    #[test]
+    fn test_tar_header() {
+        let header = tar_header("run.sh", 0o775, 12, 1700000000, b'0', "");
+        assert_eq!(&header[..6], b"run.sh");
+        assert_eq!(&header[100..108], b"0000775\0");
+        assert_eq!(&header[124..136], b"00000000014\0");
+        assert_eq!(&header[257..263], b"ustar\0");
+        let checksum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        let field = std::str::from_utf8(&header[148..154]).unwrap();
+        assert_eq!(u32::from_str_radix(field, 8).unwrap(), checksum);
+    }
+
+    #[test]
+    fn test_tar_archive() {
+        let mut writer = ArchiveWriter::new(ArchiveFormat::Tar, None, 0);
+        writer
+            .start("8ab686eafeb1f44702738c8b0f24f2567c36da6d")
+            .unwrap();
+        writer.add("mega/", EntryKind::Dir, &[]).unwrap();
+        writer
+            .add("mega/README.md", EntryKind::File, b"# Mega\n")
+            .unwrap();
+        writer
+            .add("mega/link", EntryKind::Symlink, b"README.md")
+            .unwrap();
+        let long_name = format!("mega/{}", "a".repeat(120));
+        writer.add(&long_name, EntryKind::Executable, b"").unwrap();
+        let tar = writer.finish("").unwrap();
+        assert_eq!(tar.len() % RECORD_SIZE, 0);
+
+        // the pax global header and its records, then the directory
+        assert_eq!(tar[156], b'g');
+        assert_eq!(tar[2 * BLOCK_SIZE + 156], b'5');
+        let readme = &tar[3 * BLOCK_SIZE..4 * BLOCK_SIZE];
+        assert_eq!(&readme[..14], b"mega/README.md");
+        assert_eq!(&tar[4 * BLOCK_SIZE..4 * BLOCK_SIZE + 7], b"# Mega\n");
+        let link = &tar[5 * BLOCK_SIZE..6 * BLOCK_SIZE];
+        assert_eq!(link[156], b'2');
+        assert_eq!(&link[157..166], b"README.md");
+        // the long name is in a pax header
+        assert_eq!(tar[6 * BLOCK_SIZE + 156], b'x');
+        assert_eq!(
+            &tar[8 * BLOCK_SIZE + 100..8 * BLOCK_SIZE + 108],
+            b"0000775\0"
+        );
+    }
+
+    #[test]
+    fn test_zip_archive() {
+        let mut writer = ArchiveWriter::new(ArchiveFormat::Zip, None, 1700000000);
+        writer
+            .start("8ab686eafeb1f44702738c8b0f24f2567c36da6d")
+            .unwrap();
+        writer.add("mega/", EntryKind::Dir, &[]).unwrap();
+        let content = "mega ".repeat(100);
+        writer
+            .add("mega/README.md", EntryKind::File, content.as_bytes())
+            .unwrap();
+        writer
+            .add("mega/link", EntryKind::Symlink, b"README.md")
+            .unwrap();
+        let zip = writer
+            .finish("8ab686eafeb1f44702738c8b0f24f2567c36da6d")
+            .unwrap();
+        assert_eq!(&zip[..4], &0x04034b50u32.to_le_bytes());
+        let end = zip.len() - 22 - 40;
+        assert_eq!(&zip[end..end + 4], &0x06054b50u32.to_le_bytes());
+        assert_eq!(&zip[end + 10..end + 12], &3u16.to_le_bytes());
+        assert!(zip.ends_with(b"8ab686eafeb1f44702738c8b0f24f2567c36da6d"));
+        // the README is deflated
+        assert_eq!(&zip[30 + 5 + 8..30 + 5 + 10], &8u16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_zip64_archive() {
+        let mut writer = ArchiveWriter::new(ArchiveFormat::Zip, None, 1700000000);
+        // as if 5 GiB of the archive had been sent already
+        writer.written = 5 << 30;
+        writer.add("README.md", EntryKind::File, b"mega").unwrap();
+        let zip = writer
+            .finish("8ab686eafeb1f44702738c8b0f24f2567c36da6d")
+            .unwrap();
+        // the offset of the entry is in its zip64 extra field
+        let directory = 30 + 9 + 4;
+        assert_eq!(&zip[directory + 6..directory + 8], &45u16.to_le_bytes());
+        assert_eq!(&zip[directory + 30..directory + 32], &12u16.to_le_bytes());
+        assert_eq!(
+            &zip[directory + 42..directory + 46],
+            &u32::MAX.to_le_bytes()
+        );
+        let extra = directory + 46 + 9;
+        assert_eq!(&zip[extra..extra + 4], &[1, 0, 8, 0]);
+        assert_eq!(&zip[extra + 4..extra + 12], &(5u64 << 30).to_le_bytes());
+        // then the zip64 end of central directory, its locator and the end of central directory
+        let end64 = extra + 12;
+        assert_eq!(&zip[end64..end64 + 4], &0x06064b50u32.to_le_bytes());
+        let offset = (5u64 << 30) + directory as u64;
+        assert_eq!(&zip[end64 + 48..end64 + 56], &offset.to_le_bytes());
+        let locator = end64 + 56;
+        assert_eq!(&zip[locator..locator + 4], &0x07064b50u32.to_le_bytes());
+        let cd_end = (5u64 << 30) + end64 as u64;
+        assert_eq!(&zip[locator + 8..locator + 16], &cd_end.to_le_bytes());
+        let end = locator + 20;
+        assert_eq!(&zip[end..end + 4], &0x06054b50u32.to_le_bytes());
+        assert_eq!(&zip[end + 16..end + 20], &u32::MAX.to_le_bytes());
+    }
+}
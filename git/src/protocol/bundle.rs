@@ -0,0 +1,420 @@
+//!
+//! Bundles of `git bundle`, a pack together with the refs it brings, used to move repos
+//! between networks without a connection to each other.
+//!
+//! ```bash
+//! bundle       = signature *capability *prerequisite *reference LF pack
+//! signature    = "# v2 git bundle" LF / "# v3 git bundle" LF
+//! capability   = "@" key ["=" value] LF
+//! prerequisite = "-" obj-id SP comment LF
+//! reference    = obj-id SP refname LF
+//! ```
+//!
+//! Capabilities only exist in v3 bundles, `@object-format=sha256` is the one of a bundle of a
+//! sha256 repo. The prerequisites are the commits the pack was built against, the receiver
+//! must already have them.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+
+use futures::StreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
+
+use crate::errors::GitError;
+use crate::hash::{get_hash_kind, is_zero_id, sync_with_hash_kind, with_hash_kind, HashKind};
+use crate::internal::pack::stream::decode_stream;
+
+use super::pack::{LF, SP};
+use super::{PackProtocol, RefCommand, SideBind};
+
+pub const BUNDLE_V2_SIGNATURE: &str = "# v2 git bundle";
+
+pub const BUNDLE_V3_SIGNATURE: &str = "# v3 git bundle";
+
+/// The header of a bundle, everything before the pack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleHeader {
+    pub version: u8,
+    /// the capabilities of a v3 bundle, with their value if they have one
+    pub capabilities: Vec<(String, Option<String>)>,
+    /// the commit ids the pack depends on, with the subject of the commit
+    pub prerequisites: Vec<(String, String)>,
+    /// the object ids and names of the refs
+    pub refs: Vec<(String, String)>,
+}
+
+impl BundleHeader {
+    /// Reads the header of a bundle, `reader` is left at the beginning of the pack.
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<BundleHeader, GitError> {
+        let version = match read_line(reader)?.as_str() {
+            BUNDLE_V2_SIGNATURE => 2,
+            BUNDLE_V3_SIGNATURE => 3,
+            signature => {
+                return Err(GitError::BundleError(format!(
+                    "unknown signature `{}`",
+                    signature
+                )))
+            }
+        };
+        let mut header = BundleHeader {
+            version,
+            capabilities: vec![],
+            prerequisites: vec![],
+            refs: vec![],
+        };
+        let mut kind = HashKind::Sha1;
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some(capability) = line.strip_prefix('@') {
+                if version < 3 {
+                    return Err(GitError::BundleError(format!(
+                        "capability `{}` in a v2 bundle",
+                        capability
+                    )));
+                }
+                let (key, value) = match capability.split_once('=') {
+                    Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                    None => (capability.to_owned(), None),
+                };
+                if key == "object-format" {
+                    let format = value.as_deref().unwrap_or_default();
+                    kind = format.parse().map_err(|_| {
+                        GitError::BundleError(format!("unsupported object format {}", format))
+                    })?;
+                }
+                header.capabilities.push((key, value));
+            } else if let Some(prerequisite) = line.strip_prefix('-') {
+                let (id, comment) = prerequisite.split_once(SP).unwrap_or((prerequisite, ""));
+                header
+                    .prerequisites
+                    .push((check_object_id(id, kind)?, comment.to_owned()));
+            } else {
+                let Some((id, name)) = line.split_once(SP) else {
+                    return Err(GitError::BundleError(format!(
+                        "invalid ref line `{}`",
+                        line
+                    )));
+                };
+                header
+                    .refs
+                    .push((check_object_id(id, kind)?, name.to_owned()));
+            }
+        }
+        Ok(header)
+    }
+
+    /// The object format of the bundle, sha1 unless a v3 bundle says otherwise.
+    pub fn object_format(&self) -> HashKind {
+        self.capabilities
+            .iter()
+            .find(|(key, _)| key == "object-format")
+            .and_then(|(_, value)| value.as_deref()?.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Builds the header, with the empty line that ends it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = match self.version {
+            3 => format!("{}{}", BUNDLE_V3_SIGNATURE, LF),
+            _ => format!("{}{}", BUNDLE_V2_SIGNATURE, LF),
+        };
+        for (key, value) in &self.capabilities {
+            match value {
+                Some(value) => header.push_str(&format!("@{}={}{}", key, value, LF)),
+                None => header.push_str(&format!("@{}{}", key, LF)),
+            }
+        }
+        for (id, comment) in &self.prerequisites {
+            header.push_str(&format!("-{}{}{}{}", id, SP, comment, LF));
+        }
+        for (id, name) in &self.refs {
+            header.push_str(&format!("{}{}{}{}", id, SP, name, LF));
+        }
+        header.push(LF);
+        header.into_bytes()
+    }
+}
+
+impl PackProtocol {
+    /// # Writes a bundle of the repo.
+    ///
+    /// `ref_names` selects the refs to bundle by their full or short name, `HEAD` included,
+    /// all the refs of the repo are bundled when it's empty. `basis` are the commits the
+    /// receiver already has: they are left out of the pack and listed as prerequisites.
+    ///
+    /// The pack is written while it's being generated. Returns the header of the bundle, a v3
+    /// header for a sha256 repo.
+    pub async fn create_bundle<W>(
+        &self,
+        ref_names: &[String],
+        basis: &HashSet<String>,
+        writer: &mut W,
+    ) -> Result<BundleHeader, GitError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let kind = self.get_object_format().await?;
+        with_hash_kind(kind, self.write_bundle(ref_names, basis, writer)).await
+    }
+
+    async fn write_bundle<W>(
+        &self,
+        ref_names: &[String],
+        basis: &HashSet<String>,
+        writer: &mut W,
+    ) -> Result<BundleHeader, GitError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let repo_path = self.path.to_str().unwrap();
+        let mut repo_refs: Vec<(String, String)> = self
+            .storage
+            .get_ref_object_id(repo_path)
+            .await?
+            .into_iter()
+            .map(|r| (r.ref_git_id, r.ref_name))
+            .collect();
+        repo_refs.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut refs = vec![];
+        for name in ref_names {
+            if name == "HEAD" {
+                let head = self.get_head_object_id(&self.path).await;
+                if !is_zero_id(&head) {
+                    refs.push((head, name.clone()));
+                    continue;
+                }
+            }
+            match repo_refs.iter().find(|(_, r)| ref_matches(r, name)) {
+                Some(r) => refs.push(r.clone()),
+                None => return Err(GitError::BundleError(format!("unknown ref {}", name))),
+            }
+        }
+        if ref_names.is_empty() {
+            refs = repo_refs;
+        }
+        if refs.is_empty() {
+            return Err(GitError::BundleError(String::from(
+                "refusing to create an empty bundle",
+            )));
+        }
+
+        let basis_commits = self
+            .storage
+            .get_commit_by_hashes(basis.iter().cloned().collect())
+            .await?;
+        let mut prerequisites = vec![];
+        for id in basis {
+            let commit = basis_commits
+                .iter()
+                .find(|c| c.git_id == *id && c.repo_path == repo_path);
+            let Some(commit) = commit else {
+                return Err(GitError::BundleError(format!("unknown commit {}", id)));
+            };
+            let content = commit.content.as_deref().unwrap_or_default();
+            let subject = content.lines().next().unwrap_or_default();
+            prerequisites.push((id.clone(), subject.to_owned()));
+        }
+        prerequisites.sort();
+
+        let header = match get_hash_kind() {
+            HashKind::Sha1 => BundleHeader {
+                version: 2,
+                capabilities: vec![],
+                prerequisites,
+                refs,
+            },
+            kind => BundleHeader {
+                version: 3,
+                capabilities: vec![(String::from("object-format"), Some(kind.to_string()))],
+                prerequisites,
+                refs,
+            },
+        };
+        let write_error = |e: std::io::Error| GitError::BundleError(e.to_string());
+        writer
+            .write_all(&header.to_bytes())
+            .await
+            .map_err(write_error)?;
+        let want: HashSet<String> = header.refs.iter().map(|(id, _)| id.clone()).collect();
+        let mut stream = self.get_pack_stream(&self.path, &want, basis, None, None);
+        while let Some(message) = stream.next().await {
+            if let (SideBind::PackfileData, data) = message? {
+                writer.write_all(&data).await.map_err(write_error)?;
+            }
+        }
+        writer.flush().await.map_err(write_error)?;
+        Ok(header)
+    }
+
+    /// # Imports a bundle into the repo.
+    ///
+    /// The refs of the bundle are applied like the ref updates of a push: the pack goes
+    /// through the same decoder, the nodes of the new objects are built and the push hooks
+    /// run. The HEAD of the bundle is skipped, it's not a ref of the repo. The object format
+    /// of the bundle must be the one of the repo, unless the repo is empty.
+    ///
+    /// Returns the ref update commands with their status.
+    pub async fn import_bundle<R>(&mut self, reader: R) -> Result<Vec<RefCommand>, GitError>
+    where
+        R: Read + Send + 'static,
+    {
+        let mut reader = BufReader::new(reader);
+        let header = BundleHeader::read(&mut reader)?;
+        let kind = header.object_format();
+        self.accept_object_format(kind).await?;
+        with_hash_kind(kind, self.apply_bundle(header, reader)).await
+    }
+
+    async fn apply_bundle<R>(
+        &mut self,
+        header: BundleHeader,
+        reader: BufReader<R>,
+    ) -> Result<Vec<RefCommand>, GitError>
+    where
+        R: Read + Send + 'static,
+    {
+        // the storage is shared by all the repos, the prerequisites must be commits of this one
+        let repo_path = self.path.to_str().unwrap().to_owned();
+        let ids = header
+            .prerequisites
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect();
+        let commits = self.storage.get_commit_by_hashes(ids).await?;
+        for (id, _) in &header.prerequisites {
+            if !commits
+                .iter()
+                .any(|c| c.git_id == *id && c.repo_path == repo_path)
+            {
+                return Err(GitError::BundleError(format!(
+                    "the repo lacks the prerequisite commit {}",
+                    id
+                )));
+            }
+        }
+        let repo_refs: HashMap<String, String> = self
+            .storage
+            .get_ref_object_id(&repo_path)
+            .await?
+            .into_iter()
+            .map(|r| (r.ref_name, r.ref_git_id))
+            .collect();
+        self.command_list = header
+            .refs
+            .iter()
+            .filter(|(_, name)| name != "HEAD")
+            .map(|(id, name)| {
+                let old_id = repo_refs.get(name).cloned();
+                let old_id = old_id.unwrap_or_else(|| get_hash_kind().zero_id());
+                RefCommand::new(old_id, id.clone(), name.clone())
+            })
+            .collect();
+
+        let handle = Handle::current();
+        let storage = self.storage.clone();
+        let kind = get_hash_kind();
+        let summary = tokio::task::spawn_blocking(move || {
+            sync_with_hash_kind(kind, || {
+                decode_stream(reader, storage, &repo_path, handle, |_, _| {})
+            })
+        })
+        .await
+        .map_err(|e| GitError::InvalidPackFile(e.to_string()))??;
+        self.storage
+            .save_mr_info(RefCommand::new_mr_info(summary.mr_id))
+            .await?;
+        tracing::info!(
+            "bundle unpacked: {} objects, {} local objects",
+            summary.objects,
+            summary.local_objects
+        );
+
+        let (unpack_status, command_list, _) = self.apply_push(Ok(Some(summary.mr_id)), None).await;
+        unpack_status.map_err(|e| GitError::BundleError(e.to_string()))?;
+        Ok(command_list)
+    }
+}
+
+// whether a ref matches a name the way git resolves a short ref name
+pub(crate) fn ref_matches(ref_name: &str, name: &str) -> bool {
+    ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"]
+        .iter()
+        .any(|prefix| ref_name.strip_prefix(prefix) == Some(name))
+}
+
+fn check_object_id(id: &str, kind: HashKind) -> Result<String, GitError> {
+    if kind.is_object_id(id) {
+        Ok(id.to_owned())
+    } else {
+        Err(GitError::InvalidHashValue(id.to_owned()))
+    }
+}
+
+// reads a line of the header without its LF
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, GitError> {
+    let mut line = String::new();
+    let read = reader
+        .read_line(&mut line)
+        .map_err(|e| GitError::BundleError(e.to_string()))?;
+    if read == 0 || !line.ends_with(LF) {
+        return Err(GitError::BundleError(String::from("truncated header")));
+    }
+    line.pop();
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ref_matches, BundleHeader};
+    use crate::hash::HashKind;
+
+    #[test]
+    fn test_bundle_header() {
+        let data = b"# v3 git bundle\n@object-format=sha1\n\
+            -8f8bc6ac7d4ae2ec5e0ee8e2b0c5e6d7f38a3d4e add readme\n\
+            27dd8d4cf39f3868c6eee38b601bc9e9939304f5 refs/heads/main\n\nPACK";
+        let mut reader = &data[..];
+        let header = BundleHeader::read(&mut reader).unwrap();
+        assert_eq!(reader, &b"PACK"[..]);
+        assert_eq!(header.version, 3);
+        assert_eq!(
+            header.capabilities,
+            vec![(String::from("object-format"), Some(String::from("sha1")))]
+        );
+        assert_eq!(header.prerequisites[0].1, "add readme");
+        assert_eq!(header.refs[0].1, "refs/heads/main");
+        assert_eq!(header.to_bytes(), &data[..data.len() - 4]);
+        assert_eq!(header.object_format(), HashKind::Sha1);
+
+        let data = b"# v3 git bundle\n@object-format=sha256\n\
+            dabc789f60c22621c92df8736ff8cb60e35185584772b93b9315a3e2aab55653 refs/heads/main\n\n";
+        let header = BundleHeader::read(&mut &data[..]).unwrap();
+        assert_eq!(header.object_format(), HashKind::Sha256);
+
+        let mut reader = &b"# v2 git bundle\n@object-format=sha1\n\n"[..];
+        assert!(BundleHeader::read(&mut reader).is_err());
+        let mut reader = &b"# v3 git bundle\n@object-format=md5\n\n"[..];
+        assert!(BundleHeader::read(&mut reader).is_err());
+        let mut reader = &b"# v3 git bundle\n@object-format=sha256\n\
+            27dd8d4cf39f3868c6eee38b601bc9e9939304f5 refs/heads/main\n\n"[..];
+        assert!(BundleHeader::read(&mut reader).is_err());
+        let mut reader = &b"# v2 git bundle\n27dd8d4c refs/heads/main\n\n"[..];
+        assert!(BundleHeader::read(&mut reader).is_err());
+        let mut reader = &b"# v2 git bundle\n"[..];
+        assert!(BundleHeader::read(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_ref_matches() {
+        assert!(ref_matches("refs/heads/main", "main"));
+        assert!(ref_matches("refs/heads/main", "heads/main"));
+        assert!(ref_matches("refs/tags/v1.0", "v1.0"));
+        assert!(ref_matches("refs/heads/main", "refs/heads/main"));
+        assert!(!ref_matches("refs/heads/main", "mai"));
+    }
+}
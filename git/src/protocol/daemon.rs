@@ -0,0 +1,235 @@
+//!
+//! The git:// protocol of `git daemon`, a plain TCP transport without any authentication.
+//!
+//! The client opens the connection with a single pkt-line naming the service and the repo,
+//! the protocol version it wants is passed in the extra parameters:
+//!
+//! ```bash
+//! git-proto-request = request-command SP pathname NUL
+//!                     [ host-parameter NUL ] [ NUL extra-parameters ]
+//! request-command   = "git-upload-pack" / "git-receive-pack" / "git-upload-archive"
+//! host-parameter    = "host=" hostname [ ":" port ]
+//! extra-parameters  = 1*extra-parameter
+//! extra-parameter   = 1*CHAR NUL
+//! ```
+//!
+//! The service then runs like over SSH. Only upload-pack is served, the daemon is read-only.
+//!
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Bytes, BytesMut};
+use database::driver::ObjectStorage;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::pack::{add_pkt_line_string, PKT_LINE_END_MARKER, SP};
+use super::session::{UploadPackReply, UploadPackSession, MAX_PKT_LINE_LENGTH};
+use super::sideband::SideBandStream;
+use super::{PackProtocol, Protocol, ProtocolVersion, ServiceType};
+
+/// The request line of a git:// connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaemonRequest {
+    pub service: ServiceType,
+    pub path: PathBuf,
+    pub host: Option<String>,
+    pub version: ProtocolVersion,
+}
+
+impl DaemonRequest {
+    /// Parses the payload of the first pkt-line of a connection, e.g.
+    /// `git-upload-pack /root/repotest.git\0host=localhost\0\0version=2\0`.
+    pub fn parse(line: &[u8]) -> Result<DaemonRequest> {
+        let line = std::str::from_utf8(line)?.trim_end_matches('\n');
+        let mut params = line.split('\0');
+        let command = params.next().unwrap_or_default();
+        let (service, path) = command
+            .split_once(SP)
+            .ok_or_else(|| anyhow!("invalid request: {}", command))?;
+        let service =
+            ServiceType::from_str(service).map_err(|_| anyhow!("unknown service: {}", service))?;
+        let mut host = None;
+        let mut extra = vec![];
+        // the host comes first, the extra parameters follow after an empty one
+        for (i, param) in params.filter(|p| !p.is_empty()).enumerate() {
+            match param.strip_prefix("host=") {
+                Some(h) if i == 0 => host = Some(h.to_owned()),
+                _ => extra.push(param),
+            }
+        }
+        let path = path.trim_end_matches('/');
+        Ok(DaemonRequest {
+            service,
+            path: PathBuf::from(path.strip_suffix(".git").unwrap_or(path)),
+            host,
+            version: ProtocolVersion::from_git_protocol(&extra.join(":")),
+        })
+    }
+}
+
+/// # Serves one git:// connection.
+///
+/// Reads the request line, advertises the refs of the repo, then answers the requests of the
+/// client until it hangs up or the pack is sent. Services other than upload-pack are refused
+/// with an `ERR` pkt-line.
+pub async fn serve<S>(mut stream: S, storage: Arc<dyn ObjectStorage>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(line) = read_pkt(&mut stream).await? else {
+        return Ok(());
+    };
+    let request = match DaemonRequest::parse(&line[4..]) {
+        Ok(request) => request,
+        Err(e) => {
+            send_error(&mut stream, &e.to_string()).await?;
+            return Err(e);
+        }
+    };
+    tracing::info!("git daemon request: {:?}", request);
+    if request.service != ServiceType::UploadPack {
+        let message = format!("service not enabled: {}", request.service.to_string());
+        return send_error(&mut stream, &message).await;
+    }
+
+    let mut pack_protocol = PackProtocol::new(request.path, storage, Protocol::Git);
+    pack_protocol.service_type = Some(request.service);
+    pack_protocol.version = request.version;
+    let refs = pack_protocol.git_info_refs(request.service).await?;
+    stream.write_all(&refs).await?;
+
+    let mut session = UploadPackSession::default();
+    loop {
+        let Some(pkt) = read_pkt(&mut stream).await? else {
+            return Ok(());
+        };
+        match session.receive(&mut pack_protocol, &pkt).await? {
+            UploadPackReply::Pending => {}
+            UploadPackReply::Data(buf) => stream.write_all(&buf).await?,
+            UploadPackReply::Pack(buf, pack_stream) => {
+                stream.write_all(&buf).await?;
+                return send_side_band(&mut stream, pack_stream, &pack_protocol).await;
+            }
+            UploadPackReply::UpToDate => return Ok(()),
+        }
+    }
+}
+
+async fn send_side_band<S>(
+    stream: &mut S,
+    mut pack_stream: SideBandStream,
+    pack_protocol: &PackProtocol,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    while let Some(message) = pack_stream.next().await {
+        let Some(bytes_out) = pack_protocol.build_side_band_message(&message) else {
+            bail!("failed to send the pack");
+        };
+        stream.write_all(&bytes_out).await?;
+        message?;
+    }
+    if pack_protocol.is_side_band() {
+        stream.write_all(PKT_LINE_END_MARKER).await?;
+    }
+    Ok(())
+}
+
+async fn send_error<S>(stream: &mut S, message: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    add_pkt_line_string(&mut buf, format!("ERR {}\n", message));
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+// reads a whole pkt-line with its length, `None` once the client has hung up
+async fn read_pkt<R>(reader: &mut R) -> Result<Option<Bytes>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let pkt_length = usize::from_str_radix(std::str::from_utf8(&length)?, 16)?;
+    if pkt_length == 3 || pkt_length > MAX_PKT_LINE_LENGTH {
+        bail!("invalid pkt-line length: {}", pkt_length);
+    }
+    let mut pkt = BytesMut::from(&length[..]);
+    if pkt_length > 4 {
+        pkt.resize(pkt_length, 0);
+        reader.read_exact(&mut pkt[4..]).await?;
+    }
+    Ok(Some(pkt.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio_test::block_on;
+
+    use crate::protocol::{ProtocolVersion, ServiceType};
+
+    use super::{read_pkt, DaemonRequest};
+
+    #[test]
+    fn test_parse_daemon_request() {
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /root/repotest.git\0host=localhost:9418\0")
+                .unwrap();
+        assert_eq!(
+            request,
+            DaemonRequest {
+                service: ServiceType::UploadPack,
+                path: PathBuf::from("/root/repotest"),
+                host: Some(String::from("localhost:9418")),
+                version: ProtocolVersion::V0,
+            }
+        );
+
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /root/repotest\0host=localhost\0\0version=2\0")
+                .unwrap();
+        assert_eq!(request.path, PathBuf::from("/root/repotest"));
+        assert_eq!(request.version, ProtocolVersion::V2);
+
+        let request = DaemonRequest::parse(b"git-receive-pack /root/repotest/\0").unwrap();
+        assert_eq!(request.service, ServiceType::ReceivePack);
+        assert_eq!(request.host, None);
+
+        assert!(DaemonRequest::parse(b"git-upload-pack\0").is_err());
+        assert!(DaemonRequest::parse(b"git-fetch /root/repotest\0").is_err());
+    }
+
+    #[test]
+    fn test_read_pkt() {
+        let mut reader: &[u8] = b"0009done\n00000001";
+        assert_eq!(
+            &block_on(read_pkt(&mut reader)).unwrap().unwrap()[..],
+            b"0009done\n"
+        );
+        assert_eq!(
+            &block_on(read_pkt(&mut reader)).unwrap().unwrap()[..],
+            b"0000"
+        );
+        assert_eq!(
+            &block_on(read_pkt(&mut reader)).unwrap().unwrap()[..],
+            b"0001"
+        );
+        assert!(block_on(read_pkt(&mut reader)).unwrap().is_none());
+
+        let mut reader: &[u8] = b"0003";
+        assert!(block_on(read_pkt(&mut reader)).is_err());
+    }
+}
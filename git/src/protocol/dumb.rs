@@ -0,0 +1,180 @@
+//!
+//! The dumb HTTP protocol, where the client reads the repo as a tree of static files:
+//!
+//! - `info/refs`: a `<id> TAB <ref-name> LF` line for each ref, the same file as
+//!   `git update-server-info` writes, an annotated tag is followed by its `^{}` line.
+//! - `HEAD`: the branch HEAD points to, as a symbolic ref.
+//! - `objects/info/packs`: the packs of the repo, there are none as the objects are stored
+//!   one by one in the database.
+//! - `objects/xx/yyyy`: the loose objects, zlib compressed with their header.
+//!
+//! Nothing is negotiated, so the responses can be cached by plain HTTP caches.
+//!
+
+use std::path::Path;
+
+use crate::errors::GitError;
+use crate::hash::{is_zero_id, HashKind};
+use crate::internal::object::commit::Commit;
+use crate::internal::object::ObjectT;
+use crate::internal::ObjectType;
+use crate::utils;
+
+use super::pack::LF;
+use super::PackProtocol;
+
+impl PackProtocol {
+    /// # Builds the `info/refs` file of the repo.
+    ///
+    /// Unlike the smart advertisement it doesn't list HEAD and has no capabilities.
+    pub async fn dumb_info_refs(&self) -> String {
+        let mut git_refs = self
+            .storage
+            .get_ref_object_id(self.path.to_str().unwrap())
+            .await
+            .unwrap();
+        git_refs.sort_by(|a, b| a.ref_name.cmp(&b.ref_name));
+        let peeled_refs = self.get_peeled_refs(&self.path).await;
+        let mut info_refs = String::new();
+        for git_ref in git_refs {
+            info_refs.push_str(&format!(
+                "{}\t{}{}",
+                git_ref.ref_git_id, git_ref.ref_name, LF
+            ));
+            if let Some(peeled) = peeled_refs.get(&git_ref.ref_name) {
+                info_refs.push_str(&format!("{}\t{}^{{}}{}", peeled, git_ref.ref_name, LF));
+            }
+        }
+        info_refs
+    }
+
+    /// # Builds the `HEAD` file of the repo.
+    ///
+    /// HEAD points to the default branch of the repo, see [`PackProtocol::get_head_ref`].
+    /// A repo without branches, such as a subdirectory of another repo, has a detached HEAD.
+    /// Returns `None` if the repo doesn't exist.
+    pub async fn dumb_head(&self) -> Option<String> {
+        if let Some(head) = self.get_head_ref(&self.path).await {
+            return Some(format!("ref: {}{}", head, LF));
+        }
+        let object_id = self.get_head_object_id(&self.path).await;
+        if is_zero_id(&object_id) {
+            None
+        } else {
+            Some(format!("{}{}", object_id, LF))
+        }
+    }
+
+    /// # Builds the `objects/info/packs` file of the repo.
+    ///
+    /// The objects are all served as loose objects, so no pack is listed and the client never
+    /// needs to download a whole pack to get a single object.
+    pub fn dumb_info_packs(&self) -> String {
+        LF.to_string()
+    }
+
+    /// # Reads an object of the repo in the loose format.
+    ///
+    /// Commits are rebuilt from the commit table, the other objects come from their `git_obj`
+    /// row. The storage is shared by all the repos, like the object directory of a repo only
+    /// the objects pushed to this repo are served. Returns `None` if there's no such object.
+    pub async fn get_loose_object(&self, object_id: &str) -> Result<Option<Vec<u8>>, GitError> {
+        let repo_path = self.path.to_str().unwrap();
+        let commit = self
+            .storage
+            .get_commit_by_hashes(vec![object_id.to_owned()])
+            .await?
+            .into_iter()
+            .find(|model| model.repo_path == repo_path);
+        if let Some(model) = commit {
+            let commit: Commit = model.into();
+            return loose_object(ObjectType::Commit, &commit.get_raw()).map(Some);
+        }
+        if !self.has_object(object_id).await? {
+            return Ok(None);
+        }
+        match self.storage.get_obj_data_by_id(object_id).await? {
+            Some(model) => {
+                let object_type = ObjectType::from_string(&model.object_type)?;
+                loose_object(object_type, &model.data).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // whether a tree, a blob or an annotated tag was pushed to the repo
+    async fn has_object(&self, object_id: &str) -> Result<bool, GitError> {
+        let tags = self.get_annotated_tags(&self.path).await;
+        if tags.contains_key(object_id) {
+            return Ok(true);
+        }
+        let repo_path = self.path.to_str().unwrap();
+        Ok(self
+            .storage
+            .get_nodes_by_hashes(vec![object_id.to_owned()])
+            .await?
+            .iter()
+            .any(|node| node.repo_path == repo_path))
+    }
+}
+
+/// Reads the id of a loose object of the `kind` format from the `xx/yyyy` end of its path,
+/// e.g. `objects/8a/b686eafeb1f44702738c8b0f24f2567c36da6d`.
+pub fn loose_object_id(path: &Path, kind: HashKind) -> Option<String> {
+    let mut components = path.iter().rev().take(2).map(|c| c.to_str());
+    let name = components.next()??;
+    let dir = components.next()??;
+    let id = format!("{}{}", dir, name);
+    let is_hex = id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+    (dir.len() == 2 && id.len() == kind.hex_len() && is_hex).then_some(id)
+}
+
+// the loose format of an object: `<type> SP <size> NUL <data>`, zlib compressed
+fn loose_object(object_type: ObjectType, data: &[u8]) -> Result<Vec<u8>, GitError> {
+    let mut object = Vec::with_capacity(data.len() + 32);
+    object.extend_from_slice(object_type.to_bytes());
+    object.push(b' ');
+    object.extend_from_slice(data.len().to_string().as_bytes());
+    object.push(b'\0');
+    object.extend_from_slice(data);
+    utils::compress_zlib(&object).map_err(|e| GitError::EncodeObjectError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::Path;
+
+    use flate2::read::ZlibDecoder;
+
+    use crate::hash::HashKind;
+    use crate::internal::ObjectType;
+
+    use super::{loose_object, loose_object_id};
+
+    #[test]
+    fn test_loose_object() {
+        let object = loose_object(ObjectType::Blob, b"hello\n").unwrap();
+        let mut data = Vec::new();
+        ZlibDecoder::new(&object[..])
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"blob 6\0hello\n");
+    }
+
+    #[test]
+    fn test_loose_object_id() {
+        let path = Path::new("/root/repotest/objects/ce/013625030ba8dba906f756967f9e9ca394464a");
+        assert_eq!(
+            loose_object_id(path, HashKind::Sha1).as_deref(),
+            Some("ce013625030ba8dba906f756967f9e9ca394464a")
+        );
+        assert!(loose_object_id(path, HashKind::Sha256).is_none());
+        let path = Path::new("objects/da")
+            .join("bc789f60c22621c92df8736ff8cb60e35185584772b93b9315a3e2aab55653");
+        assert!(loose_object_id(&path, HashKind::Sha256).is_some());
+        let packs = Path::new("objects/info/packs");
+        assert!(loose_object_id(packs, HashKind::Sha1).is_none());
+        assert!(loose_object_id(Path::new("objects/ce/0136"), HashKind::Sha1).is_none());
+    }
+}
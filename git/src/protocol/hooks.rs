@@ -0,0 +1,431 @@
+//!
+//! Server-side hooks of a push, they run at the same stages as the hooks of git:
+//!
+//! - `pre-receive` runs once before any ref is updated, rejecting it fails the whole push.
+//! - `update` runs once for each ref before it's updated, rejecting it fails that ref only.
+//! - `post-receive` runs once the refs are updated, it can't change the result of the push.
+//!
+//! The built-in hooks and the directory of the external hooks are configured with
+//! environment variables, see [`PushHooks::from_env`].
+//!
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use database::driver::ObjectStorage;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::internal::object::commit::Commit;
+use crate::structure::filter::SparsePatterns;
+use crate::utils;
+
+use super::RefCommand;
+
+/// A file changed by the commits of a push.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangedPath {
+    /// the path relative to the repo root
+    pub path: PathBuf,
+    /// the blob at `path` after the push, `None` if the file was deleted
+    pub new_id: Option<String>,
+}
+
+/// A ref update of a push, with what it brings to the repo.
+#[derive(Debug, Clone)]
+pub struct RefChange {
+    pub command: RefCommand,
+    /// the commits reachable from the new id that no ref of the repo pointed to before
+    pub commits: Vec<Commit>,
+    /// the files changed by `commits`
+    pub changed_paths: Vec<ChangedPath>,
+}
+
+/// What the hooks know about a push.
+#[derive(Clone)]
+pub struct PushInfo {
+    pub repo_path: PathBuf,
+    pub changes: Vec<RefChange>,
+    pub storage: Arc<dyn ObjectStorage>,
+}
+
+/// A server-side hook of a push.
+///
+/// Lines pushed to `out` are sent to the client on band 2, they are shown with a `remote:`
+/// prefix. A hook rejects with a reason, which is sent to the client as well.
+#[async_trait]
+pub trait PushHook: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn pre_receive(&self, _push: &PushInfo, _out: &mut Vec<String>) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        _push: &PushInfo,
+        _change: &RefChange,
+        _out: &mut Vec<String>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn post_receive(&self, _push: &PushInfo, _out: &mut Vec<String>) {}
+}
+
+/// The hooks run on a push, in the order they were added.
+#[derive(Clone, Default)]
+pub struct PushHooks {
+    hooks: Vec<Arc<dyn PushHook>>,
+}
+
+impl PushHooks {
+    /// Loads the hooks configured by the environment:
+    ///
+    /// - `MEGA_HOOK_MAX_FILE_SIZE`: rejects a push adding a file of more bytes than that.
+    /// - `MEGA_HOOK_DENY_PATHS`: comma separated patterns of paths that can't be changed, with
+    ///   the gitignore syntax.
+    /// - `MEGA_HOOKS_DIR`: a directory of `pre-receive`, `update` and `post-receive`
+    ///   executables, they are run like the hooks of git.
+    /// - `MEGA_HOOK_TIMEOUT`: the seconds an executable may run before it's killed, 60 by
+    ///   default.
+    ///
+    /// It's called once when a server starts, the hooks are then set on the
+    /// [`super::PackProtocol`] of each push.
+    pub fn from_env() -> Self {
+        let mut hooks = PushHooks::default();
+        let mut max_file_size = 0;
+        utils::get_env_number("MEGA_HOOK_MAX_FILE_SIZE", &mut max_file_size);
+        if max_file_size > 0 {
+            hooks.add(Arc::new(MaxFileSizeHook::new(max_file_size)));
+        }
+        if let Ok(patterns) = std::env::var("MEGA_HOOK_DENY_PATHS") {
+            if !patterns.trim().is_empty() {
+                hooks.add(Arc::new(DenyPathsHook::new(&patterns)));
+            }
+        }
+        if let Ok(dir) = std::env::var("MEGA_HOOKS_DIR") {
+            if !dir.trim().is_empty() {
+                let mut timeout = 60;
+                utils::get_env_number("MEGA_HOOK_TIMEOUT", &mut timeout);
+                let timeout = Duration::from_secs(timeout);
+                hooks.add(Arc::new(ExternalHooks::new(PathBuf::from(dir), timeout)));
+            }
+        }
+        hooks
+    }
+
+    pub fn add(&mut self, hook: Arc<dyn PushHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Runs the `pre-receive` hooks, the first rejection stops the others.
+    pub async fn pre_receive(&self, push: &PushInfo, out: &mut Vec<String>) -> Result<(), String> {
+        for hook in &self.hooks {
+            if let Err(reason) = hook.pre_receive(push, out).await {
+                tracing::info!("pre-receive hook {} declined: {}", hook.name(), reason);
+                return Err(reason);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the `update` hooks of a ref, the first rejection stops the others.
+    pub async fn update(
+        &self,
+        push: &PushInfo,
+        change: &RefChange,
+        out: &mut Vec<String>,
+    ) -> Result<(), String> {
+        for hook in &self.hooks {
+            if let Err(reason) = hook.update(push, change, out).await {
+                tracing::info!(
+                    "update hook {} declined {}: {}",
+                    hook.name(),
+                    change.command.ref_name,
+                    reason
+                );
+                return Err(reason);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn post_receive(&self, push: &PushInfo, out: &mut Vec<String>) {
+        for hook in &self.hooks {
+            hook.post_receive(push, out).await;
+        }
+    }
+}
+
+/// Rejects a push adding files larger than a limit.
+pub struct MaxFileSizeHook {
+    limit: u64,
+}
+
+impl MaxFileSizeHook {
+    pub fn new(limit: u64) -> Self {
+        MaxFileSizeHook { limit }
+    }
+}
+
+#[async_trait]
+impl PushHook for MaxFileSizeHook {
+    fn name(&self) -> &str {
+        "max-file-size"
+    }
+
+    async fn pre_receive(&self, push: &PushInfo, _out: &mut Vec<String>) -> Result<(), String> {
+        for change in &push.changes {
+            for changed in &change.changed_paths {
+                let Some(id) = &changed.new_id else {
+                    continue;
+                };
+                let Some(blob) = push.storage.get_obj_data_by_id(id).await.unwrap() else {
+                    continue;
+                };
+                if blob.data.len() as u64 > self.limit {
+                    return Err(format!(
+                        "{} is {} bytes, files are limited to {} bytes",
+                        changed.path.display(),
+                        blob.data.len(),
+                        self.limit
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects the ref updates changing files that match some patterns.
+pub struct DenyPathsHook {
+    patterns: SparsePatterns,
+}
+
+impl DenyPathsHook {
+    /// `patterns` are separated by commas.
+    pub fn new(patterns: &str) -> Self {
+        DenyPathsHook {
+            patterns: SparsePatterns::parse(&patterns.replace(',', "\n")),
+        }
+    }
+}
+
+#[async_trait]
+impl PushHook for DenyPathsHook {
+    fn name(&self) -> &str {
+        "deny-paths"
+    }
+
+    async fn update(
+        &self,
+        _push: &PushInfo,
+        change: &RefChange,
+        _out: &mut Vec<String>,
+    ) -> Result<(), String> {
+        match change
+            .changed_paths
+            .iter()
+            .find(|changed| self.patterns.is_included(&changed.path))
+        {
+            Some(changed) => Err(format!(
+                "changes to {} are not allowed",
+                changed.path.display()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs the `pre-receive`, `update` and `post-receive` executables of a directory like git
+/// runs its hooks: `pre-receive` and `post-receive` read a line `<old-id> <new-id> <ref-name>`
+/// for each ref on their standard input, `update` gets them as arguments. A non-zero exit
+/// status rejects the push, the output of the hooks is sent to the client.
+///
+/// The path of the repo is passed in the `MEGA_REPO_PATH` environment variable. A hook that
+/// runs longer than `timeout` is killed and rejects the push.
+pub struct ExternalHooks {
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+impl ExternalHooks {
+    pub fn new(dir: PathBuf, timeout: Duration) -> Self {
+        ExternalHooks { dir, timeout }
+    }
+
+    async fn run(
+        &self,
+        name: &str,
+        args: &[&str],
+        input: String,
+        repo_path: &Path,
+        out: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let mut child = Command::new(&path)
+            .args(args)
+            .env("MEGA_REPO_PATH", repo_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to run the {} hook: {}", name, e))?;
+        // written from another task, the hook may fill its output before reading all of it
+        let mut stdin = child.stdin.take().unwrap();
+        tokio::spawn(async move {
+            let _ = stdin.write_all(input.as_bytes()).await;
+        });
+        let output = tokio::time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .map_err(|_| format!("{} hook timed out", name))?
+            .map_err(|e| format!("failed to run the {} hook: {}", name, e))?;
+        for data in [&output.stdout, &output.stderr] {
+            out.extend(String::from_utf8_lossy(data).lines().map(str::to_owned));
+        }
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!("{} hook exited with {}", name, output.status))
+        }
+    }
+}
+
+// the standard input of the pre-receive and post-receive hooks
+fn hook_input(push: &PushInfo) -> String {
+    push.changes
+        .iter()
+        .map(|change| {
+            let c = &change.command;
+            format!("{} {} {}\n", c.old_id, c.new_id, c.ref_name)
+        })
+        .collect()
+}
+
+#[async_trait]
+impl PushHook for ExternalHooks {
+    fn name(&self) -> &str {
+        "external"
+    }
+
+    async fn pre_receive(&self, push: &PushInfo, out: &mut Vec<String>) -> Result<(), String> {
+        self.run("pre-receive", &[], hook_input(push), &push.repo_path, out)
+            .await
+    }
+
+    async fn update(
+        &self,
+        push: &PushInfo,
+        change: &RefChange,
+        out: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let c = &change.command;
+        let args = [c.ref_name.as_str(), c.old_id.as_str(), c.new_id.as_str()];
+        self.run("update", &args, String::new(), &push.repo_path, out)
+            .await
+    }
+
+    async fn post_receive(&self, push: &PushInfo, out: &mut Vec<String>) {
+        if let Err(e) = self
+            .run("post-receive", &[], hook_input(push), &push.repo_path, out)
+            .await
+        {
+            tracing::warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use database::driver::mysql::storage::MysqlStorage;
+    use tokio_test::block_on;
+
+    use crate::protocol::RefCommand;
+
+    use super::{ChangedPath, DenyPathsHook, ExternalHooks, PushHook, PushInfo, RefChange};
+
+    fn change(path: &str) -> RefChange {
+        RefChange {
+            command: RefCommand::new(
+                String::from("0000000000000000000000000000000000000000"),
+                String::from("8f8bc6ac7d4ae2ec5e0ee8e2b0c5e6d7f38a3d4e"),
+                String::from("refs/heads/main"),
+            ),
+            commits: vec![],
+            changed_paths: vec![ChangedPath {
+                path: PathBuf::from(path),
+                new_id: None,
+            }],
+        }
+    }
+
+    fn push(changes: Vec<RefChange>) -> PushInfo {
+        PushInfo {
+            repo_path: PathBuf::from("/root/repotest"),
+            changes,
+            storage: Arc::new(MysqlStorage::default()),
+        }
+    }
+
+    #[test]
+    fn test_deny_paths_hook() {
+        let push = push(vec![]);
+        let hook = DenyPathsHook::new("/.github/, *.key");
+        let rejected =
+            |path: &str| block_on(hook.update(&push, &change(path), &mut vec![])).is_err();
+        assert!(rejected(".github/workflows/ci.yml"));
+        assert!(rejected("certs/server.key"));
+        assert!(!rejected("src/main.rs"));
+        assert!(!rejected("docs/.github/a.md"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_external_hooks() {
+        let dir = std::env::temp_dir().join(format!("mega-hooks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        script("pre-receive", "cat\necho denied by policy\nexit 1");
+        script("update", "sleep 10");
+        let hooks = ExternalHooks::new(dir.clone(), Duration::from_millis(500));
+        let push = push(vec![change("src/main.rs")]);
+
+        let mut out = vec![];
+        let rejected = block_on(hooks.pre_receive(&push, &mut out)).unwrap_err();
+        assert!(rejected.starts_with("pre-receive hook exited with"));
+        assert_eq!(
+            out,
+            [
+                "0000000000000000000000000000000000000000 \
+                 8f8bc6ac7d4ae2ec5e0ee8e2b0c5e6d7f38a3d4e refs/heads/main",
+                "denied by policy"
+            ]
+        );
+        let timed_out = block_on(hooks.update(&push, &push.changes[0], &mut vec![]));
+        assert_eq!(timed_out, Err(String::from("update hook timed out")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,21 +4,170 @@
 //!
 //!
 use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
 
 use anyhow::Result;
 use axum::body::Body;
 use axum::http::response::Builder;
-use axum::http::{Response, StatusCode};
+use axum::http::{header, HeaderMap, Response, StatusCode};
 
 use bytes::{BufMut, Bytes, BytesMut};
 
+use flate2::write::{GzDecoder, ZlibDecoder};
+
 use futures::StreamExt;
 use hyper::body::Sender;
 use hyper::Request;
 
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+
+use super::dumb::loose_object_id;
+use super::sideband::SideBandStream;
+use super::{pack, PackProtocol, ProtocolVersion};
+use crate::utils;
+
+/// # Reads the protocol version requested by the client.
+///
+/// Clients that want protocol v1 or v2 send it in the `Git-Protocol` header of both the
+/// `info/refs` discovery and the following service request, e.g. `Git-Protocol: version=2`.
+pub fn get_protocol_version(headers: &HeaderMap) -> ProtocolVersion {
+    headers
+        .get("Git-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .map(ProtocolVersion::from_git_protocol)
+        .unwrap_or_default()
+}
+
+/// The largest request body held in memory once decoded: a whole upload-pack request, or the
+/// ref update commands of a push. The default is the one of `git http-backend`, it can be
+/// changed with the `GIT_HTTP_MAX_REQUEST_BUFFER` environment variable like there.
+pub fn max_request_buffer() -> usize {
+    let mut max_size = 10 * 1024 * 1024;
+    utils::get_env_number("GIT_HTTP_MAX_REQUEST_BUFFER", &mut max_size);
+    max_size
+}
+
+// the compressed body is inflated by pieces of that many bytes, so that a small body can't
+// inflate far beyond the maximum size before it's checked
+const DECODE_PIECE_SIZE: usize = 8 * 1024;
+
+enum ContentDecoder {
+    Identity,
+    Gzip(GzDecoder<Vec<u8>>),
+    // `deflate` of HTTP is the zlib format
+    Deflate(ZlibDecoder<Vec<u8>>),
+}
+
+impl ContentDecoder {
+    // decodes the next bytes of the body and takes what they yield
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            ContentDecoder::Identity => return Ok(data.to_vec()),
+            ContentDecoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+            ContentDecoder::Deflate(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+}
+
+/// # Decodes a request body sent with a `Content-Encoding`.
+///
+/// Git compresses the large upload-pack requests with gzip, and some proxies compress the
+/// push bodies too. The body is decoded chunk by chunk as it's received, so that a push can
+/// still be streamed into the storage.
+///
+/// A body that decodes to more than the maximum size set with
+/// [`BodyDecoder::set_max_size`] is rejected with 413 Payload Too Large.
+pub struct BodyDecoder {
+    decoder: ContentDecoder,
+    max_size: Option<usize>,
+    // the number of decoded bytes so far
+    size: usize,
+}
+
+impl BodyDecoder {
+    /// The decoder for the `Content-Encoding` of the request, an unknown encoding is rejected
+    /// with 415 Unsupported Media Type.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, (StatusCode, String)> {
+        let encoding = headers
+            .get(header::CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().unwrap_or_default().trim())
+            .unwrap_or_default();
+        let decoder = match encoding {
+            "identity" | "" => ContentDecoder::Identity,
+            "gzip" | "x-gzip" => ContentDecoder::Gzip(GzDecoder::new(vec![])),
+            "deflate" => ContentDecoder::Deflate(ZlibDecoder::new(vec![])),
+            other => {
+                return Err((
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    format!("unsupported content encoding: {}", other),
+                ))
+            }
+        };
+        Ok(BodyDecoder {
+            decoder,
+            max_size: None,
+            size: 0,
+        })
+    }
 
-use super::{pack, PackProtocol};
+    /// Limits the size of what's decoded from now on, counting what was decoded before.
+    /// `None` lifts the limit, e.g. for a pack that is streamed into the storage.
+    pub fn set_max_size(&mut self, max_size: Option<usize>) {
+        self.max_size = max_size;
+    }
+
+    /// Decodes the next chunk of the body, it may yield nothing until more is received.
+    pub fn decode(&mut self, chunk: Bytes) -> Result<Bytes, (StatusCode, String)> {
+        if matches!(self.decoder, ContentDecoder::Identity) {
+            self.size += chunk.len();
+            self.check_size()?;
+            return Ok(chunk);
+        }
+        let mut output = vec![];
+        for piece in chunk.chunks(DECODE_PIECE_SIZE) {
+            let decoded = self.decoder.write(piece).map_err(bad_request)?;
+            self.size += decoded.len();
+            self.check_size()?;
+            output.extend_from_slice(&decoded);
+        }
+        Ok(Bytes::from(output))
+    }
+
+    /// Decodes what's left once the whole body is received, and checks that it wasn't
+    /// truncated.
+    pub fn finish(mut self) -> Result<Bytes, (StatusCode, String)> {
+        let output = match std::mem::replace(&mut self.decoder, ContentDecoder::Identity) {
+            ContentDecoder::Identity => vec![],
+            ContentDecoder::Gzip(decoder) => decoder.finish().map_err(bad_request)?,
+            ContentDecoder::Deflate(decoder) => decoder.finish().map_err(bad_request)?,
+        };
+        self.size += output.len();
+        self.check_size()?;
+        Ok(Bytes::from(output))
+    }
+
+    fn check_size(&self) -> Result<(), (StatusCode, String)> {
+        match self.max_size {
+            Some(max_size) if self.size > max_size => Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("request body larger than {} bytes", max_size),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn bad_request(e: io::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
 
 /// # Build Response headers for Smart Server.
 /// Clients MUST NOT reuse or revalidate a cached response.
@@ -38,46 +187,61 @@ pub fn build_res_header(content_type: String) -> Builder {
     resp
 }
 
-/// # Sends a Git pack to the remote server.
+/// # Build Response headers for the objects of the dumb protocol.
+/// An object never changes once it's named by its id, so it can be cached for a year like
+/// `git http-backend` does.
+pub fn build_cacheable_res_header(content_type: String) -> Builder {
+    Response::builder()
+        .header("Content-Type", content_type)
+        .header("Cache-Control", "public, max-age=31536000")
+}
+
+/// # Sends a side-band stream to the client.
+///
+/// This function takes a `Sender` for sending data to the client, the `stream` producing the
+/// pack or the report status with their progress, and the `pack_protocol` describing the pack
+/// transfer protocol. Each message is formatted using the side-band format specified by the
+/// `pack_protocol` and sent as soon as it's produced, the pack is never held in memory as a whole.
 ///
-/// This function takes a `Sender` for sending data to the remote server, the `result` vector
-/// containing the pack data, and the `pack_protocol` describing the pack transfer protocol.
-/// It asynchronously reads the pack data from the `result` vector in chunks, formats it using the
-/// side-band format specified by the `pack_protocol`, and sends it to the remote server using
-/// the `Sender`.
+/// An error is reported to the client on band 3. Without a side-band the response is aborted.
 ///
 /// # Arguments
 ///
-/// * `sender` - The sender for sending data to the remote server.
-/// * `result` - The vector containing the pack data to be sent.
+/// * `sender` - The sender for sending data to the client.
+/// * `stream` - The side-band stream to be sent.
 /// * `pack_protocol` - The pack protocol describing the pack transfer.
 ///
 /// # Returns
 ///
-/// * `Ok(())` - If the pack data is successfully sent to the remote server.
+/// * `Ok(())` - If the stream is successfully sent to the client.
 /// * `Err((StatusCode, &'static str))` - If there is an error during the sending process, with the
 ///   error status code and a corresponding error message.
 pub async fn send_pack(
     mut sender: Sender,
-    result: Vec<u8>,
+    mut stream: SideBandStream,
     pack_protocol: PackProtocol,
 ) -> Result<(), (StatusCode, &'static str)> {
-    let mut reader = BufReader::new(result.as_slice());
-    loop {
-        let mut temp = BytesMut::new();
-        temp.reserve(65500);
-        let length = reader.read_buf(&mut temp).await.unwrap();
-        if temp.is_empty() {
-            let mut bytes_out = BytesMut::new();
-            bytes_out.put_slice(pack::PKT_LINE_END_MARKER);
-            tracing::info!("send: bytes_out: {:?}", bytes_out.clone().freeze());
-            sender.send_data(bytes_out.freeze()).await.unwrap();
+    while let Some(message) = stream.next().await {
+        let Some(bytes_out) = pack_protocol.build_side_band_message(&message) else {
+            sender.abort();
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to send the pack"));
+        };
+        tracing::info!("send: packet length: {:?}", bytes_out.len());
+        if sender.send_data(bytes_out.freeze()).await.is_err() {
+            // the client has closed the connection
             return Ok(());
         }
-        let bytes_out = pack_protocol.build_side_band_format(temp, length);
-        tracing::info!("send: packet length: {:?}", bytes_out.len());
-        sender.send_data(bytes_out.freeze()).await.unwrap();
+        if message.is_err() {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to send the pack"));
+        }
     }
+    if pack_protocol.is_side_band() {
+        let mut bytes_out = BytesMut::new();
+        bytes_out.put_slice(pack::PKT_LINE_END_MARKER);
+        tracing::info!("send: bytes_out: {:?}", bytes_out.clone().freeze());
+        let _ = sender.send_data(bytes_out.freeze()).await;
+    }
+    Ok(())
 }
 /// # Handles a Git upload pack request and prepares the response.
 ///
@@ -85,38 +249,52 @@ pub async fn send_pack(
 /// parameter containing the configuration for the Git pack protocol.
 ///
 /// The function extracts the request body into a `BytesMut` buffer by iterating over the chunks
-/// of the request body using `body.next().await`. The chunks are concatenated into the `upload_request`
-/// buffer.
+/// of the request body using `body.next().await`. The chunks are decoded with a [`BodyDecoder`]
+/// if the client compressed them and concatenated into the `upload_request` buffer.
 ///
 /// The `pack_protocol` is then used to process the `upload_request` using the `git_upload_pack` method.
-/// It returns the `send_pack_data` and `buf` containing the response data.
+/// It returns the pack stream and `buf` containing the response data.
 ///
 /// A response header is constructed using the `build_res_header` function with a content type of
 /// "application/x-git-upload-pack-result". The response body channel is created using `Body::channel()`.
 ///
 /// The `buf` is sent as the initial data using the `sender` to establish the response body.
 ///
-/// A new task is spawned to send the remaining `send_pack_data` using the `send_pack` function.
+/// If the negotiation is done, a new task is spawned to send the pack stream using the `send_pack` function.
+///
+/// For protocol v2 the request carries a single command, a `fetch` that ends the negotiation
+/// is followed by its pack stream like in v0.
 ///
 /// Finally, the constructed response with the response body is returned.
 pub async fn git_upload_pack(
     req: Request<Body>,
     mut pack_protocol: PackProtocol,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-    let (_parts, mut body) = req.into_parts();
+    let (parts, mut body) = req.into_parts();
+    let mut decoder = BodyDecoder::from_headers(&parts.headers)?;
+    decoder.set_max_size(Some(max_request_buffer()));
 
     let mut upload_request = BytesMut::new();
 
     while let Some(chunk) = body.next().await {
         tracing::info!("client sends :{:?}", chunk);
-        let bytes = chunk.unwrap();
-        upload_request.extend_from_slice(&bytes);
+        let bytes = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        upload_request.extend_from_slice(&decoder.decode(bytes)?);
     }
+    upload_request.extend_from_slice(&decoder.finish()?);
 
-    let (send_pack_data, buf) = pack_protocol
-        .git_upload_pack(&mut upload_request.freeze())
-        .await
-        .unwrap();
+    let (pack_stream, buf) = if pack_protocol.version == ProtocolVersion::V2 {
+        let (buf, pack_stream) = pack_protocol
+            .git_upload_pack_v2(&mut upload_request.freeze())
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        (pack_stream, buf)
+    } else {
+        pack_protocol
+            .git_upload_pack(&mut upload_request.freeze())
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
     let resp = build_res_header("application/x-git-upload-pack-result".to_owned());
 
     tracing::info!("send buf: {:?}", buf);
@@ -124,54 +302,206 @@ pub async fn git_upload_pack(
     let (mut sender, body) = Body::channel();
     sender.send_data(buf.freeze()).await.unwrap();
 
-    tokio::spawn(send_pack(sender, send_pack_data, pack_protocol));
+    // the negotiation isn't finished yet, the client will send another request with more haves
+    if let Some(pack_stream) = pack_stream {
+        tokio::spawn(send_pack(sender, pack_stream, pack_protocol));
+    }
     Ok(resp.body(body).unwrap())
 }
 
+/// # Serves a file of the dumb HTTP protocol.
+///
+/// `file` is the path of the file relative to the repo, one of `info/refs`, `HEAD`,
+/// `objects/info/packs` or the `objects/xx/yyyy` path of a loose object, see
+/// [`super::dumb`]. The refs and HEAD change with every push so they are never cached,
+/// the loose objects are.
+pub async fn git_dumb_request(
+    pack_protocol: PackProtocol,
+    file: &str,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, format!("{} not found\n", file));
+    let text = "text/plain; charset=utf-8".to_owned();
+    let resp = match file {
+        "info/refs" => {
+            build_res_header(text).body(Body::from(pack_protocol.dumb_info_refs().await))
+        }
+        "HEAD" => {
+            let head = pack_protocol.dumb_head().await.ok_or_else(not_found)?;
+            build_res_header(text).body(Body::from(head))
+        }
+        "objects/info/packs" => {
+            build_res_header(text).body(Body::from(pack_protocol.dumb_info_packs()))
+        }
+        _ => {
+            let kind = pack_protocol
+                .get_object_format()
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            let object_id = loose_object_id(Path::new(file), kind).ok_or_else(not_found)?;
+            let object = pack_protocol
+                .get_loose_object(&object_id)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .ok_or_else(not_found)?;
+            build_cacheable_res_header("application/x-git-loose-object".to_owned())
+                .body(Body::from(object))
+        }
+    };
+    Ok(resp.unwrap())
+}
+
 /// # Handles a Git receive pack request and prepares the response.
 ///
 /// The function takes a `req` parameter representing the HTTP request received and a `pack_protocol`
 /// parameter containing the configuration for the Git pack protocol.
 ///
-/// The function extracts the request body into a vector of bytes, `combined_body_bytes`, by iterating over the
-/// chunks of the request body using `body.next().await`. The chunks are appended to the `combined_body_bytes`.
-///
-/// The `pack_protocol` is then used to process the `combined_body_bytes` using the `git_receive_pack` method.
-/// It returns the `pack_data` containing the response data.
-///
-/// The `pack_data` is passed to the `git_receive_pack` method again to obtain the final response data as a `buf`.
-///
-/// The `buf` is converted into a `Body` using `Body::from()` and assigned to `body`.
-/// Tracing information is logged regarding the status of the response body.
+/// The request body is read chunk by chunk, decoded with a [`BodyDecoder`] if it's compressed.
+/// The ref update commands at the beginning of the body are
+/// buffered until the flush-pkt and parsed with `parse_receive_commands`. The remaining chunks hold the
+/// pack, they are forwarded through a bounded channel to `git_receive_pack_stream`, which decodes and
+/// stores the objects while the body is still being received, so the push is never held in memory.
 ///
 /// A response header is constructed using the `build_res_header` function with a content type of
-/// "application/x-git-receive-pack-result". The response body is set to `body`.
+/// "application/x-git-receive-pack-result". The response body is streamed with `send_pack`: the
+/// progress of the unpacking, then the report status of the push.
 ///
 /// Finally, the constructed response is returned.
 pub async fn git_receive_pack(
     req: Request<Body>,
     mut pack_protocol: PackProtocol,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-    let (_parts, mut body) = req.into_parts();
-    let mut combined_body_bytes = Vec::new();
-    while let Some(chunk) = body.next().await {
-        let body_bytes = chunk.unwrap();
-        combined_body_bytes.extend(&body_bytes);
-    }
+    let (parts, mut body) = req.into_parts();
+    let mut decoder = BodyDecoder::from_headers(&parts.headers)?;
+    decoder.set_max_size(Some(max_request_buffer()));
 
-    let pack_data = pack_protocol
-        .git_receive_pack(Bytes::from(combined_body_bytes))
-        .await
-        .unwrap();
+    let mut buf = BytesMut::new();
+    loop {
+        let parsed = pack_protocol
+            .parse_receive_commands(&mut buf)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if parsed {
+            break;
+        }
+        match body.next().await {
+            Some(chunk) => {
+                let bytes = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                buf.extend_from_slice(&decoder.decode(bytes)?);
+            }
+            None => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    String::from("incomplete receive-pack request"),
+                ))
+            }
+        }
+    }
 
-    let buf = pack_protocol.git_receive_pack(pack_data).await.unwrap();
+    // the pack is streamed into the storage, its size isn't limited
+    decoder.set_max_size(None);
+    let (sender, receiver) = mpsc::channel(pack::PACK_CHANNEL_SIZE);
+    let rest = buf.freeze();
+    tokio::spawn(async move {
+        if !rest.is_empty() && sender.send(rest).await.is_err() {
+            return;
+        }
+        while let Some(chunk) = body.next().await {
+            let bytes = match chunk {
+                Ok(bytes) => decoder.decode(bytes),
+                Err(e) => {
+                    tracing::error!("read receive-pack body failed: {}", e);
+                    return;
+                }
+            };
+            match bytes {
+                Ok(bytes) if bytes.is_empty() => {}
+                Ok(bytes) => {
+                    if sender.send(bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Err((_, e)) => {
+                    tracing::error!("decode receive-pack body failed: {}", e);
+                    return;
+                }
+            }
+        }
+        match decoder.finish() {
+            Ok(rest) if !rest.is_empty() => {
+                let _ = sender.send(rest).await;
+            }
+            Ok(_) => {}
+            Err((_, e)) => tracing::error!("decode receive-pack body failed: {}", e),
+        }
+    });
 
-    let body = Body::from(buf);
-    tracing::info!("report status:{:?}", body);
+    let stream = pack_protocol.git_receive_pack_stream(receiver);
     let resp = build_res_header("application/x-git-receive-pack-result".to_owned());
-
-    let resp = resp.body(body).unwrap();
-    Ok(resp)
+    let (sender, body) = Body::channel();
+    tokio::spawn(send_pack(sender, stream, pack_protocol));
+    Ok(resp.body(body).unwrap())
 }
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::io::Write;
+
+    use axum::http::{HeaderMap, StatusCode};
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use crate::protocol::ProtocolVersion;
+
+    use super::{get_protocol_version, BodyDecoder};
+
+    #[test]
+    fn test_get_protocol_version() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_protocol_version(&headers), ProtocolVersion::V0);
+        headers.insert("Git-Protocol", "version=2".parse().unwrap());
+        assert_eq!(get_protocol_version(&headers), ProtocolVersion::V2);
+    }
+
+    #[test]
+    fn test_body_decoder() {
+        let request = b"0032want 8ab686eafeb1f44702738c8b0f24f2567c36da6d\n00000009done\n";
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(request).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Encoding", "gzip".parse().unwrap());
+        let mut decoder = BodyDecoder::from_headers(&headers).unwrap();
+        let mut decoded = vec![];
+        // the body is received in small chunks
+        for chunk in body.chunks(7) {
+            let bytes = decoder.decode(Bytes::copy_from_slice(chunk)).unwrap();
+            decoded.extend_from_slice(&bytes);
+        }
+        decoded.extend_from_slice(&decoder.finish().unwrap());
+        assert_eq!(decoded, request);
+
+        // a truncated body
+        let mut decoder = BodyDecoder::from_headers(&headers).unwrap();
+        decoder
+            .decode(Bytes::copy_from_slice(&body[..body.len() - 8]))
+            .unwrap();
+        assert!(decoder.finish().is_err());
+
+        // a small body inflating beyond the maximum size
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&vec![0; 1024 * 1024]).unwrap();
+        let body = encoder.finish().unwrap();
+        let mut decoder = BodyDecoder::from_headers(&headers).unwrap();
+        decoder.set_max_size(Some(64 * 1024));
+        assert_eq!(
+            decoder.decode(Bytes::from(body)).err().unwrap().0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        headers.insert("Content-Encoding", "br".parse().unwrap());
+        assert_eq!(
+            BodyDecoder::from_headers(&headers).err().unwrap().0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+}
@@ -3,9 +3,19 @@
 //!
 //!
 //!
+pub mod archive;
+pub mod bundle;
+pub mod daemon;
+pub mod dumb;
+pub mod hooks;
 pub mod http;
+pub mod object_format;
 pub mod pack;
+pub mod protection;
+pub mod session;
+pub mod sideband;
 pub mod ssh;
+pub mod v2;
 
 use std::{
     io::Cursor,
@@ -14,21 +24,22 @@ use std::{
     sync::Arc,
 };
 
-use database::driver::{mysql::storage::MysqlStorage, ObjectStorage};
+use database::driver::{mysql::storage::MysqlStorage, ObjectStorage, RefUpdate};
 
 use crate::{
     errors::GitError,
-    internal::pack::{
-        decode::HashCounter,
-        preload::{decode_load, PackPreload},
-    },
+    hash::{get_hash_kind, is_zero_id, sync_with_hash_kind, HashKind},
+    internal::pack::stream::{decode_stream, ChannelReader},
+    protocol::hooks::PushHooks,
     protocol::pack::SP,
+    protocol::sideband::{Progress, SideBandSender},
 };
 
 use bytes::Bytes;
-use common::{errors::MegaError, utils::ZERO_ID};
+use common::errors::MegaError;
 use entity::{mr_info, refs};
 use sea_orm::{ActiveValue::NotSet, Set};
+use tokio::{runtime::Handle, sync::mpsc::Receiver};
 
 #[derive(Clone)]
 pub struct PackProtocol {
@@ -39,6 +50,11 @@ pub struct PackProtocol {
     pub command_list: Vec<RefCommand>,
     // only needed in ssh protocal
     pub service_type: Option<ServiceType>,
+    pub version: ProtocolVersion,
+    // run on the ref updates of a push
+    pub hooks: PushHooks,
+    // the authenticated user, checked against the pushers allowed on the protected refs
+    pub pusher: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Default)]
@@ -51,10 +67,81 @@ pub enum Protocol {
     P2p,
 }
 
+/// The wire protocol version negotiated with the client, see
+/// [protocol-v2](https://git-scm.com/docs/protocol-v2).
+///
+/// Clients request a version through the `Git-Protocol` HTTP header or the `GIT_PROTOCOL`
+/// environment variable over SSH; anything unrecognised falls back to version 0.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    V0,
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Parses a colon separated `Git-Protocol` value such as `version=2:object-format=sha1`,
+    /// taking the highest version offered by the client.
+    pub fn from_git_protocol(value: &str) -> Self {
+        value
+            .split(':')
+            .filter_map(|param| param.trim().strip_prefix("version="))
+            .filter_map(|version| match version {
+                "1" => Some(ProtocolVersion::V1),
+                "2" => Some(ProtocolVersion::V2),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// The shallow related lines of an upload request, they tell how the history sent to the
+/// client should be truncated.
+///
+/// ```bash
+/// shallow <obj-id>          # a commit that is already shallow on the client side
+/// deepen <depth>            # --depth
+/// deepen-relative           # depth is counted from the current shallow boundary
+/// deepen-since <timestamp>  # --shallow-since
+/// deepen-not <rev>          # --shallow-exclude
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Deepen {
+    pub shallow: Vec<String>,
+    pub depth: Option<u32>,
+    pub relative: bool,
+    pub since: Option<i64>,
+    pub not: Vec<String>,
+}
+
+impl Deepen {
+    /// Parses one line of the request, returns `false` if the line isn't shallow related.
+    pub fn parse_line(&mut self, line: &str) -> anyhow::Result<bool> {
+        let line = line.trim_end();
+        match line.split_once(' ') {
+            Some(("shallow", id)) => self.shallow.push(id.to_owned()),
+            Some(("deepen", depth)) => self.depth = Some(depth.parse()?),
+            Some(("deepen-since", timestamp)) => self.since = Some(timestamp.parse()?),
+            Some(("deepen-not", rev)) => self.not.push(rev.to_owned()),
+            _ if line == "deepen-relative" => self.relative = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Whether the client asked for a truncated history.
+    pub fn is_requested(&self) -> bool {
+        self.depth.is_some() || self.since.is_some() || !self.not.is_empty()
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ServiceType {
     UploadPack,
     ReceivePack,
+    UploadArchive,
 }
 
 impl ToString for ServiceType {
@@ -62,6 +149,7 @@ impl ToString for ServiceType {
         match self {
             ServiceType::UploadPack => "git-upload-pack".to_owned(),
             ServiceType::ReceivePack => "git-receive-pack".to_owned(),
+            ServiceType::UploadArchive => "git-upload-archive".to_owned(),
         }
     }
 }
@@ -73,6 +161,7 @@ impl FromStr for ServiceType {
         match s {
             "git-upload-pack" => Ok(ServiceType::UploadPack),
             "git-receive-pack" => Ok(ServiceType::ReceivePack),
+            "git-upload-archive" => Ok(ServiceType::UploadArchive),
             _ => Err(MegaError {
                 error: anyhow::anyhow!("Invalid service name: {}", s).into(),
                 code: 400,
@@ -94,6 +183,12 @@ pub enum Capability {
     OfsDelta,
     DeepenSince,
     DeepenNot,
+    NoProgress,
+    Quiet,
+    Atomic,
+    ThinPack,
+    IncludeTag,
+    ObjectFormat(HashKind),
 }
 
 impl FromStr for Capability {
@@ -111,11 +206,20 @@ impl FromStr for Capability {
             "no-done" => Ok(Capability::NoDone),
             "deepen-since" => Ok(Capability::DeepenSince),
             "deepen-not" => Ok(Capability::DeepenNot),
-            _ => Err(()),
+            "no-progress" => Ok(Capability::NoProgress),
+            "quiet" => Ok(Capability::Quiet),
+            "atomic" => Ok(Capability::Atomic),
+            "thin-pack" => Ok(Capability::ThinPack),
+            "include-tag" => Ok(Capability::IncludeTag),
+            _ => match s.strip_prefix("object-format=") {
+                Some(format) => format.parse().map(Capability::ObjectFormat).map_err(|_| ()),
+                None => Err(()),
+            },
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SideBind {
     // sideband 1 will contain packfile data,
     PackfileData,
@@ -161,9 +265,9 @@ impl RefCommand {
     const FAILED_STATUS: &str = "ng";
 
     pub fn new(old_id: String, new_id: String, ref_name: String) -> Self {
-        let command_type = if ZERO_ID == old_id {
+        let command_type = if is_zero_id(&old_id) {
             CommandType::Create
-        } else if ZERO_ID == new_id {
+        } else if is_zero_id(&new_id) {
             CommandType::Delete
         } else {
             CommandType::Update
@@ -181,46 +285,28 @@ impl RefCommand {
     pub async fn unpack(
         &mut self,
         storage: Arc<dyn ObjectStorage>,
+        repo_path: &Path,
         pack_file: &mut Bytes,
     ) -> Result<i64, anyhow::Error> {
-        let result: Result<i64, GitError> = {
-            let count_hash: bool = true;
-            let curosr_pack = Cursor::new(pack_file);
-            let reader = HashCounter::new(curosr_pack, count_hash);
-            // // Read the header of the pack file
-            // let mut pack = Pack::check_header(&mut reader)?;
-
-            // let mut iterator = EntriesIter::new(&mut reader, pack.number_of_objects() as u32);
-            // iterator.set_storage(Some(storage.clone()));
-            // let mut save_models: Vec<git::ActiveModel> = Vec::new();
-            // let mr_id = generate_id();
-            // let batch_size = 10000;
-            // for i in 0..pack.number_of_objects() {
-            //     let obj = iterator.next_obj().await?;
-            //     if i % 1000 == 0 {
-            //         tracing::info!("{}", i)
-            //     }
-            //     save_models.push(obj.convert_to_mr_model(mr_id));
-            //     if save_models.len() == batch_size {
-            //         storage.save_git_objects(save_models.clone()).await.unwrap();
-            //         save_models.clear();
-            //     }
-            // }
-            // if !save_models.is_empty() {
-            //     storage.save_git_objects(save_models).await.unwrap();
-            // }
-            // drop(iterator);
-            // let _hash = reader.final_hash();
-            // pack.signature = _hash;
-            // //pack.signature = Hash::new_from_bytes(&id[..]);
-
-            // // pack.signature = read_tail_hash(&mut reader);
-            // // assert_eq!(_hash, pack.signature);
-            let p = PackPreload::new(reader);
-            let mr_id = decode_load(p, storage.clone()).await?;
-            storage.save_mr_info(self.new_mr_info(mr_id)).await.unwrap();
-            Ok(mr_id)
-        };
+        let handle = Handle::current();
+        let reader = Cursor::new(pack_file.clone());
+        let decode_storage = storage.clone();
+        let repo_path = repo_path.to_str().unwrap().to_owned();
+        let kind = get_hash_kind();
+        let result = async {
+            let summary = tokio::task::spawn_blocking(move || {
+                sync_with_hash_kind(kind, || {
+                    decode_stream(reader, decode_storage, &repo_path, handle, |_, _| {})
+                })
+            })
+            .await
+            .map_err(|e| GitError::InvalidPackFile(e.to_string()))??;
+            storage
+                .save_mr_info(RefCommand::new_mr_info(summary.mr_id))
+                .await?;
+            Ok::<i64, GitError>(summary.mr_id)
+        }
+        .await;
         match result {
             Ok(mr_id) => {
                 self.status = RefCommand::OK_STATUS.to_owned();
@@ -254,6 +340,19 @@ impl RefCommand {
         self.error_msg = msg;
     }
 
+    pub fn is_ok(&self) -> bool {
+        self.status == RefCommand::OK_STATUS
+    }
+
+    pub fn to_ref_update(&self) -> RefUpdate {
+        RefUpdate {
+            ref_name: self.ref_name.clone(),
+            old_id: self.old_id.clone(),
+            new_id: self.new_id.clone(),
+            tag: None,
+        }
+    }
+
     pub fn convert_to_model(&self, path: &str) -> refs::ActiveModel {
         refs::ActiveModel {
             id: NotSet,
@@ -265,7 +364,7 @@ impl RefCommand {
         }
     }
 
-    pub fn new_mr_info(&self, mr_id: i64) -> mr_info::ActiveModel {
+    pub fn new_mr_info(mr_id: i64) -> mr_info::ActiveModel {
         mr_info::ActiveModel {
             id: NotSet,
             mr_id: Set(mr_id),
@@ -303,6 +402,9 @@ impl PackProtocol {
             storage,
             command_list: Vec::new(),
             service_type: None,
+            version: ProtocolVersion::default(),
+            hooks: PushHooks::default(),
+            pusher: None,
         }
     }
 
@@ -314,9 +416,96 @@ impl PackProtocol {
             storage: Arc::new(MysqlStorage::default()),
             command_list: Vec::new(),
             service_type: None,
+            version: ProtocolVersion::default(),
+            hooks: PushHooks::default(),
+            pusher: None,
         }
     }
+
+    /// Same as [`RefCommand::unpack`], but the pack is decoded while it's being received:
+    /// `first` is the first chunk of the pack, the following chunks arrive through `pack_rx`.
+    /// The progress of the unpacking is sent to `sender` if there is one.
+    pub async fn unpack_stream(
+        &self,
+        first: Bytes,
+        pack_rx: Receiver<Bytes>,
+        sender: Option<SideBandSender>,
+    ) -> Result<i64, anyhow::Error> {
+        let handle = Handle::current();
+        let storage = self.storage.clone();
+        let repo_path = self.path.to_str().unwrap().to_owned();
+        let kind = get_hash_kind();
+        let progress_sender = sender.clone();
+        let summary = tokio::task::spawn_blocking(move || {
+            let mut unpacking = None;
+            let on_progress = |current: usize, total: usize| {
+                let Some(sender) = &progress_sender else {
+                    return;
+                };
+                let progress = unpacking
+                    .get_or_insert_with(|| Progress::new("Unpacking objects", Some(total)));
+                if current == total {
+                    let message = progress.done(current);
+                    let _ = sender.blocking_send(Ok((SideBind::ProgressInfo, message)));
+                } else if let Some(message) = progress.update(current) {
+                    // never wait for the client here, it may still be sending the pack
+                    let _ = sender.try_send(Ok((SideBind::ProgressInfo, message)));
+                }
+            };
+            let reader = ChannelReader::new(first, pack_rx);
+            sync_with_hash_kind(kind, || {
+                decode_stream(reader, storage, &repo_path, handle, on_progress)
+            })
+        })
+        .await??;
+        if let (Some(sender), true) = (&sender, summary.deltas > 0) {
+            // a thin pack is completed with objects of the storage
+            let done = match summary.local_objects {
+                0 => String::from("done"),
+                n => format!("completed with {} local objects", n),
+            };
+            let message = format!("Resolving deltas: 100% ({0}/{0}), {1}.\n", summary.deltas, done);
+            let _ = sender
+                .send(Ok((SideBind::ProgressInfo, message.into())))
+                .await;
+        }
+        self.storage
+            .save_mr_info(RefCommand::new_mr_info(summary.mr_id))
+            .await
+            .map_err(GitError::from)?;
+        Ok(summary.mr_id)
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::{Deepen, ProtocolVersion};
+
+    #[test]
+    fn test_protocol_version_from_git_protocol() {
+        assert_eq!(ProtocolVersion::from_git_protocol("version=2"), ProtocolVersion::V2);
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("object-format=sha1:version=1"),
+            ProtocolVersion::V1
+        );
+        assert_eq!(ProtocolVersion::from_git_protocol("version=3"), ProtocolVersion::V0);
+        assert_eq!(ProtocolVersion::from_git_protocol(""), ProtocolVersion::V0);
+    }
+
+    #[test]
+    fn test_parse_deepen_lines() {
+        let mut deepen = Deepen::default();
+        assert!(deepen
+            .parse_line("shallow 27dd8d4cf39f3868c6eee38b601bc9e9939304f5\n")
+            .unwrap());
+        assert!(deepen.parse_line("deepen 1").unwrap());
+        assert!(deepen.parse_line("deepen-since 1690000000").unwrap());
+        assert!(deepen.parse_line("deepen-not refs/heads/main").unwrap());
+        assert!(deepen.parse_line("deepen-relative").unwrap());
+        assert!(!deepen.parse_line("done").unwrap());
+        assert!(deepen.parse_line("deepen x").is_err());
+        assert_eq!(deepen.depth, Some(1));
+        assert_eq!(deepen.since, Some(1690000000));
+        assert!(deepen.relative && deepen.is_requested());
+    }
+}
@@ -0,0 +1,87 @@
+//!
+//! The object format of the repos, the hash algorithm that names their objects.
+//!
+//! A repo is sha1 unless its first push comes from a sha256 repo. The format is recorded once
+//! the first push is validated and doesn't change afterwards, every request on the repo is
+//! then handled with its [`HashKind`] in scope, see [`crate::hash::with_hash_kind`]. A folder
+//! of a monorepo has the format of the repo it belongs to.
+//!
+
+use crate::errors::GitError;
+use crate::hash::HashKind;
+
+use super::{Capability, PackProtocol};
+
+impl PackProtocol {
+    /// The object format of the repo, sha1 if none is recorded.
+    pub async fn get_object_format(&self) -> Result<HashKind, GitError> {
+        Ok(self.recorded_object_format().await?.unwrap_or_default())
+    }
+
+    async fn recorded_object_format(&self) -> Result<Option<HashKind>, GitError> {
+        let format = self
+            .storage
+            .get_object_format(self.path.to_str().unwrap())
+            .await?;
+        format.map(|format| format.parse()).transpose()
+    }
+
+    /// The object formats a push to the repo can use: both of them for an empty repo without
+    /// a recorded format, only the format of the repo otherwise.
+    pub async fn get_push_object_formats(&self) -> Result<Vec<HashKind>, GitError> {
+        if let Some(kind) = self.recorded_object_format().await? {
+            return Ok(vec![kind]);
+        }
+        let refs = self
+            .storage
+            .get_ref_object_id(self.path.to_str().unwrap())
+            .await?;
+        if refs.is_empty() {
+            Ok(vec![HashKind::Sha1, HashKind::Sha256])
+        } else {
+            Ok(vec![HashKind::Sha1])
+        }
+    }
+
+    /// Checks the `object-format` capability of a push, sha1 if the client didn't send it,
+    /// see [`PackProtocol::accept_object_format`].
+    pub async fn check_push_object_format(&self) -> Result<HashKind, GitError> {
+        let kind = self
+            .capabilities
+            .iter()
+            .find_map(|cap| match cap {
+                Capability::ObjectFormat(kind) => Some(*kind),
+                _ => None,
+            })
+            .unwrap_or_default();
+        self.accept_object_format(kind).await?;
+        Ok(kind)
+    }
+
+    /// Checks that objects of the `kind` format can be added to the repo, the format is
+    /// recorded later by [`PackProtocol::record_object_format`].
+    pub async fn accept_object_format(&self, kind: HashKind) -> Result<(), GitError> {
+        if !self.get_push_object_formats().await?.contains(&kind) {
+            return Err(GitError::UnsupportedObjectFormat(kind.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Records `kind` as the object format of the repo if it has none yet, once a push is
+    /// validated. Two first pushes may race to record it, the one that loses fails unless
+    /// both have the same format.
+    pub(crate) async fn record_object_format(&self, kind: HashKind) -> Result<(), GitError> {
+        let recorded = match self.recorded_object_format().await? {
+            Some(recorded) => recorded,
+            None => self
+                .storage
+                .save_object_format(self.path.to_str().unwrap(), kind.name())
+                .await?
+                .parse()?,
+        };
+        if recorded != kind {
+            return Err(GitError::UnsupportedObjectFormat(kind.to_string()));
+        }
+        Ok(())
+    }
+}
@@ -3,15 +3,25 @@
 //!
 //!
 
-use crate::protocol::ZERO_ID;
-use crate::structure::conversion;
+use crate::errors::GitError;
+use crate::hash::{get_hash_kind, is_zero_id, with_hash_kind, HashKind};
+use crate::structure::conversion::{self, ShallowInfo};
+use crate::structure::filter::ObjectFilter;
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::collections::HashSet;
+use database::driver::{RefUpdate, RefUpdateError};
+use entity::commit;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc::Receiver;
 
-use super::{Capability, PackProtocol, Protocol, RefCommand, ServiceType, SideBind};
+use super::hooks::PushInfo;
+use super::sideband::{spawn_side_band_stream, SideBandMessage, SideBandSender, SideBandStream};
+use super::{
+    Capability, CommandType, Deepen, PackProtocol, Protocol, ProtocolVersion, RefCommand,
+    ServiceType, SideBind,
+};
 
-const LF: char = '\n';
+pub(crate) const LF: char = '\n';
 
 pub const SP: char = ' ';
 
@@ -19,17 +29,92 @@ const NUL: char = '\0';
 
 pub const PKT_LINE_END_MARKER: &[u8; 4] = b"0000";
 
+// Delimiter packet, separates the sections of a protocol v2 request or response.
+pub const PKT_LINE_DELIM_MARKER: &[u8; 4] = b"0001";
+
+// The largest payload that fits in a side-band-64k packet: 65520 bytes minus the length and band bytes.
+pub(crate) const SIDE_BAND_64K_MAX_DATA: usize = 65515;
+
+// The largest payload of a packet with the older side-band capability, limited to 1000 bytes.
+pub(crate) const SIDE_BAND_MAX_DATA: usize = 995;
+
+// How many chunks of a pack being pushed can wait for the decoder, it bounds the memory
+// used by a push together with the decoder's cache and batch size.
+pub(crate) const PACK_CHANNEL_SIZE: usize = 16;
+
 // The atomic, report-status, report-status-v2, delete-refs, quiet,
 // and push-cert capabilities are sent and recognized by the receive-pack (push to server) process.
 const RECEIVE_CAP_LIST: &str = "report-status report-status-v2 delete-refs quiet atomic ";
 
 // The ofs-delta and side-band-64k capabilities are sent and recognized by both upload-pack and receive-pack protocols.
 // The agent and session-id capabilities may optionally be sent in both protocols.
-const CAP_LIST: &str = "side-band-64k ofs-delta object-format=sha1";
+const CAP_LIST: &str = "side-band-64k ofs-delta";
 
 // All other capabilities are only recognized by the upload-pack (fetch from server) process.
 const UPLOAD_CAP_LIST: &str =
-    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done ";
+    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done no-progress thin-pack filter allow-reachable-sha1-in-want include-tag ";
+
+/// The state of an upload-pack negotiation: what the client wants and the common commits found
+/// so far. Over HTTP it only lasts for one request, a stateful transport keeps it across the
+/// rounds of haves so that each round only handles its new `have` lines.
+#[derive(Clone, Default)]
+pub(crate) struct UploadNegotiation {
+    kind: HashKind,
+    want: HashSet<String>,
+    // the haves of the round
+    have: Vec<String>,
+    deepen: Deepen,
+    filter: Option<ObjectFilter>,
+    done: bool,
+    read_first_line: bool,
+    shallow_info: Option<ShallowInfo>,
+    // the commits of the repo that aren't common yet, with the children of each commit
+    commits: HashSet<String>,
+    children: HashMap<String, Vec<String>>,
+    // the wanted commits, with the annotated tags peeled
+    want_commits: HashSet<String>,
+    // the common commits in the order the client sent them, and the commits they reach
+    common: Vec<String>,
+    reached: HashSet<String>,
+}
+
+impl UploadNegotiation {
+    // keeps what the rounds of haves need from the commit graph of the repo
+    fn set_graph(&mut self, graph: HashMap<String, commit::Model>, want: HashSet<String>) {
+        for (id, model) in graph {
+            for pid in model.pid {
+                self.children.entry(pid).or_default().push(id.clone());
+            }
+            self.commits.insert(id);
+        }
+        self.want_commits = want;
+    }
+
+    // moves the haves of the round that are commits of the repo to the common commits, each
+    // commit the client has is only visited once. Returns the new common commits.
+    fn add_haves(&mut self) -> Vec<String> {
+        let mut new_common = vec![];
+        for id in std::mem::take(&mut self.have) {
+            if !self.commits.remove(&id) {
+                continue;
+            }
+            let mut queue = vec![id.clone()];
+            while let Some(id) = queue.pop() {
+                if self.reached.insert(id.clone()) {
+                    queue.extend(self.children.get(&id).into_iter().flatten().cloned());
+                }
+            }
+            self.common.push(id.clone());
+            new_common.push(id);
+        }
+        new_common
+    }
+
+    // whether each want reaches a common commit, "ready" in the protocol
+    fn is_ready(&self) -> bool {
+        !self.common.is_empty() && self.want_commits.iter().all(|id| self.reached.contains(id))
+    }
+}
 
 impl PackProtocol {
     /// # Retrieves the information about Git references (refs) for the specified service type.
@@ -56,46 +141,145 @@ impl PackProtocol {
     /// Tracing information is logged regarding the response packet line stream.
     ///
     /// Finally, the constructed packet line stream is returned.
-    pub async fn git_info_refs(&mut self, service_type: ServiceType) -> BytesMut {
+    pub async fn git_info_refs(&mut self, service_type: ServiceType) -> Result<BytesMut, GitError> {
+        let kind = self.get_object_format().await?;
+        with_hash_kind(kind, self.info_refs(service_type)).await
+    }
+
+    async fn info_refs(&mut self, service_type: ServiceType) -> Result<BytesMut, GitError> {
+        // protocol v2 is only defined for upload-pack, receive-pack keeps the v0 advertisement
+        if self.version == ProtocolVersion::V2 && service_type == ServiceType::UploadPack {
+            return Ok(self.git_capability_advertisement());
+        }
         // The stream MUST include capability declarations behind a NUL on the first ref.
         let object_id = self.get_head_object_id(&self.path).await;
-        let name = if object_id == ZERO_ID {
+        let name = if is_zero_id(&object_id) {
             "capabilities^{}"
         } else {
             "HEAD"
         };
-        let cap_list = match service_type {
+        let mut cap_list = match service_type {
             ServiceType::UploadPack => format!("{}{}", UPLOAD_CAP_LIST, CAP_LIST),
             ServiceType::ReceivePack => format!("{}{}", RECEIVE_CAP_LIST, CAP_LIST),
-            // _ => CAP_LIST.to_owned(),
+            ServiceType::UploadArchive => CAP_LIST.to_owned(),
+        };
+        // an empty repo takes the object format of its first push
+        let formats = match service_type {
+            ServiceType::UploadPack | ServiceType::UploadArchive => vec![get_hash_kind()],
+            ServiceType::ReceivePack => self.get_push_object_formats().await?,
         };
+        for kind in formats {
+            cap_list.push_str(&format!("{}object-format={}", SP, kind));
+        }
+        // tells the client which branch to check out after a clone
+        if let Some(head) = self.get_head_ref(&self.path).await {
+            cap_list.push_str(&format!("{}symref=HEAD:{}", SP, head));
+        }
         let pkt_line = format!("{}{}{}{}{}{}", object_id, SP, name, NUL, cap_list, LF);
-        let mut ref_list = vec![pkt_line];
+        let mut ref_list = vec![];
+        if self.version == ProtocolVersion::V1 {
+            ref_list.push(format!("version 1{}", LF));
+        }
+        ref_list.push(pkt_line);
 
         let git_refs = self
             .storage
             .get_ref_object_id(self.path.to_str().unwrap())
             .await
             .unwrap();
+        // an annotated tag is followed by the object it points to
+        let peeled_refs = self.get_peeled_refs(&self.path).await;
         for git_ref in git_refs {
             let pkt_line = format!("{}{}{}{}", git_ref.ref_git_id, SP, git_ref.ref_name, LF);
             ref_list.push(pkt_line);
+            if let Some(peeled) = peeled_refs.get(&git_ref.ref_name) {
+                ref_list.push(format!("{}{}{}^{{}}{}", peeled, SP, git_ref.ref_name, LF));
+            }
         }
         let pkt_line_stream = self.build_smart_reply(&ref_list, service_type.to_string());
         tracing::info!("git_info_refs response: {:?}", pkt_line_stream);
-        pkt_line_stream
+        Ok(pkt_line_stream)
     }
 
+    /// # Handles one round of the have/want negotiation of upload-pack.
+    ///
+    /// The request holds the `want` lines, followed by the `have` lines of the client, and
+    /// ends either with a flush-pkt when the client wants to keep negotiating or with `done`.
+    ///
+    /// Every `have` that is known by the server is acknowledged according to the capabilities
+    /// the client asked for:
+    /// - `multi_ack_detailed`: `ACK obj-id common` for each common commit and `ACK obj-id ready`
+    ///   once the common commits are enough to build the pack.
+    /// - `multi_ack`: `ACK obj-id continue` for each common commit.
+    /// - otherwise only the first common commit is acknowledged with `ACK obj-id`.
+    ///
+    /// The round is closed with `NAK` or with a final `ACK obj-id` for the last common commit.
+    ///
+    /// When the client asks for a shallow history with `deepen`, `deepen-since` or `deepen-not`,
+    /// or already is shallow, a shallow-update section with the new `shallow` and `unshallow`
+    /// commits is sent before the acknowledgments and the pack only contains the truncated history.
+    ///
+    /// A `filter` line asks for a partial clone, the objects left out by the filter are fetched
+    /// later by the client with `want` lines of their own.
+    ///
+    /// Returns the stream of the pack to be sent after the acknowledgments, it's `None` when
+    /// the client needs to send more `have` lines before the pack can be generated.
     pub async fn git_upload_pack(
         &mut self,
         upload_request: &mut Bytes,
-    ) -> Result<(Vec<u8>, BytesMut)> {
-        let mut want: HashSet<String> = HashSet::new();
-        let mut have: HashSet<String> = HashSet::new();
+    ) -> Result<(Option<SideBandStream>, BytesMut)> {
+        let mut negotiation = UploadNegotiation::default();
+        let mut buf = self
+            .begin_upload_negotiation(upload_request, &mut negotiation)
+            .await?;
+        let (pack_stream, round) = self
+            .continue_upload_negotiation(&mut Bytes::new(), &mut negotiation)
+            .await?;
+        buf.extend_from_slice(&round);
+        Ok((pack_stream, buf))
+    }
+
+    /// Starts a negotiation from the want list of the client, along with the `have` lines when
+    /// the whole request is sent at once. Returns the shallow-update section, if any.
+    ///
+    /// A stateful transport answers each round of haves with
+    /// [`PackProtocol::continue_upload_negotiation`] afterwards, the `negotiation` keeps the
+    /// wants and the common commits found so far.
+    pub(crate) async fn begin_upload_negotiation(
+        &mut self,
+        upload_request: &mut Bytes,
+        negotiation: &mut UploadNegotiation,
+    ) -> Result<BytesMut> {
+        negotiation.kind = self.get_object_format().await?;
+        with_hash_kind(negotiation.kind, async {
+            self.read_upload_request(upload_request, negotiation)?;
+            self.begin_negotiation(negotiation).await
+        })
+        .await
+    }
 
-        let mut read_first_line = false;
+    /// Answers the `have` lines of the client that came after the ones already answered, see
+    /// [`PackProtocol::git_upload_pack`]. Returns the stream of the pack once the negotiation
+    /// is over.
+    pub(crate) async fn continue_upload_negotiation(
+        &mut self,
+        upload_request: &mut Bytes,
+        negotiation: &mut UploadNegotiation,
+    ) -> Result<(Option<SideBandStream>, BytesMut)> {
+        with_hash_kind(negotiation.kind, async {
+            self.read_upload_request(upload_request, negotiation)?;
+            Ok::<_, anyhow::Error>(self.negotiation_round(negotiation))
+        })
+        .await
+    }
+
+    // adds the lines of a request to the negotiation, the haves are kept for the next round
+    fn read_upload_request(
+        &mut self,
+        upload_request: &mut Bytes,
+        negotiation: &mut UploadNegotiation,
+    ) -> Result<()> {
         loop {
-            tracing::info!("loop start");
             let (bytes_take, pkt_line) = read_pkt_line(upload_request);
             // read 0000 to continue and read empty str to break
             if bytes_take == 0 {
@@ -107,12 +291,28 @@ impl PackProtocol {
             }
             tracing::debug!("read line: {:?}", pkt_line);
             let dst = pkt_line.to_vec();
+            let line = String::from_utf8_lossy(&dst);
+            if negotiation.deepen.parse_line(&line)? {
+                continue;
+            }
+            if let Some(spec) = line.strip_prefix("filter ") {
+                negotiation.filter = Some(spec.parse()?);
+                continue;
+            }
             let commands = &dst[0..4];
+            // the id of either object format, followed by the capabilities on the first line
+            let args = line.get(5..).unwrap_or_default();
+            let (id, caps) = args.split_once(' ').unwrap_or((args.trim_end(), ""));
 
             match commands {
-                b"want" => want.insert(String::from_utf8(dst[5..45].to_vec()).unwrap()),
-                b"have" => have.insert(String::from_utf8(dst[5..45].to_vec()).unwrap()),
-                b"done" => break,
+                b"want" => {
+                    negotiation.want.insert(id.to_owned());
+                }
+                b"have" => negotiation.have.push(id.to_owned()),
+                b"done" => {
+                    negotiation.done = true;
+                    break;
+                }
                 other => {
                     tracing::error!(
                         "unsupported command: {:?}",
@@ -121,62 +321,97 @@ impl PackProtocol {
                     continue;
                 }
             };
-            if !read_first_line {
-                self.parse_capabilities(&String::from_utf8(dst[46..].to_vec()).unwrap());
-                read_first_line = true;
+            if !negotiation.read_first_line {
+                self.parse_capabilities(caps);
+                negotiation.read_first_line = true;
             }
         }
+        Ok(())
+    }
 
+    // checks the wants and sends the shallow-update section before the acknowledgments
+    async fn begin_negotiation(&self, negotiation: &mut UploadNegotiation) -> Result<BytesMut> {
         tracing::info!(
-            "want commands: {:?}\n have commans: {:?}\n caps:{:?}",
-            want,
-            have,
+            "want commands: {:?}\n caps:{:?}",
+            negotiation.want,
             self.capabilities
         );
+        let graph = self.get_commit_graph(&self.path).await?;
+        let want = &negotiation.want;
+        if let Some(id) = self.find_unreachable_want(&self.path, &graph, want).await {
+            anyhow::bail!("upload-pack: not our ref {}", id);
+        }
 
-        let mut send_pack_data = vec![];
         let mut buf = BytesMut::new();
+        let deepen = &negotiation.deepen;
+        if deepen.is_requested() || !deepen.shallow.is_empty() {
+            let info = self
+                .get_shallow_info(&self.path, &graph, want, deepen)
+                .await?;
+            for id in &info.shallow {
+                add_pkt_line_string(&mut buf, format!("shallow {}\n", id));
+            }
+            for id in &info.unshallow {
+                add_pkt_line_string(&mut buf, format!("unshallow {}\n", id));
+            }
+            buf.put(&PKT_LINE_END_MARKER[..]);
+            negotiation.shallow_info = Some(info);
+        }
+        let want_commits = self.peel_wants(&self.path, want).await;
+        negotiation.set_graph(graph, want_commits);
+        Ok(buf)
+    }
 
-        if have.is_empty() {
-            send_pack_data = self.get_full_pack_data(&self.path).await.unwrap();
-            add_pkt_line_string(&mut buf, String::from("NAK\n"));
-        } else {
-            if self.capabilities.contains(&Capability::MultiAckDetailed) {
-                // multi_ack_detailed mode, the server will differentiate the ACKs where it is signaling that
-                // it is ready to send data with ACK obj-id ready lines,
-                // and signals the identified common commits with ACK obj-id common lines
-                for hash in &have {
-                    if self.storage.get_commit_by_hash(hash).await.is_ok() {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
-                    }
-                    // no need to send NAK in this mode if missing commit?
-                }
+    // acknowledges the new haves, and closes the round or sends the pack
+    fn negotiation_round(
+        &self,
+        negotiation: &mut UploadNegotiation,
+    ) -> (Option<SideBandStream>, BytesMut) {
+        let multi_ack_detailed = self.capabilities.contains(&Capability::MultiAckDetailed);
+        let multi_ack = multi_ack_detailed || self.capabilities.contains(&Capability::MultiAck);
+        let no_done = self.capabilities.contains(&Capability::NoDone);
 
-                send_pack_data = self
-                    .get_incremental_pack_data(&self.path, &want, &have)
-                    .await
-                    .unwrap();
-
-                for hash in &want {
-                    if self.storage.get_commit_by_hash(hash).await.is_ok() {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
-                    }
-                    if self.capabilities.contains(&Capability::NoDone) {
-                        // If multi_ack_detailed and no-done are both present, then the sender is free to immediately send a pack
-                        // following its first "ACK obj-id ready" message.
-                        add_pkt_line_string(&mut buf, format!("ACK {} ready\n", hash));
-                    }
-                }
-            } else {
-                tracing::error!("capability unsupported");
+        let acked = !negotiation.common.is_empty();
+        let new_common = negotiation.add_haves();
+        let ready = negotiation.is_ready();
+        let mut buf = BytesMut::new();
+        for (i, hash) in new_common.iter().enumerate() {
+            if multi_ack_detailed {
+                let status = if ready { "ready" } else { "common" };
+                add_pkt_line_string(&mut buf, format!("ACK {} {}\n", hash, status));
+            } else if multi_ack {
+                add_pkt_line_string(&mut buf, format!("ACK {} continue\n", hash));
+            } else if i == 0 && !acked {
+                add_pkt_line_string(&mut buf, format!("ACK {}\n", hash));
+            }
+        }
+
+        if !negotiation.done {
+            // the client flushed its have list, it sends more haves in the next request
+            // unless it's allowed to skip `done` and the server is ready to send the pack
+            if negotiation.common.is_empty() || multi_ack {
+                add_pkt_line_string(&mut buf, String::from("NAK\n"));
             }
-            // TODO: hard-code here
-            add_pkt_line_string(
-                &mut buf,
-                format!("ACK {} \n", "27dd8d4cf39f3868c6eee38b601bc9e9939304f5"),
-            );
+            if !(no_done && multi_ack_detailed && ready) {
+                return (None, buf);
+            }
+        }
+
+        match negotiation.common.last() {
+            Some(last) if multi_ack => add_pkt_line_string(&mut buf, format!("ACK {}\n", last)),
+            Some(_) => {}
+            None => add_pkt_line_string(&mut buf, String::from("NAK\n")),
         }
-        Ok((send_pack_data, buf))
+
+        let have: HashSet<String> = negotiation.common.iter().cloned().collect();
+        let pack_stream = self.get_pack_stream(
+            &self.path,
+            &negotiation.want,
+            &have,
+            negotiation.shallow_info.as_ref(),
+            negotiation.filter.as_ref(),
+        );
+        (Some(pack_stream), buf)
     }
 
     pub async fn git_receive_pack(&mut self, mut body_bytes: Bytes) -> Result<Bytes> {
@@ -185,35 +420,13 @@ impl PackProtocol {
         }
 
         if body_bytes.starts_with(&[b'P', b'A', b'C', b'K']) {
-            let mut command_list = self.command_list.clone();
-            let command = command_list.last_mut().unwrap();
-            let mr_id = command
-                .unpack(self.storage.clone(), &mut body_bytes)
+            let mut command = self.command_list.last().unwrap().clone();
+            let unpacked = command
+                .unpack(self.storage.clone(), &self.path, &mut body_bytes)
                 .await
-                .unwrap();
-            let path = &self.path;
-            let parse_obj_result =
-                conversion::save_node_from_mr(self.storage.clone(), mr_id, path).await;
-            if parse_obj_result.is_ok() {
-                command.save_to_db(self.storage.clone(), path).await;
-                self.handle_directory().await.unwrap();
-            } else {
-                tracing::error!("{}", parse_obj_result.err().unwrap());
-                command.failed(String::from("db operation failed"));
-            }
-            // After receiving the pack data from the sender, the receiver sends a report
-            let mut report_status = BytesMut::new();
-            // TODO: replace this hard code "unpack ok\n"
-            add_pkt_line_string(&mut report_status, "unpack ok\n".to_owned());
-            for c in command_list {
-                add_pkt_line_string(&mut report_status, c.get_status());
-            }
-            report_status.put(&PKT_LINE_END_MARKER[..]);
-
-            let length = report_status.len();
-            let mut buf = self.build_side_band_format(report_status, length);
-            buf.put(&PKT_LINE_END_MARKER[..]);
-            Ok(buf.into())
+                .map(Some);
+            let report_status = self.complete_push(unpacked, None).await;
+            Ok(self.build_report_status(&report_status))
         } else {
             let (bytes_take, mut pkt_line) = read_pkt_line(&mut body_bytes);
             if bytes_take == 0 && pkt_line.is_empty() {
@@ -227,6 +440,422 @@ impl PackProtocol {
         }
     }
 
+    /// # Parses the ref update commands at the start of a streamed receive-pack request.
+    ///
+    /// The commands are pkt-lines ended by a flush-pkt, the capabilities are sent behind a NUL
+    /// on the first command. Returns `false` if `buf` doesn't hold the whole command list yet,
+    /// nothing is consumed in that case. Otherwise the command list is consumed and what's
+    /// left in `buf` is the beginning of the pack.
+    pub fn parse_receive_commands(&mut self, buf: &mut BytesMut) -> Result<bool> {
+        let mut end = 0;
+        loop {
+            if buf.len() < end + 4 {
+                return Ok(false);
+            }
+            let pkt_length = usize::from_str_radix(std::str::from_utf8(&buf[end..end + 4])?, 16)?;
+            if pkt_length == 0 {
+                end += 4;
+                break;
+            }
+            if pkt_length < 4 {
+                anyhow::bail!("invalid pkt-line length: {}", pkt_length);
+            }
+            end += pkt_length;
+        }
+
+        let mut commands = buf.split_to(end).freeze();
+        loop {
+            let (bytes_take, mut pkt_line) = read_pkt_line(&mut commands);
+            if bytes_take == 0 {
+                break;
+            }
+            let command = self.parse_ref_update(&mut pkt_line);
+            if self.command_list.is_empty() {
+                self.parse_capabilities(&String::from_utf8(pkt_line.to_vec())?);
+            }
+            tracing::debug!("receive command: {:?}", command);
+            self.command_list.push(command);
+        }
+        tracing::debug!("caps: {:?}", self.capabilities);
+        Ok(true)
+    }
+
+    /// # Receives the pack of a push while it's being uploaded.
+    ///
+    /// Must be called after [`PackProtocol::parse_receive_commands`], the chunks of the pack
+    /// arrive through `pack_rx` and are decoded and stored as they come, so the push is never
+    /// held in memory as a whole. A push that only deletes refs carries no pack.
+    ///
+    /// Returns the side-band stream of the response: the progress of the unpacking goes to
+    /// band 2, an unpack failure to band 3 and the report-status to band 1 once the pack is
+    /// stored.
+    pub fn git_receive_pack_stream(&self, pack_rx: Receiver<Bytes>) -> SideBandStream {
+        let protocol = self.clone();
+        spawn_side_band_stream(move |sender| async move {
+            let report_status = match protocol.check_push_object_format().await {
+                Ok(kind) => with_hash_kind(kind, protocol.receive_pack(pack_rx, &sender)).await,
+                Err(e) => protocol.complete_push(Err(e.into()), Some(&sender)).await,
+            };
+            let _ = sender
+                .send(Ok((SideBind::PackfileData, report_status)))
+                .await;
+            Ok(())
+        })
+    }
+
+    async fn receive_pack(&self, mut pack_rx: Receiver<Bytes>, sender: &SideBandSender) -> Bytes {
+        let delete_only = self
+            .command_list
+            .iter()
+            .all(|c| c.command_type == CommandType::Delete);
+        let unpacked = if delete_only {
+            Ok(None)
+        } else {
+            let mut first = None;
+            while let Some(chunk) = pack_rx.recv().await {
+                if !chunk.is_empty() {
+                    first = Some(chunk);
+                    break;
+                }
+            }
+            match first {
+                Some(first) => self
+                    .unpack_stream(first, pack_rx, Some(sender.clone()))
+                    .await
+                    .map(Some),
+                None => Err(anyhow::anyhow!("missing pack data")),
+            }
+        };
+        if let Err(e) = &unpacked {
+            let message = format!("unpack failed: {}\n", e);
+            let _ = sender.send(Ok((SideBind::Error, message.into()))).await;
+        }
+        self.complete_push(unpacked, Some(sender)).await
+    }
+
+    /// # Completes a push once its pack is unpacked.
+    ///
+    /// `unpacked` is the mr id of the stored objects, `None` when the push carries no pack.
+    /// The objects stay in quarantine while the ref updates are checked and the push hooks
+    /// run: the nodes of the new objects are only built once a ref update is accepted, the
+    /// objects are dropped otherwise. The ref updates are then applied, with the `atomic`
+    /// capability either all of them or none. The output of the push hooks is sent to `sender`
+    /// if there is one. Returns the report-status.
+    async fn complete_push(
+        &self,
+        unpacked: Result<Option<i64>>,
+        sender: Option<&SideBandSender>,
+    ) -> Bytes {
+        let (unpack_status, command_list, forced) = self.apply_push(unpacked, sender).await;
+        self.report_status(&unpack_status, &command_list, &forced)
+    }
+
+    /// Same as [`PackProtocol::complete_push`], but returns the unpack status, the ref update
+    /// commands with their status and the refs that were force updated instead of the
+    /// report-status.
+    pub(crate) async fn apply_push(
+        &self,
+        unpacked: Result<Option<i64>>,
+        sender: Option<&SideBandSender>,
+    ) -> (Result<()>, Vec<RefCommand>, HashSet<String>) {
+        let mut command_list = self.command_list.clone();
+        let (unpack_status, mr_id) = match unpacked {
+            Ok(mr_id) => (Ok(()), mr_id),
+            Err(e) => (Err(e), None),
+        };
+        let forced = match &unpack_status {
+            Ok(()) => self.update_refs(&mut command_list, mr_id, sender).await,
+            Err(e) => {
+                tracing::error!("unpack failed: {}", e);
+                for command in command_list.iter_mut() {
+                    command.failed(String::from("unpacker error"));
+                }
+                HashSet::new()
+            }
+        };
+        (unpack_status, command_list, forced)
+    }
+
+    // checks the ref update commands and applies the valid ones, for an atomic push one invalid
+    // command fails them all. The commits of the quarantined `mr_id` are part of the history
+    // the commands are checked against. Returns the refs that were force updated.
+    async fn update_refs(
+        &self,
+        command_list: &mut [RefCommand],
+        mr_id: Option<i64>,
+        sender: Option<&SideBandSender>,
+    ) -> HashSet<String> {
+        let mut forced = HashSet::new();
+        let rules = self.get_protected_refs().await;
+        let graph = match self.get_push_commit_graph(mr_id).await {
+            Ok(graph) => graph,
+            Err(e) => {
+                tracing::error!("{}: {}", self.path.display(), e);
+                for command in command_list.iter_mut() {
+                    command.failed(String::from("failed to read the commits"));
+                }
+                if let Some(mr_id) = mr_id {
+                    self.release_quarantine(mr_id, command_list).await;
+                }
+                return HashSet::new();
+            }
+        };
+        for command in command_list.iter_mut() {
+            let checked = match self.check_ref_command(&graph, command).await {
+                Ok(force) => self
+                    .check_protection(&graph, &rules, command, force)
+                    .await
+                    .map(|_| force),
+                Err(reason) => Err(reason),
+            };
+            match checked {
+                Ok(true) => {
+                    forced.insert(command.ref_name.clone());
+                }
+                Ok(false) => {}
+                Err(reason) => command.failed(reason),
+            }
+        }
+        let push = self.run_receive_hooks(&graph, command_list, sender).await;
+        // the repo takes the object format of the push once something is going to be applied
+        let atomic = self.capabilities.contains(&Capability::Atomic);
+        let applied = command_list
+            .iter()
+            .any(|c| c.is_ok() && c.command_type != CommandType::Delete);
+        if applied && (!atomic || command_list.iter().all(|c| c.is_ok())) {
+            if let Err(e) = self.record_object_format(get_hash_kind()).await {
+                tracing::error!("{}: {}", self.path.display(), e);
+                for command in command_list.iter_mut().filter(|c| c.is_ok()) {
+                    command.failed(String::from("object format mismatch"));
+                }
+            }
+        }
+
+        // the tags are saved with their refs, a tag that can't be read fails its command
+        let mut updates = HashMap::new();
+        for command in command_list.iter_mut().filter(|c| c.is_ok()) {
+            match self.new_ref_update(command).await {
+                Ok(update) => {
+                    updates.insert(command.ref_name.clone(), update);
+                }
+                Err(e) => {
+                    tracing::error!("{}: {}", command.ref_name, e);
+                    command.failed(String::from("invalid tag"));
+                }
+            }
+        }
+
+        if atomic && command_list.iter().any(|c| !c.is_ok()) {
+            for command in command_list.iter_mut().filter(|c| c.is_ok()) {
+                command.failed(String::from("atomic push failure"));
+            }
+        }
+        if let Some(mr_id) = mr_id {
+            self.release_quarantine(mr_id, command_list).await;
+        }
+
+        let repo_path = self.path.to_str().unwrap();
+        if atomic {
+            if command_list.iter().any(|c| !c.is_ok()) {
+                return HashSet::new();
+            }
+            let updates: Vec<RefUpdate> = command_list
+                .iter()
+                .filter_map(|c| updates.remove(&c.ref_name))
+                .collect();
+            if let Err(e) = self.storage.apply_ref_updates(repo_path, &updates).await {
+                tracing::error!("atomic push failed: {}", e);
+                for command in command_list.iter_mut() {
+                    command.failed(String::from("atomic transaction failed"));
+                }
+                return HashSet::new();
+            }
+        } else {
+            for command in command_list.iter_mut().filter(|c| c.is_ok()) {
+                let Some(update) = updates.remove(&command.ref_name) else {
+                    continue;
+                };
+                if let Err(e) = self.storage.apply_ref_updates(repo_path, &[update]).await {
+                    tracing::error!("update {} failed: {}", command.ref_name, e);
+                    command.failed(match e {
+                        RefUpdateError::Stale(_) => String::from("failed to lock"),
+                        RefUpdateError::Db(_) => String::from("failed to update ref"),
+                    });
+                }
+            }
+        }
+        if command_list.iter().any(|c| c.is_ok()) {
+            self.handle_directory().await.unwrap();
+        }
+        if let Some(mut push) = push {
+            push.changes.retain(|change| {
+                command_list
+                    .iter()
+                    .any(|c| c.is_ok() && c.ref_name == change.command.ref_name)
+            });
+            if !push.changes.is_empty() {
+                let mut out = vec![];
+                self.hooks.post_receive(&push, &mut out).await;
+                send_hook_output(sender, out).await;
+            }
+        }
+        forced.retain(|ref_name| {
+            command_list
+                .iter()
+                .any(|c| c.is_ok() && c.ref_name == *ref_name)
+        });
+        forced
+    }
+
+    // saves the nodes and commits of the quarantined objects once a ref update is accepted, a
+    // failure fails the accepted commands. The objects are dropped if nothing is accepted.
+    async fn release_quarantine(&self, mr_id: i64, command_list: &mut [RefCommand]) {
+        if !command_list.iter().any(|c| c.is_ok()) {
+            if let Err(e) = self.storage.delete_mr_objects(mr_id).await {
+                tracing::error!("failed to drop the objects of mr {}: {}", mr_id, e);
+            }
+            return;
+        }
+        if let Err(e) = conversion::save_node_from_mr(self.storage.clone(), mr_id, &self.path).await
+        {
+            tracing::error!("failed to store the objects: {}", e);
+            for command in command_list.iter_mut().filter(|c| c.is_ok()) {
+                command.failed(String::from("failed to store the objects"));
+            }
+        }
+    }
+
+    // runs the pre-receive and update hooks on the valid commands, a rejected pre-receive fails
+    // them all. Returns what the hooks were told about the push, for the post-receive hooks.
+    async fn run_receive_hooks(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        command_list: &mut [RefCommand],
+        sender: Option<&SideBandSender>,
+    ) -> Option<PushInfo> {
+        if self.hooks.is_empty() || !command_list.iter().any(|c| c.is_ok()) {
+            return None;
+        }
+        let valid: Vec<RefCommand> = command_list.iter().filter(|c| c.is_ok()).cloned().collect();
+        let push = PushInfo {
+            repo_path: self.path.clone(),
+            changes: self.get_ref_changes(graph, &valid).await,
+            storage: self.storage.clone(),
+        };
+        let mut out = vec![];
+        if let Err(reason) = self.hooks.pre_receive(&push, &mut out).await {
+            out.push(format!("error: {}", reason));
+            send_hook_output(sender, out).await;
+            for command in command_list.iter_mut().filter(|c| c.is_ok()) {
+                command.failed(String::from("pre-receive hook declined"));
+            }
+            return None;
+        }
+        for change in &push.changes {
+            if let Err(reason) = self.hooks.update(&push, change, &mut out).await {
+                out.push(format!("error: {}", reason));
+                if let Some(command) = command_list
+                    .iter_mut()
+                    .find(|c| c.ref_name == change.command.ref_name)
+                {
+                    command.failed(String::from("hook declined"));
+                }
+            }
+        }
+        send_hook_output(sender, out).await;
+        Some(push)
+    }
+
+    // returns whether the command is a force update, or the reason to reject it
+    async fn check_ref_command(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        command: &RefCommand,
+    ) -> Result<bool, String> {
+        if !command.ref_name.starts_with("refs/") {
+            return Err(String::from("funny refname"));
+        }
+        if command.command_type == CommandType::Delete {
+            return Ok(false);
+        }
+        let exists = self
+            .storage
+            .get_commit_by_hash(&command.new_id)
+            .await
+            .unwrap()
+            .is_some()
+            || self
+                .storage
+                .get_obj_data_by_id(&command.new_id)
+                .await
+                .unwrap()
+                .is_some();
+        if !exists {
+            return Err(String::from("missing necessary objects"));
+        }
+        Ok(command.command_type == CommandType::Update
+            && !self.is_fast_forward(graph, &command.old_id, &command.new_id))
+    }
+
+    // After receiving the pack data from the sender, the receiver sends a report, with
+    // report-status-v2 the updated refs are followed by option lines
+    fn report_status(
+        &self,
+        unpack_status: &Result<()>,
+        command_list: &[RefCommand],
+        forced: &HashSet<String>,
+    ) -> Bytes {
+        let mut report_status = BytesMut::new();
+        let unpack = match unpack_status {
+            Ok(()) => String::from("ok"),
+            // the status must fit on one line
+            Err(e) => e.to_string().replace('\n', " "),
+        };
+        add_pkt_line_string(&mut report_status, format!("unpack {}{}", unpack, LF));
+        let v2 = self.capabilities.contains(&Capability::ReportStatusv2);
+        for c in command_list {
+            add_pkt_line_string(&mut report_status, format!("{}{}", c.get_status(), LF));
+            if v2 && c.is_ok() {
+                let mut options = vec![
+                    format!("option old-oid {}{}", c.old_id, LF),
+                    format!("option new-oid {}{}", c.new_id, LF),
+                ];
+                if forced.contains(&c.ref_name) {
+                    options.push(format!("option forced-update{}", LF));
+                }
+                for option in options {
+                    add_pkt_line_string(&mut report_status, option);
+                }
+            }
+        }
+        report_status.put(&PKT_LINE_END_MARKER[..]);
+        report_status.freeze()
+    }
+
+    // the report status in the side-band format, followed by the flush-pkt of the side-band
+    fn build_report_status(&self, report_status: &[u8]) -> Bytes {
+        let mut buf = self.build_side_band_data(SideBind::PackfileData, report_status);
+        if self.is_side_band() {
+            buf.put(&PKT_LINE_END_MARKER[..]);
+        }
+        buf.freeze()
+    }
+
+    /// Frames a message of a side-band stream, an error is reported on band 3.
+    ///
+    /// Returns `None` for an error when the client didn't ask for a side-band, there is no way
+    /// to tell it what went wrong and the response should be aborted.
+    pub fn build_side_band_message(&self, message: &SideBandMessage) -> Option<BytesMut> {
+        match message {
+            Ok((band, data)) => Some(self.build_side_band_data(*band, data)),
+            Err(e) if self.is_side_band() => {
+                let message = format!("{}\n", e);
+                Some(self.build_side_band_data(SideBind::Error, message.as_bytes()))
+            }
+            Err(_) => None,
+        }
+    }
+
     /// # Builds the packet data in the sideband format if the SideBand/64k capability is enabled.
     ///
     /// If the `SideBand` or `SideBand64k` capability is present in the `capabilities` vector,
@@ -259,6 +888,35 @@ impl PackProtocol {
         from_bytes
     }
 
+    pub fn is_side_band(&self) -> bool {
+        self.capabilities.contains(&Capability::SideBand)
+            || self.capabilities.contains(&Capability::SideBand64k)
+    }
+
+    /// Frames a message of a side-band stream for the client.
+    ///
+    /// With a side-band capability the data is split into packets of its band, the progress is
+    /// left out if the client asked for `no-progress` or `quiet`. Without it, only band 1 is
+    /// sent as it is, the client has no way to receive the other bands.
+    pub fn build_side_band_data(&self, band: SideBind, data: &[u8]) -> BytesMut {
+        let mut to_bytes = BytesMut::new();
+        let quiet = self.capabilities.contains(&Capability::NoProgress)
+            || self.capabilities.contains(&Capability::Quiet);
+        if band == SideBind::ProgressInfo && quiet {
+            return to_bytes;
+        }
+        if self.capabilities.contains(&Capability::SideBand64k) {
+            add_side_band_data(&mut to_bytes, band, data);
+        } else if self.capabilities.contains(&Capability::SideBand) {
+            for chunk in data.chunks(SIDE_BAND_MAX_DATA) {
+                add_side_band_data(&mut to_bytes, band, chunk);
+            }
+        } else if band == SideBind::PackfileData {
+            to_bytes.put_slice(data);
+        }
+        to_bytes
+    }
+
     pub fn build_smart_reply(&self, ref_list: &Vec<String>, service: String) -> BytesMut {
         let mut pkt_line_stream = BytesMut::new();
         if self.protocol == Protocol::Http {
@@ -293,6 +951,21 @@ impl PackProtocol {
     }
 }
 
+// sends the lines printed by the push hooks on band 2
+async fn send_hook_output(sender: Option<&SideBandSender>, out: Vec<String>) {
+    for line in out {
+        match sender {
+            Some(sender) => {
+                let message = format!("{}{}", line, LF);
+                let _ = sender
+                    .send(Ok((SideBind::ProgressInfo, message.into())))
+                    .await;
+            }
+            None => tracing::info!("hook: {}", line),
+        }
+    }
+}
+
 fn read_until_white_space(bytes: &mut Bytes) -> String {
     let mut buf = Vec::new();
     while bytes.has_remaining() {
@@ -305,11 +978,22 @@ fn read_until_white_space(bytes: &mut Bytes) -> String {
     String::from_utf8(buf).unwrap()
 }
 
-fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
+pub(crate) fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
     let buf_str_length = buf_str.len() + 4;
     pkt_line_stream.put(Bytes::from(format!("{buf_str_length:04x}")));
     pkt_line_stream.put(buf_str.as_bytes());
 }
+
+/// Writes `data` to the given side-band channel, splitting it into as many side-band-64k
+/// packets as needed.
+pub(crate) fn add_side_band_data(pkt_line_stream: &mut BytesMut, band: SideBind, data: &[u8]) {
+    for chunk in data.chunks(SIDE_BAND_64K_MAX_DATA) {
+        let length = chunk.len() + 5;
+        pkt_line_stream.put(Bytes::from(format!("{length:04x}")));
+        pkt_line_stream.put_u8(band.value());
+        pkt_line_stream.put(chunk);
+    }
+}
 /// Read a single pkt-format line from the `bytes` buffer and return the line length and line bytes.
 ///
 /// If the `bytes` buffer is empty, indicating no more data is available, the function returns a line length of 0 and an empty `Bytes` object.
@@ -361,11 +1045,13 @@ pub fn read_pkt_line(bytes: &mut Bytes) -> (usize, Bytes) {
 
 #[cfg(test)]
 pub mod test {
+    use std::collections::HashSet;
+
     use bytes::{Bytes, BytesMut};
 
     use crate::protocol::{Capability, CommandType, PackProtocol, RefCommand};
 
-    use super::{add_pkt_line_string, read_pkt_line, read_until_white_space};
+    use super::{add_pkt_line_string, read_pkt_line, read_until_white_space, UploadNegotiation};
 
     #[test]
     pub fn test_read_pkt_line() {
@@ -441,4 +1127,79 @@ pub mod test {
             vec![Capability::ReportStatusv2, Capability::SideBand64k]
         );
     }
+
+    #[test]
+    pub fn test_report_status() {
+        let mut mock = PackProtocol::mock();
+        mock.capabilities = vec![Capability::ReportStatusv2];
+        let old_id = "7bdc783132575d5b3e78400ace9971970ff43a18";
+        let new_id = "27dd8d4cf39f3868c6eee38b601bc9e9939304f5";
+        let main = RefCommand::new(
+            old_id.to_owned(),
+            new_id.to_owned(),
+            String::from("refs/heads/main"),
+        );
+        let mut dev = RefCommand::new(
+            old_id.to_owned(),
+            new_id.to_owned(),
+            String::from("refs/heads/dev"),
+        );
+        dev.failed(String::from("atomic push failure"));
+        let forced = HashSet::from([String::from("refs/heads/main")]);
+        let report = mock.report_status(&Ok(()), &[main, dev], &forced);
+        let expected = [
+            "000eunpack ok\n",
+            "0017ok refs/heads/main\n",
+            "003coption old-oid 7bdc783132575d5b3e78400ace9971970ff43a18\n",
+            "003coption new-oid 27dd8d4cf39f3868c6eee38b601bc9e9939304f5\n",
+            "0019option forced-update\n",
+            "002ang refs/heads/dev atomic push failure\n",
+            "0000",
+        ]
+        .concat();
+        assert_eq!(&report[..], expected.as_bytes());
+
+        let unpack_status = Err(anyhow::anyhow!("missing pack data"));
+        let report = mock.report_status(&unpack_status, &[], &forced);
+        assert_eq!(&report[..], b"001dunpack missing pack data\n0000");
+    }
+
+    #[test]
+    fn test_upload_negotiation_rounds() {
+        // a <- b <- c, the client wants c
+        let commit = |id: &str, pid: &[&str]| entity::commit::Model {
+            id: 0,
+            git_id: id.to_owned(),
+            tree: String::new(),
+            pid: pid.iter().map(|p| p.to_string()).collect(),
+            repo_path: String::from("/root/repo"),
+            author: None,
+            committer: None,
+            content: None,
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        };
+        let graph = [commit("a", &[]), commit("b", &["a"]), commit("c", &["b"])]
+            .into_iter()
+            .map(|model| (model.git_id.clone(), model))
+            .collect();
+        let mut negotiation = UploadNegotiation::default();
+        negotiation.set_graph(graph, HashSet::from([String::from("c")]));
+
+        negotiation.have = vec![String::from("x")];
+        assert!(negotiation.add_haves().is_empty());
+        assert!(!negotiation.is_ready());
+
+        // only the haves of the round are acknowledged, a commit only once
+        negotiation.have = vec![String::from("a"), String::from("x")];
+        assert_eq!(negotiation.add_haves(), vec![String::from("a")]);
+        assert!(negotiation.is_ready());
+        negotiation.have = vec![String::from("a"), String::from("b")];
+        assert_eq!(negotiation.add_haves(), vec![String::from("b")]);
+        assert_eq!(
+            negotiation.common,
+            vec![String::from("a"), String::from("b")]
+        );
+        assert!(negotiation.is_ready());
+    }
 }
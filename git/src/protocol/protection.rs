@@ -0,0 +1,126 @@
+//!
+//! Protected refs, the rules of a repo restricting how some of its refs are updated by a push:
+//!
+//! - `allowed_pushers`: the users who can update the ref, anyone if it's empty.
+//! - `allow_force_push`: whether an update may drop commits, i.e. not be a fast-forward.
+//! - `allow_deletion`: whether the ref may be deleted.
+//! - `require_linear_history`: whether the pushed commits may contain merge commits.
+//!
+//! A rule applies to the refs matching its `ref_pattern`, a ref name where `*` matches any
+//! part of a single component, e.g. `refs/heads/release-*`. Every rule matching a ref applies.
+//!
+//! A rejected force push is reported as `ng <ref-name> non-fast-forward`, the other rejections
+//! as `ng <ref-name> protected branch`.
+//!
+
+use std::collections::HashMap;
+
+use entity::{commit, protected_ref};
+
+use super::{CommandType, PackProtocol, RefCommand};
+
+pub const NON_FAST_FORWARD: &str = "non-fast-forward";
+pub const PROTECTED_BRANCH: &str = "protected branch";
+
+impl PackProtocol {
+    /// The protection rules of the repo.
+    pub async fn get_protected_refs(&self) -> Vec<protected_ref::Model> {
+        self.storage
+            .get_protected_refs(self.path.to_str().unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Checks a ref update against the protection `rules` of the repo, `forced` tells whether
+    /// the update isn't a fast-forward and `graph` is the commit graph of the repo. Returns the
+    /// reason to reject it.
+    pub(crate) async fn check_protection(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        rules: &[protected_ref::Model],
+        command: &RefCommand,
+        forced: bool,
+    ) -> Result<(), String> {
+        let rules = rules
+            .iter()
+            .filter(|rule| ref_pattern_matches(&rule.ref_pattern, &command.ref_name));
+        for rule in rules {
+            let allowed = rule.allowed_pushers.is_empty()
+                || self
+                    .pusher
+                    .as_ref()
+                    .is_some_and(|pusher| rule.allowed_pushers.contains(pusher));
+            if !allowed {
+                return Err(String::from(PROTECTED_BRANCH));
+            }
+            match command.command_type {
+                CommandType::Delete if !rule.allow_deletion => {
+                    return Err(String::from(PROTECTED_BRANCH));
+                }
+                CommandType::Update if forced && !rule.allow_force_push => {
+                    return Err(String::from(NON_FAST_FORWARD));
+                }
+                _ => {}
+            }
+            if rule.require_linear_history && command.command_type != CommandType::Delete {
+                // a new ref only brings the commits no other ref of the repo reaches
+                let known = match command.command_type {
+                    CommandType::Update => vec![command.old_id.clone()],
+                    _ => self
+                        .storage
+                        .get_ref_object_id(self.path.to_str().unwrap())
+                        .await
+                        .unwrap()
+                        .into_iter()
+                        .map(|r| r.ref_git_id)
+                        .collect(),
+                };
+                if self.has_merge_commits(graph, &command.new_id, &known) {
+                    return Err(String::from(PROTECTED_BRANCH));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `ref_name` matches `pattern`, see the module documentation.
+pub fn ref_pattern_matches(pattern: &str, ref_name: &str) -> bool {
+    let patterns: Vec<&str> = pattern.split('/').collect();
+    let components: Vec<&str> = ref_name.split('/').collect();
+    patterns.len() == components.len()
+        && patterns
+            .iter()
+            .zip(components)
+            .all(|(pattern, component)| wildcard_matches(pattern.as_bytes(), component.as_bytes()))
+}
+
+fn wildcard_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| wildcard_matches(rest, &text[i..])),
+        Some((c, rest)) => text.first() == Some(c) && wildcard_matches(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ref_pattern_matches;
+
+    #[test]
+    fn test_ref_pattern_matches() {
+        assert!(ref_pattern_matches("refs/heads/main", "refs/heads/main"));
+        assert!(!ref_pattern_matches("refs/heads/main", "refs/heads/main2"));
+        assert!(ref_pattern_matches("refs/heads/*", "refs/heads/dev"));
+        assert!(!ref_pattern_matches("refs/heads/*", "refs/heads/feature/x"));
+        assert!(ref_pattern_matches(
+            "refs/heads/release-*",
+            "refs/heads/release-1.0"
+        ));
+        assert!(!ref_pattern_matches(
+            "refs/heads/release-*",
+            "refs/tags/release-1.0"
+        ));
+        assert!(ref_pattern_matches("refs/*/v*", "refs/tags/v0.1.0"));
+    }
+}
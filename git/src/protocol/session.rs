@@ -0,0 +1,146 @@
+//!
+//! Upload-pack over the stateful transports, SSH and git://, where the whole negotiation
+//! happens on one connection.
+//!
+//! The data of the client arrives in chunks of any size, so it's first cut into pkt-lines
+//! with [`split_pkt_line`]. The pkt-lines are then buffered by an [`UploadPackSession`] until
+//! they form a whole request, which is answered like over HTTP.
+//!
+
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+
+use super::pack::{UploadNegotiation, PKT_LINE_END_MARKER};
+use super::sideband::SideBandStream;
+use super::{PackProtocol, ProtocolVersion};
+
+// the largest pkt-line allowed by the protocol, with its length
+pub const MAX_PKT_LINE_LENGTH: usize = 65520;
+
+/// What to send back after a pkt-line of the client.
+pub enum UploadPackReply {
+    /// the request isn't complete yet
+    Pending,
+    /// the response to a request, the client sends another one afterwards
+    Data(BytesMut),
+    /// the last response, followed by the pack
+    Pack(BytesMut, SideBandStream),
+    /// the client is up to date and hangs up
+    UpToDate,
+}
+
+/// The state of an upload-pack conversation.
+///
+/// With protocol v0 and v1 the client sends its want list, then rounds of haves until `done`.
+/// The want list starts the negotiation and gets the shallow-update section, each round then
+/// only acknowledges its own haves, the common commits of the earlier rounds are kept in the
+/// session. With protocol v2 every command request ends with a flush-pkt and gets its whole
+/// response.
+#[derive(Clone, Default)]
+pub struct UploadPackSession {
+    request: BytesMut,
+    negotiation: Option<UploadNegotiation>,
+}
+
+impl UploadPackSession {
+    /// Handles a whole pkt-line of the client, with its length.
+    pub async fn receive(
+        &mut self,
+        pack_protocol: &mut PackProtocol,
+        pkt: &[u8],
+    ) -> Result<UploadPackReply> {
+        self.request.extend_from_slice(pkt);
+        if pack_protocol.version == ProtocolVersion::V2 {
+            if pkt != PKT_LINE_END_MARKER {
+                return Ok(UploadPackReply::Pending);
+            }
+            let mut request = self.request.split().freeze();
+            let (response, pack_stream) = pack_protocol.git_upload_pack_v2(&mut request).await?;
+            return Ok(match pack_stream {
+                Some(pack_stream) => UploadPackReply::Pack(response, pack_stream),
+                None => UploadPackReply::Data(response),
+            });
+        }
+
+        let done = pkt.len() > 4 && pkt[4..].starts_with(b"done");
+        if pkt != PKT_LINE_END_MARKER && !done {
+            return Ok(UploadPackReply::Pending);
+        }
+
+        let mut request = self.request.split().freeze();
+        let mut buf = BytesMut::new();
+        let negotiation = match &mut self.negotiation {
+            Some(negotiation) => negotiation,
+            None => {
+                // a client that is up to date sends no want
+                if &request[..] == PKT_LINE_END_MARKER {
+                    return Ok(UploadPackReply::UpToDate);
+                }
+                let mut negotiation = UploadNegotiation::default();
+                buf = pack_protocol
+                    .begin_upload_negotiation(&mut request, &mut negotiation)
+                    .await?;
+                if !done {
+                    self.negotiation = Some(negotiation);
+                    return Ok(UploadPackReply::Data(buf));
+                }
+                self.negotiation.insert(negotiation)
+            }
+        };
+
+        let (pack_stream, round) = pack_protocol
+            .continue_upload_negotiation(&mut request, negotiation)
+            .await?;
+        buf.extend_from_slice(&round);
+        Ok(match pack_stream {
+            Some(pack_stream) => UploadPackReply::Pack(buf, pack_stream),
+            None => UploadPackReply::Data(buf),
+        })
+    }
+}
+
+/// Takes the first pkt-line of `buf` with its length, `None` if it isn't complete yet.
+pub fn split_pkt_line(buf: &mut BytesMut) -> Result<Option<Bytes>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let pkt_length = usize::from_str_radix(std::str::from_utf8(&buf[..4])?, 16)?;
+    if pkt_length == 3 || pkt_length > MAX_PKT_LINE_LENGTH {
+        bail!("invalid pkt-line length: {}", pkt_length);
+    }
+    // flush-pkt, delim-pkt and response-end-pkt are only a length
+    let pkt_length = pkt_length.max(4);
+    if buf.len() < pkt_length {
+        return Ok(None);
+    }
+    Ok(Some(buf.split_to(pkt_length).freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::split_pkt_line;
+
+    #[test]
+    fn test_split_pkt_line() {
+        let mut buf =
+            BytesMut::from(&b"0032want 8ab686eafeb1f44702738c8b0f24f2567c36da6d\n0000000"[..]);
+        assert_eq!(
+            &split_pkt_line(&mut buf).unwrap().unwrap()[..],
+            b"0032want 8ab686eafeb1f44702738c8b0f24f2567c36da6d\n"
+        );
+        assert_eq!(&split_pkt_line(&mut buf).unwrap().unwrap()[..], b"0000");
+        // the rest of the next pkt-line hasn't arrived yet
+        assert!(split_pkt_line(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"9done\n");
+        assert_eq!(
+            &split_pkt_line(&mut buf).unwrap().unwrap()[..],
+            b"0009done\n"
+        );
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"0003"[..]);
+        assert!(split_pkt_line(&mut buf).is_err());
+    }
+}
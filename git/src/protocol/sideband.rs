@@ -0,0 +1,151 @@
+//!
+//! Side-band channels of the fetch and push responses, see `side-band` and `side-band-64k` in
+//! [protocol-capabilities](https://git-scm.com/docs/protocol-capabilities#_side_band_side_band_64k).
+//!
+//! A response is produced by a background task as a stream of messages tagged with the band
+//! they go to: the pack or the report status on band 1, the progress on band 2 and a fatal
+//! error on band 3. The consumer frames them according to the client's capabilities.
+//!
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use super::SideBind;
+use crate::errors::GitError;
+use crate::hash::{get_hash_kind, with_hash_kind};
+
+pub type SideBandMessage = Result<(SideBind, Bytes), GitError>;
+
+pub type SideBandStream = Pin<Box<dyn Stream<Item = SideBandMessage> + Send>>;
+
+pub type SideBandSender = mpsc::Sender<SideBandMessage>;
+
+// messages waiting to be sent to the client
+const SIDE_BAND_BUFFER: usize = 8;
+
+/// Runs `producer` in a background task and streams the messages it sends.
+///
+/// The producer stops early when its sends fail, which means the stream was dropped. If it
+/// returns an error or panics, the error is the last message of the stream. It runs with the
+/// hash kind of the caller.
+pub fn spawn_side_band_stream<F, Fut>(producer: F) -> SideBandStream
+where
+    F: FnOnce(SideBandSender) -> Fut,
+    Fut: Future<Output = Result<(), GitError>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(SIDE_BAND_BUFFER);
+    let task = tokio::spawn(with_hash_kind(get_hash_kind(), producer(tx.clone())));
+    tokio::spawn(async move {
+        let error = match task.await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e,
+            // storage errors are unwrapped in many places
+            Err(e) => GitError::SideBandStreamError(e.to_string()),
+        };
+        tracing::error!("{}", error);
+        let _ = tx.send(Err(error)).await;
+    });
+    Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|message| (message, rx))
+    }))
+}
+
+/// Formats the progress of a step the way git does, e.g. `Counting objects:  42% (420/1000)`.
+///
+/// Updates are throttled to one per percent, or one per second when the total isn't known.
+pub struct Progress {
+    title: &'static str,
+    total: Option<usize>,
+    last_percent: Option<usize>,
+    last_update: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(title: &'static str, total: Option<usize>) -> Self {
+        Progress {
+            title,
+            total,
+            last_percent: None,
+            last_update: None,
+        }
+    }
+
+    /// Returns the message to show for `current`, `None` if it's too early for an update.
+    pub fn update(&mut self, current: usize) -> Option<Bytes> {
+        let message = match self.total {
+            Some(total) => {
+                let percent = percent(current, total);
+                if self.last_percent == Some(percent) {
+                    return None;
+                }
+                self.last_percent = Some(percent);
+                format!("{}: {:3}% ({}/{})\r", self.title, percent, current, total)
+            }
+            None => {
+                if matches!(self.last_update, Some(last) if last.elapsed() < Duration::from_secs(1))
+                {
+                    return None;
+                }
+                self.last_update = Some(Instant::now());
+                format!("{}: {}\r", self.title, current)
+            }
+        };
+        Some(message.into())
+    }
+
+    /// The last message of the step.
+    pub fn done(&self, current: usize) -> Bytes {
+        match self.total {
+            Some(total) => format!(
+                "{}: {:3}% ({}/{}), done.\n",
+                self.title,
+                percent(current, total),
+                current,
+                total
+            ),
+            None => format!("{}: {}, done.\n", self.title, current),
+        }
+        .into()
+    }
+}
+
+fn percent(current: usize, total: usize) -> usize {
+    (current * 100).checked_div(total).unwrap_or(100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Progress;
+
+    #[test]
+    fn test_progress() {
+        let mut progress = Progress::new("Compressing objects", Some(200));
+        assert_eq!(
+            progress.update(1).unwrap(),
+            "Compressing objects:   0% (1/200)\r"
+        );
+        assert_eq!(
+            progress.update(2).unwrap(),
+            "Compressing objects:   1% (2/200)\r"
+        );
+        assert!(progress.update(3).is_none());
+        assert_eq!(
+            progress.update(100).unwrap(),
+            "Compressing objects:  50% (100/200)\r"
+        );
+        assert_eq!(
+            progress.done(200),
+            "Compressing objects: 100% (200/200), done.\n"
+        );
+
+        let mut progress = Progress::new("Counting objects", None);
+        assert_eq!(progress.update(10).unwrap(), "Counting objects: 10\r");
+        assert!(progress.update(20).is_none());
+        assert_eq!(progress.done(30), "Counting objects: 30, done.\n");
+    }
+}
@@ -6,33 +6,63 @@
 
 use async_trait::async_trait;
 use bytes::{BufMut, Bytes, BytesMut};
-use russh::server::{self, Auth, Msg, Session};
+use russh::server::{self, Auth, Handle, Msg, Session};
 use russh::{Channel, ChannelId};
 
+use anyhow::{anyhow, Result};
 use database::driver::ObjectStorage;
 use russh_keys::key;
 use std::collections::HashMap;
 
+use futures::StreamExt;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
 
 use crate::protocol::ServiceType;
 
+use super::hooks::PushHooks;
 use super::pack::{self};
-use super::{PackProtocol, Protocol};
+use super::session::{split_pkt_line, UploadPackReply, UploadPackSession};
+use super::sideband::SideBandStream;
+use super::{PackProtocol, Protocol, ProtocolVersion};
 
 type ClientMap = HashMap<(usize, ChannelId), Channel<Msg>>;
 
+/// The state of a channel, a client may run several git commands at once on one connection,
+/// each of them on its own channel.
+#[derive(Clone, Default)]
+pub struct ChannelState {
+    // set by the `GIT_PROTOCOL` environment variable the client sends before exec
+    pub version: ProtocolVersion,
+    // the git command run by exec
+    pub pack_protocol: Option<PackProtocol>,
+    // the data received and not handled yet, until it holds a whole request
+    pub buf: BytesMut,
+    pub upload_pack: UploadPackSession,
+    // the argument pkt-lines of upload-archive, until the flush-pkt ends them
+    pub archive_request: BytesMut,
+    // forwards the pack of a push to its decoder
+    pub pack_sender: Option<mpsc::Sender<Bytes>>,
+    // a task is sending the pack or the report status, it closes the channel once done
+    pub streaming: bool,
+}
+
 #[derive(Clone)]
 pub struct SshServer {
     pub client_pubkey: Arc<russh_keys::key::PublicKey>,
     pub clients: Arc<Mutex<ClientMap>>,
     pub id: usize,
     pub storage: Arc<dyn ObjectStorage>,
-    // TODO: consider is it a good choice to bind data here, find a better solution to bind data with ssh client
-    pub pack_protocol: Option<PackProtocol>,
+    // the channels of the connection
+    pub channels: HashMap<ChannelId, ChannelState>,
+    // the user owning the public key the client authenticated with
+    pub user: Option<String>,
+    // any password is accepted when enabled, the client stays anonymous
+    pub allow_password_auth: bool,
+    // run on the pushes of the clients
+    pub hooks: PushHooks,
 }
 
 impl server::Server for SshServer {
@@ -49,11 +79,12 @@ impl server::Handler for SshServer {
     type Error = anyhow::Error;
 
     async fn channel_open_session(
-        self,
+        mut self,
         channel: Channel<Msg>,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
         tracing::info!("SshServer::channel_open_session:{}", channel.id());
+        self.channels.insert(channel.id(), ChannelState::default());
         {
             let mut clients = self.clients.lock().unwrap();
             clients.insert((self.id, channel.id()), channel);
@@ -62,8 +93,10 @@ impl server::Handler for SshServer {
     }
     /// # Executes a request on the SSH server.
     ///
-    /// This function processes the received data from the specified channel and performs the
-    /// corresponding action based on the received command.
+    /// The command is `git-upload-pack '<path>'`, `git-receive-pack '<path>'` or
+    /// `git-upload-archive '<path>'`, the refs of the repo are advertised right away except for
+    /// upload-archive, which waits for the arguments of the client. Any other command is
+    /// refused with an error on stderr.
     ///
     /// Arguments:
     /// - `self`: The current instance of the SSH server.
@@ -79,146 +112,364 @@ impl server::Handler for SshServer {
     ) -> Result<(Self, Session), Self::Error> {
         let data = String::from_utf8_lossy(data).trim().to_owned();
         tracing::info!("exec: {:?},{}", channel, data);
-        let res = self.handle_git_command(&data).await;
-        session.data(channel, res.into());
+        match self.handle_git_command(channel, &data).await {
+            Ok(res) => session.data(channel, res.to_vec().into()),
+            Err(e) => send_error(&mut session, channel, &e),
+        }
         Ok((self, session))
     }
 
+    /// # Handles the environment variables sent by the client.
+    ///
+    /// Git clients pass the requested wire protocol version as `GIT_PROTOCOL=version=2`
+    /// before executing `git-upload-pack`.
+    async fn env_request(
+        mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        tracing::info!("env: {:?}, {}={}", channel, variable_name, variable_value);
+        if variable_name == "GIT_PROTOCOL" {
+            self.channels.entry(channel).or_default().version =
+                ProtocolVersion::from_git_protocol(variable_value);
+        }
+        Ok((self, session))
+    }
+
+    /// # Authenticates the client with its public key.
+    ///
+    /// The key is looked up by its SHA-256 fingerprint among the registered keys, the client
+    /// is then identified as the user owning the key, whatever user name it sent.
     async fn auth_publickey(
-        self,
+        mut self,
         user: &str,
         public_key: &key::PublicKey,
     ) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_publickey: {} / {:?}", user, public_key);
-        Ok((self, server::Auth::Accept))
+        let fingerprint = public_key.fingerprint();
+        let ssh_key = self
+            .storage
+            .get_ssh_key_by_fingerprint(&fingerprint)
+            .await
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        match ssh_key {
+            Some(ssh_key) => {
+                tracing::info!(
+                    "auth_publickey: {} accepted as {} with key {}",
+                    user,
+                    ssh_key.user_name,
+                    ssh_key.title
+                );
+                self.user = Some(ssh_key.user_name);
+                Ok((self, server::Auth::Accept))
+            }
+            None => {
+                tracing::warn!(
+                    "auth_publickey: {} rejected, unknown key {}",
+                    user,
+                    fingerprint
+                );
+                Ok((self, Self::reject()))
+            }
+        }
     }
 
-    async fn auth_password(self, user: &str, password: &str) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_password: {} / {}", user, password);
-        // in this example implementation, any username/password combination is accepted
+    async fn auth_password(self, user: &str, _password: &str) -> Result<(Self, Auth), Self::Error> {
+        if !self.allow_password_auth {
+            tracing::warn!(
+                "auth_password: {} rejected, password authentication is disabled",
+                user
+            );
+            return Ok((self, Self::reject()));
+        }
+        tracing::info!("auth_password: {} accepted anonymously", user);
         Ok((self, server::Auth::Accept))
     }
 
+    /// # Handles the data the client sends on a channel.
+    ///
+    /// The data is buffered until it holds a whole request, it may arrive in chunks of any
+    /// size. A protocol error is sent back on stderr and closes the channel, the other
+    /// channels of the connection go on.
     async fn data(
         mut self,
         channel: ChannelId,
         data: &[u8],
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let pack_protocol = self.pack_protocol.as_mut().unwrap();
-        let data_str = String::from_utf8_lossy(data).trim().to_owned();
-        tracing::info!(
-            "SSH: client sends data: {:?}, channel:{}",
-            data_str,
+        tracing::debug!(
+            "SSH: client sends {} bytes on channel {}",
+            data.len(),
             channel
         );
-        match pack_protocol.service_type {
+        let service_type = self
+            .channels
+            .get(&channel)
+            .and_then(|state| state.pack_protocol.as_ref())
+            .and_then(|pack_protocol| pack_protocol.service_type);
+        let res = match service_type {
             Some(ServiceType::UploadPack) => {
-                self.handle_upload_pack(channel, data, &mut session).await;
+                self.handle_upload_pack(channel, data, &mut session).await
             }
             Some(ServiceType::ReceivePack) => {
-                self.handle_receive_pack(channel, data, &mut session).await;
+                self.handle_receive_pack(channel, data, &mut session).await
+            }
+            Some(ServiceType::UploadArchive) => {
+                self.handle_upload_archive(channel, data, &mut session)
+                    .await
             }
-            _ => panic!(),
+            None => Err(anyhow!("no git command was run before sending data")),
         };
+        if let Err(e) = res {
+            send_error(&mut session, channel, &e);
+            self.channels.remove(&channel);
+        }
         Ok((self, session))
     }
 
     async fn channel_eof(
-        self,
+        mut self,
         channel: ChannelId,
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        // session.close(channel);
-        // match session.flush() {
-        //     Ok(_) => {},
-        //     Err(e) => println!("Error flushing session: {:?}", e),
-        // }
-        // session.disconnect(Disconnect::ByApplication, "channel close", "en");
-        // match session.disconnect(None, "Closing session") {
-        //     Ok(_) => {},
-        //     Err(e) => println!("Error disconnecting session: {:?}", e),
-        // }
-        session.close(channel);
+        // the end of a pack without a trailer is left to the decoder to report
+        let streaming = match self.channels.get_mut(&channel) {
+            Some(state) => {
+                state.pack_sender = None;
+                state.streaming
+            }
+            None => false,
+        };
+        if !streaming {
+            session.close(channel);
+        }
         Ok((self, session))
     }
 
-    // async fn channel_close(
-    //     self,
-    //     channel: ChannelId,
-    //     session: Session,
-    // ) -> Result<(Self, Session), Self::Error> {
-    //     tracing::info!("channel_close: {:?}", channel);
-    //     Ok((self, session))
-    // }
+    async fn channel_close(
+        mut self,
+        channel: ChannelId,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        tracing::info!("channel_close: {:?}", channel);
+        self.channels.remove(&channel);
+        self.clients.lock().unwrap().remove(&(self.id, channel));
+        Ok((self, session))
+    }
 }
 
 impl SshServer {
-    async fn handle_git_command(&mut self, command: &str) -> String {
-        let command: Vec<_> = command.split(' ').collect();
-        // command:
-        // Push: git-receive-pack '/root/repotest/src.git'
-        // Pull: git-upload-pack '/root/repotest/src.git'
-        let path = command[1];
-        let end = path.len() - ".git'".len();
-        let mut pack_protocol = PackProtocol::new(
-            PathBuf::from(&path[1..end]),
-            self.storage.clone(),
-            Protocol::Ssh,
+    fn reject() -> Auth {
+        server::Auth::Reject {
+            proceed_with_methods: None,
+        }
+    }
+
+    // runs `git-upload-pack '<path>'`, `git-receive-pack '<path>'` or
+    // `git-upload-archive '<path>'`, returns the advertisement of the refs
+    async fn handle_git_command(&mut self, channel: ChannelId, command: &str) -> Result<BytesMut> {
+        let (service, path) = command
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("invalid command: {}", command))?;
+        let service_type =
+            ServiceType::from_str(service).map_err(|_| anyhow!("unknown command: {}", service))?;
+        let path = path
+            .trim_matches(|c| c == '\'' || c == '"')
+            .trim_end_matches('/');
+        let path = path.strip_suffix(".git").unwrap_or(path);
+        if path.is_empty() {
+            return Err(anyhow!("invalid command: {}", command));
+        }
+        tracing::info!(
+            "{} runs {} on {}",
+            self.user.as_deref().unwrap_or("anonymous"),
+            service,
+            path
         );
-        let service_type = ServiceType::from_str(command[0]).unwrap();
-        pack_protocol.service_type = Some(service_type);
-        let res = pack_protocol.git_info_refs(service_type).await;
 
-        self.pack_protocol = Some(pack_protocol);
-        String::from_utf8(res.to_vec()).unwrap()
+        let state = self.channels.entry(channel).or_default();
+        let mut pack_protocol =
+            PackProtocol::new(PathBuf::from(path), self.storage.clone(), Protocol::Ssh);
+        pack_protocol.service_type = Some(service_type);
+        pack_protocol.version = state.version;
+        pack_protocol.pusher = self.user.clone();
+        pack_protocol.hooks = self.hooks.clone();
+        // upload-archive starts with the request of the client
+        let res = match service_type {
+            ServiceType::UploadArchive => BytesMut::new(),
+            _ => pack_protocol.git_info_refs(service_type).await?,
+        };
+        state.pack_protocol = Some(pack_protocol);
+        Ok(res)
     }
 
-    async fn handle_upload_pack(&mut self, channel: ChannelId, data: &[u8], session: &mut Session) {
-        let pack_protocol = self.pack_protocol.as_mut().unwrap();
-
-        let (send_pack_data, buf) = pack_protocol
-            .git_upload_pack(&mut Bytes::copy_from_slice(data))
-            .await
-            .unwrap();
-
-        tracing::info!("buf is {:?}", buf);
-        session.data(channel, String::from_utf8(buf.to_vec()).unwrap().into());
-
-        let mut reader = BufReader::new(send_pack_data.as_slice());
-        loop {
-            let mut temp = BytesMut::new();
-            let length = reader.read_buf(&mut temp).await.unwrap();
-            if temp.is_empty() {
-                let mut bytes_out = BytesMut::new();
-                bytes_out.put_slice(pack::PKT_LINE_END_MARKER);
-                tracing::info!("send: ends: {:?}", bytes_out.clone().freeze());
-                session.data(channel, bytes_out.to_vec().into());
-                return;
+    async fn handle_upload_pack(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<()> {
+        let state = self.channels.get_mut(&channel).unwrap();
+        if state.streaming {
+            // the client has nothing more to say once the pack is on its way
+            return Ok(());
+        }
+        let pack_protocol = state.pack_protocol.as_mut().unwrap();
+        state.buf.extend_from_slice(data);
+        while let Some(pkt) = split_pkt_line(&mut state.buf)? {
+            match state.upload_pack.receive(pack_protocol, &pkt).await? {
+                UploadPackReply::Pending => {}
+                UploadPackReply::Data(buf) => session.data(channel, buf.to_vec().into()),
+                UploadPackReply::Pack(buf, pack_stream) => {
+                    session.data(channel, buf.to_vec().into());
+                    // the pack is sent from its own task so that the session keeps being served
+                    state.streaming = true;
+                    let pack_protocol = pack_protocol.clone();
+                    let handle = session.handle();
+                    tokio::spawn(async move {
+                        send_side_band(&handle, channel, pack_stream, &pack_protocol).await;
+                        let _ = handle.close(channel).await;
+                    });
+                    return Ok(());
+                }
+                UploadPackReply::UpToDate => {
+                    session.close(channel);
+                    return Ok(());
+                }
             }
-            let bytes_out = pack_protocol.build_side_band_format(temp, length);
-            tracing::info!("send: packet lentgh : {:?}", bytes_out.len());
-            session.data(channel, bytes_out.to_vec().into());
         }
+        Ok(())
     }
 
+    /// # Handles the data of a push.
+    ///
+    /// The ref update commands are buffered until the flush-pkt, then the pack is forwarded
+    /// chunk by chunk to `git_receive_pack_stream`, which runs in its own task and sends the
+    /// report status back once the pack is stored.
     async fn handle_receive_pack(
         &mut self,
         channel: ChannelId,
         data: &[u8],
         session: &mut Session,
-    ) {
-        let pack_protocol = self.pack_protocol.as_mut().unwrap();
+    ) -> Result<()> {
+        let state = self.channels.get_mut(&channel).unwrap();
+        if let Some(sender) = &state.pack_sender {
+            if sender.send(Bytes::copy_from_slice(data)).await.is_err() {
+                tracing::error!("the pack decoder of channel {:?} has stopped", channel);
+            }
+            return Ok(());
+        }
+        if state.streaming {
+            return Ok(());
+        }
 
-        let buf = pack_protocol
-            .git_receive_pack(Bytes::from(data.to_vec()))
-            .await
-            .unwrap();
-        if !buf.is_empty() {
-            tracing::info!("report status: {:?}", buf);
+        let pack_protocol = state.pack_protocol.as_mut().unwrap();
+        state.buf.extend_from_slice(data);
+        if !pack_protocol
+            .parse_receive_commands(&mut state.buf)
+            .map_err(|e| anyhow!("invalid receive-pack commands: {}", e))?
+        {
+            return Ok(());
+        }
+
+        let (sender, receiver) = mpsc::channel(pack::PACK_CHANNEL_SIZE);
+        let rest = state.buf.split().freeze();
+        if !rest.is_empty() {
+            sender.send(rest).await.unwrap();
+        }
+        state.pack_sender = Some(sender);
+        state.streaming = true;
+
+        let pack_protocol = pack_protocol.clone();
+        let stream = pack_protocol.git_receive_pack_stream(receiver);
+        let handle = session.handle();
+        tokio::spawn(async move {
+            send_side_band(&handle, channel, stream, &pack_protocol).await;
+            let _ = handle.close(channel).await;
+        });
+        Ok(())
+    }
+
+    /// # Handles the request of `git archive --remote`.
+    ///
+    /// The arguments are buffered until the flush-pkt, then the archive is sent on the
+    /// side-band from its own task. A request the archive can't be built for is answered by a
+    /// `NACK`, which ends the command.
+    async fn handle_upload_archive(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<()> {
+        let state = self.channels.get_mut(&channel).unwrap();
+        if state.streaming {
+            return Ok(());
+        }
+        let pack_protocol = state.pack_protocol.as_mut().unwrap();
+        state.buf.extend_from_slice(data);
+        while let Some(pkt) = split_pkt_line(&mut state.buf)? {
+            state.archive_request.extend_from_slice(&pkt);
+            if pkt[..] != pack::PKT_LINE_END_MARKER[..] {
+                continue;
+            }
+            let mut request = state.archive_request.split().freeze();
+            let (buf, archive_stream) = pack_protocol.git_upload_archive(&mut request).await;
             session.data(channel, buf.to_vec().into());
-        } else {
-            session.close(channel);
+            let Some(archive_stream) = archive_stream else {
+                session.close(channel);
+                return Ok(());
+            };
+            state.streaming = true;
+            let pack_protocol = pack_protocol.clone();
+            let handle = session.handle();
+            tokio::spawn(async move {
+                send_side_band(&handle, channel, archive_stream, &pack_protocol).await;
+                let _ = handle.close(channel).await;
+            });
+            return Ok(());
         }
+        Ok(())
+    }
+}
+
+// reports a protocol error on stderr, git shows it as `fatal: <message>`, and ends the command
+fn send_error(session: &mut Session, channel: ChannelId, error: &anyhow::Error) {
+    tracing::error!("SSH: channel {:?} failed: {}", channel, error);
+    let message = format!("fatal: {}\n", error);
+    session.extended_data(channel, 1, message.into_bytes().into());
+    session.exit_status_request(channel, 128);
+    session.close(channel);
+}
+
+/// Sends a side-band stream over the channel, an error is reported to the client on band 3.
+///
+/// Returns `false` if the stream failed or the channel was closed.
+async fn send_side_band(
+    handle: &Handle,
+    channel: ChannelId,
+    mut stream: SideBandStream,
+    pack_protocol: &PackProtocol,
+) -> bool {
+    while let Some(message) = stream.next().await {
+        let Some(bytes_out) = pack_protocol.build_side_band_message(&message) else {
+            return false;
+        };
+        tracing::info!("send: packet lentgh : {:?}", bytes_out.len());
+        if handle
+            .data(channel, bytes_out.to_vec().into())
+            .await
+            .is_err()
+            || message.is_err()
+        {
+            return false;
+        }
+    }
+    if pack_protocol.is_side_band() {
+        let mut bytes_out = BytesMut::new();
+        bytes_out.put_slice(pack::PKT_LINE_END_MARKER);
+        tracing::info!("send: ends: {:?}", bytes_out.clone().freeze());
+        let _ = handle.data(channel, bytes_out.to_vec().into()).await;
     }
+    true
 }
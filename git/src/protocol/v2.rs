@@ -0,0 +1,480 @@
+//!
+//! Git wire protocol version 2, see [protocol-v2](https://git-scm.com/docs/protocol-v2).
+//!
+//! In protocol v2 the server no longer starts with a full ref advertisement. It advertises its
+//! capabilities and the client then issues one command per request: `ls-refs` to list (a filtered
+//! subset of) the refs, `fetch` to negotiate and receive a pack, and `object-info` to query
+//! object metadata.
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::pack::{add_pkt_line_string, LF, PKT_LINE_DELIM_MARKER, PKT_LINE_END_MARKER, SP};
+use super::sideband::SideBandStream;
+use super::{Capability, Deepen, PackProtocol};
+use crate::hash::{get_hash_kind, is_zero_id, with_hash_kind};
+use crate::structure::filter::ObjectFilter;
+
+const AGENT: &str = concat!("agent=mega/", env!("CARGO_PKG_VERSION"));
+
+// Capabilities advertised after the `version 2` line, each one in its own pkt-line.
+// The `object-format` capability follows them with the format of the repo.
+const V2_CAP_LIST: [&str; 3] = ["ls-refs", "fetch=shallow filter", "object-info"];
+
+/// A single pkt-line of a protocol v2 stream, including the special packets.
+#[derive(Debug, PartialEq)]
+pub enum PktLine {
+    /// `0000`, ends a request or a response
+    Flush,
+    /// `0001`, separates the capability list from the command arguments
+    Delim,
+    /// `0002`, ends a response in stateless connections
+    ResponseEnd,
+    Data(Bytes),
+}
+
+/// A command request sent by the client:
+///
+/// ```bash
+/// command=<name> LF
+/// *capability-list
+/// delim-pkt
+/// *command-args
+/// flush-pkt
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub struct CommandRequest {
+    pub command: String,
+    pub capabilities: Vec<String>,
+    pub args: Vec<String>,
+}
+
+/// Arguments of the `fetch` command.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FetchArgs {
+    pub wants: HashSet<String>,
+    pub haves: HashSet<String>,
+    pub done: bool,
+    pub thin_pack: bool,
+    pub no_progress: bool,
+    pub include_tag: bool,
+    pub ofs_delta: bool,
+    pub deepen: Deepen,
+    pub filter: Option<ObjectFilter>,
+}
+
+impl FetchArgs {
+    pub fn parse(args: &[String]) -> Result<FetchArgs> {
+        let mut fetch_args = FetchArgs::default();
+        for arg in args {
+            if fetch_args.deepen.parse_line(arg)? {
+                continue;
+            }
+            match arg.split_once(SP) {
+                Some(("want", id)) => {
+                    fetch_args.wants.insert(id.to_owned());
+                }
+                Some(("have", id)) => {
+                    fetch_args.haves.insert(id.to_owned());
+                }
+                Some(("filter", spec)) => fetch_args.filter = Some(spec.parse()?),
+                _ => match arg.as_str() {
+                    "done" => fetch_args.done = true,
+                    "thin-pack" => fetch_args.thin_pack = true,
+                    "no-progress" => fetch_args.no_progress = true,
+                    "include-tag" => fetch_args.include_tag = true,
+                    "ofs-delta" => fetch_args.ofs_delta = true,
+                    other => tracing::warn!("unsupported fetch argument: {}", other),
+                },
+            }
+        }
+        Ok(fetch_args)
+    }
+}
+
+impl PackProtocol {
+    /// # Builds the protocol v2 capability advertisement.
+    ///
+    /// Unlike v0, the server does not list any refs here, the client asks for them
+    /// afterwards with the `ls-refs` command.
+    ///
+    /// ```bash
+    /// version 2
+    /// agent=mega/0.1.0
+    /// ls-refs
+    /// fetch=shallow filter
+    /// object-info
+    /// object-format=sha1
+    /// ```
+    ///
+    /// git http-backend only sends the `# service=` header for v0 advertisements, so it is
+    /// omitted here for every transport.
+    pub fn git_capability_advertisement(&self) -> BytesMut {
+        let mut pkt_line_stream = BytesMut::new();
+        add_pkt_line_string(&mut pkt_line_stream, format!("version 2{}", LF));
+        add_pkt_line_string(&mut pkt_line_stream, format!("{}{}", AGENT, LF));
+        for cap in V2_CAP_LIST {
+            add_pkt_line_string(&mut pkt_line_stream, format!("{}{}", cap, LF));
+        }
+        let object_format = format!("object-format={}{}", get_hash_kind(), LF);
+        add_pkt_line_string(&mut pkt_line_stream, object_format);
+        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
+        pkt_line_stream
+    }
+
+    /// # Handles the command requests of a protocol v2 upload-pack session.
+    ///
+    /// Over HTTP each request carries exactly one command, over SSH the client may send several
+    /// commands back to back, so every complete command found in `request` is executed and the
+    /// responses are concatenated.
+    ///
+    /// A `fetch` that sends a pack ends the response: the pack comes as a side-band stream,
+    /// see [`PackProtocol::fetch`], the commands after it are left in `request`.
+    pub async fn git_upload_pack_v2(
+        &mut self,
+        request: &mut Bytes,
+    ) -> Result<(BytesMut, Option<SideBandStream>)> {
+        let kind = self.get_object_format().await?;
+        with_hash_kind(kind, self.upload_pack_v2(request)).await
+    }
+
+    async fn upload_pack_v2(
+        &mut self,
+        request: &mut Bytes,
+    ) -> Result<(BytesMut, Option<SideBandStream>)> {
+        let mut response = BytesMut::new();
+        while let Some(command) = parse_command_request(request)? {
+            tracing::debug!("v2 command: {:?}", command);
+            match command.command.as_str() {
+                "ls-refs" => response.put(self.ls_refs(&command.args).await?),
+                "fetch" => {
+                    let (buf, pack_stream) = self.fetch(&command.args).await?;
+                    response.put(buf);
+                    if pack_stream.is_some() {
+                        return Ok((response, pack_stream));
+                    }
+                }
+                "object-info" => response.put(self.object_info(&command.args).await?),
+                other => bail!("unknown protocol v2 command: {}", other),
+            }
+        }
+        Ok((response, None))
+    }
+
+    /// # Lists the refs of the repository, filtered by the `ref-prefix` arguments.
+    ///
+    /// ```bash
+    /// output = *ref
+    ///          flush-pkt
+    /// ref = PKT-LINE(obj-id SP refname *(SP ref-attribute) LF)
+    /// ```
+    pub async fn ls_refs(&self, args: &[String]) -> Result<BytesMut> {
+        let mut prefixes = Vec::new();
+        let mut peel = false;
+        let mut symrefs = false;
+        for arg in args {
+            match arg.split_once(SP) {
+                Some(("ref-prefix", prefix)) => prefixes.push(prefix.to_owned()),
+                _ => match arg.as_str() {
+                    "peel" => peel = true,
+                    "symrefs" => symrefs = true,
+                    "unborn" => {}
+                    other => tracing::warn!("unsupported ls-refs argument: {}", other),
+                },
+            }
+        }
+        let is_wanted =
+            |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+        let mut pkt_line_stream = BytesMut::new();
+        let head_id = self.get_head_object_id(&self.path).await;
+        if !is_zero_id(&head_id) && is_wanted("HEAD") {
+            let head_ref = if symrefs {
+                self.get_head_ref(&self.path).await
+            } else {
+                None
+            };
+            let attributes = match head_ref {
+                Some(head_ref) => format!("{}symref-target:{}", SP, head_ref),
+                None => String::new(),
+            };
+            add_pkt_line_string(
+                &mut pkt_line_stream,
+                format!("{}{}HEAD{}{}", head_id, SP, attributes, LF),
+            );
+        }
+        let git_refs = self
+            .storage
+            .get_ref_object_id(self.path.to_str().unwrap())
+            .await
+            .map_err(|e| anyhow!("{}", e))?;
+        let peeled_refs = if peel {
+            self.get_peeled_refs(&self.path).await
+        } else {
+            HashMap::new()
+        };
+        for git_ref in git_refs.iter().filter(|r| is_wanted(&r.ref_name)) {
+            let attributes = match peeled_refs.get(&git_ref.ref_name) {
+                Some(peeled) => format!("{}peeled:{}", SP, peeled),
+                None => String::new(),
+            };
+            add_pkt_line_string(
+                &mut pkt_line_stream,
+                format!(
+                    "{}{}{}{}{}",
+                    git_ref.ref_git_id, SP, git_ref.ref_name, attributes, LF
+                ),
+            );
+        }
+        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
+        Ok(pkt_line_stream)
+    }
+
+    /// # Negotiates with the client and sends the packfile.
+    ///
+    /// ```bash
+    /// output = acknowledgements flush-pkt |
+    ///          [acknowledgments delim-pkt] [shallow-info delim-pkt]
+    ///          [wanted-refs delim-pkt] [packfile-uris delim-pkt]
+    ///          packfile flush-pkt
+    /// ```
+    ///
+    /// The acknowledgments section is only sent while the client has not sent `done`; if no
+    /// common commit is found yet the response ends there and the client sends more `have` lines.
+    ///
+    /// Returns the response up to the `packfile` line, and the stream of the pack once the
+    /// negotiation is over. The pack is sent on band 1 of side-band-64k, the progress on band 2
+    /// unless the client asked for `no-progress`, and the response ends with a flush-pkt, which
+    /// is what [`PackProtocol::build_side_band_message`] produces for the stream.
+    pub async fn fetch(&mut self, args: &[String]) -> Result<(BytesMut, Option<SideBandStream>)> {
+        let fetch_args = FetchArgs::parse(args)?;
+        if fetch_args.wants.is_empty() {
+            bail!("fetch command without any want");
+        }
+        let graph = self.get_commit_graph(&self.path).await?;
+        let unreachable = self
+            .find_unreachable_want(&self.path, &graph, &fetch_args.wants)
+            .await;
+        if let Some(id) = unreachable {
+            bail!("upload-pack: not our ref {}", id);
+        }
+        // in v2 the packfile section is always multiplexed
+        self.capabilities.push(Capability::SideBand64k);
+        if fetch_args.ofs_delta {
+            self.capabilities.push(Capability::OfsDelta);
+        }
+
+        let haves: Vec<String> = fetch_args.haves.iter().cloned().collect();
+        let (common, ready) = self
+            .negotiate(&self.path, &graph, &fetch_args.wants, &haves)
+            .await;
+
+        let mut pkt_line_stream = BytesMut::new();
+        if !fetch_args.done {
+            add_pkt_line_string(&mut pkt_line_stream, format!("acknowledgments{}", LF));
+            if common.is_empty() {
+                add_pkt_line_string(&mut pkt_line_stream, format!("NAK{}", LF));
+            }
+            for hash in &common {
+                add_pkt_line_string(&mut pkt_line_stream, format!("ACK {}{}", hash, LF));
+            }
+            if !ready {
+                pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
+                return Ok((pkt_line_stream, None));
+            }
+            add_pkt_line_string(&mut pkt_line_stream, format!("ready{}", LF));
+            pkt_line_stream.put(&PKT_LINE_DELIM_MARKER[..]);
+        }
+
+        let common: HashSet<String> = common.into_iter().collect();
+        let deepen = &fetch_args.deepen;
+        let shallow_info = if deepen.is_requested() || !deepen.shallow.is_empty() {
+            let info = self
+                .get_shallow_info(&self.path, &graph, &fetch_args.wants, deepen)
+                .await?;
+            add_pkt_line_string(&mut pkt_line_stream, format!("shallow-info{}", LF));
+            for id in &info.shallow {
+                add_pkt_line_string(&mut pkt_line_stream, format!("shallow {}{}", id, LF));
+            }
+            for id in &info.unshallow {
+                add_pkt_line_string(&mut pkt_line_stream, format!("unshallow {}{}", id, LF));
+            }
+            pkt_line_stream.put(&PKT_LINE_DELIM_MARKER[..]);
+            Some(info)
+        } else {
+            None
+        };
+        if fetch_args.thin_pack && !self.capabilities.contains(&Capability::ThinPack) {
+            self.capabilities.push(Capability::ThinPack);
+        }
+        if fetch_args.include_tag && !self.capabilities.contains(&Capability::IncludeTag) {
+            self.capabilities.push(Capability::IncludeTag);
+        }
+        if fetch_args.no_progress && !self.capabilities.contains(&Capability::NoProgress) {
+            self.capabilities.push(Capability::NoProgress);
+        }
+        let pack_stream = self.get_pack_stream(
+            &self.path,
+            &fetch_args.wants,
+            &common,
+            shallow_info.as_ref(),
+            fetch_args.filter.as_ref(),
+        );
+        add_pkt_line_string(&mut pkt_line_stream, format!("packfile{}", LF));
+        Ok((pkt_line_stream, Some(pack_stream)))
+    }
+
+    /// # Retrieves the size of the requested objects.
+    ///
+    /// ```bash
+    /// output = info flush-pkt
+    /// info = PKT-LINE(attrs) LF)
+    ///        *PKT-LINE(obj-info LF)
+    /// obj-info = obj-id SP obj-size
+    /// ```
+    ///
+    /// Objects that can't be found are answered with an empty size, like `git upload-pack` does.
+    pub async fn object_info(&self, args: &[String]) -> Result<BytesMut> {
+        let mut size = false;
+        let mut oids = Vec::new();
+        for arg in args {
+            match arg.split_once(SP) {
+                Some(("oid", id)) => oids.push(id.to_owned()),
+                _ if arg == "size" => size = true,
+                _ => tracing::warn!("unsupported object-info argument: {}", arg),
+            }
+        }
+
+        let mut pkt_line_stream = BytesMut::new();
+        if size {
+            add_pkt_line_string(&mut pkt_line_stream, format!("size{}", LF));
+        }
+        for oid in oids {
+            let mut line = oid.clone();
+            if size {
+                line.push(SP);
+                if let Ok(Some(model)) = self.storage.get_obj_data_by_id(&oid).await {
+                    line.push_str(&model.data.len().to_string());
+                }
+            }
+            line.push(LF);
+            add_pkt_line_string(&mut pkt_line_stream, line);
+        }
+        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
+        Ok(pkt_line_stream)
+    }
+}
+
+/// Reads one pkt-line from `bytes`, returns `None` if there is no more data.
+pub fn read_v2_pkt_line(bytes: &mut Bytes) -> Result<Option<PktLine>> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    if bytes.len() < 4 {
+        bail!("truncated pkt-line length: {:?}", bytes);
+    }
+    let pkt_length = bytes.copy_to_bytes(4);
+    let pkt_length = usize::from_str_radix(std::str::from_utf8(&pkt_length)?, 16)?;
+    match pkt_length {
+        0 => Ok(Some(PktLine::Flush)),
+        1 => Ok(Some(PktLine::Delim)),
+        2 => Ok(Some(PktLine::ResponseEnd)),
+        3 => bail!("invalid pkt-line length: {}", pkt_length),
+        _ if bytes.len() < pkt_length - 4 => bail!("truncated pkt-line"),
+        _ => Ok(Some(PktLine::Data(bytes.copy_to_bytes(pkt_length - 4)))),
+    }
+}
+
+/// Parses the next command request from `bytes`, returns `None` once the client has no more
+/// commands to send.
+pub fn parse_command_request(bytes: &mut Bytes) -> Result<Option<CommandRequest>> {
+    let mut request = CommandRequest::default();
+    let mut in_args = false;
+    loop {
+        let line = match read_v2_pkt_line(bytes)? {
+            None if request.command.is_empty() => return Ok(None),
+            None => bail!("command request is not terminated by a flush-pkt"),
+            Some(line) => line,
+        };
+        match line {
+            // a lone flush-pkt tells the server the client is done
+            PktLine::Flush if request.command.is_empty() => return Ok(None),
+            PktLine::Flush => return Ok(Some(request)),
+            PktLine::Delim => in_args = true,
+            PktLine::ResponseEnd => bail!("unexpected response-end-pkt in request"),
+            PktLine::Data(data) => {
+                let line = String::from_utf8(data.to_vec())?;
+                let line = line.trim_end_matches(LF).to_owned();
+                if in_args {
+                    request.args.push(line);
+                } else if let Some(command) = line.strip_prefix("command=") {
+                    request.command = command.to_owned();
+                } else {
+                    request.capabilities.push(line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::protocol::PackProtocol;
+
+    use super::{parse_command_request, FetchArgs};
+
+    #[test]
+    fn test_parse_command_request() {
+        let mut bytes = Bytes::from_static(
+            b"0014command=ls-refs\n0015agent=git/2.40.0\n00010009peel\n000csymrefs\n001bref-prefix refs/heads/\n0000",
+        );
+        let request = parse_command_request(&mut bytes).unwrap().unwrap();
+        assert_eq!(request.command, "ls-refs");
+        assert_eq!(request.capabilities, vec!["agent=git/2.40.0"]);
+        assert_eq!(
+            request.args,
+            vec!["peel", "symrefs", "ref-prefix refs/heads/"]
+        );
+        assert!(parse_command_request(&mut bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_command_request_flush_only() {
+        let mut bytes = Bytes::from_static(b"0000");
+        assert!(parse_command_request(&mut bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_fetch_args() {
+        let args = vec![
+            String::from("thin-pack"),
+            String::from("ofs-delta"),
+            String::from("want 7bdc783132575d5b3e78400ace9971970ff43a18"),
+            String::from("have 27dd8d4cf39f3868c6eee38b601bc9e9939304f5"),
+            String::from("deepen 1"),
+            String::from("done"),
+        ];
+        let fetch_args = FetchArgs::parse(&args).unwrap();
+        assert!(fetch_args.thin_pack && fetch_args.ofs_delta && fetch_args.done);
+        assert!(fetch_args
+            .wants
+            .contains("7bdc783132575d5b3e78400ace9971970ff43a18"));
+        assert!(fetch_args
+            .haves
+            .contains("27dd8d4cf39f3868c6eee38b601bc9e9939304f5"));
+        assert_eq!(fetch_args.deepen.depth, Some(1));
+    }
+
+    #[test]
+    fn test_capability_advertisement() {
+        let mock = PackProtocol::mock();
+        let advertisement = mock.git_capability_advertisement();
+        assert!(advertisement.starts_with(b"000eversion 2\n"));
+        assert!(advertisement.ends_with(b"0000"));
+    }
+}
@@ -1,23 +1,34 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::path::{Component, Path, PathBuf};
 use std::{collections::HashSet, sync::Arc};
 
+use super::filter::{ObjectFilter, TreeFilter};
 use super::nodes::NodeBuilder;
+use super::tags;
 use crate::errors::GitError;
-use crate::hash::Hash;
+use crate::hash::{get_hash_kind, sync_with_hash_kind, Hash};
 use crate::internal::object::blob::Blob;
 use crate::internal::object::commit::Commit;
-use crate::internal::object::tree::Tree;
+use crate::internal::object::signature::Signature;
+use crate::internal::object::tree::{Tree, TreeItemMode};
 use crate::internal::object::ObjectT;
-use crate::internal::pack::encode::pack_encode;
-use crate::protocol::PackProtocol;
+use crate::internal::pack::delta::DeltaIndex;
+use crate::internal::pack::encode::{
+    name_hash, DeltaObject, DeltaOptions, Encoder, MAX_DELTA_OBJECT_SIZE,
+};
+use crate::internal::ObjectType;
+use crate::protocol::hooks::{ChangedPath, RefChange};
+use crate::protocol::sideband::{spawn_side_band_stream, Progress, SideBandSender, SideBandStream};
+use crate::protocol::{Capability, CommandType, Deepen, PackProtocol, RefCommand, SideBind};
+use crate::utils;
 use anyhow::Result;
 use async_recursion::async_recursion;
-use common::utils::ZERO_ID;
+use bytes::Bytes;
 use database::driver::ObjectStorage;
-use entity::{git_obj, refs, repo_directory};
+use entity::{commit, git_obj, refs, repo_directory};
+use futures::StreamExt;
 use sea_orm::ActiveValue::NotSet;
-use sea_orm::Set;
+use sea_orm::{Set, TryIntoModel};
 
 impl PackProtocol {
     /// Asynchronously retrieves the full pack data for the specified repository path.
@@ -32,78 +43,578 @@ impl PackProtocol {
     /// * `Result<Vec<u8>, GitError>` - The packed binary data as a vector of bytes.
     ///
     pub async fn get_full_pack_data(&self, repo_path: &Path) -> Result<Vec<u8>, GitError> {
-        let mut hash_object: HashMap<Hash, Arc<dyn ObjectT>> = HashMap::new();
-        let commit_models = self
+        collect_pack_stream(self.get_full_pack_stream(repo_path)).await
+    }
+
+    /// Streams the full pack of a repository, see [`PackProtocol::get_full_pack_data`].
+    ///
+    /// Only the object ids are collected up front, the object data is paged out of the
+    /// storage while the pack is being sent. The progress goes to band 2 of the stream.
+    pub fn get_full_pack_stream(&self, repo_path: &Path) -> SideBandStream {
+        let protocol = self.clone();
+        let repo_path = repo_path.to_path_buf();
+        spawn_side_band_stream(move |sender| async move {
+            protocol.generate_full_pack(&repo_path, &sender).await
+        })
+    }
+
+    async fn generate_full_pack(
+        &self,
+        repo_path: &Path,
+        sender: &SideBandSender,
+    ) -> Result<(), GitError> {
+        let commits: Vec<Commit> = self
             .storage
             .get_all_commits_by_path(repo_path.to_str().unwrap())
             .await
-            .unwrap();
-        commit_models.into_iter().for_each(|model| {
-            let commit: Commit = model.into();
-            hash_object.insert(commit.id, Arc::new(commit));
-        });
-        let blob_and_tree = self.storage.get_node_by_path(repo_path).await.unwrap();
-        let git_ids = blob_and_tree
-            .iter()
-            .map(|model| model.git_id.clone())
+            .unwrap()
+            .into_iter()
+            .map(|model| model.into())
             .collect();
-        // may take lots of time
-        let obj_datas = self.storage.get_obj_data_by_ids(git_ids).await.unwrap();
-        obj_datas.iter().for_each(|model| {
-            let hash = Hash::new_from_str(&model.git_id);
-            let obj: Arc<dyn ObjectT> = match model.object_type.as_str() {
-                "blob" => {
-                    let mut blob = Blob::new_from_data(model.data.clone());
-                    blob.set_hash(hash);
-                    Arc::new(blob)
-                }
-                "tree" => {
-                    let mut tree = Tree::new_from_data(model.data.clone());
-                    tree.set_hash(hash);
-                    Arc::new(tree)
-                }
-                _ => panic!("not supported node type: {}", model.object_type),
-            };
-            hash_object.insert(hash, obj);
-        });
-        let meta_vec: Vec<Arc<dyn ObjectT>> = hash_object.into_values().collect();
-        let result: Vec<u8> = pack_encode(meta_vec).unwrap();
-        Ok(result)
+        let mut seen = HashSet::new();
+        let mut objects: Vec<PackObject> = self
+            .storage
+            .get_node_by_path(repo_path)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|model| seen.insert(model.git_id.clone()))
+            .map(|model| {
+                let object_type = match model.node_type.as_str() {
+                    "tree" => ObjectType::Tree,
+                    _ => ObjectType::Blob,
+                };
+                PackObject::new(model.git_id, object_type, Path::new(&model.full_path))
+            })
+            .collect();
+        // the tag objects of the annotated tags, the lightweight ones are only refs
+        objects.extend(
+            self.get_annotated_tags(repo_path)
+                .await
+                .into_keys()
+                .map(PackObject::tag),
+        );
+        let counting = Progress::new("Counting objects", None);
+        let count = commits.len() + objects.len();
+        send_progress(sender, counting.done(count)).await;
+        encode_pack(
+            self.storage.clone(),
+            commits,
+            objects,
+            &HashMap::new(),
+            self.delta_options(),
+            sender,
+        )
+        .await
     }
 
+    /// Asynchronously retrieves the pack data a client needs to update from the commits it
+    /// already has (`have`) to the commits it asks for (`want`).
+    ///
+    /// The commit graph is walked through `commit::Model.pid` starting from every `want`, the
+    /// walk stops at any commit reachable from a common `have`. Trees and blobs reachable from
+    /// the common commits are also known by the client, so they are left out of the pack.
+    ///
+    /// # Arguments
+    /// * `repo_path` - The path to the repository.
+    /// * `want` - The commit ids requested by the client.
+    /// * `have` - The commit ids the client already has, ids unknown to the server are ignored.
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>, GitError>` - The packed binary data as a vector of bytes.
     pub async fn get_incremental_pack_data(
         &self,
         repo_path: &Path,
         want: &HashSet<String>,
-        _have: &HashSet<String>,
+        have: &HashSet<String>,
+    ) -> Result<Vec<u8>, GitError> {
+        self.get_pack_data(repo_path, want, have, None, None).await
+    }
+
+    /// Asynchronously retrieves the pack data of an upload request.
+    ///
+    /// Besides the negotiation done by [`PackProtocol::get_incremental_pack_data`]:
+    /// - with a `shallow` request only the commits of the truncated history are packed.
+    /// - with a `filter` the trees and blobs are filtered while walking the trees.
+    /// - `want` may also contain tree or blob ids, which is how promisor clients fetch the
+    ///   objects a partial clone left out. Those objects are always sent.
+    ///
+    /// A plain clone without any of them sends all the objects of the repo.
+    pub async fn get_pack_data(
+        &self,
+        repo_path: &Path,
+        want: &HashSet<String>,
+        have: &HashSet<String>,
+        shallow: Option<&ShallowInfo>,
+        filter: Option<&ObjectFilter>,
     ) -> Result<Vec<u8>, GitError> {
-        let mut hash_meta: HashMap<String, Arc<dyn ObjectT>> = HashMap::new();
-        let all_commits = self
+        let stream = self.get_pack_stream(repo_path, want, have, shallow, filter);
+        collect_pack_stream(stream).await
+    }
+
+    /// Streams the pack of an upload request, see [`PackProtocol::get_pack_data`].
+    ///
+    /// The objects are enumerated and encoded in a background task, which reports its progress
+    /// on band 2 of the stream. Errors, like an unknown `sparse:oid`, end the stream.
+    pub fn get_pack_stream(
+        &self,
+        repo_path: &Path,
+        want: &HashSet<String>,
+        have: &HashSet<String>,
+        shallow: Option<&ShallowInfo>,
+        filter: Option<&ObjectFilter>,
+    ) -> SideBandStream {
+        let protocol = self.clone();
+        let repo_path = repo_path.to_path_buf();
+        let (want, have) = (want.clone(), have.clone());
+        let (shallow, filter) = (shallow.cloned(), filter.cloned());
+        spawn_side_band_stream(move |sender| async move {
+            protocol
+                .generate_pack(
+                    &repo_path,
+                    &want,
+                    &have,
+                    shallow.as_ref(),
+                    filter.as_ref(),
+                    &sender,
+                )
+                .await
+        })
+    }
+
+    async fn generate_pack(
+        &self,
+        repo_path: &Path,
+        want: &HashSet<String>,
+        have: &HashSet<String>,
+        shallow: Option<&ShallowInfo>,
+        filter: Option<&ObjectFilter>,
+        sender: &SideBandSender,
+    ) -> Result<(), GitError> {
+        let graph = self.get_commit_graph(repo_path).await?;
+        // a wanted annotated tag is sent along with the history of the object it points to
+        let annotated_tags = self.get_annotated_tags(repo_path).await;
+        let want_tags: Vec<String> = want
+            .iter()
+            .filter(|id| annotated_tags.contains_key(*id))
+            .cloned()
+            .collect();
+        let want = &tags::peel(&annotated_tags, want);
+        let common: HashSet<String> = have
+            .iter()
+            .filter(|id| graph.contains_key(*id))
+            .cloned()
+            .collect();
+        let object_wants: HashSet<String> = want
+            .iter()
+            .filter(|id| !graph.contains_key(*id))
+            .cloned()
+            .collect();
+        if common.is_empty() && object_wants.is_empty() && shallow.is_none() && filter.is_none() {
+            return self.generate_full_pack(repo_path, sender).await;
+        }
+
+        let known_commits = get_ancestors(&graph, common.iter(), &HashSet::new());
+        let send_commits = match shallow {
+            Some(info) => info
+                .commits
+                .iter()
+                .filter(|id| !known_commits.contains(*id))
+                .cloned()
+                .collect(),
+            None => get_ancestors(&graph, want.iter(), &known_commits),
+        };
+
+        let sparse_spec = match filter {
+            Some(ObjectFilter::SparseOid(oid)) => {
+                match self.storage.get_obj_data_by_id(oid).await {
+                    Ok(Some(model)) => Some(model.data),
+                    _ => return Err(GitError::InvalidFilter(format!("sparse:oid={}", oid))),
+                }
+            }
+            _ => None,
+        };
+        let tree_filter = TreeFilter::new(filter, sparse_spec.as_deref());
+        let (commits, mut objects, delta_bases) = self
+            .enumerate_objects(
+                &graph,
+                send_commits,
+                &common,
+                &object_wants,
+                &tree_filter,
+                sender,
+            )
+            .await?;
+        // with `include-tag` the annotated tags pointing into the pack are sent too
+        if self.capabilities.contains(&Capability::IncludeTag) {
+            let sent: HashSet<String> = commits.iter().map(|c| c.id.to_plain_str()).collect();
+            objects.extend(
+                annotated_tags
+                    .values()
+                    .filter(|t| sent.contains(&t.object_id) && !want_tags.contains(&t.tag_id))
+                    .map(|t| PackObject::tag(t.tag_id.clone())),
+            );
+        }
+        objects.extend(want_tags.into_iter().map(PackObject::tag));
+        encode_pack(
+            self.storage.clone(),
+            commits,
+            objects,
+            &delta_bases,
+            self.delta_options(),
+            sender,
+        )
+        .await
+    }
+
+    // deltas refer to their base by offset if the client advertised `ofs-delta`
+    fn delta_options(&self) -> DeltaOptions {
+        DeltaOptions::from_env(self.capabilities.contains(&Capability::OfsDelta))
+    }
+
+    /// Truncates the history reachable from `want` according to the `deepen` request.
+    ///
+    /// `deepen-not` revisions can be either commit ids or ref names, ref names are resolved
+    /// against the refs of the repo. `graph` is the commit graph of the repo, see
+    /// [`PackProtocol::get_commit_graph`].
+    pub async fn get_shallow_info(
+        &self,
+        repo_path: &Path,
+        graph: &HashMap<String, commit::Model>,
+        want: &HashSet<String>,
+        deepen: &Deepen,
+    ) -> Result<ShallowInfo, GitError> {
+        let mut not_ids = vec![];
+        if !deepen.not.is_empty() {
+            let refs = self
+                .storage
+                .get_ref_object_id(repo_path.to_str().unwrap())
+                .await?;
+            for rev in &deepen.not {
+                if graph.contains_key(rev) {
+                    not_ids.push(rev.clone());
+                } else if let Some(r) = refs.iter().find(|r| {
+                    r.ref_name == *rev
+                        || r.ref_name == format!("refs/heads/{}", rev)
+                        || r.ref_name == format!("refs/tags/{}", rev)
+                }) {
+                    not_ids.push(r.ref_git_id.clone());
+                } else {
+                    tracing::warn!("deepen-not: unknown revision {}", rev);
+                }
+            }
+        }
+        // a ref may name an annotated tag
+        let not_ids = self
+            .peel_wants(repo_path, &not_ids.into_iter().collect())
+            .await;
+        let excluded = get_ancestors(graph, not_ids.iter(), &HashSet::new());
+        let want = self.peel_wants(repo_path, want).await;
+        Ok(shallow_walk(graph, &want, deepen, &excluded))
+    }
+
+    // collects the given commits and their trees and blobs, skipping the objects
+    // reachable from the `common` commits, `object_wants` are trees or blobs explicitly asked for
+    async fn enumerate_objects(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        send_commits: HashSet<String>,
+        common: &HashSet<String>,
+        object_wants: &HashSet<String>,
+        filter: &TreeFilter,
+        sender: &SideBandSender,
+    ) -> Result<(Vec<Commit>, Vec<PackObject>, HashMap<String, String>), GitError> {
+        // objects of the common commits are already on the client side, with `thin-pack` they
+        // can be the bases of the deltas
+        let thin = self.capabilities.contains(&Capability::ThinPack) && !common.is_empty();
+        let mut thin_bases = thin.then(ThinPackBases::default);
+        let mut known_objects = HashSet::new();
+        for commit_id in common {
+            let tree_id = &graph[commit_id].tree;
+            if known_objects.insert(tree_id.clone()) {
+                let mut known_paths = thin_bases.as_mut().map(|thin| &mut thin.known);
+                if let Some(known_paths) = known_paths.as_deref_mut() {
+                    known_paths.insert(PathBuf::new(), tree_id.clone());
+                }
+                get_tree_ids(
+                    tree_id,
+                    Path::new(""),
+                    &mut known_objects,
+                    known_paths,
+                    self.storage.clone(),
+                )
+                .await;
+            }
+        }
+
+        // only the ids are kept here, the data is read again while encoding
+        let mut objects: Vec<PackObject> = vec![];
+        let mut seen = HashSet::new();
+        let objs = self
             .storage
-            .get_all_commits_by_path(repo_path.to_str().unwrap())
+            .get_obj_data_by_ids(object_wants.iter().cloned().collect())
             .await
             .unwrap();
+        for obj in objs {
+            match obj.object_type.as_str() {
+                "tree" => {
+                    get_child_trees(
+                        &obj,
+                        Path::new(""),
+                        0,
+                        &mut objects,
+                        &mut seen,
+                        &HashSet::new(),
+                        filter,
+                        thin_bases.as_mut(),
+                        self.storage.clone(),
+                    )
+                    .await
+                }
+                "blob" => {
+                    if seen.insert(obj.git_id.clone()) {
+                        objects.push(PackObject::new(obj.git_id, ObjectType::Blob, Path::new("")));
+                    }
+                }
+                other => tracing::warn!("unsupported object type in want: {}", other),
+            }
+        }
 
-        for model in all_commits {
-            let commit_id = model.git_id.clone();
-            if want.contains(&commit_id) {
-                let c: Commit = model.into();
-                if let Some(root) = self
-                    .storage
-                    .get_obj_data_by_id(&c.tree_id.to_plain_str())
+        let mut commits = vec![];
+        let mut counting = Progress::new("Counting objects", None);
+        for commit_id in send_commits {
+            let c: Commit = graph[&commit_id].clone().into();
+            let tree_id = c.tree_id.to_plain_str();
+            if filter.keep_tree(0) && !known_objects.contains(&tree_id) && !seen.contains(&tree_id)
+            {
+                if let Some(root) = self.storage.get_obj_data_by_id(&tree_id).await.unwrap() {
+                    get_child_trees(
+                        &root,
+                        Path::new(""),
+                        0,
+                        &mut objects,
+                        &mut seen,
+                        &known_objects,
+                        filter,
+                        thin_bases.as_mut(),
+                        self.storage.clone(),
+                    )
                     .await
-                    .unwrap()
-                {
-                    get_child_trees(&root, &mut hash_meta, self.storage.clone()).await
                 } else {
-                    return Err(GitError::InvalidTreeObject(c.tree_id.to_plain_str()));
+                    return Err(GitError::InvalidTreeObject(tree_id));
                 };
-                hash_meta.insert(commit_id, Arc::new(c));
+            }
+            commits.push(c);
+            if let Some(message) = counting.update(commits.len() + objects.len()) {
+                send_progress(sender, message).await;
+            }
+        }
+        send_progress(sender, counting.done(commits.len() + objects.len())).await;
+        let delta_bases = thin_bases.map(|thin| thin.bases).unwrap_or_default();
+        Ok((commits, objects, delta_bases))
+    }
+
+    /// Finds out which of the client's `have` commits are known by the server, and whether
+    /// they are enough to build a pack for every `want`.
+    ///
+    /// # Returns
+    /// * `(Vec<String>, bool)` - The common commits in the order the client sent them, and
+    ///   `true` if each `want` can reach at least one of them ("ready" in the protocol).
+    pub async fn negotiate(
+        &self,
+        repo_path: &Path,
+        graph: &HashMap<String, commit::Model>,
+        want: &HashSet<String>,
+        have: &[String],
+    ) -> (Vec<String>, bool) {
+        let mut common: Vec<String> = vec![];
+        for id in have {
+            if graph.contains_key(id) && !common.contains(id) {
+                common.push(id.clone());
+            }
+        }
+        let want = self.peel_wants(repo_path, want).await;
+        let ready = !common.is_empty()
+            && want.iter().all(|id| {
+                let ancestors = get_ancestors(graph, std::iter::once(id), &HashSet::new());
+                common.iter().any(|c| ancestors.contains(c))
+            });
+        (common, ready)
+    }
+
+    /// Checks the `want` ids of an upload request against the refs of the repo. The storage is
+    /// shared by all the repos, so an object is only served when a ref of the repo reaches it,
+    /// as `allow-reachable-sha1-in-want` means: commits are looked up in the history of the
+    /// refs, trees and blobs in the trees of that history.
+    ///
+    /// Returns the first `want` that isn't reachable.
+    pub async fn find_unreachable_want(
+        &self,
+        repo_path: &Path,
+        graph: &HashMap<String, commit::Model>,
+        want: &HashSet<String>,
+    ) -> Option<String> {
+        let tips: HashSet<String> = self
+            .storage
+            .get_ref_object_id(repo_path.to_str().unwrap())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.ref_git_id)
+            .collect();
+        let mut pending: HashSet<String> = want.difference(&tips).cloned().collect();
+        if pending.is_empty() {
+            return None;
+        }
+        let annotated_tags = self.get_annotated_tags(repo_path).await;
+        let tips = tags::peel(&annotated_tags, &tips);
+        let reachable = get_ancestors(graph, tips.iter(), &HashSet::new());
+        pending.retain(|id| !reachable.contains(id));
+        // a commit out of the history of the refs, or a tag no ref points to
+        if let Some(id) = pending
+            .iter()
+            .find(|id| graph.contains_key(*id) || annotated_tags.contains_key(*id))
+        {
+            return Some(id.clone());
+        }
+        let mut seen = HashSet::new();
+        for commit_id in &reachable {
+            if pending.is_empty() {
+                return None;
+            }
+            let tree_id = &graph[commit_id].tree;
+            if seen.insert(tree_id.clone()) {
+                get_tree_ids(
+                    tree_id,
+                    Path::new(""),
+                    &mut seen,
+                    None,
+                    self.storage.clone(),
+                )
+                .await;
+                pending.retain(|id| !seen.contains(id));
+            }
+        }
+        pending.into_iter().next()
+    }
+
+    /// Whether `old_id` is an ancestor of `new_id` in `graph`, i.e. moving a ref from `old_id`
+    /// to `new_id` doesn't drop any commit.
+    pub fn is_fast_forward(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        old_id: &str,
+        new_id: &str,
+    ) -> bool {
+        let new_id = new_id.to_owned();
+        get_ancestors(graph, std::iter::once(&new_id), &HashSet::new()).contains(old_id)
+    }
+
+    /// Whether the commits reachable from `new_id` contain a merge commit, the history
+    /// reachable from `known` is already in the repo and isn't checked.
+    pub fn has_merge_commits(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        new_id: &str,
+        known: &[String],
+    ) -> bool {
+        let known = get_ancestors(graph, known.iter(), &HashSet::new());
+        let new_id = new_id.to_owned();
+        get_ancestors(graph, std::iter::once(&new_id), &known)
+            .iter()
+            .any(|id| graph[id].pid.len() > 1)
+    }
+
+    /// Collects what the ref updates of a push bring to the repo for the push hooks: the
+    /// commits that no ref of the repo reached before and the files these commits change.
+    pub async fn get_ref_changes(
+        &self,
+        graph: &HashMap<String, commit::Model>,
+        command_list: &[RefCommand],
+    ) -> Vec<RefChange> {
+        let known: HashSet<String> = self
+            .storage
+            .get_ref_object_id(self.path.to_str().unwrap())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.ref_git_id)
+            .collect();
+        let mut changes = vec![];
+        for command in command_list {
+            let mut models: Vec<&commit::Model> = match command.command_type {
+                CommandType::Delete => vec![],
+                _ => get_ancestors(graph, std::iter::once(&command.new_id), &known)
+                    .iter()
+                    .map(|id| &graph[id])
+                    .collect(),
+            };
+            // the oldest commits first, so that the last change of a file wins
+            models.sort_by_key(|model| commit_time(model));
+            let mut changed = BTreeMap::new();
+            for model in &models {
+                let parent_tree = model
+                    .pid
+                    .first()
+                    .and_then(|pid| graph.get(pid))
+                    .map(|parent| parent.tree.clone());
+                diff_trees(
+                    parent_tree,
+                    Some(model.tree.clone()),
+                    Path::new(""),
+                    &mut changed,
+                    self.storage.clone(),
+                )
+                .await;
+            }
+            changes.push(RefChange {
+                command: command.clone(),
+                commits: models
+                    .into_iter()
+                    .map(|model| model.clone().into())
+                    .collect(),
+                changed_paths: changed
+                    .into_iter()
+                    .map(|(path, new_id)| ChangedPath { path, new_id })
+                    .collect(),
+            });
+        }
+        changes
+    }
+
+    /// All commits of the repo keyed by their id, the parent ids form the commit graph. It's
+    /// loaded once per request and passed to the functions walking the history.
+    pub(crate) async fn get_commit_graph(
+        &self,
+        repo_path: &Path,
+    ) -> Result<HashMap<String, commit::Model>, GitError> {
+        let commits = self
+            .storage
+            .get_all_commits_by_path(repo_path.to_str().unwrap())
+            .await?;
+        Ok(commits
+            .into_iter()
+            .map(|model| (model.git_id.clone(), model))
+            .collect())
+    }
+
+    /// The commit graph of the repo along with the commits of a push whose objects are in
+    /// quarantine under `mr_id`, they are only saved to the repo once the push is accepted.
+    pub(crate) async fn get_push_commit_graph(
+        &self,
+        mr_id: Option<i64>,
+    ) -> Result<HashMap<String, commit::Model>, GitError> {
+        let mut graph = self.get_commit_graph(&self.path).await?;
+        if let Some(mr_id) = mr_id {
+            let commits: Vec<Commit> =
+                get_objects_vec_from_mr(self.storage.clone(), mr_id, "commit").await;
+            for c in commits {
+                let mut model = c.convert_to_model(&self.path);
+                model.id = Set(0);
+                graph.insert(c.id.to_plain_str(), model.try_into_model().unwrap());
             }
         }
-        let meta_vec: Vec<Arc<dyn ObjectT>> = hash_meta.into_values().collect();
-        let result: Vec<u8> = pack_encode(meta_vec).unwrap();
-        Ok(result)
+        Ok(graph)
     }
 
     pub async fn get_head_object_id(&self, repo_path: &Path) -> String {
@@ -111,22 +622,35 @@ impl PackProtocol {
         let refs_list = self.storage.search_refs(path_str).await.unwrap();
 
         if refs_list.is_empty() {
-            ZERO_ID.to_string()
+            get_hash_kind().zero_id()
         } else {
-            for refs in &refs_list {
-                if repo_path.to_str().unwrap() == refs.repo_path {
-                    return refs.ref_git_id.clone();
-                }
+            let own_refs: Vec<&refs::Model> = refs_list
+                .iter()
+                .filter(|r| r.repo_path == path_str)
+                .collect();
+            if !own_refs.is_empty() {
+                let head = self.get_head_ref(repo_path).await;
+                let refs = own_refs
+                    .iter()
+                    .find(|r| Some(&r.ref_name) == head.as_ref())
+                    .unwrap_or(&own_refs[0]);
+                return refs.ref_git_id.clone();
             }
-            for refs in &refs_list {
-                // if repo_path is subdirectory of some commit, we should generae a fake commit
-                if repo_path.starts_with(refs.repo_path.clone()) {
-                    return generate_child_commit_and_refs(self.storage.clone(), refs, repo_path)
-                        .await;
-                }
+            // if repo_path is subdirectory of some commit, we should generae a fake commit
+            if let Some(parent) = refs_list
+                .iter()
+                .find(|r| repo_path.starts_with(&r.repo_path))
+            {
+                // from the branch HEAD of the parent repo points to
+                let head = self.get_head_ref(Path::new(&parent.repo_path)).await;
+                let refs = refs_list
+                    .iter()
+                    .find(|r| r.repo_path == parent.repo_path && Some(&r.ref_name) == head.as_ref())
+                    .unwrap_or(parent);
+                return generate_child_commit_and_refs(self.storage.clone(), refs, repo_path).await;
             }
             //situation: repo_path: root/repotest2/src, commit: root/repotest
-            ZERO_ID.to_string()
+            get_hash_kind().zero_id()
         }
     }
 
@@ -177,31 +701,520 @@ impl PackProtocol {
     }
 }
 
-// retrieve all sub trees recursively
+/// Collects the commits reachable from `starts` by following the parent ids, commits in
+/// `stop` and their ancestors are not visited.
+fn get_ancestors<'a>(
+    graph: &HashMap<String, commit::Model>,
+    starts: impl Iterator<Item = &'a String>,
+    stop: &HashSet<String>,
+) -> HashSet<String> {
+    let mut visited = HashSet::new();
+    let mut queue: VecDeque<&String> = starts.collect();
+    while let Some(id) = queue.pop_front() {
+        if stop.contains(id) || visited.contains(id) {
+            continue;
+        }
+        if let Some(model) = graph.get(id) {
+            visited.insert(id.clone());
+            queue.extend(model.pid.iter());
+        }
+    }
+    visited
+}
+
+/// The result of truncating the history for a shallow fetch.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShallowInfo {
+    /// commits that become shallow on the client side
+    pub shallow: Vec<String>,
+    /// commits that were shallow on the client side and whose parents are sent now
+    pub unshallow: Vec<String>,
+    /// commits that make up the truncated history
+    pub commits: HashSet<String>,
+}
+
+/// Walks the history from `want` and stops at the limits of the `deepen` request:
+/// - `deepen <n>`: at most n commits deep from the wants, or from the client's current shallow
+///   commits with `deepen-relative`.
+/// - `deepen-since`: commits older than the timestamp are left out.
+/// - `deepen-not`: commits in `excluded` are left out.
+///
+/// A commit that is kept but has some of its parents left out becomes a shallow commit.
+fn shallow_walk(
+    graph: &HashMap<String, commit::Model>,
+    want: &HashSet<String>,
+    deepen: &Deepen,
+    excluded: &HashSet<String>,
+) -> ShallowInfo {
+    let client_shallow: HashSet<&String> = deepen.shallow.iter().collect();
+    let is_left_out = |model: &commit::Model| {
+        if excluded.contains(&model.git_id) {
+            return true;
+        }
+        match (deepen.since, commit_time(model)) {
+            (Some(since), Some(time)) => time < since,
+            _ => false,
+        }
+    };
+
+    let mut info = ShallowInfo::default();
+    let mut boundary = HashSet::new();
+    let start_depth = if deepen.relative { None } else { Some(1) };
+    let mut queue: VecDeque<(&String, Option<u32>)> =
+        want.iter().map(|id| (id, start_depth)).collect();
+    while let Some((id, depth)) = queue.pop_front() {
+        let Some(model) = graph.get(id) else {
+            continue;
+        };
+        if info.commits.contains(id) || is_left_out(model) {
+            continue;
+        }
+        info.commits.insert(id.clone());
+        // with deepen-relative the depth is counted from the current shallow boundary
+        let depth = match depth {
+            None if client_shallow.contains(id) => Some(0),
+            other => other,
+        };
+        let parents: Vec<&commit::Model> = model.pid.iter().filter_map(|p| graph.get(p)).collect();
+        // without a deepen request the client's shallow commits stay the boundary
+        let at_limit = if deepen.is_requested() {
+            matches!((depth, deepen.depth), (Some(d), Some(limit)) if d >= limit)
+        } else {
+            client_shallow.contains(id)
+        };
+        if parents.iter().any(|p| at_limit || is_left_out(p)) {
+            boundary.insert(id.clone());
+        }
+        if !at_limit {
+            for parent in parents.into_iter().filter(|p| !is_left_out(p)) {
+                queue.push_back((&parent.git_id, depth.map(|d| d + 1)));
+            }
+        }
+    }
+
+    for id in &info.commits {
+        if boundary.contains(id) {
+            if !client_shallow.contains(id) {
+                info.shallow.push(id.clone());
+            }
+        } else if client_shallow.contains(id) {
+            info.unshallow.push(id.clone());
+        }
+    }
+    info
+}
+
+// the committer timestamp of a commit
+fn commit_time(model: &commit::Model) -> Option<i64> {
+    let committer = model.committer.clone()?;
+    Signature::new_from_data(committer.into_bytes())
+        .ok()
+        .map(|s| s.timestamp as i64)
+}
+
+// retrieve all sub trees recursively, objects in `skip` are already known by the client and
+// `path` and `depth` locate `root` in the commit's tree for the filter. Only trees are read from
+// the storage, blobs are read too when the filter has to know their size. The delta bases of
+// the objects are looked up in `thin` for a thin pack.
+#[allow(clippy::too_many_arguments)]
 #[async_recursion]
 async fn get_child_trees(
     root: &git_obj::Model,
-    hash_object: &mut HashMap<String, Arc<dyn ObjectT>>,
+    path: &Path,
+    depth: u64,
+    objects: &mut Vec<PackObject>,
+    seen: &mut HashSet<String>,
+    skip: &HashSet<String>,
+    filter: &TreeFilter,
+    mut thin: Option<&mut ThinPackBases>,
     storage: Arc<dyn ObjectStorage>,
 ) {
+    if !seen.insert(root.git_id.clone()) {
+        return;
+    }
+    objects.push(PackObject::new(root.git_id.clone(), ObjectType::Tree, path));
+    if let Some(thin) = thin.as_deref_mut() {
+        thin.record(path, &root.git_id);
+    }
     let t = Tree::new_from_data(root.data.clone());
     let mut search_child_ids = vec![];
     for item in &t.tree_items {
-        if !hash_object.contains_key(&item.id.to_plain_str()) {
-            search_child_ids.push(item.id.to_plain_str());
+        let id = item.id.to_plain_str();
+        if seen.contains(&id) || skip.contains(&id) {
+            continue;
+        }
+        let item_path = path.join(&item.name);
+        match item.mode {
+            // submodule commits are not stored in this repo
+            TreeItemMode::Commit => {}
+            TreeItemMode::Tree => {
+                if filter.keep_tree(depth + 1) {
+                    search_child_ids.push(id);
+                }
+            }
+            // a blob of size 0 is only left out by `blob:none`
+            _ if !filter.keep_blob(&item_path, 0, depth + 1) => {}
+            _ if filter.blob_limit.is_some() => search_child_ids.push(id),
+            _ => {
+                if let Some(thin) = thin.as_deref_mut() {
+                    thin.record(&item_path, &id);
+                }
+                seen.insert(id.clone());
+                objects.push(PackObject::new(id, ObjectType::Blob, &item_path));
+            }
         }
     }
-    let objs = storage.get_obj_data_by_ids(search_child_ids).await.unwrap();
-    for obj in objs {
+    let objs: HashMap<String, git_obj::Model> = storage
+        .get_obj_data_by_ids(search_child_ids)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|obj| (obj.git_id.clone(), obj))
+        .collect();
+    for item in &t.tree_items {
+        let id = item.id.to_plain_str();
+        let Some(obj) = objs.get(&id) else {
+            continue;
+        };
+        let item_path = path.join(&item.name);
         if obj.object_type == "tree" {
-            get_child_trees(&obj, hash_object, storage.clone()).await;
+            get_child_trees(
+                obj,
+                &item_path,
+                depth + 1,
+                objects,
+                seen,
+                skip,
+                filter,
+                thin.as_deref_mut(),
+                storage.clone(),
+            )
+            .await;
+        } else if filter.keep_blob(&item_path, obj.data.len() as u64, depth + 1)
+            && seen.insert(id.clone())
+        {
+            if let Some(thin) = thin.as_deref_mut() {
+                thin.record(&item_path, &id);
+            }
+            objects.push(PackObject::new(id, ObjectType::Blob, &item_path));
+        }
+    }
+}
+
+// records the files that differ between two trees with the id of their new blob, `None`
+// stands for a missing tree or a deleted file. Submodules are left out.
+#[async_recursion]
+async fn diff_trees(
+    old: Option<String>,
+    new: Option<String>,
+    path: &Path,
+    changed: &mut BTreeMap<PathBuf, Option<String>>,
+    storage: Arc<dyn ObjectStorage>,
+) {
+    if old == new {
+        return;
+    }
+    let old_items = read_tree_items(old, &storage).await;
+    let new_items = read_tree_items(new, &storage).await;
+    let names: BTreeSet<&String> = old_items.keys().chain(new_items.keys()).collect();
+    for name in names {
+        let item_path = path.join(name);
+        let split = |item: Option<&(TreeItemMode, String)>| match item {
+            Some((TreeItemMode::Tree, id)) => (Some(id.clone()), None),
+            Some((TreeItemMode::Commit, _)) | None => (None, None),
+            Some((_, id)) => (None, Some(id.clone())),
+        };
+        let (old_tree, old_blob) = split(old_items.get(name));
+        let (new_tree, new_blob) = split(new_items.get(name));
+        if old_tree.is_some() || new_tree.is_some() {
+            diff_trees(old_tree, new_tree, &item_path, changed, storage.clone()).await;
+        }
+        if old_blob != new_blob {
+            changed.insert(item_path, new_blob);
+        }
+    }
+}
+
+// the mode and id of the items of a tree keyed by their name
+async fn read_tree_items(
+    tree_id: Option<String>,
+    storage: &Arc<dyn ObjectStorage>,
+) -> HashMap<String, (TreeItemMode, String)> {
+    let Some(tree_id) = tree_id else {
+        return HashMap::new();
+    };
+    let Some(model) = storage.get_obj_data_by_id(&tree_id).await.unwrap() else {
+        return HashMap::new();
+    };
+    Tree::new_from_data(model.data)
+        .tree_items
+        .into_iter()
+        .map(|item| (item.name, (item.mode, item.id.to_plain_str())))
+        .collect()
+}
+
+// collect the ids of all the objects under a tree at `path`, and their paths in `known_paths`
+#[async_recursion]
+async fn get_tree_ids(
+    tree_id: &str,
+    path: &Path,
+    ids: &mut HashSet<String>,
+    mut known_paths: Option<&mut HashMap<PathBuf, String>>,
+    storage: Arc<dyn ObjectStorage>,
+) {
+    let Some(model) = storage.get_obj_data_by_id(tree_id).await.unwrap() else {
+        return;
+    };
+    let t = Tree::new_from_data(model.data);
+    for item in &t.tree_items {
+        let id = item.id.to_plain_str();
+        if !ids.insert(id.clone()) {
+            continue;
+        }
+        let item_path = path.join(&item.name);
+        if let Some(known_paths) = known_paths.as_deref_mut() {
+            known_paths.insert(item_path.clone(), id.clone());
+        }
+        if item.mode == TreeItemMode::Tree {
+            get_tree_ids(
+                &id,
+                &item_path,
+                ids,
+                known_paths.as_deref_mut(),
+                storage.clone(),
+            )
+            .await;
+        }
+    }
+}
+
+/// The delta bases of a thin pack: the objects the client already has keyed by their path in
+/// the trees of the common commits. An object sent at the same path is encoded as a delta
+/// against the one the client has, without sending the base.
+#[derive(Debug, Default)]
+struct ThinPackBases {
+    known: HashMap<PathBuf, String>,
+    // the id of an object to send and the id of its base
+    bases: HashMap<String, String>,
+}
+
+impl ThinPackBases {
+    fn record(&mut self, path: &Path, id: &str) {
+        if let Some(base) = self.known.get(path) {
+            if base != id {
+                self.bases.insert(id.to_owned(), base.clone());
+            }
+        }
+    }
+}
+
+// the delta of an object from a base of a thin pack, if it's worth sending
+fn thin_pack_delta(base: &git_obj::Model, object: &DeltaObject) -> Option<Vec<u8>> {
+    if base.object_type != object.obj_type.to_string()
+        || base.data.len() > MAX_DELTA_OBJECT_SIZE
+        || object.data.len() > MAX_DELTA_OBJECT_SIZE
+    {
+        return None;
+    }
+    DeltaIndex::new(base.data.clone()).delta(&object.data, object.data.len().saturating_sub(1))
+}
+
+/// An object to send in a pack. The objects are sorted by type and by the [`name_hash`] of
+/// their path before being encoded, so that the versions of a file are close enough for the
+/// delta search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackObject {
+    pub id: String,
+    pub object_type: ObjectType,
+    pub name_hash: u32,
+}
+
+impl PackObject {
+    pub fn new(id: String, object_type: ObjectType, path: &Path) -> Self {
+        Self {
+            id,
+            object_type,
+            name_hash: name_hash(&path.to_string_lossy()),
+        }
+    }
+
+    pub fn tag(id: String) -> Self {
+        Self {
+            id,
+            object_type: ObjectType::Tag,
+            name_hash: 0,
+        }
+    }
+}
+
+/// Encodes `commits` and `objects` into a pack and sends it on band 1.
+///
+/// The objects are read from the storage a page at a time (`GIT_INTERNAL_ENCODE_PAGE_SIZE`,
+/// 1000 by default) and the pack checksum is computed as they are encoded, so the memory used
+/// doesn't depend on the size of the repo. It stops early when the stream is dropped.
+///
+/// The objects are sent as deltas against the objects before them as set by `options`.
+/// `delta_bases` maps objects to the objects the client already has, they are sent as
+/// `REF_DELTA` against their base when it's smaller, which makes a thin pack.
+pub async fn encode_pack(
+    storage: Arc<dyn ObjectStorage>,
+    commits: Vec<Commit>,
+    mut objects: Vec<PackObject>,
+    delta_bases: &HashMap<String, String>,
+    options: DeltaOptions,
+    sender: &SideBandSender,
+) -> Result<(), GitError> {
+    let mut page_size: usize = 1000;
+    utils::get_env_number("GIT_INTERNAL_ENCODE_PAGE_SIZE", &mut page_size);
+    let page_size = page_size.max(1);
+    let encode_error = |e: std::io::Error| GitError::EncodeObjectError(e.to_string());
+
+    let total = commits.len() + objects.len();
+    let mut compressing = Progress::new("Compressing objects", Some(total));
+    let mut encoded = 0;
+    let mut deltas = 0;
+    let mut encoder = Encoder::init(total, Vec::new()).with_delta_options(options);
+    let mut page_deltas;
+    let mut commits = commits.into_iter().peekable();
+    while commits.peek().is_some() {
+        let page: Vec<(DeltaObject, _)> = commits
+            .by_ref()
+            .take(page_size)
+            .map(|c| {
+                (
+                    DeltaObject::new(c.id, ObjectType::Commit, c.get_raw()),
+                    None,
+                )
+            })
+            .collect();
+        encoded += page.len();
+        (encoder, page_deltas) = add_delta_objects(encoder, page).await?;
+        deltas += page_deltas;
+        // the client has gone away
+        if !send_pack_data(sender, encoder.take_output()).await {
+            return Ok(());
+        }
+        if let Some(message) = compressing.update(encoded) {
+            send_progress(sender, message).await;
+        }
+    }
+    // the sizes are only known once the data is read, the pages are sorted by size too
+    objects.sort_by_key(|o| (o.object_type, o.name_hash));
+    for chunk in objects.chunks(page_size) {
+        let ids: Vec<String> = chunk.iter().map(|o| o.id.clone()).collect();
+        // the same object may be stored more than once
+        let mut models: HashMap<String, git_obj::Model> = storage
+            .get_obj_data_by_ids(ids.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|model| (model.git_id.clone(), model))
+            .collect();
+        let base_ids: Vec<String> = ids
+            .iter()
+            .filter_map(|id| delta_bases.get(id))
+            .cloned()
+            .collect();
+        let base_models: HashMap<String, Arc<git_obj::Model>> = if base_ids.is_empty() {
+            HashMap::new()
         } else {
-            let blob = Blob::new_from_data(obj.data.clone());
-            hash_object.insert(obj.git_id.clone(), Arc::new(blob));
+            storage
+                .get_obj_data_by_ids(base_ids)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|model| (model.git_id.clone(), Arc::new(model)))
+                .collect()
+        };
+        let mut page = Vec::with_capacity(chunk.len());
+        for object in chunk {
+            let model = models
+                .remove(&object.id)
+                .ok_or_else(|| GitError::NotFountHashValue(object.id.clone()))?;
+            let base = delta_bases
+                .get(&object.id)
+                .and_then(|base_id| base_models.get(base_id))
+                .cloned();
+            // the data of the model is the object as it is, tags included
+            let object = DeltaObject {
+                hash: Hash::new_from_str(&model.git_id),
+                obj_type: ObjectType::from_string(&model.object_type)?,
+                data: model.data,
+                name_hash: object.name_hash,
+            };
+            page.push((object, base));
+        }
+        encoded += page.len();
+        (encoder, page_deltas) = add_delta_objects(encoder, page).await?;
+        deltas += page_deltas;
+        if !send_pack_data(sender, encoder.take_output()).await {
+            return Ok(());
+        }
+        if let Some(message) = compressing.update(encoded) {
+            send_progress(sender, message).await;
         }
     }
-    let tree = Tree::new_from_data(t.get_raw());
-    hash_object.insert(t.id.to_plain_str(), Arc::new(tree));
+    encoder.finish().map_err(encode_error)?;
+    send_progress(sender, compressing.done(encoded)).await;
+    let message = format!("Total {} (delta {})\n", total, deltas);
+    send_progress(sender, message.into()).await;
+    send_pack_data(sender, encoder.take_output()).await;
+    Ok(())
+}
+
+// adds a page of objects, an object with the base of a thin pack is sent as a delta against
+// it when that's smaller. The delta search is CPU bound, it runs on a blocking thread with the
+// hash kind of the task. Returns the encoder and how many objects are deltas.
+async fn add_delta_objects(
+    mut encoder: Encoder<Vec<u8>>,
+    page: Vec<(DeltaObject, Option<Arc<git_obj::Model>>)>,
+) -> Result<(Encoder<Vec<u8>>, usize), GitError> {
+    let kind = get_hash_kind();
+    let (encoder, deltas) = tokio::task::spawn_blocking(move || {
+        let deltas = sync_with_hash_kind(kind, || {
+            let mut deltas = 0;
+            let mut objects = Vec::with_capacity(page.len());
+            for (object, base) in page {
+                match base.and_then(|base| thin_pack_delta(&base, &object).map(|d| (base, d))) {
+                    Some((base, delta)) => {
+                        encoder.add_ref_delta(&Hash::new_from_str(&base.git_id), &delta)?;
+                        deltas += 1;
+                    }
+                    None => objects.push(object),
+                }
+            }
+            Ok::<_, std::io::Error>(deltas + encoder.add_delta_objects(objects)?)
+        });
+        (encoder, deltas)
+    })
+    .await
+    .map_err(|e| GitError::EncodeObjectError(e.to_string()))?;
+    let deltas = deltas.map_err(|e| GitError::EncodeObjectError(e.to_string()))?;
+    Ok((encoder, deltas))
+}
+
+// returns false if the stream was dropped
+async fn send_pack_data(sender: &SideBandSender, data: Vec<u8>) -> bool {
+    sender
+        .send(Ok((SideBind::PackfileData, data.into())))
+        .await
+        .is_ok()
+}
+
+async fn send_progress(sender: &SideBandSender, message: Bytes) {
+    let _ = sender.send(Ok((SideBind::ProgressInfo, message))).await;
+}
+
+/// Collects the pack of a side-band stream in memory, for the callers that need the pack at
+/// once. The progress messages are dropped.
+pub async fn collect_pack_stream(mut stream: SideBandStream) -> Result<Vec<u8>, GitError> {
+    let mut pack = Vec::new();
+    while let Some(message) = stream.next().await {
+        if let (SideBind::PackfileData, data) = message? {
+            pack.extend_from_slice(&data);
+        }
+    }
+    Ok(pack)
 }
 
 /// Generates a new commit for a subdirectory of the original project directory.
@@ -244,7 +1257,7 @@ pub async fn generate_child_commit_and_refs(
         storage.save_refs(vec![child_refs]).await.unwrap();
         commit_id
     } else {
-        ZERO_ID.to_string()
+        get_hash_kind().zero_id()
     }
 }
 
@@ -404,3 +1417,99 @@ pub async fn get_objects_vec_from_mr<T: ObjectT>(
         .collect();
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use entity::commit;
+
+    use crate::protocol::Deepen;
+
+    use super::{get_ancestors, shallow_walk};
+
+    fn commit_model(id: &str, pid: &[&str]) -> (String, commit::Model) {
+        let model = commit::Model {
+            id: 0,
+            git_id: id.to_owned(),
+            tree: String::new(),
+            pid: pid.iter().map(|p| p.to_string()).collect(),
+            repo_path: String::from("/root/repo"),
+            author: None,
+            committer: None,
+            content: None,
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        };
+        (id.to_owned(), model)
+    }
+
+    #[test]
+    fn test_get_ancestors() {
+        // a <- b <- c <- e
+        //       \- d -/
+        let graph: HashMap<String, commit::Model> = [
+            commit_model("a", &[]),
+            commit_model("b", &["a"]),
+            commit_model("c", &["b"]),
+            commit_model("d", &["b"]),
+            commit_model("e", &["c", "d"]),
+        ]
+        .into_iter()
+        .collect();
+
+        let want = [String::from("e")];
+        let all = get_ancestors(&graph, want.iter(), &HashSet::new());
+        assert_eq!(all.len(), 5);
+
+        let have = [String::from("c")];
+        let known = get_ancestors(&graph, have.iter(), &HashSet::new());
+        let send = get_ancestors(&graph, want.iter(), &known);
+        let expected: HashSet<String> = ["e", "d"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(send, expected);
+    }
+
+    #[test]
+    fn test_shallow_walk_depth() {
+        // a <- b <- c <- d
+        let graph: HashMap<String, commit::Model> = [
+            commit_model("a", &[]),
+            commit_model("b", &["a"]),
+            commit_model("c", &["b"]),
+            commit_model("d", &["c"]),
+        ]
+        .into_iter()
+        .collect();
+        let want: HashSet<String> = [String::from("d")].into_iter().collect();
+
+        let deepen = Deepen {
+            depth: Some(2),
+            ..Default::default()
+        };
+        let info = shallow_walk(&graph, &want, &deepen, &HashSet::new());
+        assert_eq!(info.shallow, vec![String::from("c")]);
+        assert!(info.unshallow.is_empty());
+        assert_eq!(info.commits.len(), 2);
+
+        // deepen the existing shallow clone by one commit
+        let deepen = Deepen {
+            shallow: vec![String::from("c")],
+            depth: Some(1),
+            relative: true,
+            ..Default::default()
+        };
+        let info = shallow_walk(&graph, &want, &deepen, &HashSet::new());
+        assert_eq!(info.shallow, vec![String::from("b")]);
+        assert_eq!(info.unshallow, vec![String::from("c")]);
+
+        // deepen-not stops at the excluded history
+        let deepen = Deepen {
+            not: vec![String::from("b")],
+            ..Default::default()
+        };
+        let excluded = get_ancestors(&graph, [String::from("b")].iter(), &HashSet::new());
+        let info = shallow_walk(&graph, &want, &deepen, &excluded);
+        assert_eq!(info.shallow, vec![String::from("c")]);
+        assert_eq!(info.commits.len(), 2);
+    }
+}
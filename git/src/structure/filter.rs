@@ -0,0 +1,259 @@
+//!
+//! Object filters of partial clones, see `--filter` in
+//! [git-rev-list](https://git-scm.com/docs/git-rev-list#Documentation/git-rev-list.txt---filterltfilter-specgt).
+//!
+//! A partial clone leaves some blobs or trees out of the pack, the client becomes a promisor and
+//! fetches the missing objects later on demand by sending their ids as `want` lines.
+//!
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+/// A filter spec sent by the client in a `filter <spec>` line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectFilter {
+    /// `blob:none`, omits all blobs
+    BlobNone,
+    /// `blob:limit=<n>[kmg]`, omits the blobs of n bytes or more
+    BlobLimit(u64),
+    /// `tree:<depth>`, omits the trees and blobs whose depth from the root tree is >= depth
+    TreeDepth(u64),
+    /// `sparse:oid=<blob-ish>`, omits the blobs outside of the sparse-checkout specification
+    /// stored in the given blob
+    SparseOid(String),
+}
+
+impl FromStr for ObjectFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "blob:none" {
+            return Ok(ObjectFilter::BlobNone);
+        }
+        if let Some(limit) = s.strip_prefix("blob:limit=") {
+            return Ok(ObjectFilter::BlobLimit(parse_size(limit)?));
+        }
+        if let Some(depth) = s.strip_prefix("tree:") {
+            return Ok(ObjectFilter::TreeDepth(depth.parse()?));
+        }
+        if let Some(oid) = s.strip_prefix("sparse:oid=") {
+            return Ok(ObjectFilter::SparseOid(oid.to_owned()));
+        }
+        Err(anyhow!("unsupported filter spec: {}", s))
+    }
+}
+
+// sizes can use the k, m or g unit suffix like git config values
+fn parse_size(value: &str) -> Result<u64> {
+    let value = value.to_lowercase();
+    let (number, unit) = match value.chars().last() {
+        Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value.as_str(), 1),
+    };
+    Ok(number.parse::<u64>()? * unit)
+}
+
+/// The filter applied while walking the trees of the commits to pack.
+///
+/// The root tree of a commit has depth 0, its entries have depth 1 and so on.
+#[derive(Debug, Default)]
+pub struct TreeFilter {
+    pub blob_limit: Option<u64>,
+    pub tree_depth: Option<u64>,
+    pub sparse: Option<SparsePatterns>,
+}
+
+impl TreeFilter {
+    /// Builds the filter, `sparse_spec` is the content of the blob of a `sparse:oid` filter.
+    pub fn new(filter: Option<&ObjectFilter>, sparse_spec: Option<&[u8]>) -> Self {
+        let mut tree_filter = TreeFilter::default();
+        match filter {
+            Some(ObjectFilter::BlobNone) => tree_filter.blob_limit = Some(0),
+            Some(ObjectFilter::BlobLimit(limit)) => tree_filter.blob_limit = Some(*limit),
+            Some(ObjectFilter::TreeDepth(depth)) => tree_filter.tree_depth = Some(*depth),
+            Some(ObjectFilter::SparseOid(_)) => {
+                tree_filter.sparse = Some(SparsePatterns::parse(&String::from_utf8_lossy(
+                    sparse_spec.unwrap_or_default(),
+                )))
+            }
+            None => {}
+        }
+        tree_filter
+    }
+
+    pub fn keep_tree(&self, depth: u64) -> bool {
+        self.tree_depth.is_none_or(|max| depth < max)
+    }
+
+    pub fn keep_blob(&self, path: &Path, size: u64, depth: u64) -> bool {
+        if !self.keep_tree(depth) {
+            return false;
+        }
+        if let Some(limit) = self.blob_limit {
+            if size >= limit {
+                return false;
+            }
+        }
+        match &self.sparse {
+            Some(patterns) => patterns.is_included(path),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SparsePattern {
+    negated: bool,
+    anchored: bool,
+    dir_only: bool,
+    pattern: String,
+}
+
+/// The patterns of a sparse-checkout file, they use the gitignore syntax: the last pattern
+/// that matches a path decides whether it's included, `!` negates a pattern.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SparsePatterns {
+    patterns: Vec<SparsePattern>,
+}
+
+impl SparsePatterns {
+    pub fn parse(spec: &str) -> Self {
+        let patterns = spec
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let dir_only = line.ends_with('/');
+                let line = line.trim_end_matches('/');
+                let anchored = line.starts_with('/') || line.contains('/');
+                SparsePattern {
+                    negated,
+                    anchored,
+                    dir_only,
+                    pattern: line.trim_start_matches('/').to_owned(),
+                }
+            })
+            .collect();
+        SparsePatterns { patterns }
+    }
+
+    /// Whether a blob at `path` (relative to the repo root) belongs to the sparse checkout.
+    pub fn is_included(&self, path: &Path) -> bool {
+        let components: Vec<&str> = path.iter().filter_map(|c| c.to_str()).collect();
+        let mut included = false;
+        for p in &self.patterns {
+            // a pattern matching a directory also matches everything below it
+            let matched = (1..=components.len()).any(|len| {
+                let is_dir = len < components.len();
+                if p.dir_only && !is_dir {
+                    return false;
+                }
+                if p.anchored {
+                    glob_match(&p.pattern, &components[..len].join("/"))
+                } else {
+                    glob_match(&p.pattern, components[len - 1])
+                }
+            });
+            if matched {
+                included = !p.negated;
+            }
+        }
+        included
+    }
+}
+
+// matches `*` and `?` wildcards, they don't match a `/`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' && text[t] != b'/' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star.filter(|(_, st)| text[*st] != b'/') {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{glob_match, ObjectFilter, SparsePatterns, TreeFilter};
+
+    #[test]
+    fn test_parse_object_filter() {
+        assert_eq!(
+            "blob:none".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobNone
+        );
+        assert_eq!(
+            "blob:limit=1k".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobLimit(1024)
+        );
+        assert_eq!(
+            "tree:0".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::TreeDepth(0)
+        );
+        assert_eq!(
+            "sparse:oid=8b5d5e1c".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::SparseOid(String::from("8b5d5e1c"))
+        );
+        assert!("combine:blob:none+tree:1".parse::<ObjectFilter>().is_err());
+    }
+
+    #[test]
+    fn test_tree_filter() {
+        let filter = TreeFilter::new(Some(&ObjectFilter::BlobLimit(100)), None);
+        assert!(filter.keep_blob(Path::new("a.txt"), 99, 1));
+        assert!(!filter.keep_blob(Path::new("a.txt"), 100, 1));
+
+        let filter = TreeFilter::new(Some(&ObjectFilter::TreeDepth(2)), None);
+        assert!(filter.keep_tree(1));
+        assert!(!filter.keep_tree(2));
+        assert!(filter.keep_blob(Path::new("a.txt"), 1000, 1));
+        assert!(!filter.keep_blob(Path::new("src/a.txt"), 1000, 2));
+    }
+
+    #[test]
+    fn test_sparse_patterns() {
+        // cone mode: files at the root and everything under src/
+        let patterns = SparsePatterns::parse("/*\n!/*/\n/src/\n");
+        assert!(patterns.is_included(Path::new("README.md")));
+        assert!(patterns.is_included(Path::new("src/lib/mod.rs")));
+        assert!(!patterns.is_included(Path::new("docs/index.md")));
+
+        let patterns = SparsePatterns::parse("*.rs\n");
+        assert!(patterns.is_included(Path::new("src/main.rs")));
+        assert!(!patterns.is_included(Path::new("Cargo.toml")));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "README.md"));
+        assert!(glob_match("src", "src"));
+        assert!(glob_match("*.r?", "main.rs"));
+        assert!(!glob_match("*", "src/main.rs"));
+        assert!(!glob_match("src", "src2"));
+    }
+}
@@ -0,0 +1,459 @@
+//!
+//! Checks that the tables a repo is stored in are consistent with each other, like `git fsck`
+//! does for the object database of a git repo:
+//!
+//! - the objects read from `git_obj` and the commits of the `commit` table are hashed again,
+//!   they must hash to their id.
+//! - the commits, trees, blobs and tag objects reachable from the refs must all be stored.
+//! - the objects of the `node` rows of the repo must be stored, and their `last_commit` must be
+//!   a stored commit.
+//!
+//! The problems are collected in a [`FsckReport`], which serializes to JSON.
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use entity::{commit, git_obj};
+use serde::Serialize;
+
+use crate::errors::GitError;
+use crate::hash::{get_hash_kind, with_hash_kind, Hash};
+use crate::internal::object::commit::Commit;
+use crate::internal::object::meta::Meta;
+use crate::internal::object::signature::Signature;
+use crate::internal::object::ObjectT;
+use crate::internal::ObjectType;
+use crate::protocol::PackProtocol;
+
+use super::tags::TagData;
+
+// how many objects are read from the storage at once
+const PAGE_SIZE: usize = 1000;
+
+/// The result of [`PackProtocol::fsck`], the repo is consistent when all the lists are empty.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct FsckReport {
+    pub repo_path: String,
+    /// The number of refs, commits, `git_obj` rows and nodes that were checked.
+    pub refs: usize,
+    pub commits: usize,
+    pub objects: usize,
+    pub nodes: usize,
+    /// Stored objects that don't hash to their id or can't be parsed.
+    pub corrupt_objects: Vec<CorruptObject>,
+    /// Objects reachable from the refs that aren't stored.
+    pub missing_objects: Vec<MissingObject>,
+    /// Nodes whose object isn't stored.
+    pub dangling_nodes: Vec<DanglingNode>,
+    /// Nodes whose `last_commit` isn't a stored commit.
+    pub broken_last_commits: Vec<BrokenLastCommit>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_objects.is_empty()
+            && self.missing_objects.is_empty()
+            && self.dangling_nodes.is_empty()
+            && self.broken_last_commits.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct CorruptObject {
+    pub git_id: String,
+    pub object_type: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct MissingObject {
+    pub git_id: String,
+    pub object_type: String,
+    /// The ref or the id of the object pointing to it.
+    pub referenced_by: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct DanglingNode {
+    pub full_path: String,
+    pub git_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct BrokenLastCommit {
+    pub full_path: String,
+    pub git_id: String,
+    pub last_commit: String,
+}
+
+// an object reachable from the refs, `referenced_by` is the ref or the object pointing to it
+struct Reachable {
+    id: String,
+    object_type: ObjectType,
+    referenced_by: String,
+}
+
+impl Reachable {
+    fn new(id: &str, object_type: ObjectType, referenced_by: &str) -> Self {
+        Reachable {
+            id: id.to_owned(),
+            object_type,
+            referenced_by: referenced_by.to_owned(),
+        }
+    }
+
+    fn missing(&self) -> MissingObject {
+        MissingObject {
+            git_id: self.id.clone(),
+            object_type: self.object_type.to_string(),
+            referenced_by: self.referenced_by.clone(),
+        }
+    }
+}
+
+impl PackProtocol {
+    /// Checks the repo of this protocol, see the [module documentation](self).
+    ///
+    /// Every reachable object is read once, a page at a time. The objects of the nodes that
+    /// aren't reachable from the refs are read too, so that all the objects of the repo are
+    /// hashed again.
+    pub async fn fsck(&self) -> Result<FsckReport, GitError> {
+        let kind = self.get_object_format().await?;
+        with_hash_kind(kind, self.check_repo()).await
+    }
+
+    async fn check_repo(&self) -> Result<FsckReport, GitError> {
+        let repo_path = self.path.to_str().unwrap();
+        let mut report = FsckReport {
+            repo_path: repo_path.to_owned(),
+            ..Default::default()
+        };
+        let graph = self.get_commit_graph(&self.path).await?;
+        report.commits = graph.len();
+        report
+            .corrupt_objects
+            .extend(graph.values().filter_map(check_commit));
+
+        let annotated_tags = self.get_annotated_tags(&self.path).await;
+        let refs = self.storage.get_ref_object_id(repo_path).await?;
+        report.refs = refs.len();
+        let mut reachable: Vec<Reachable> = refs
+            .iter()
+            .map(|r| {
+                let object_type = if annotated_tags.contains_key(&r.ref_git_id) {
+                    ObjectType::Tag
+                } else {
+                    ObjectType::Commit
+                };
+                Reachable::new(&r.ref_git_id, object_type, &r.ref_name)
+            })
+            .collect();
+
+        // the ids of the objects with data, trees and blobs are looked up in the nodes next
+        let mut stored = HashSet::new();
+        let mut seen = HashSet::new();
+        while !reachable.is_empty() {
+            let mut objects = vec![];
+            for object in std::mem::take(&mut reachable) {
+                if !seen.insert(object.id.clone()) {
+                    continue;
+                }
+                if object.object_type != ObjectType::Commit {
+                    objects.push(object);
+                    continue;
+                }
+                let Some(commit) = graph.get(&object.id) else {
+                    report.missing_objects.push(object.missing());
+                    continue;
+                };
+                reachable.push(Reachable::new(
+                    &commit.tree,
+                    ObjectType::Tree,
+                    &commit.git_id,
+                ));
+                reachable.extend(
+                    commit
+                        .pid
+                        .iter()
+                        .map(|id| Reachable::new(id, ObjectType::Commit, &commit.git_id)),
+                );
+            }
+            for page in objects.chunks(PAGE_SIZE) {
+                let ids = page.iter().map(|object| object.id.clone()).collect();
+                let models = self.read_objects(ids, &mut report).await?;
+                for object in page {
+                    let Some(model) = models.get(&object.id) else {
+                        report.missing_objects.push(object.missing());
+                        continue;
+                    };
+                    stored.insert(object.id.clone());
+                    match follow_object(model, object.object_type) {
+                        Ok(next) => reachable.extend(next),
+                        Err(reason) => report.corrupt_objects.push(corrupt(model, reason)),
+                    }
+                }
+            }
+        }
+
+        let nodes = self.storage.get_node_by_path(&self.path).await?;
+        report.nodes = nodes.len();
+        let unread: HashSet<String> = nodes
+            .iter()
+            .filter(|node| !seen.contains(&node.git_id))
+            .map(|node| node.git_id.clone())
+            .collect();
+        let unread: Vec<String> = unread.into_iter().collect();
+        for ids in unread.chunks(PAGE_SIZE) {
+            let models = self.read_objects(ids.to_vec(), &mut report).await?;
+            stored.extend(models.into_keys());
+        }
+        report.dangling_nodes = nodes
+            .iter()
+            .filter(|node| !stored.contains(&node.git_id))
+            .map(|node| DanglingNode {
+                full_path: node.full_path.clone(),
+                git_id: node.git_id.clone(),
+            })
+            .collect();
+
+        // the last commit of a node may be stored under another repo path
+        let other_commits: HashSet<String> = nodes
+            .iter()
+            .filter(|node| !graph.contains_key(&node.last_commit))
+            .map(|node| node.last_commit.clone())
+            .collect();
+        let other_commits: Vec<String> = other_commits.into_iter().collect();
+        let mut known_commits = HashSet::new();
+        for ids in other_commits.chunks(PAGE_SIZE) {
+            let commits = self.storage.get_commit_by_hashes(ids.to_vec()).await?;
+            known_commits.extend(commits.into_iter().map(|c| c.git_id));
+        }
+        report.broken_last_commits = nodes
+            .iter()
+            .filter(|node| {
+                !graph.contains_key(&node.last_commit) && !known_commits.contains(&node.last_commit)
+            })
+            .map(|node| BrokenLastCommit {
+                full_path: node.full_path.clone(),
+                git_id: node.git_id.clone(),
+                last_commit: node.last_commit.clone(),
+            })
+            .collect();
+
+        report.corrupt_objects.sort();
+        report.missing_objects.sort();
+        report.dangling_nodes.sort();
+        report.broken_last_commits.sort();
+        Ok(report)
+    }
+
+    // reads the objects of `ids` and hashes them again, an object stored more than once is
+    // checked once per row
+    async fn read_objects(
+        &self,
+        ids: Vec<String>,
+        report: &mut FsckReport,
+    ) -> Result<HashMap<String, git_obj::Model>, GitError> {
+        let models = self.storage.get_obj_data_by_ids(ids).await?;
+        report.objects += models.len();
+        report
+            .corrupt_objects
+            .extend(models.iter().filter_map(check_object));
+        Ok(models
+            .into_iter()
+            .map(|model| (model.git_id.clone(), model))
+            .collect())
+    }
+}
+
+fn corrupt(model: &git_obj::Model, reason: String) -> CorruptObject {
+    CorruptObject {
+        git_id: model.git_id.clone(),
+        object_type: model.object_type.clone(),
+        reason,
+    }
+}
+
+// the problem of a `git_obj` row whose data doesn't hash to its id
+fn check_object(model: &git_obj::Model) -> Option<CorruptObject> {
+    let Ok(object_type) = ObjectType::from_string(&model.object_type) else {
+        return Some(corrupt(model, "unknown object type".to_owned()));
+    };
+    let id = Meta::calculate_id(object_type, &model.data).to_plain_str();
+    (id != model.git_id).then(|| corrupt(model, format!("hashes to {}", id)))
+}
+
+// the problem of a row of the `commit` table whose commit doesn't hash to its id
+fn check_commit(model: &commit::Model) -> Option<CorruptObject> {
+    let problem = |reason: &str| {
+        Some(CorruptObject {
+            git_id: model.git_id.clone(),
+            object_type: ObjectType::Commit.to_string(),
+            reason: reason.to_owned(),
+        })
+    };
+    let kind = get_hash_kind();
+    if !kind.is_object_id(&model.tree) || !model.pid.iter().all(|id| kind.is_object_id(id)) {
+        return problem("invalid tree or parent id");
+    }
+    let signature = |data: &Option<String>| {
+        let data = data.as_ref()?;
+        Signature::new_from_data(data.as_bytes().to_vec()).ok()
+    };
+    let (Some(author), Some(committer)) = (signature(&model.author), signature(&model.committer))
+    else {
+        return problem("invalid author or committer");
+    };
+    let commit = Commit {
+        id: Hash::zero(kind),
+        tree_id: Hash::new_from_str(&model.tree),
+        parent_tree_ids: model.pid.iter().map(|id| Hash::new_from_str(id)).collect(),
+        author,
+        committer,
+        message: model.content.clone().unwrap_or_default(),
+    };
+    let id = Meta::calculate_id(ObjectType::Commit, &commit.get_raw()).to_plain_str();
+    if id != model.git_id {
+        return problem(&format!("hashes to {}", id));
+    }
+    None
+}
+
+// the objects `model` points to, `object_type` is what the object pointing to it expects
+fn follow_object(
+    model: &git_obj::Model,
+    object_type: ObjectType,
+) -> Result<Vec<Reachable>, String> {
+    if model.object_type != object_type.to_string() {
+        return Err(format!("expected a {}", object_type));
+    }
+    match object_type {
+        ObjectType::Tree => Ok(parse_tree(&model.data)?
+            .into_iter()
+            .map(|(object_type, id)| Reachable::new(&id, object_type, &model.git_id))
+            .collect()),
+        ObjectType::Tag => {
+            let tag = TagData::parse(&model.data).map_err(|e| e.to_string())?;
+            let object_type = ObjectType::from_string(&tag.object_type)
+                .map_err(|_| format!("tags an unknown type {}", tag.object_type))?;
+            if !get_hash_kind().is_object_id(&tag.object) {
+                return Err(format!("tags an invalid id {}", tag.object));
+            }
+            Ok(vec![Reachable::new(
+                &tag.object,
+                object_type,
+                &model.git_id,
+            )])
+        }
+        _ => Ok(vec![]),
+    }
+}
+
+// the types and ids of the entries of a tree, submodules are left out as their commits are
+// stored in other repos
+fn parse_tree(data: &[u8]) -> Result<Vec<(ObjectType, String)>, String> {
+    let hash_size = get_hash_kind().size();
+    let mut entries = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (Some(space), Some(nul)) = (
+            rest.iter().position(|b| *b == b' '),
+            rest.iter().position(|b| *b == b'\0'),
+        ) else {
+            return Err("truncated tree entry".to_owned());
+        };
+        let end = nul + 1 + hash_size;
+        if space > nul || rest.len() < end {
+            return Err("truncated tree entry".to_owned());
+        }
+        let id = Hash::new_from_bytes(&rest[nul + 1..end]).to_plain_str();
+        match &rest[..space] {
+            b"40000" => entries.push((ObjectType::Tree, id)),
+            b"100644" | b"100755" | b"100664" | b"120000" => entries.push((ObjectType::Blob, id)),
+            b"160000" => {}
+            mode => return Err(format!("invalid mode {}", String::from_utf8_lossy(mode))),
+        }
+        rest = &rest[end..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use entity::git_obj;
+
+    use super::{check_object, parse_tree, CorruptObject, FsckReport};
+    use crate::internal::object::meta::Meta;
+    use crate::internal::ObjectType;
+
+    fn object(object_type: ObjectType, data: &[u8]) -> git_obj::Model {
+        git_obj::Model {
+            id: 0,
+            git_id: Meta::calculate_id(object_type, &data.to_vec()).to_plain_str(),
+            object_type: object_type.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_check_object() {
+        let mut blob = object(ObjectType::Blob, b"hello world\n");
+        assert_eq!(blob.git_id, "3b18e512dba79e4c8300dd08aeb37f8e728b8dad");
+        assert_eq!(check_object(&blob), None);
+
+        blob.data = b"hello world!\n".to_vec();
+        let problem = check_object(&blob).unwrap();
+        assert_eq!(problem.git_id, "3b18e512dba79e4c8300dd08aeb37f8e728b8dad");
+        assert!(problem.reason.starts_with("hashes to "));
+
+        blob.object_type = "note".to_owned();
+        assert_eq!(check_object(&blob).unwrap().reason, "unknown object type");
+    }
+
+    #[test]
+    fn test_parse_tree() {
+        let blob_id = [0x3bu8; 20];
+        let tree_id = [0x4bu8; 20];
+        let mut data = b"100644 README.md\0".to_vec();
+        data.extend(blob_id);
+        data.extend(b"40000 src\0");
+        data.extend(tree_id);
+        data.extend(b"160000 vendor\0");
+        data.extend([0x5bu8; 20]);
+        let entries = parse_tree(&data).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (ObjectType::Blob, hex::encode(blob_id)),
+                (ObjectType::Tree, hex::encode(tree_id)),
+            ]
+        );
+
+        assert_eq!(
+            parse_tree(&data[..data.len() - 1]).unwrap_err(),
+            "truncated tree entry"
+        );
+        let mut data = b"040000 src\0".to_vec();
+        data.extend(tree_id);
+        assert_eq!(parse_tree(&data).unwrap_err(), "invalid mode 040000");
+    }
+
+    #[test]
+    fn test_report_json() {
+        let mut report = FsckReport {
+            repo_path: "/projects/mega".to_owned(),
+            ..Default::default()
+        };
+        assert!(report.is_ok());
+        report.corrupt_objects.push(CorruptObject {
+            git_id: "3b18e512dba79e4c8300dd08aeb37f8e728b8dad".to_owned(),
+            object_type: "blob".to_owned(),
+            reason: "unknown object type".to_owned(),
+        });
+        assert!(!report.is_ok());
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["repo_path"], "/projects/mega");
+        assert_eq!(json["corrupt_objects"][0]["object_type"], "blob");
+        assert_eq!(json["missing_objects"], serde_json::json!([]));
+    }
+}
@@ -21,7 +21,11 @@ use crate::{
 use self::nodes::{FileNode, Node, TreeNode};
 
 pub mod conversion;
+pub mod filter;
+pub mod fsck;
 pub mod nodes;
+pub mod symrefs;
+pub mod tags;
 /// only blob and tree should implement this trait
 pub trait GitNodeObject {
     fn convert_to_node(
@@ -167,7 +171,7 @@ impl TreeItem {
         };
         TreeItem {
             mode,
-            id: Hash::new_from_bytes(model.git_id.as_bytes()),
+            id: Hash::new_from_str(&model.git_id),
             name: model.name.unwrap(),
         }
     }
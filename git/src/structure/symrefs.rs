@@ -0,0 +1,97 @@
+//!
+//! Symbolic refs, the refs that point to another ref instead of an object. The only one a repo
+//! has is `HEAD`, which names its default branch, the branch a clone checks out.
+//!
+//! `HEAD` is kept in the `symref` table once the default branch has been chosen. Until then,
+//! or once that branch is deleted, it points to `main` or `master` if the repo has one of them,
+//! else to its first branch.
+//!
+
+use std::path::Path;
+
+use crate::errors::GitError;
+use crate::protocol::PackProtocol;
+
+pub const HEAD: &str = "HEAD";
+
+// the branches HEAD points to when the repo has them, in order of preference
+const DEFAULT_BRANCHES: [&str; 2] = ["refs/heads/main", "refs/heads/master"];
+
+impl PackProtocol {
+    /// The branch `HEAD` points to, e.g. `refs/heads/main`. Returns `None` if the repo has no
+    /// branch, such as a subdirectory of another repo.
+    pub async fn get_head_ref(&self, repo_path: &Path) -> Option<String> {
+        let path_str = repo_path.to_str().unwrap();
+        let git_refs = self.storage.get_ref_object_id(path_str).await.unwrap();
+        let branches: Vec<&str> = git_refs
+            .iter()
+            .map(|git_ref| git_ref.ref_name.as_str())
+            .filter(|name| name.starts_with("refs/heads/"))
+            .collect();
+        let symref = self.storage.get_symref(path_str, HEAD).await.unwrap();
+        head_branch(symref.as_ref().map(|s| s.target.as_str()), branches).map(str::to_owned)
+    }
+
+    /// # Changes the default branch of a repo.
+    ///
+    /// `branch` is either a branch name or the full name of its ref, the branch must exist.
+    /// Returns the ref `HEAD` now points to.
+    pub async fn set_default_branch(
+        &self,
+        repo_path: &Path,
+        branch: &str,
+    ) -> Result<String, GitError> {
+        let target = if branch.starts_with("refs/heads/") {
+            branch.to_owned()
+        } else {
+            format!("refs/heads/{}", branch)
+        };
+        let path_str = repo_path.to_str().unwrap();
+        let git_refs = self.storage.get_ref_object_id(path_str).await.unwrap();
+        if !git_refs.iter().any(|git_ref| git_ref.ref_name == target) {
+            return Err(GitError::UnknownBranch(branch.to_owned()));
+        }
+        self.storage
+            .save_symref(path_str, HEAD, &target)
+            .await
+            .unwrap();
+        Ok(target)
+    }
+}
+
+// the stored target if it's still a branch, else the preferred default branch
+fn head_branch<'a>(symref: Option<&'a str>, mut branches: Vec<&'a str>) -> Option<&'a str> {
+    if let Some(target) = symref.filter(|target| branches.contains(target)) {
+        return Some(target);
+    }
+    branches.sort();
+    DEFAULT_BRANCHES
+        .into_iter()
+        .find(|name| branches.contains(name))
+        .or_else(|| branches.first().copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::head_branch;
+
+    #[test]
+    fn test_head_branch() {
+        let branches = vec!["refs/heads/dev", "refs/heads/master", "refs/heads/main"];
+        assert_eq!(head_branch(None, branches.clone()), Some("refs/heads/main"));
+        assert_eq!(
+            head_branch(Some("refs/heads/dev"), branches.clone()),
+            Some("refs/heads/dev")
+        );
+        // the stored branch has been deleted
+        assert_eq!(
+            head_branch(Some("refs/heads/old"), branches),
+            Some("refs/heads/main")
+        );
+        assert_eq!(
+            head_branch(None, vec!["refs/heads/b", "refs/heads/a"]),
+            Some("refs/heads/a")
+        );
+        assert_eq!(head_branch(Some("refs/heads/main"), vec![]), None);
+    }
+}
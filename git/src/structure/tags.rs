@@ -0,0 +1,183 @@
+//!
+//! The tags of a repo, kept in the `tag` table so that they can be listed without reading
+//! their objects.
+//!
+//! A tag is a ref under `refs/tags/`. An annotated tag points to a tag object, which holds the
+//! tagger and the message and points to the tagged object in turn, usually a commit. A
+//! lightweight tag points to the tagged object directly. Either way the table keeps the
+//! tagged object "peeled", i.e. with all the tag objects in between followed.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use database::driver::RefUpdate;
+use entity::tag;
+use sea_orm::ActiveValue::NotSet;
+use sea_orm::Set;
+
+use crate::errors::GitError;
+use crate::protocol::{CommandType, PackProtocol, RefCommand};
+
+// a tag of a tag of ... is followed that many times at most
+const MAX_TAG_DEPTH: usize = 16;
+
+/// The fields of a tag object, read without panicking on the tags that have no tagger.
+#[derive(Debug, Default, PartialEq)]
+pub struct TagData {
+    pub object: String,
+    pub object_type: String,
+    pub tagger: Option<String>,
+    pub message: String,
+}
+
+impl TagData {
+    pub fn parse(data: &[u8]) -> Result<TagData, GitError> {
+        let data = String::from_utf8_lossy(data);
+        let (headers, message) = data.split_once("\n\n").unwrap_or((&data, ""));
+        let mut tag = TagData {
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        for line in headers.lines() {
+            match line.split_once(' ') {
+                Some(("object", id)) => tag.object = id.to_owned(),
+                Some(("type", object_type)) => tag.object_type = object_type.to_owned(),
+                Some(("tagger", tagger)) => tag.tagger = Some(tagger.to_owned()),
+                _ => {}
+            }
+        }
+        if tag.object.is_empty() || tag.object_type.is_empty() {
+            return Err(GitError::InvalidTagObject(data.into_owned()));
+        }
+        Ok(tag)
+    }
+}
+
+impl PackProtocol {
+    /// # The ref update of a command of a push.
+    ///
+    /// A created or moved tag comes with its row of the `tag` table, so that the row is
+    /// replaced in the same transaction as the ref.
+    pub async fn new_ref_update(&self, command: &RefCommand) -> Result<RefUpdate, GitError> {
+        let mut update = command.to_ref_update();
+        if let Some(tag_name) = command.ref_name.strip_prefix("refs/tags/") {
+            if command.command_type != CommandType::Delete {
+                update.tag = Some(self.new_tag_model(tag_name, &command.new_id).await?);
+            }
+        }
+        Ok(update)
+    }
+
+    // follows the tag objects from `tag_id` down to the tagged object
+    async fn new_tag_model(
+        &self,
+        tag_name: &str,
+        tag_id: &str,
+    ) -> Result<tag::ActiveModel, GitError> {
+        let mut object_id = tag_id.to_owned();
+        let mut object_type = String::from("tag");
+        let mut annotation = None;
+        for _ in 0..MAX_TAG_DEPTH {
+            object_type = match self.storage.get_obj_data_by_id(&object_id).await.unwrap() {
+                Some(model) if model.object_type == "tag" => {
+                    let data = TagData::parse(&model.data)?;
+                    object_id = data.object.clone();
+                    annotation.get_or_insert(data);
+                    continue;
+                }
+                Some(model) => model.object_type,
+                // the commits generated for subdirectories only live in the commit table
+                None if self
+                    .storage
+                    .get_commit_by_hash(&object_id)
+                    .await
+                    .unwrap()
+                    .is_some() =>
+                {
+                    String::from("commit")
+                }
+                None => return Err(GitError::NotFountHashValue(object_id)),
+            };
+            break;
+        }
+        if object_type == "tag" {
+            return Err(GitError::InvalidTagObject(format!(
+                "{} is nested too deep",
+                tag_id
+            )));
+        }
+        let (tagger, message) = match annotation {
+            Some(data) => (data.tagger, Some(data.message)),
+            None => (None, None),
+        };
+        Ok(tag::ActiveModel {
+            id: NotSet,
+            repo_path: Set(self.path.to_str().unwrap().to_owned()),
+            tag_name: Set(tag_name.to_owned()),
+            tag_id: Set(tag_id.to_owned()),
+            object_id: Set(object_id),
+            object_type: Set(object_type),
+            tagger: Set(tagger),
+            message: Set(message),
+            created_at: Set(chrono::Utc::now().naive_utc()),
+        })
+    }
+
+    /// The annotated tags of a repo, keyed by their tag object id.
+    pub async fn get_annotated_tags(&self, repo_path: &Path) -> HashMap<String, tag::Model> {
+        self.storage
+            .get_tags_by_repo_path(repo_path.to_str().unwrap())
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|t| t.tag_id != t.object_id)
+            .map(|t| (t.tag_id.clone(), t))
+            .collect()
+    }
+
+    /// The peeled object of each annotated tag, keyed by the name of its ref. They are
+    /// advertised as `<object-id> refs/tags/<name>^{}` after the ref.
+    pub async fn get_peeled_refs(&self, repo_path: &Path) -> HashMap<String, String> {
+        self.get_annotated_tags(repo_path)
+            .await
+            .into_values()
+            .map(|t| (format!("refs/tags/{}", t.tag_name), t.object_id))
+            .collect()
+    }
+
+    /// Replaces the annotated tags among `want` with the objects they point to, the commit
+    /// walks only know about commits.
+    pub async fn peel_wants(&self, repo_path: &Path, want: &HashSet<String>) -> HashSet<String> {
+        peel(&self.get_annotated_tags(repo_path).await, want)
+    }
+}
+
+pub(crate) fn peel(tags: &HashMap<String, tag::Model>, want: &HashSet<String>) -> HashSet<String> {
+    want.iter()
+        .map(|id| tags.get(id).map_or(id, |t| &t.object_id).clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TagData;
+
+    #[test]
+    fn test_parse_tag_data() {
+        let data = b"object 4b00093bee9b3ef5afc5f8e3645dc39cfa2f49aa\ntype commit\ntag v.0.1.0\n\
+            tagger Quanyi Ma <eli@patch.sh> 1678102132 +0800\n\nInit Mega project\n";
+        let tag = TagData::parse(data).unwrap();
+        assert_eq!(tag.object, "4b00093bee9b3ef5afc5f8e3645dc39cfa2f49aa");
+        assert_eq!(tag.object_type, "commit");
+        assert_eq!(
+            tag.tagger.as_deref(),
+            Some("Quanyi Ma <eli@patch.sh> 1678102132 +0800")
+        );
+        assert_eq!(tag.message, "Init Mega project\n");
+
+        let data = b"object 4b00093bee9b3ef5afc5f8e3645dc39cfa2f49aa\ntype commit\ntag v0\n\nold\n";
+        assert_eq!(TagData::parse(data).unwrap().tagger, None);
+        assert!(TagData::parse(b"tag v0\n\nno object\n").is_err());
+    }
+}
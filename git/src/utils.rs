@@ -9,7 +9,7 @@ use std::{
     vec,
 };
 
-use crate::hash::Hash;
+use crate::hash::{get_hash_kind, Hash};
 
 const TYPE_BITS: u8 = 3;
 const VAR_INT_ENCODING_BITS: u8 = 7;
@@ -67,14 +67,15 @@ pub fn read_chars<R: Read>(input: &mut R, buf: &mut Vec<u8>, size: usize) -> io:
     buf.resize(bytes_read, 0); // Resize the buffer to the actual number of bytes read
     Ok(())
 }
-/// Read a hash from the reader
+/// Read a hash of the current object format from the reader
 ///
 ///
 #[inline]
 pub fn read_hash<R: Read>(stream: &mut R) -> io::Result<Hash> {
-    let bytes = read_bytes(stream)?;
+    let mut bytes = vec![0; get_hash_kind().size()];
+    stream.read_exact(&mut bytes)?;
 
-    Ok(Hash(bytes))
+    Ok(Hash::new_from_bytes(&bytes))
 }
 
 /// Read a vec until the delimiter is read
@@ -203,10 +204,11 @@ pub fn write_offset_encoding(number: u64) -> Vec<u8> {
     num.push((number & 0x7f) as u8);
     number >>= 7;
 
-    // Encode the remaining bits in subsequent bytes
+    // Encode the remaining bits in subsequent bytes, minus 1 as the decoder adds 1 to each
     while number > 0 {
+        number -= 1;
         // Set the most significant bit to indicate continuation
-        num.push(((number & 0x7f) - 1) as u8 | 0x80);
+        num.push((number & 0x7f) as u8 | 0x80);
         number >>= 7;
     }
 
@@ -329,7 +331,7 @@ pub fn get_offset<R: Seek>(file: &mut R) -> io::Result<u64> {
 ///
 ///
 pub fn get_pack_raw_data(data: Vec<u8>) -> Vec<u8> {
-    let result = &data[12..data.len() - 20];
+    let result = &data[12..data.len() - get_hash_kind().size()];
     result.to_vec()
 }
 
@@ -406,4 +408,17 @@ mod test {
         get_env_number("GIT_INTERNAL_DECODE_STORAGE_BATCH_SIZE", &mut batch_size);
         assert_eq!(batch_size, 10000);
     }
+
+    #[test]
+    fn test_offset_encoding() {
+        assert_eq!(write_offset_encoding(127), vec![0x7f]);
+        assert_eq!(write_offset_encoding(128), vec![0x80, 0x00]);
+        assert_eq!(write_offset_encoding(16384), vec![0xff, 0x00]);
+        for offset in [0, 1, 300, 16383, 16511, 16512, 1 << 21, u32::MAX as u64] {
+            let data = write_offset_encoding(offset);
+            let mut consume = 0;
+            let read = read_offset_encoding(&mut data.as_slice(), &mut consume).unwrap();
+            assert_eq!((read, consume), (offset, data.len()));
+        }
+    }
 }
@@ -397,7 +397,7 @@ pub async fn git_info_refs_event_handler(
                     let _git_ids = response.1;
                     tracing::info!("repo_name: {}", repo_name);
                     tracing::info!("ref_git_id: {:?}", ref_git_id);
-                    if ref_git_id == *utils::ZERO_ID {
+                    if utils::is_zero_id(&ref_git_id) {
                         eprintln!("Repo not found");
                         return;
                     }
@@ -615,7 +615,7 @@ async fn git_upload_pack_handler(
 ) -> Result<(Vec<u8>, String), String> {
     let pack_protocol = get_pack_protocol(path, client_paras.storage.clone()).await;
     let object_id = pack_protocol.get_head_object_id(Path::new(path)).await;
-    if object_id == *utils::ZERO_ID {
+    if utils::is_zero_id(&object_id) {
         return Err("Repository not found".to_string());
     }
     tracing::info!("object_id:{}", object_id);
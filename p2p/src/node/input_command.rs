@@ -131,7 +131,7 @@ pub async fn handle_mega_command(
             let path = get_repo_full_path(&repo_name);
             let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone()).await;
             let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
-            if object_id == *utils::ZERO_ID {
+            if utils::is_zero_id(&object_id) {
                 eprintln!("Repository not found");
                 return;
             }
@@ -221,7 +221,7 @@ pub async fn handle_mega_command(
             let path = get_repo_full_path(repo_name);
             let pack_protocol = get_pack_protocol(&path, client_paras.storage.clone()).await;
             let object_id = pack_protocol.get_head_object_id(Path::new(&path)).await;
-            if object_id == *utils::ZERO_ID {
+            if utils::is_zero_id(&object_id) {
                 eprintln!("local repo not found");
                 return;
             }
@@ -1,10 +1,10 @@
 
 CREATE TABLE IF NOT EXISTS `commit` (
   `id` int NOT NULL AUTO_INCREMENT,
-  `git_id` varchar(40) NOT NULL,
-  `tree` varchar(40) NOT NULL,
+  `git_id` varchar(64) NOT NULL,
+  `tree` varchar(64) NOT NULL,
   -- fix array arguments later 
-  `pid` varchar(40) DEFAULT NULL,
+  `pid` varchar(64) DEFAULT NULL,
   `repo_path` varchar(128) NOT NULL,
   `author` TINYTEXT DEFAULT NULL,
   `committer` TINYTEXT DEFAULT NULL,
@@ -25,7 +25,7 @@ CREATE TABLE IF NOT EXISTS `node` (
   `node_type` varchar(16) NOT NULL,
   `name` varchar(128) DEFAULT NULL,
   `mode` blob NOT NULL,
-  `content_sha` varchar(40) DEFAULT NULL,
+  `content_sha` varchar(64) DEFAULT NULL,
   `size` INT NOT NULL,
   `data` mediumblob NOT NULL,
   `repo_path` VARCHAR(256) NOT NULL,
@@ -43,17 +43,96 @@ CREATE TABLE IF NOT EXISTS `refs` (
   `id` int NOT NULL AUTO_INCREMENT,
   `repo_path` varchar(64) NOT NULL,
   `ref_name` varchar(32) NOT NULL,
-  `ref_git_id` varchar(40) NOT NULL,
+  `ref_git_id` varchar(64) NOT NULL,
   `created_at` datetime NOT NULL,
   `updated_at` datetime NOT NULL,
   PRIMARY KEY (`id`)
 );
 
 
+CREATE TABLE IF NOT EXISTS `symref` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `repo_path` varchar(128) NOT NULL,
+  `ref_name` varchar(128) NOT NULL,
+  `target` varchar(128) NOT NULL,
+  `created_at` datetime NOT NULL,
+  `updated_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `uniq_symref_name` (`repo_path`, `ref_name`)
+);
+
+
+CREATE TABLE IF NOT EXISTS `protected_ref` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `repo_path` varchar(128) NOT NULL,
+  `ref_pattern` varchar(128) NOT NULL,
+  -- fix array arguments later
+  `allowed_pushers` TEXT NOT NULL,
+  `allow_force_push` BOOLEAN NOT NULL,
+  `allow_deletion` BOOLEAN NOT NULL,
+  `require_linear_history` BOOLEAN NOT NULL,
+  `created_at` datetime NOT NULL,
+  `updated_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `uniq_protected_ref_pattern` (`repo_path`, `ref_pattern`)
+);
+
+
+CREATE TABLE IF NOT EXISTS `ssh_key` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `user_name` varchar(64) NOT NULL,
+  `title` varchar(128) NOT NULL,
+  `public_key` TEXT NOT NULL,
+  `fingerprint` varchar(64) NOT NULL,
+  `created_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `uniq_ssh_key_fingerprint` (`fingerprint`),
+  KEY `idx_ssh_key_user_name` (`user_name`)
+);
+
+
+CREATE TABLE IF NOT EXISTS `access_token` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `user_name` varchar(64) NOT NULL,
+  `name` varchar(128) NOT NULL,
+  `token_hash` varchar(64) NOT NULL,
+  `created_at` datetime NOT NULL,
+  `expires_at` datetime DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `uniq_access_token_hash` (`token_hash`),
+  KEY `idx_access_token_user_name` (`user_name`)
+);
+
+
+CREATE TABLE IF NOT EXISTS `object_format` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `repo_path` varchar(128) NOT NULL,
+  `format` varchar(16) NOT NULL,
+  `created_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `uniq_object_format_repo_path` (`repo_path`)
+);
+
+
+CREATE TABLE IF NOT EXISTS `tag` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `repo_path` varchar(128) NOT NULL,
+  `tag_name` varchar(128) NOT NULL,
+  `tag_id` varchar(64) NOT NULL,
+  `object_id` varchar(64) NOT NULL,
+  `object_type` varchar(16) NOT NULL,
+  `tagger` TEXT DEFAULT NULL,
+  `message` TEXT DEFAULT NULL,
+  `created_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `idx_tag_repo_path` (`repo_path`)
+);
+
+
 CREATE TABLE IF NOT EXISTS `mr` (
   `id` BIGINT NOT NULL,
   `mr_id` BIGINT NOT NULL,
-  `git_id` varchar(40) NOT NULL,
+  `git_id` varchar(64) NOT NULL,
   `object_type` varchar(16) NOT NULL,
   `created_at` datetime NOT NULL,
   PRIMARY KEY (`id`),
@@ -64,7 +143,7 @@ CREATE TABLE IF NOT EXISTS `mr` (
 
 CREATE TABLE IF NOT EXISTS git_obj (
   `id` BIGINT NOT NULL,
-  `git_id` VARCHAR(40),
+  `git_id` VARCHAR(64),
   `object_type` VARCHAR(16),
   `data` mediumblob NOT NULL,
   PRIMARY KEY (`id`),
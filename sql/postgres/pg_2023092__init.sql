@@ -1,8 +1,8 @@
 
 CREATE TABLE IF NOT EXISTS "commit" (
   "id" SERIAL PRIMARY KEY,
-  "git_id" VARCHAR(40) NOT NULL,
-  "tree" VARCHAR(40) NOT NULL,
+  "git_id" VARCHAR(64) NOT NULL,
+  "tree" VARCHAR(64) NOT NULL,
   "pid" TEXT[],
   "repo_path" VARCHAR(128) NOT NULL,
   "author" TEXT,
@@ -19,12 +19,12 @@ CREATE INDEX "idx_c_repo_path" ON "commit" ("repo_path");
 CREATE TABLE IF NOT EXISTS "node" (
   "id" BIGSERIAL PRIMARY KEY,
   "node_id" BIGINT NOT NULL,
-  "git_id" VARCHAR(40) NOT NULL,
-  "last_commit" VARCHAR(40) NOT NULL,
+  "git_id" VARCHAR(64) NOT NULL,
+  "last_commit" VARCHAR(64) NOT NULL,
   "node_type" VARCHAR(16) NOT NULL,
   "name" VARCHAR(128),
   "mode" BYTEA NOT NULL,
-  "content_sha" VARCHAR(40),
+  "content_sha" VARCHAR(64),
   "size" INT NOT NULL,
   "repo_path" VARCHAR(256) NOT NULL,
   "full_path" VARCHAR(512) NOT NULL,
@@ -40,17 +40,88 @@ CREATE TABLE IF NOT EXISTS "refs" (
   "id" SERIAL PRIMARY KEY,
   "repo_path" VARCHAR(64) NOT NULL,
   "ref_name" VARCHAR(32) NOT NULL,
-  "ref_git_id" VARCHAR(40) NOT NULL,
+  "ref_git_id" VARCHAR(64) NOT NULL,
   "created_at" TIMESTAMP NOT NULL,
   "updated_at" TIMESTAMP NOT NULL
 );
 
 
+CREATE TABLE IF NOT EXISTS "symref" (
+  "id" SERIAL PRIMARY KEY,
+  "repo_path" VARCHAR(128) NOT NULL,
+  "ref_name" VARCHAR(128) NOT NULL,
+  "target" VARCHAR(128) NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  "updated_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_symref_name UNIQUE ("repo_path", "ref_name")
+);
+
+
+CREATE TABLE IF NOT EXISTS "protected_ref" (
+  "id" SERIAL PRIMARY KEY,
+  "repo_path" VARCHAR(128) NOT NULL,
+  "ref_pattern" VARCHAR(128) NOT NULL,
+  "allowed_pushers" TEXT[] NOT NULL,
+  "allow_force_push" BOOLEAN NOT NULL,
+  "allow_deletion" BOOLEAN NOT NULL,
+  "require_linear_history" BOOLEAN NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  "updated_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_protected_ref_pattern UNIQUE ("repo_path", "ref_pattern")
+);
+
+
+CREATE TABLE IF NOT EXISTS "ssh_key" (
+  "id" SERIAL PRIMARY KEY,
+  "user_name" VARCHAR(64) NOT NULL,
+  "title" VARCHAR(128) NOT NULL,
+  "public_key" TEXT NOT NULL,
+  "fingerprint" VARCHAR(64) NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_ssh_key_fingerprint UNIQUE ("fingerprint")
+);
+CREATE INDEX "idx_ssh_key_user_name" ON "ssh_key" ("user_name");
+
+
+CREATE TABLE IF NOT EXISTS "access_token" (
+  "id" SERIAL PRIMARY KEY,
+  "user_name" VARCHAR(64) NOT NULL,
+  "name" VARCHAR(128) NOT NULL,
+  "token_hash" VARCHAR(64) NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  "expires_at" TIMESTAMP,
+  CONSTRAINT uniq_access_token_hash UNIQUE ("token_hash")
+);
+CREATE INDEX "idx_access_token_user_name" ON "access_token" ("user_name");
+
+
+CREATE TABLE IF NOT EXISTS "object_format" (
+  "id" SERIAL PRIMARY KEY,
+  "repo_path" VARCHAR(128) NOT NULL,
+  "format" VARCHAR(16) NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_object_format_repo_path UNIQUE ("repo_path")
+);
+
+
+CREATE TABLE IF NOT EXISTS "tag" (
+  "id" SERIAL PRIMARY KEY,
+  "repo_path" VARCHAR(128) NOT NULL,
+  "tag_name" VARCHAR(128) NOT NULL,
+  "tag_id" VARCHAR(64) NOT NULL,
+  "object_id" VARCHAR(64) NOT NULL,
+  "object_type" VARCHAR(16) NOT NULL,
+  "tagger" TEXT,
+  "message" TEXT,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_tag_repo_path" ON "tag" ("repo_path");
+
 
 CREATE TABLE IF NOT EXISTS "mr" (
   "id" BIGINT NOT NULL,
   "mr_id" BIGINT NOT NULL,
-  "git_id" VARCHAR(40),
+  "git_id" VARCHAR(64),
   "object_type" VARCHAR(16),
   "created_at" TIMESTAMP NOT NULL,
   PRIMARY KEY ("id")
@@ -61,7 +132,7 @@ CREATE INDEX "idx_mr_id" ON "mr" ("mr_id", "object_type");
 
 CREATE TABLE IF NOT EXISTS "git_obj" (
   "id" BIGINT NOT NULL,
-  "git_id" VARCHAR(40),
+  "git_id" VARCHAR(64),
   "object_type" VARCHAR(16),
   "data" BYTEA,
   PRIMARY KEY ("id")
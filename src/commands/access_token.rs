@@ -0,0 +1,29 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use crate::cli::Config;
+use common::errors::MegaResult;
+use gateway::access_token::{self, AccessTokenOptions};
+
+pub fn cli() -> Command {
+    AccessTokenOptions::augment_args_for_update(
+        Command::new("access-token").about("Manage the access tokens accepted by the http server"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let access_token_matchers = AccessTokenOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    println!("{access_token_matchers:#?}");
+    access_token::run(&access_token_matchers).await.unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
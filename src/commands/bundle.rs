@@ -0,0 +1,29 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use crate::cli::Config;
+use common::errors::MegaResult;
+use gateway::bundle::{self, BundleOptions};
+
+pub fn cli() -> Command {
+    BundleOptions::augment_args_for_update(
+        Command::new("bundle").about("Export a repo to a git bundle or import one"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let bundle_matchers = BundleOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    println!("{bundle_matchers:#?}");
+    bundle::run(&bundle_matchers).await.unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
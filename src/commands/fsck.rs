@@ -0,0 +1,35 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use crate::cli::Config;
+use common::errors::{MegaError, MegaResult};
+use gateway::fsck::{self, FsckOptions};
+
+pub fn cli() -> Command {
+    FsckOptions::augment_args_for_update(
+        Command::new("fsck").about("Check the consistency of the stored objects of a repo"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let fsck_matchers = FsckOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    // the report is the output, the options aren't printed
+    let report = fsck::run(&fsck_matchers).await?;
+    if !report.is_ok() {
+        return Err(MegaError::new(
+            anyhow::anyhow!("{} is inconsistent", report.repo_path),
+            1,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
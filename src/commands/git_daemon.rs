@@ -0,0 +1,29 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use crate::cli::Config;
+use common::errors::MegaResult;
+use gateway::git_daemon::{self, GitDaemonOptions};
+
+pub fn cli() -> Command {
+    GitDaemonOptions::augment_args_for_update(
+        Command::new("git-daemon").about("Start Git daemon server for the git:// protocol"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let server_matchers = GitDaemonOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    println!("{server_matchers:#?}");
+    git_daemon::server(&server_matchers).await.unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
@@ -3,9 +3,14 @@
 //!
 //!
 //!
+mod access_token;
+mod bundle;
+mod fsck;
+mod git_daemon;
 mod https;
 mod p2p;
 mod ssh;
+mod ssh_key;
 mod mda;
 mod webhook;
 use clap::{ArgMatches, Command};
@@ -14,13 +19,29 @@ use crate::cli::Config;
 use common::errors::MegaResult;
 
 pub fn builtin() -> Vec<Command> {
-    vec![https::cli(), ssh::cli(), p2p::cli(),mda::cli(),webhook::cli()]
+    vec![
+        https::cli(),
+        ssh::cli(),
+        ssh_key::cli(),
+        access_token::cli(),
+        git_daemon::cli(),
+        bundle::cli(),
+        fsck::cli(),
+        p2p::cli(),
+        mda::cli(),
+        webhook::cli(),
+    ]
 }
 
 pub(crate) fn builtin_exec(cmd: &str) -> Option<fn(Config, &ArgMatches) -> MegaResult> {
     let f = match cmd {
         "https" => https::exec,
         "ssh" => ssh::exec,
+        "ssh-key" => ssh_key::exec,
+        "access-token" => access_token::exec,
+        "git-daemon" => git_daemon::exec,
+        "bundle" => bundle::exec,
+        "fsck" => fsck::exec,
         "p2p" => p2p::exec,
         "mda"=> mda::exec,
         "webhook" => webhook::exec,
@@ -0,0 +1,29 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use crate::cli::Config;
+use common::errors::MegaResult;
+use gateway::ssh_key::{self, SshKeyOptions};
+
+pub fn cli() -> Command {
+    SshKeyOptions::augment_args_for_update(
+        Command::new("ssh-key").about("Manage the public keys accepted by the ssh server"),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let ssh_key_matchers = SshKeyOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    println!("{ssh_key_matchers:#?}");
+    ssh_key::run(&ssh_key_matchers).await.unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}